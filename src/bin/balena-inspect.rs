@@ -0,0 +1,20 @@
+#[cfg(target_os = "linux")]
+fn main() {
+    use balena_migrate::{common::MigErrorKind, inspect};
+    if let Err(error) = inspect() {
+        match error.kind() {
+            MigErrorKind::Displayed => {
+                println!("balena-inspect failed with an error, see messages above");
+            }
+            _ => {
+                println!("balena-inspect failed with an error: {}", error);
+            }
+        }
+        std::process::exit(1);
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn main() {
+    println!("This program is only meant to be run on linux");
+}
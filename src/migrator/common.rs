@@ -25,8 +25,24 @@ pub(crate) mod path_info;
 
 pub(crate) mod file_digest;
 
+pub(crate) mod download;
+
+pub(crate) mod status_report;
+
+pub(crate) mod bundle;
+
+pub(crate) mod staging;
+
+pub(crate) mod bmap;
+
+pub(crate) mod file_type;
+
 pub(crate) mod disk_util;
 
+pub(crate) mod device_type_json;
+
+pub(crate) mod image_os_info;
+
 pub(crate) mod backup;
 
 pub(crate) mod migrate_info;
@@ -0,0 +1,362 @@
+use digest::{Digest, DynDigest};
+use failure::ResultExt;
+use lazy_static::lazy_static;
+use log::{debug, error, trace};
+use regex::Regex;
+use sha1::Sha1;
+use sha2::Sha256;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use crate::common::{file_exists, MigErrCtx, MigError, MigErrorKind};
+
+// *************************************************************************************************
+// * Block maps as created by bmaptool, list the ranges of an image that contain data.
+// * Versions 1.x and 2.0 of the format are supported, block ranges carry a sha1 or sha256 digest
+// * of their contents.
+// *************************************************************************************************
+
+const BMAP_EXT: &str = "bmap";
+// compression extensions stripped from the image name when looking for a block map
+const COMPRESSION_EXTS: &[&str] = &["gz", "xz", "zst", "zip"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ChecksumType {
+    Sha1,
+    Sha256,
+}
+
+impl ChecksumType {
+    pub fn get_descr(&self) -> &'static str {
+        match self {
+            ChecksumType::Sha1 => "sha1",
+            ChecksumType::Sha256 => "sha256",
+        }
+    }
+
+    pub fn new_hasher(&self) -> Box<dyn DynDigest> {
+        match self {
+            ChecksumType::Sha1 => Box::new(Sha1::new()),
+            ChecksumType::Sha256 => Box::new(Sha256::new()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BmapRange {
+    // first & last block of the range, inclusive
+    pub first: u64,
+    pub last: u64,
+    pub checksum: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BlockMap {
+    pub image_size: u64,
+    pub block_size: u64,
+    pub checksum_type: ChecksumType,
+    pub ranges: Vec<BmapRange>,
+}
+
+pub(crate) fn to_hex_string(digest: &[u8]) -> String {
+    let mut res = String::with_capacity(digest.len() * 2);
+    for byte in digest {
+        res.push_str(&format!("{:02x}", byte));
+    }
+    res
+}
+
+fn parse_error(path: &Path, msg: &str) -> MigError {
+    MigError::from_remark(
+        MigErrorKind::InvParam,
+        &format!("Invalid block map '{}': {}", path.display(), msg),
+    )
+}
+
+fn get_number(content: &str, path: &Path, regex: &Regex, name: &str) -> Result<u64, MigError> {
+    if let Some(captures) = regex.captures(content) {
+        Ok(captures
+            .get(1)
+            .unwrap()
+            .as_str()
+            .parse::<u64>()
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::InvParam,
+                &format!("Invalid value for '{}' in '{}'", name, path.display()),
+            ))?)
+    } else {
+        Err(parse_error(path, &format!("missing element '{}'", name)))
+    }
+}
+
+// look for image.bmap or the block map of the uncompressed image next to the image
+pub(crate) fn find_bmap_file<P: AsRef<Path>>(image_path: P) -> Option<PathBuf> {
+    let image_path = image_path.as_ref();
+    let file_name = image_path.file_name()?.to_string_lossy().to_string();
+
+    let mut candidates: Vec<String> = vec![format!("{}.{}", file_name, BMAP_EXT)];
+    if let Some(ext) = image_path.extension() {
+        if COMPRESSION_EXTS.contains(&ext.to_string_lossy().as_ref()) {
+            if let Some(stem) = image_path.file_stem() {
+                candidates.push(format!("{}.{}", stem.to_string_lossy(), BMAP_EXT));
+            }
+        }
+    }
+
+    candidates
+        .iter()
+        .map(|name| image_path.with_file_name(name))
+        .find(|path| file_exists(path))
+}
+
+impl BlockMap {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<BlockMap, MigError> {
+        let path = path.as_ref();
+        trace!("from_file: entered with '{}'", path.display());
+        let content = read_to_string(path).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to read block map from '{}'", path.display()),
+        ))?;
+        BlockMap::from_str(&content, path)
+    }
+
+    fn from_str(content: &str, path: &Path) -> Result<BlockMap, MigError> {
+        lazy_static! {
+            static ref VERSION_RE: Regex = Regex::new(r#"<bmap\s+version\s*=\s*"(\d+)\.(\d+)""#).unwrap();
+            static ref IMAGE_SIZE_RE: Regex =
+                Regex::new(r"<ImageSize>\s*(\d+)\s*</ImageSize>").unwrap();
+            static ref BLOCK_SIZE_RE: Regex =
+                Regex::new(r"<BlockSize>\s*(\d+)\s*</BlockSize>").unwrap();
+            static ref BLOCKS_COUNT_RE: Regex =
+                Regex::new(r"<BlocksCount>\s*(\d+)\s*</BlocksCount>").unwrap();
+            static ref MAPPED_BLOCKS_RE: Regex =
+                Regex::new(r"<MappedBlocksCount>\s*(\d+)\s*</MappedBlocksCount>").unwrap();
+            static ref CHECKSUM_TYPE_RE: Regex =
+                Regex::new(r"<ChecksumType>\s*(\S+)\s*</ChecksumType>").unwrap();
+            static ref FILE_CHECKSUM_RE: Regex =
+                Regex::new(r"<BmapFileChecksum>\s*([0-9a-fA-F]+)\s*</BmapFileChecksum>").unwrap();
+            static ref BLOCK_MAP_RE: Regex =
+                Regex::new(r"(?s)<BlockMap>(.*)</BlockMap>").unwrap();
+            static ref RANGE_RE: Regex = Regex::new(
+                r#"<Range(?:\s+chksum\s*=\s*"([0-9a-fA-F]+)")?\s*>\s*(\d+)(?:\s*-\s*(\d+))?\s*</Range>"#
+            )
+            .unwrap();
+        }
+
+        let (major, minor) = if let Some(captures) = VERSION_RE.captures(content) {
+            (
+                captures
+                    .get(1)
+                    .unwrap()
+                    .as_str()
+                    .parse::<u32>()
+                    .unwrap_or(0),
+                captures
+                    .get(2)
+                    .unwrap()
+                    .as_str()
+                    .parse::<u32>()
+                    .unwrap_or(0),
+            )
+        } else {
+            return Err(parse_error(path, "bmap version not found"));
+        };
+
+        if major != 1 && major != 2 {
+            return Err(parse_error(
+                path,
+                &format!("unsupported bmap version {}.{}", major, minor),
+            ));
+        }
+
+        let checksum_type = if let Some(captures) = CHECKSUM_TYPE_RE.captures(content) {
+            match captures.get(1).unwrap().as_str() {
+                "sha1" => ChecksumType::Sha1,
+                "sha256" => ChecksumType::Sha256,
+                checksum_type => {
+                    return Err(parse_error(
+                        path,
+                        &format!("unsupported checksum type '{}'", checksum_type),
+                    ));
+                }
+            }
+        } else if major == 1 {
+            // versions up to 1.3 imply sha1
+            ChecksumType::Sha1
+        } else {
+            return Err(parse_error(path, "missing element 'ChecksumType'"));
+        };
+
+        // the file checksum is computed with the checksum itself replaced by zeros
+        if let Some(captures) = FILE_CHECKSUM_RE.captures(content) {
+            let checksum = captures.get(1).unwrap();
+            let mut zeroed = String::from(&content[0..checksum.start()]);
+            zeroed.push_str(&"0".repeat(checksum.as_str().len()));
+            zeroed.push_str(&content[checksum.end()..]);
+
+            let mut hasher = checksum_type.new_hasher();
+            hasher.input(zeroed.as_bytes());
+            let computed = to_hex_string(&hasher.result());
+            if computed != checksum.as_str().to_lowercase() {
+                return Err(parse_error(
+                    path,
+                    &format!(
+                        "file checksum mismatch, expected {}, computed {}",
+                        checksum.as_str(),
+                        computed
+                    ),
+                ));
+            }
+            debug!("from_str: '{}' file checksum is ok", path.display());
+        }
+
+        let image_size = get_number(content, path, &IMAGE_SIZE_RE, "ImageSize")?;
+        let block_size = get_number(content, path, &BLOCK_SIZE_RE, "BlockSize")?;
+        let blocks_count = get_number(content, path, &BLOCKS_COUNT_RE, "BlocksCount")?;
+        let mapped_blocks = get_number(content, path, &MAPPED_BLOCKS_RE, "MappedBlocksCount")?;
+
+        if block_size == 0 || (image_size + block_size - 1) / block_size != blocks_count {
+            return Err(parse_error(
+                path,
+                &format!(
+                    "image size {} does not match {} blocks of {} bytes",
+                    image_size, blocks_count, block_size
+                ),
+            ));
+        }
+
+        let block_map = if let Some(captures) = BLOCK_MAP_RE.captures(content) {
+            captures.get(1).unwrap().as_str()
+        } else {
+            return Err(parse_error(path, "missing element 'BlockMap'"));
+        };
+
+        let mut ranges: Vec<BmapRange> = Vec::new();
+        let mut range_blocks: u64 = 0;
+        for captures in RANGE_RE.captures_iter(block_map) {
+            let first = captures
+                .get(2)
+                .unwrap()
+                .as_str()
+                .parse::<u64>()
+                .unwrap_or(0);
+            let last = if let Some(last) = captures.get(3) {
+                last.as_str().parse::<u64>().unwrap_or(0)
+            } else {
+                first
+            };
+
+            if last < first || last >= blocks_count {
+                return Err(parse_error(
+                    path,
+                    &format!("invalid block range {}-{}", first, last),
+                ));
+            }
+
+            if let Some(prev) = ranges.last() {
+                if first <= prev.last {
+                    return Err(parse_error(
+                        path,
+                        &format!("block ranges are not in ascending order at {}", first),
+                    ));
+                }
+            }
+
+            range_blocks += last - first + 1;
+            ranges.push(BmapRange {
+                first,
+                last,
+                checksum: captures.get(1).map(|val| val.as_str().to_lowercase()),
+            });
+        }
+
+        if range_blocks != mapped_blocks {
+            error!(
+                "The block map '{}' lists {} blocks in ranges, expected {}",
+                path.display(),
+                range_blocks,
+                mapped_blocks
+            );
+            return Err(parse_error(path, "mapped blocks count mismatch"));
+        }
+
+        debug!(
+            "from_str: '{}' version {}.{}, image size: {}, block size: {}, {} of {} blocks mapped in {} ranges, checksum: {}",
+            path.display(),
+            major,
+            minor,
+            image_size,
+            block_size,
+            mapped_blocks,
+            blocks_count,
+            ranges.len(),
+            checksum_type.get_descr()
+        );
+
+        Ok(BlockMap {
+            image_size,
+            block_size,
+            checksum_type,
+            ranges,
+        })
+    }
+
+    // byte offsets of a range in the image, start inclusive, end exclusive
+    pub fn get_range_bytes(&self, range: &BmapRange) -> (u64, u64) {
+        (
+            range.first * self.block_size,
+            ((range.last + 1) * self.block_size).min(self.image_size),
+        )
+    }
+
+    pub fn get_mapped_size(&self) -> u64 {
+        self.ranges
+            .iter()
+            .map(|range| {
+                let (start, end) = self.get_range_bytes(range);
+                end - start
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_BMAP: &str = "./test_data/part.img.bmap";
+
+    #[test]
+    fn parse_bmap() {
+        let bmap = BlockMap::from_file(TEST_BMAP).unwrap();
+        assert_eq!(bmap.image_size, 10_485_760);
+        assert_eq!(bmap.block_size, 4096);
+        assert_eq!(bmap.checksum_type, ChecksumType::Sha256);
+        assert_eq!(
+            bmap.ranges
+                .iter()
+                .map(|range| (range.first, range.last))
+                .collect::<Vec<(u64, u64)>>(),
+            vec![(0, 0), (1024, 1024), (1536, 1536)]
+        );
+        assert!(bmap.ranges.iter().all(|range| range.checksum.is_some()));
+        assert_eq!(bmap.get_mapped_size(), 3 * 4096);
+    }
+
+    #[test]
+    fn reject_modified_bmap() {
+        let content = read_to_string(TEST_BMAP)
+            .unwrap()
+            .replace("> 1536 <", "> 1535-1536 <");
+        assert!(BlockMap::from_str(&content, Path::new(TEST_BMAP)).is_err());
+    }
+
+    #[test]
+    fn find_bmap() {
+        assert_eq!(
+            find_bmap_file("./test_data/part.img.gz"),
+            Some(PathBuf::from("./test_data/part.img.bmap"))
+        );
+        assert_eq!(find_bmap_file("./test_data/gpt.img.gz"), None);
+    }
+}
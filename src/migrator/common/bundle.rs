@@ -0,0 +1,354 @@
+use failure::ResultExt;
+use log::{error, info, warn};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::fs::{read, read_to_string};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    common::{
+        bmap::to_hex_string, config::migrate_config::BundleCfg, file_digest::DigestManifest,
+        file_exists, path_append, MigErrCtx, MigError, MigErrorKind,
+    },
+    defs::BUNDLE_PUBLIC_KEY,
+};
+
+// *************************************************************************************************
+// * Signed migration bundles.
+// * A bundle is a digest manifest in sha256sum format covering the files used for migration and an
+// * Ed25519 signature over the manifest. The signature is checked against the public key compiled
+// * in or pinned in the migrate config, a pinned key that differs from the compiled in key is
+// * refused. Stage 1 checks the bundle and passes it on to stage 2 which checks it again against
+// * the compiled in key, stage 2 never takes the key from its own config.
+// *************************************************************************************************
+
+const DEFAULT_BUNDLE_MANIFEST: &str = "migrate-bundle.sums";
+const SIGNATURE_EXT: &str = ".sig";
+
+const ED25519_KEY_SIZE: usize = 32;
+const ED25519_SIG_SIZE: usize = 64;
+// DER header of an Ed25519 SubjectPublicKeyInfo as written by openssl
+const ED25519_SPKI_HEADER: &[u8] = &[
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct SignedBundle {
+    // manifest path relative to work_dir
+    manifest: PathBuf,
+    // the signed manifest
+    content: String,
+    // hex encoded signature
+    signature: String,
+}
+
+impl SignedBundle {
+    // load and check the bundle, a bundle is required once a public key is known
+    pub fn load<P: AsRef<Path>>(
+        work_dir: P,
+        config: Option<&BundleCfg>,
+    ) -> Result<Option<SignedBundle>, MigError> {
+        let work_dir = work_dir.as_ref();
+        let pinned_key = if let Some(config) = config {
+            config.public_key.as_deref()
+        } else {
+            None
+        };
+
+        let public_key = if let Some(public_key) = get_public_key(pinned_key)? {
+            public_key
+        } else if config.is_some() {
+            error!("A signed bundle was configured but no public key was given to check it with");
+            return Err(MigError::displayed());
+        } else {
+            return Ok(None);
+        };
+
+        let manifest = if let Some(manifest) = config.and_then(|cfg| cfg.manifest.as_ref()) {
+            manifest.clone()
+        } else {
+            PathBuf::from(DEFAULT_BUNDLE_MANIFEST)
+        };
+
+        let manifest_path = path_append(work_dir, &manifest);
+        if !file_exists(&manifest_path) {
+            error!(
+                "The signed bundle manifest '{}' could not be found, unsigned bundles are refused",
+                manifest_path.display()
+            );
+            return Err(MigError::displayed());
+        }
+
+        let sig_path = if let Some(signature) = config.and_then(|cfg| cfg.signature.as_ref()) {
+            path_append(work_dir, signature)
+        } else {
+            PathBuf::from(format!("{}{}", manifest_path.display(), SIGNATURE_EXT))
+        };
+
+        if !file_exists(&sig_path) {
+            error!(
+                "The signature '{}' could not be found, unsigned bundles are refused",
+                sig_path.display()
+            );
+            return Err(MigError::displayed());
+        }
+
+        let content = read_to_string(&manifest_path).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "Failed to read bundle manifest '{}'",
+                manifest_path.display()
+            ),
+        ))?;
+
+        let signature = decode_signature(&read(&sig_path).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to read signature '{}'", sig_path.display()),
+        ))?)?;
+
+        let bundle = SignedBundle {
+            manifest,
+            content,
+            signature: to_hex_string(&signature),
+        };
+
+        bundle.verify_with(&public_key)?;
+        info!(
+            "The signature of bundle manifest '{}' is valid",
+            manifest_path.display()
+        );
+        Ok(Some(bundle))
+    }
+
+    // check the signature against the compiled in key and return the signed digests
+    pub fn verify(&self) -> Result<DigestManifest, MigError> {
+        if let Some(public_key) = get_public_key(None)? {
+            self.verify_with(&public_key)
+        } else {
+            warn!(
+                "No public key is compiled in, using the digests of bundle manifest '{}' as checked by stage 1",
+                self.manifest.display()
+            );
+            DigestManifest::from_str(&self.manifest, &self.content)
+        }
+    }
+
+    fn verify_with(&self, public_key: &[u8]) -> Result<DigestManifest, MigError> {
+        let signature = decode_text(&self.signature)?;
+        if !verify_signature(self.content.as_bytes(), &signature, public_key) {
+            error!(
+                "The signature of bundle manifest '{}' is invalid, refusing to migrate",
+                self.manifest.display()
+            );
+            return Err(MigError::displayed());
+        }
+
+        DigestManifest::from_str(&self.manifest, &self.content)
+    }
+}
+
+fn verify_signature(content: &[u8], signature: &[u8], public_key: &[u8]) -> bool {
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(content, signature)
+        .is_ok()
+}
+
+// a key pinned in config has to match the compiled in key, a mismatch is refused
+fn get_public_key(pinned: Option<&str>) -> Result<Option<Vec<u8>>, MigError> {
+    let pinned = if let Some(key) = pinned {
+        Some(decode_public_key(key)?)
+    } else {
+        None
+    };
+
+    let compiled = if let Some(key) = BUNDLE_PUBLIC_KEY {
+        Some(decode_public_key(key)?)
+    } else {
+        None
+    };
+
+    match (compiled, pinned) {
+        (Some(compiled), Some(pinned)) => {
+            if pinned != compiled {
+                error!(
+                    "The bundle public key '{}' in the config differs from the compiled in key '{}', refusing to migrate",
+                    to_hex_string(&pinned),
+                    to_hex_string(&compiled)
+                );
+                return Err(MigError::displayed());
+            }
+            info!(
+                "Checking the bundle with the compiled in public key '{}', it matches the key in the config",
+                to_hex_string(&compiled)
+            );
+            Ok(Some(compiled))
+        }
+        (Some(compiled), None) => {
+            info!(
+                "Checking the bundle with the compiled in public key '{}'",
+                to_hex_string(&compiled)
+            );
+            Ok(Some(compiled))
+        }
+        (None, Some(pinned)) => {
+            info!(
+                "Checking the bundle with the public key '{}' from the config",
+                to_hex_string(&pinned)
+            );
+            Ok(Some(pinned))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+fn decode_public_key(key: &str) -> Result<Vec<u8>, MigError> {
+    let key = decode_text(key)?;
+    let key = if key.len() == ED25519_SPKI_HEADER.len() + ED25519_KEY_SIZE
+        && key.starts_with(ED25519_SPKI_HEADER)
+    {
+        key[ED25519_SPKI_HEADER.len()..].to_vec()
+    } else {
+        key
+    };
+
+    if key.len() == ED25519_KEY_SIZE {
+        Ok(key)
+    } else {
+        Err(MigError::from_remark(
+            MigErrorKind::InvParam,
+            &format!("Invalid Ed25519 public key size: {} bytes", key.len()),
+        ))
+    }
+}
+
+// signatures are accepted raw or hex / base64 encoded
+fn decode_signature(data: &[u8]) -> Result<Vec<u8>, MigError> {
+    let signature = if data.len() == ED25519_SIG_SIZE {
+        data.to_vec()
+    } else if let Ok(text) = std::str::from_utf8(data) {
+        decode_text(text)?
+    } else {
+        data.to_vec()
+    };
+
+    if signature.len() == ED25519_SIG_SIZE {
+        Ok(signature)
+    } else {
+        Err(MigError::from_remark(
+            MigErrorKind::InvParam,
+            &format!("Invalid Ed25519 signature size: {} bytes", signature.len()),
+        ))
+    }
+}
+
+// decode hex or base64, PEM armor lines are skipped
+fn decode_text(text: &str) -> Result<Vec<u8>, MigError> {
+    let text: String = text
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+
+    if text.len() % 2 == 0 && text.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok((0..text.len())
+            .step_by(2)
+            .map(|idx| u8::from_str_radix(&text[idx..idx + 2], 16).unwrap())
+            .collect())
+    } else {
+        Ok(base64::decode(&text).context(MigErrCtx::from_remark(
+            MigErrorKind::InvParam,
+            "Failed to decode key or signature, expected hex or base64",
+        ))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{config::balena_config::FileRef, file_digest::HashInfo};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    const MANIFEST: &str =
+        "9be2a59a8286c8acacebd69a6ac1ff3caa02f3950f91ba2df1514f9f69f9495e  part.img.gz\n";
+
+    fn setup(name: &str, signed: &str) -> (PathBuf, BundleCfg) {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[7u8; 32]).unwrap();
+        let work_dir = path_append(std::env::temp_dir(), name);
+        let _res = remove_dir_all(&work_dir);
+        create_dir_all(&work_dir).unwrap();
+        write(path_append(&work_dir, DEFAULT_BUNDLE_MANIFEST), MANIFEST).unwrap();
+        write(
+            path_append(&work_dir, "migrate-bundle.sums.sig"),
+            base64::encode(key_pair.sign(signed.as_bytes()).as_ref()),
+        )
+        .unwrap();
+
+        let config = BundleCfg {
+            manifest: None,
+            signature: None,
+            public_key: Some(to_hex_string(key_pair.public_key().as_ref())),
+        };
+        (work_dir, config)
+    }
+
+    #[test]
+    fn signed_bundle() {
+        let (work_dir, config) = setup("balena-migrate-bundle-ok", MANIFEST);
+        let bundle = SignedBundle::load(&work_dir, Some(&config))
+            .unwrap()
+            .unwrap();
+        remove_dir_all(&work_dir).unwrap();
+
+        let manifest = bundle.verify().unwrap();
+        let file_ref = manifest
+            .require(&FileRef {
+                path: PathBuf::from("./test_data/part.img.gz"),
+                hash: None,
+            })
+            .unwrap();
+        assert_eq!(
+            file_ref.hash,
+            Some(HashInfo::Sha256(String::from(
+                "9be2a59a8286c8acacebd69a6ac1ff3caa02f3950f91ba2df1514f9f69f9495e"
+            )))
+        );
+        assert!(manifest
+            .require(&FileRef {
+                path: PathBuf::from("balena.zImage"),
+                hash: None,
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn tampered_bundle() {
+        let (work_dir, config) = setup("balena-migrate-bundle-tampered", "tampered");
+        let res = SignedBundle::load(&work_dir, Some(&config));
+        remove_dir_all(&work_dir).unwrap();
+        assert!(res.is_err());
+
+        let (work_dir, mut config) = setup("balena-migrate-bundle-unsigned", MANIFEST);
+        config.signature = Some(PathBuf::from("missing.sig"));
+        let res = SignedBundle::load(&work_dir, Some(&config));
+        remove_dir_all(&work_dir).unwrap();
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn tampered_config_bundle() {
+        let (work_dir, config) = setup("balena-migrate-bundle-config", MANIFEST);
+        let mut bundle = SignedBundle::load(&work_dir, Some(&config))
+            .unwrap()
+            .unwrap();
+        remove_dir_all(&work_dir).unwrap();
+
+        // the key pinned in stage 1 is not part of the bundle passed on to stage 2
+        let yaml = serde_yaml::to_string(&bundle).unwrap();
+        assert!(!yaml.contains(config.public_key.as_ref().unwrap()));
+
+        let public_key = decode_public_key(config.public_key.as_ref().unwrap()).unwrap();
+        bundle.content = MANIFEST.replace("9be2", "0000");
+        assert!(bundle.verify_with(&public_key).is_err());
+    }
+}
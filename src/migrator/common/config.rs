@@ -45,6 +45,9 @@ pub(crate) struct Config {
     pub migrate: MigrateConfig,
     pub balena: BalenaConfig,
     pub debug: DebugConfig,
+    // the config file read, if any
+    #[serde(skip)]
+    config_file: Option<PathBuf>,
 }
 
 impl<'a> Config {
@@ -169,6 +172,7 @@ impl<'a> Config {
         let mut config = if let Some(config_path) = config_path {
             if file_exists(&config_path) {
                 let mut config = Config::from_file(&config_path)?;
+                config.config_file = Some(config_path.clone());
                 // use config path as workdir if nothing other was defined
                 if !config.migrate.has_work_dir() && work_dir.is_none() {
                     config
@@ -228,6 +232,15 @@ impl<'a> Config {
             migrate: MigrateConfig::default(),
             balena: BalenaConfig::default(),
             debug: DebugConfig::default(),
+            config_file: None,
+        }
+    }
+
+    pub fn get_config_file(&'a self) -> Option<&'a Path> {
+        if let Some(ref val) = self.config_file {
+            Some(val)
+        } else {
+            None
         }
     }
 
@@ -317,6 +330,10 @@ mod tests {
             }
         );
         assert_eq!(config.migrate.get_fail_mode(), &FailMode::Reboot);
+        assert_eq!(
+            config.migrate.get_fail_mode_non_recoverable(),
+            &FailMode::PowerOff
+        );
         /*        assert_eq!(
                     config.migrate.get_force_slug(),
                     Some(String::from("dummy_device"))
@@ -454,6 +471,8 @@ migrate:
         filter: 'balena-.*'
   ## what to do on a recoverable fail in phase 2, either reboot or rescueshell
   fail_mode: Reboot
+  ## what to do on a non recoverable fail in phase 2
+  fail_mode_non_recoverable: PowerOff
   ## forced use of a device slug other than the one detected
   force_slug: 'dummy_device'
 balena:
@@ -484,6 +503,8 @@ debug:
   force_flash_device: '/dev/sdb'
   ## run migration up to phase2 but stop & reboot before flashing
   no_flash: true
+  ## flash to force_flash_device, do not restore boot config or reboot
+  simulate: false
 "###;
     const TEST_FS_CONFIG_OK: &str = r###"
 migrate:
@@ -519,12 +540,27 @@ migrate:
     - sprint
   # use internal gzip with dd
   gzip_internal: ~
+  # read back and compare the image after flashing
+  flash_verify: true
+  # flash attempts repeated if verification fails
+  flash_retries: 1
+  # discard the target and skip writing zero blocks if no block map is present
+  flash_skip_zeros: false
   # Extra kernel commandline options
   kernel_opts: "panic=20"
   # Use the given device instead of the boot device to flash to
   force_flash_device: ~
   # delay migration by n seconds - workaround for stem watchdog
   delay: 60
+  # seconds to wait for devices to show up in stage 2
+  mount_timeout: 30
+  # hooks run in stage 2 from the work directory
+  hooks:
+    - path: hooks/set-gpio.sh
+      stage: before_flash
+      args: ["17", "1"]
+      timeout: 10
+      on_fail: ignore
   # test kicking watchdogs - work in progress - not currently working
   watchdogs:
     # path to watchdog device
@@ -533,6 +569,30 @@ migrate:
       #  interval: ~
       # optional close, false disables MAGICCLOSE flag read from device
       # close: false
+  # progress events reported in stage 2
+  progress:
+    # minimum interval between events in seconds
+    interval: 10
+    sinks:
+      - log
+      # - console
+      # file on the log device
+      # - file: progress.log
+      # - serial: /dev/ttyS0
+  # download files referenced by URL
+  download:
+    max_size: 1073741824
+    timeout: 20
+  # post migration status reports
+  status_report:
+    url: "https://status.example.com/migrations"
+    token: secret
+  # digests missing from the config are looked up in this manifest in work_dir
+  digest_manifest: SHA256SUMS
+  # signed bundle checked with this key
+  bundle:
+    manifest: migrate-bundle.sums
+    public_key: ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c
 balena:
   image:
     # use filesystem writes instead of Flasher (dd)
@@ -1,6 +1,6 @@
 use super::MigMode;
 use crate::common::{file_digest::HashInfo, MigError, MigErrorKind};
-use log::debug;
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
@@ -62,10 +62,32 @@ pub(crate) struct ApiInfo {
     key: Option<String>,
 }
 
+// parameters used to generate config.json if none is supplied
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct ConfigJsonParams {
+    pub app_name: String,
+    pub app_id: u64,
+    // defaults to the detected device slug
+    pub device_type: Option<String>,
+    pub user_id: Option<u64>,
+    pub username: Option<String>,
+    // provisioning key
+    pub api_key: String,
+    // appUpdatePollInterval in ms
+    pub poll_interval: Option<u64>,
+    pub api_endpoint: Option<String>,
+    pub vpn_endpoint: Option<String>,
+    pub registry_endpoint: Option<String>,
+    pub delta_endpoint: Option<String>,
+    pub listen_port: Option<u16>,
+    pub vpn_port: Option<u16>,
+}
+
 #[derive(Debug, Deserialize)]
 pub(crate) struct BalenaConfig {
     image: Option<ImageType>,
     config: Option<FileRef>,
+    generate_config: Option<ConfigJsonParams>,
     app_name: Option<String>,
     api: Option<ApiInfo>,
     check_vpn: Option<bool>,
@@ -77,6 +99,7 @@ impl<'a> BalenaConfig {
         BalenaConfig {
             image: None,
             config: None,
+            generate_config: None,
             app_name: None,
             api: None,
             check_vpn: None,
@@ -97,17 +120,52 @@ impl<'a> BalenaConfig {
                 ));
             }
 
-            if self.config.is_none() {
+            if self.config.is_none() && self.generate_config.is_none() {
                 return Err(MigError::from_remark(
                     MigErrorKind::InvParam,
                     &format!(
-                        "{}::check: no config.json was specified in mode: IMMEDIATE",
+                        "{}::check: no config.json or generate_config was specified in mode: IMMEDIATE",
                         MODULE
                     ),
                 ));
             }
         }
 
+        if let Some(ref params) = self.generate_config {
+            if self.config.is_some() {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!(
+                        "{}::check: config and generate_config are mutually exclusive",
+                        MODULE
+                    ),
+                ));
+            }
+
+            if params.app_name.is_empty() {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!("{}::check: generate_config: app_name is empty", MODULE),
+                ));
+            }
+
+            if params.api_key.is_empty() {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!("{}::check: generate_config: api_key is empty", MODULE),
+                ));
+            }
+
+            if let Some(ref app_name) = self.app_name {
+                if *app_name != params.app_name {
+                    warn!(
+                        "The configured app_name '{}' differs from generate_config app_name '{}'",
+                        app_name, params.app_name
+                    );
+                }
+            }
+        }
+
         Ok(())
     }
 
@@ -134,6 +192,14 @@ impl<'a> BalenaConfig {
         }));
     }
 
+    pub fn get_config_params(&'a self) -> Option<&'a ConfigJsonParams> {
+        if let Some(ref params) = self.generate_config {
+            Some(params)
+        } else {
+            None
+        }
+    }
+
     // The following functions can only be safely called after check has succeeded
 
     pub fn get_image_path(&'a self) -> &'a ImageType {
@@ -11,6 +11,8 @@ pub(crate) struct DebugConfig {
     force_flash_device: Option<PathBuf>,
     // pretend mode, stop after unmounting former root
     no_flash: Option<bool>,
+    // simulate stage 2 against migrate.force_flash_device, do not restore boot config or reboot
+    simulate: Option<bool>,
 }
 
 impl<'a> DebugConfig {
@@ -19,6 +21,7 @@ impl<'a> DebugConfig {
             force_flash_device: None,
             // TODO: default to false when project is mature
             no_flash: None,
+            simulate: None,
         }
     }
 
@@ -31,6 +34,14 @@ impl<'a> DebugConfig {
         }
     }
 
+    pub fn is_simulate(&self) -> bool {
+        if let Some(val) = self.simulate {
+            val
+        } else {
+            false
+        }
+    }
+
     pub fn check(&self, _mig_mode: &MigMode) -> Result<(), MigError> {
         // TODO: implement
         Ok(())
@@ -3,7 +3,7 @@ use std::path::{Path, PathBuf};
 
 use crate::{
     common::{MigError, MigErrorKind},
-    defs::FailMode,
+    defs::{FailMode, DEFAULT_FLASH_RETRIES, DEFAULT_MOUNT_TIMEOUT},
 };
 
 use crate::common::config::balena_config::FileRef;
@@ -43,6 +43,10 @@ impl MigMode {
 
 const DEFAULT_MIG_MODE: MigMode = MigMode::Pretend;
 
+const DEFAULT_HOOK_TIMEOUT: u64 = 60;
+
+const NO_HOOKS: &[HookCfg] = &[];
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub(crate) enum UEnvStrategy {
     #[serde(rename = "uname")]
@@ -64,6 +68,132 @@ pub(crate) struct WatchdogCfg {
     pub close: Option<bool>,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub(crate) enum ProgressSinkCfg {
+    // stage 2 log
+    #[serde(rename = "log")]
+    Log,
+    // system console
+    #[serde(rename = "console")]
+    Console,
+    // file on the log device, one json event per line
+    #[serde(rename = "file")]
+    File(PathBuf),
+    // serial device, one json event per line
+    #[serde(rename = "serial")]
+    Serial(PathBuf),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct ProgressCfg {
+    // minimum interval between progress events in seconds
+    pub interval: Option<u64>,
+    pub sinks: Vec<ProgressSinkCfg>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct DownloadCfg {
+    // maximum size of a downloaded file in bytes
+    pub max_size: Option<u64>,
+    // connect & read timeout in seconds
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct StatusReportCfg {
+    // status reports are posted to this http(s) URL
+    pub url: String,
+    // sent as bearer token
+    pub token: Option<String>,
+    // identifies the device to the backend, defaults to the host name
+    pub device_id: Option<String>,
+    // connect & read timeout in seconds
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub(crate) enum HookStage {
+    // files are copied, balena OS has not been written yet
+    #[serde(rename = "before_flash")]
+    BeforeFlash,
+    // balena OS is written and its partitions are mounted
+    #[serde(rename = "after_flash")]
+    AfterFlash,
+    // all file systems except the log device are unmounted
+    #[serde(rename = "before_reboot")]
+    BeforeReboot,
+}
+
+impl HookStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookStage::BeforeFlash => "before_flash",
+            HookStage::AfterFlash => "after_flash",
+            HookStage::BeforeReboot => "before_reboot",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub(crate) enum HookFailPolicy {
+    // log the failure and continue
+    #[serde(rename = "ignore")]
+    Ignore,
+    // abort the migration and apply the configured fail_mode
+    #[serde(rename = "recoverable")]
+    Recoverable,
+    // abort the migration and exit to the rescue shell
+    #[serde(rename = "non_recoverable")]
+    NonRecoverable,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct HookCfg {
+    // script or binary in work_dir
+    pub path: PathBuf,
+    pub stage: HookStage,
+    pub args: Option<Vec<String>>,
+    // timeout in seconds
+    pub timeout: Option<u64>,
+    pub on_fail: Option<HookFailPolicy>,
+}
+
+impl HookCfg {
+    pub fn get_args(&self) -> &[String] {
+        if let Some(ref args) = self.args {
+            args.as_slice()
+        } else {
+            &[]
+        }
+    }
+
+    pub fn get_timeout(&self) -> u64 {
+        if let Some(val) = self.timeout {
+            val
+        } else {
+            DEFAULT_HOOK_TIMEOUT
+        }
+    }
+
+    pub fn get_on_fail(&self) -> HookFailPolicy {
+        if let Some(val) = self.on_fail {
+            val
+        } else {
+            HookFailPolicy::Recoverable
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct BundleCfg {
+    // signed digest manifest in work_dir
+    pub manifest: Option<PathBuf>,
+    // signature of the manifest, defaults to the manifest name with '.sig' appended
+    pub signature: Option<PathBuf>,
+    // Ed25519 public key, hex or base64 encoded
+    pub public_key: Option<String>,
+}
+
 /*
 #[derive(Debug, Deserialize, Clone)]
 pub(crate) struct UBootEnv {
@@ -113,14 +243,25 @@ pub(crate) struct MigrateConfig {
     device_tree: Option<Vec<FileRef>>,
     // TODO: check fail mode processing
     fail_mode: Option<FailMode>,
+    fail_mode_non_recoverable: Option<FailMode>,
     backup: Option<Vec<VolumeConfig>>,
     // TODO: find a good way to do digests on NetworkManager files
     nwmgr_files: Option<Vec<PathBuf>>,
     require_nwmgr_config: Option<bool>,
     gzip_internal: Option<bool>,
+    flash_verify: Option<bool>,
+    flash_retries: Option<u32>,
+    flash_skip_zeros: Option<bool>,
     tar_internal: Option<bool>,
     watchdogs: Option<Vec<WatchdogCfg>>,
+    progress: Option<ProgressCfg>,
+    download: Option<DownloadCfg>,
+    status_report: Option<StatusReportCfg>,
+    digest_manifest: Option<PathBuf>,
+    bundle: Option<BundleCfg>,
     delay: Option<u64>,
+    mount_timeout: Option<u64>,
+    hooks: Option<Vec<HookCfg>>,
     kernel_opts: Option<String>,
     force_flash_device: Option<PathBuf>,
     uboot: Option<UBootCfg>,
@@ -140,13 +281,24 @@ impl<'a> MigrateConfig {
             initrd: None,
             device_tree: None,
             fail_mode: None,
+            fail_mode_non_recoverable: None,
             backup: None,
             nwmgr_files: None,
             require_nwmgr_config: None,
             gzip_internal: None,
+            flash_verify: None,
+            flash_retries: None,
+            flash_skip_zeros: None,
             tar_internal: None,
             watchdogs: None,
+            progress: None,
+            download: None,
+            status_report: None,
+            digest_manifest: None,
+            bundle: None,
             delay: None,
+            mount_timeout: None,
+            hooks: None,
             kernel_opts: None,
             force_flash_device: None,
             uboot: None,
@@ -181,6 +333,26 @@ impl<'a> MigrateConfig {
                     return Err(MigError::displayed());
                 }
 
+                if let Some(hook) = self.get_hooks().iter().find(|hook| hook.path.is_absolute()) {
+                    error!(
+                        "Hooks are expected in the working directory, found absolute path '{}'",
+                        hook.path.display()
+                    );
+                    return Err(MigError::displayed());
+                }
+
+                if let Some(ref status_report) = self.status_report {
+                    if !status_report.url.starts_with("http://")
+                        && !status_report.url.starts_with("https://")
+                    {
+                        error!(
+                            "The status report URL must be a http or https URL, found '{}'",
+                            status_report.url
+                        );
+                        return Err(MigError::displayed());
+                    }
+                }
+
                 Ok(())
             }
         }
@@ -194,6 +366,30 @@ impl<'a> MigrateConfig {
         }
     }
 
+    pub fn is_flash_verify(&self) -> bool {
+        if let Some(val) = self.flash_verify {
+            val
+        } else {
+            false
+        }
+    }
+
+    pub fn get_flash_retries(&self) -> u32 {
+        if let Some(val) = self.flash_retries {
+            val
+        } else {
+            DEFAULT_FLASH_RETRIES
+        }
+    }
+
+    pub fn is_flash_skip_zeros(&self) -> bool {
+        if let Some(val) = self.flash_skip_zeros {
+            val
+        } else {
+            false
+        }
+    }
+
     pub fn is_tar_internal(&self) -> bool {
         if let Some(val) = self.tar_internal {
             val
@@ -244,6 +440,22 @@ impl<'a> MigrateConfig {
         }
     }
 
+    pub fn get_mount_timeout(&self) -> u64 {
+        if let Some(val) = self.mount_timeout {
+            val
+        } else {
+            DEFAULT_MOUNT_TIMEOUT
+        }
+    }
+
+    pub fn get_hooks(&'a self) -> &'a [HookCfg] {
+        if let Some(ref val) = self.hooks {
+            val.as_slice()
+        } else {
+            NO_HOOKS
+        }
+    }
+
     pub fn get_uboot_cfg(&'a self) -> Option<&'a UBootCfg> {
         if let Some(ref val) = self.uboot {
             Some(val)
@@ -260,6 +472,46 @@ impl<'a> MigrateConfig {
         }
     }
 
+    pub fn get_download(&'a self) -> Option<&'a DownloadCfg> {
+        if let Some(ref val) = self.download {
+            Some(val)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_status_report(&'a self) -> Option<&'a StatusReportCfg> {
+        if let Some(ref val) = self.status_report {
+            Some(val)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_digest_manifest(&'a self) -> Option<&'a Path> {
+        if let Some(ref val) = self.digest_manifest {
+            Some(val)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_bundle(&'a self) -> Option<&'a BundleCfg> {
+        if let Some(ref val) = self.bundle {
+            Some(val)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_progress(&'a self) -> Option<&'a ProgressCfg> {
+        if let Some(ref val) = self.progress {
+            Some(val)
+        } else {
+            None
+        }
+    }
+
     pub fn get_kernel_opts(&self) -> Option<String> {
         if let Some(ref val) = self.kernel_opts {
             Some(val.clone())
@@ -288,6 +540,14 @@ impl<'a> MigrateConfig {
         }
     }
 
+    pub fn get_fail_mode_non_recoverable(&'a self) -> &'a FailMode {
+        if let Some(ref val) = self.fail_mode_non_recoverable {
+            val
+        } else {
+            FailMode::get_default_non_recoverable()
+        }
+    }
+
     pub fn get_wifis(&self) -> MigrateWifis {
         if let Some(ref wifis) = self.wifis {
             MigrateWifis::List(wifis.clone())
@@ -366,12 +626,11 @@ impl<'a> MigrateConfig {
         "warn"
     }
 
-    pub fn get_log_console(&self) -> bool {
+    pub fn get_log_console(&self) -> Option<bool> {
         if let Some(ref log_info) = self.log {
-            if let Some(console) = log_info.console {
-                return console;
-            }
+            log_info.console
+        } else {
+            None
         }
-        false
     }
 }
@@ -0,0 +1,103 @@
+use log::{debug, warn};
+use serde::Deserialize;
+
+use crate::{
+    common::{
+        disk_util::{Disk, FatFs, PartInfo, PartitionIterator, PartitionType},
+        MigError, MigErrorKind,
+    },
+    defs::OSArch,
+};
+
+// *************************************************************************************************
+// * device-type.json as found on the resin-boot partition of a balena OS image
+// *************************************************************************************************
+
+const DEVICE_TYPE_FILE: &str = "/device-type.json";
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct DeviceTypeJson {
+    pub slug: String,
+    pub arch: String,
+}
+
+impl DeviceTypeJson {
+    // read device-type.json from the boot partition of a plain or compressed image
+    pub fn from_disk(disk: &mut Disk) -> Result<DeviceTypeJson, MigError> {
+        let boot_part = if let Some(part) = PartitionIterator::new(disk)?.find(|part| {
+            matches!(
+                PartitionType::from_ptype(part.ptype),
+                PartitionType::Fat | PartitionType::Linux
+            )
+        }) {
+            part
+        } else {
+            return Err(MigError::from_remark(
+                MigErrorKind::NotFound,
+                &format!(
+                    "No boot partition found in image '{}'",
+                    disk.get_image_file().display()
+                ),
+            ));
+        };
+
+        DeviceTypeJson::from_partition(disk, &boot_part)
+    }
+
+    pub fn from_partition(disk: &mut Disk, part: &PartInfo) -> Result<DeviceTypeJson, MigError> {
+        let mut fat_fs = FatFs::from_disk(disk, part)?;
+        let dev_type_str = fat_fs.read_to_string(DEVICE_TYPE_FILE)?;
+        DeviceTypeJson::parse(&dev_type_str)
+    }
+
+    pub fn parse(dev_type_str: &str) -> Result<DeviceTypeJson, MigError> {
+        match serde_json::from_str::<DeviceTypeJson>(dev_type_str) {
+            Ok(dev_type) => {
+                debug!(
+                    "parse: found slug '{}', arch '{}'",
+                    dev_type.slug, dev_type.arch
+                );
+                Ok(dev_type)
+            }
+            Err(why) => Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("Failed to parse '{}', error: {:?}", DEVICE_TYPE_FILE, why),
+            )),
+        }
+    }
+
+    // Check if the image architecture can run on a device running the given OS architecture.
+    // A 32 bit OS might well be running on 64 bit hardware, so the check is lenient there.
+    pub fn is_arch_compatible(&self, os_arch: &OSArch) -> bool {
+        match os_arch {
+            OSArch::AMD64 => self.arch == "amd64",
+            OSArch::I386 => {
+                if self.arch == "amd64" {
+                    warn!("The image requires a 64 bit CPU, the current OS is 32 bit");
+                    true
+                } else {
+                    self.arch.starts_with("i386")
+                }
+            }
+            OSArch::ARMHF => matches!(self.arch.as_str(), "armv7hf" | "rpi" | "aarch64"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEV_TYPE_RPI3: &str = r###"
+{"slug": "raspberrypi3", "version": 1, "aliases": ["raspberrypi3"], "name": "Raspberry Pi 3", "arch": "armv7hf", "state": "RELEASED"}
+"###;
+
+    #[test]
+    fn parse_dev_type() {
+        let dev_type = DeviceTypeJson::parse(DEV_TYPE_RPI3).unwrap();
+        assert_eq!(dev_type.slug, "raspberrypi3");
+        assert_eq!(dev_type.arch, "armv7hf");
+        assert!(dev_type.is_arch_compatible(&OSArch::ARMHF));
+        assert!(!dev_type.is_arch_compatible(&OSArch::AMD64));
+    }
+}
@@ -17,7 +17,23 @@ pub(crate) use gzip_file::GZipFile;
 mod plain_file;
 pub(crate) use plain_file::PlainFile;
 
-// TODO: implement GPT partition
+mod compression;
+pub(crate) use compression::Compression;
+
+mod zip_file;
+
+mod stream_file;
+use stream_file::StreamFile;
+
+mod gpt;
+use gpt::{Gpt, GptEntry, GPT_PROTECTIVE_PTYPE};
+pub(crate) use gpt::{GptPartInfo, Guid};
+
+mod fat_fs;
+pub(crate) use fat_fs::FatFs;
+
+mod ext_fs;
+pub(crate) use ext_fs::ExtFs;
 
 #[derive(Debug)]
 pub(crate) enum PartitionType {
@@ -37,7 +53,7 @@ impl PartitionType {
             0x00 => PartitionType::Empty,
             0x05 | 0x0f => PartitionType::Container,
             0xee => PartitionType::GPT,
-            0x0c | 0x0e => PartitionType::Fat,
+            0x0c | 0x0e | 0xef => PartitionType::Fat,
             0x83 => PartitionType::Linux,
             _ => PartitionType::Other,
         }
@@ -75,6 +91,13 @@ struct MasterBootRecord {
 }
 
 impl MasterBootRecord {
+    // a protective (or hybrid) MBR contains a partition of type 0xEE covering the GPT
+    fn get_protective_part(&self) -> Option<&PartEntry> {
+        self.part_tbl
+            .iter()
+            .find(|part| part.ptype == GPT_PROTECTIVE_PTYPE)
+    }
+
     pub fn get_disk_id(&self) -> Option<u32> {
         if self.zeros[0] == 0 && self.zeros[1] == 0 {
             let mut disk_sig_32: u32 = 0;
@@ -99,6 +122,8 @@ pub(crate) struct PartInfo {
     pub status: u8,
     pub start_lba: u64,
     pub num_sectors: u64,
+    // only present for partitions from a GPT, ptype is then derived from the type GUID
+    pub gpt_info: Option<GptPartInfo>,
 }
 
 #[derive(Debug)]
@@ -120,6 +145,7 @@ pub(crate) struct Disk {
     disk: Box<dyn ImageFile>,
     // writable: bool,
     block_size: u64,
+    compression: Compression,
 }
 
 impl Disk {
@@ -128,9 +154,45 @@ impl Disk {
             disk: Box::new(GZipFile::new(image.as_ref())?),
             // writable: false,
             block_size: DEF_BLOCK_SIZE as u64,
+            compression: Compression::GZip,
         })
     }
 
+    // open a plain or compressed image, telling them apart by their magic numbers
+    pub fn from_image_file<P: AsRef<Path>>(image: P) -> Result<Disk, MigError> {
+        Disk::open_image(image.as_ref(), None)
+    }
+
+    // like from_image_file, the random access index of a gzipped image is cached in index_dir
+    pub fn from_image_file_with_index<P: AsRef<Path>>(
+        image: P,
+        index_dir: &Path,
+    ) -> Result<Disk, MigError> {
+        Disk::open_image(image.as_ref(), Some(index_dir))
+    }
+
+    fn open_image(image: &Path, index_dir: Option<&Path>) -> Result<Disk, MigError> {
+        match Compression::from_file(image)? {
+            Compression::None => Disk::from_drive_file(image, None),
+            Compression::GZip => {
+                if let Some(index_dir) = index_dir {
+                    Ok(Disk {
+                        disk: Box::new(GZipFile::with_index_dir(image, index_dir)?),
+                        block_size: DEF_BLOCK_SIZE as u64,
+                        compression: Compression::GZip,
+                    })
+                } else {
+                    Disk::from_gzip_img(image)
+                }
+            }
+            compression => Ok(Disk {
+                disk: Box::new(StreamFile::new(image, compression)?),
+                block_size: DEF_BLOCK_SIZE as u64,
+                compression,
+            }),
+        }
+    }
+
     pub fn from_drive_file<P: AsRef<Path>>(
         drive: P,
         // writable: bool,
@@ -144,6 +206,7 @@ impl Disk {
             } else {
                 DEF_BLOCK_SIZE as u64
             },
+            compression: Compression::None,
         })
     }
 
@@ -151,12 +214,25 @@ impl Disk {
         self.disk.get_path()
     }
 
+    pub fn get_compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn get_block_size(&self) -> u64 {
+        self.block_size
+    }
+
     pub fn get_label(&mut self) -> Result<LabelType, MigError> {
         match self.read_mbr(0) {
-            Ok(mbr) => match PartitionType::from_ptype(mbr.part_tbl[0].ptype) {
-                PartitionType::GPT => Ok(LabelType::GPT),
-                _ => Ok(LabelType::Dos),
-            },
+            Ok(mbr) => {
+                if mbr.get_protective_part().is_some() {
+                    // make sure the GPT is actually readable
+                    let _gpt = self.read_gpt(&mbr)?;
+                    Ok(LabelType::GPT)
+                } else {
+                    Ok(LabelType::Dos)
+                }
+            }
             Err(why) => {
                 if why.kind() == MigErrorKind::InvParam {
                     Ok(LabelType::Other)
@@ -188,6 +264,21 @@ impl Disk {
         Ok(mbr)
     }
 
+    fn read_gpt(&mut self, mbr: &MasterBootRecord) -> Result<Gpt, MigError> {
+        // the protective partition spans the whole disk, its end is where the backup header lives
+        let backup_lba = if let Some(part) = mbr.get_protective_part() {
+            if part.num_sectors != 0xFFFF_FFFF && part.num_sectors > 0 {
+                Some(u64::from(part.first_lba) + u64::from(part.num_sectors) - 1)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        Gpt::from_image(self.disk.as_mut(), self.block_size, backup_lba)
+    }
+
     /*
         pub fn get_partition_iterator(&mut self) -> Result<PartitionIterator, MigError> {
             Ok(PartitionIterator::new(self)?)
@@ -212,6 +303,8 @@ pub(crate) struct PartitionIterator<'a> {
     index: usize,
     part_idx: usize,
     disk_id: Option<u32>,
+    disk_guid: Option<Guid>,
+    gpt: Option<Vec<GptEntry>>,
 }
 
 impl<'a> PartitionIterator<'a> {
@@ -220,6 +313,18 @@ impl<'a> PartitionIterator<'a> {
         let mbr = disk.read_mbr(offset)?;
         let disk_id = mbr.get_disk_id();
 
+        let (gpt, disk_guid) = if mbr.get_protective_part().is_some() {
+            let gpt = disk.read_gpt(&mbr)?;
+            debug!(
+                "PartitionIterator::new: found GPT, disk GUID: {}, {} partitions",
+                gpt.disk_guid,
+                gpt.entries.len()
+            );
+            (Some(gpt.entries), Some(gpt.disk_guid))
+        } else {
+            (None, None)
+        };
+
         Ok(PartitionIterator {
             disk,
             mbr: Some(mbr),
@@ -227,12 +332,17 @@ impl<'a> PartitionIterator<'a> {
             index: 0,
             part_idx: 0,
             disk_id,
+            disk_guid,
+            gpt,
         })
     }
 
-    #[allow(dead_code)]
-    pub fn get_disk_id(&'a self) -> &'a Option<u32> {
-        &self.disk_id
+    pub fn get_disk_id(&self) -> Option<u32> {
+        self.disk_id
+    }
+
+    pub fn get_disk_guid(&self) -> Option<&Guid> {
+        self.disk_guid.as_ref()
     }
 }
 
@@ -249,6 +359,24 @@ impl<'a> Iterator for PartitionIterator<'a> {
         trace!("PartitionIterator::next: entered");
         // TODO: check for 0 size partition ?
 
+        if let Some(ref entries) = self.gpt {
+            // GPT partitions are all listed in one table, no extended partitions to follow, empty
+            // entries leave gaps in the partition numbers
+            return if let Some(entry) = entries.get(self.index) {
+                self.index += 1;
+                Some(PartInfo {
+                    index: entry.index,
+                    ptype: entry.info.get_ptype(),
+                    status: 0,
+                    start_lba: entry.first_lba,
+                    num_sectors: entry.last_lba - entry.first_lba + 1,
+                    gpt_info: Some(entry.info.clone()),
+                })
+            } else {
+                None
+            };
+        }
+
         #[allow(clippy::large_enum_variant)] //TODO refactor to remove clippy warning
         enum SetMbr {
             Leave,
@@ -289,6 +417,7 @@ impl<'a> Iterator for PartitionIterator<'a> {
                                     status: part.status,
                                     start_lba: u64::from(part.first_lba),
                                     num_sectors: u64::from(part.num_sectors),
+                                    gpt_info: None,
                                 }),
                                 SetMbr::ToNone,
                             )
@@ -305,6 +434,7 @@ impl<'a> Iterator for PartitionIterator<'a> {
                                     status: part.status,
                                     start_lba: u64::from(part.first_lba),
                                     num_sectors: u64::from(part.num_sectors),
+                                    gpt_info: None,
                                 }),
                                 SetMbr::Leave,
                             )
@@ -351,6 +481,7 @@ impl<'a> Iterator for PartitionIterator<'a> {
                                                     start_lba: self.offset
                                                         + u64::from(part.first_lba),
                                                     num_sectors: u64::from(part.num_sectors),
+                                                    gpt_info: None,
                                                 }),
                                                 SetMbr::ToMbr(mbr),
                                             )
@@ -411,6 +542,7 @@ impl<'a> Iterator for PartitionIterator<'a> {
                                     status: part.status,
                                     start_lba: self.offset + u64::from(part.first_lba),
                                     num_sectors: u64::from(part.num_sectors),
+                                    gpt_info: None,
                                 }),
                                 SetMbr::ToMbr(mbr),
                             )
@@ -528,10 +660,117 @@ impl<'a> Read for PartitionReader<'a> {
 #[cfg(test)]
 mod test {
 
+    use flate2::read::GzDecoder;
     use mod_logger::{Level, Logger};
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::PathBuf;
 
     use crate::common::disk_util::PartitionIterator;
-    use crate::common::disk_util::{Disk, LabelType};
+    use crate::common::disk_util::{Compression, Disk, ImageFile, LabelType};
+    use crate::common::MigError;
+    use crate::defs::DEF_BLOCK_SIZE;
+
+    struct MemFile {
+        data: Vec<u8>,
+    }
+
+    impl ImageFile for MemFile {
+        fn fill(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), MigError> {
+            let offset = offset as usize;
+            buffer.copy_from_slice(&self.data[offset..offset + buffer.len()]);
+            Ok(())
+        }
+        fn get_path(&self) -> PathBuf {
+            PathBuf::from("memory")
+        }
+    }
+
+    fn check_gpt_parts(disk: &mut Disk) {
+        if let LabelType::GPT = disk.get_label().unwrap() {
+            let iterator = PartitionIterator::new(disk).unwrap();
+            let parts: Vec<_> = iterator.collect();
+            assert_eq!(parts.len(), 3);
+            assert_eq!(parts[0].ptype, 0xef);
+            assert_eq!(parts[0].start_lba, 2048);
+            assert_eq!(parts[0].num_sectors, 2048);
+            assert_eq!(parts[0].gpt_info.as_ref().unwrap().name, "resin-boot");
+            assert_eq!(parts[1].ptype, 0x83);
+            assert_eq!(parts[2].gpt_info.as_ref().unwrap().name, "resin-data");
+            assert_eq!(
+                parts[2].gpt_info.as_ref().unwrap().part_guid.to_string(),
+                "11111111-2222-3333-4444-555555555503"
+            );
+        } else {
+            panic!("Invalid label type - not GPT");
+        }
+    }
+
+    #[test]
+    fn read_gzipped_gpt() {
+        let mut disk = Disk::from_gzip_img("./test_data/gpt.img.gz").unwrap();
+        check_gpt_parts(&mut disk);
+    }
+
+    #[test]
+    fn gpt_part_numbers() {
+        use crate::common::disk_util::gpt::crc32;
+
+        let mut data: Vec<u8> = Vec::new();
+        GzDecoder::new(File::open("./test_data/gpt.img.gz").unwrap())
+            .read_to_end(&mut data)
+            .unwrap();
+
+        let read_u32 = |data: &[u8], offset: usize| -> u32 {
+            let mut value: [u8; 4] = [0; 4];
+            value.copy_from_slice(&data[offset..offset + 4]);
+            u32::from_le_bytes(value)
+        };
+
+        // move the third partition entry to the fifth slot
+        let header = DEF_BLOCK_SIZE;
+        let table = read_u32(&data, header + 72) as usize * DEF_BLOCK_SIZE;
+        let entry_size = read_u32(&data, header + 84) as usize;
+        let table_size = read_u32(&data, header + 80) as usize * entry_size;
+        let entry = data[table + 2 * entry_size..table + 3 * entry_size].to_vec();
+        for byte in &mut data[table + 2 * entry_size..table + 3 * entry_size] {
+            *byte = 0;
+        }
+        data[table + 4 * entry_size..table + 5 * entry_size].copy_from_slice(&entry);
+
+        let table_crc = crc32(&data[table..table + table_size]);
+        data[header + 88..header + 92].copy_from_slice(&table_crc.to_le_bytes());
+        let header_size = read_u32(&data, header + 12) as usize;
+        data[header + 16..header + 20].copy_from_slice(&[0; 4]);
+        let header_crc = crc32(&data[header..header + header_size]);
+        data[header + 16..header + 20].copy_from_slice(&header_crc.to_le_bytes());
+
+        let mut disk = Disk {
+            disk: Box::new(MemFile { data }),
+            block_size: DEF_BLOCK_SIZE as u64,
+            compression: Compression::None,
+        };
+        let parts: Vec<_> = PartitionIterator::new(&mut disk).unwrap().collect();
+        let indexes: Vec<usize> = parts.iter().map(|part| part.index).collect();
+        assert_eq!(indexes, vec![1, 2, 5]);
+        assert_eq!(parts[2].gpt_info.as_ref().unwrap().name, "resin-data");
+    }
+
+    #[test]
+    fn read_gpt_backup() {
+        let mut data: Vec<u8> = Vec::new();
+        GzDecoder::new(File::open("./test_data/gpt.img.gz").unwrap())
+            .read_to_end(&mut data)
+            .unwrap();
+        // damage the primary header
+        data[DEF_BLOCK_SIZE + 40] ^= 0xFF;
+        let mut disk = Disk {
+            disk: Box::new(MemFile { data }),
+            block_size: DEF_BLOCK_SIZE as u64,
+            compression: Compression::None,
+        };
+        check_gpt_parts(&mut disk);
+    }
 
     #[test]
     fn read_gzipped_part() {
@@ -553,4 +792,22 @@ mod test {
             panic!("Invalid label type - not Dos");
         }
     }
+
+    #[test]
+    fn read_compressed_images() {
+        for (image, compression) in &[
+            ("./test_data/part.img.gz", Compression::GZip),
+            ("./test_data/part.img.xz", Compression::Xz),
+            ("./test_data/part.img.zst", Compression::Zstd),
+            ("./test_data/part.img.zip", Compression::Zip),
+        ] {
+            let mut disk = Disk::from_image_file(image).unwrap();
+            assert_eq!(disk.get_compression(), *compression);
+            let ptypes: Vec<u8> = PartitionIterator::new(&mut disk)
+                .unwrap()
+                .map(|partition| partition.ptype)
+                .collect();
+            assert_eq!(ptypes, vec![0x0e, 0x83, 0x83, 0x05, 0x83, 0x83]);
+        }
+    }
 }
@@ -0,0 +1,129 @@
+use flate2::read::GzDecoder;
+use log::debug;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::common::{disk_util::zip_file::ZipEntry, MigError, MigErrorKind};
+
+// *************************************************************************************************
+// * Compression formats supported for balena OS images, detected by their magic numbers
+// *************************************************************************************************
+
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+const XZ_MAGIC: &[u8] = &[0xFD, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xB5, 0x2F, 0xFD];
+const ZIP_MAGIC: &[u8] = &[b'P', b'K', 0x03, 0x04];
+
+const MAX_MAGIC_SIZE: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Compression {
+    None,
+    GZip,
+    Xz,
+    Zstd,
+    Zip,
+}
+
+fn open_file(path: &Path) -> Result<File, MigError> {
+    match File::open(path) {
+        Ok(file) => Ok(file),
+        Err(why) => Err(MigError::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "failed to open file for reading: '{}', error {:?}",
+                path.display(),
+                why
+            ),
+        )),
+    }
+}
+
+impl Compression {
+    pub fn from_magic(header: &[u8]) -> Compression {
+        if header.starts_with(GZIP_MAGIC) {
+            Compression::GZip
+        } else if header.starts_with(XZ_MAGIC) {
+            Compression::Xz
+        } else if header.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else if header.starts_with(ZIP_MAGIC) {
+            Compression::Zip
+        } else {
+            Compression::None
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Compression, MigError> {
+        let path = path.as_ref();
+        Compression::from_reader(&mut open_file(path)?, &path.display().to_string())
+    }
+
+    // determine the compression from the start of a stream
+    pub fn from_reader(reader: &mut dyn Read, descr: &str) -> Result<Compression, MigError> {
+        let mut header: [u8; MAX_MAGIC_SIZE] = [0; MAX_MAGIC_SIZE];
+        let mut header_len: usize = 0;
+        while header_len < header.len() {
+            match reader.read(&mut header[header_len..]) {
+                Ok(0) => break,
+                Ok(bytes_read) => header_len += bytes_read,
+                Err(why) => {
+                    return Err(MigError::from_remark(
+                        MigErrorKind::Upstream,
+                        &format!("failed to read from file: '{}', error {:?}", descr, why),
+                    ));
+                }
+            }
+        }
+
+        let compression = Compression::from_magic(&header[0..header_len]);
+        debug!("from_reader: '{}' is {}", descr, compression.get_descr());
+        Ok(compression)
+    }
+
+    pub fn get_descr(&self) -> &'static str {
+        match self {
+            Compression::None => "uncompressed",
+            Compression::GZip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+            Compression::Zip => "zip",
+        }
+    }
+
+    // open a reader delivering the uncompressed contents of path
+    pub fn open_reader(&self, path: &Path) -> Result<Box<dyn Read>, MigError> {
+        match self {
+            Compression::Zip => ZipEntry::from_file(path)?.open_reader(path),
+            _ => self.decode_reader(Box::new(open_file(path)?), &path.display().to_string()),
+        }
+    }
+
+    // uncompress a stream, zip archives need a seekable file and are not supported
+    pub fn decode_reader(
+        &self,
+        reader: Box<dyn Read>,
+        descr: &str,
+    ) -> Result<Box<dyn Read>, MigError> {
+        match self {
+            Compression::None => Ok(reader),
+            Compression::GZip => Ok(Box::new(GzDecoder::new(reader))),
+            Compression::Xz => Ok(Box::new(xz2::read::XzDecoder::new(reader))),
+            Compression::Zstd => match zstd::stream::read::Decoder::new(reader) {
+                Ok(decoder) => Ok(Box::new(decoder)),
+                Err(why) => Err(MigError::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!(
+                        "failed to create zstd decoder for '{}', error {:?}",
+                        descr, why
+                    ),
+                )),
+            },
+            Compression::Zip => Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("zip archive '{}' can not be read as a stream", descr),
+            )),
+        }
+    }
+}
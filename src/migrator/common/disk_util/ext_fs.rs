@@ -0,0 +1,712 @@
+use log::{debug, trace, warn};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Component, Path};
+
+use crate::common::{
+    disk_util::{Disk, PartInfo},
+    MigError, MigErrorKind,
+};
+
+// *************************************************************************************************
+// * Read-only ext2/3/4 file system access
+// * Supports block maps & extent trees, linear directory lookup (htree directories can be read
+// * linearly) and symlinks. Enough to read a couple of files out of a rootfs without mounting it.
+// *************************************************************************************************
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT_MAGIC: u16 = 0xEF53;
+const ROOT_INODE: u32 = 2;
+const OS_RELEASE_PATH: &str = "/etc/os-release";
+const MODULES_PATH: &str = "/lib/modules";
+const MAX_SYMLINKS: usize = 40;
+
+const COMPAT_HAS_JOURNAL: u32 = 0x0004;
+
+// read only features known to ext2 / ext3: sparse_super, large_file & btree_dir
+const RO_COMPAT_EXT3: u32 = 0x0007;
+
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_RECOVER: u32 = 0x0004;
+const INCOMPAT_JOURNAL_DEV: u32 = 0x0008;
+const INCOMPAT_EXTENTS: u32 = 0x0040;
+const INCOMPAT_64BIT: u32 = 0x0080;
+const INCOMPAT_MMP: u32 = 0x0100;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_RECOVER
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_MMP
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_LARGEDIR
+    | INCOMPAT_CSUM_SEED;
+// incompatible features known to ext2 / ext3
+const INCOMPAT_EXT3: u32 = INCOMPAT_FILETYPE | INCOMPAT_RECOVER;
+
+const INODE_FLAG_EXTENTS: u32 = 0x0008_0000;
+const INODE_FLAG_INLINE_DATA: u32 = 0x1000_0000;
+
+const EXTENT_MAGIC: u16 = 0xF30A;
+const EXTENT_MAX_DEPTH: u16 = 5;
+const EXTENT_UNINIT_LEN: u16 = 32768;
+
+const S_IFMT: u16 = 0xF000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFREG: u16 = 0x8000;
+const S_IFLNK: u16 = 0xA000;
+
+// directory entry file types
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ExtFileType {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ExtDirEntry {
+    pub name: String,
+    pub inode: u32,
+    pub file_type: ExtFileType,
+}
+
+struct Inode {
+    mode: u16,
+    size: u64,
+    flags: u32,
+    block: [u8; 60],
+}
+
+impl Inode {
+    fn get_file_type(&self) -> ExtFileType {
+        match self.mode & S_IFMT {
+            S_IFDIR => ExtFileType::Dir,
+            S_IFREG => ExtFileType::File,
+            S_IFLNK => ExtFileType::Symlink,
+            _ => ExtFileType::Other,
+        }
+    }
+}
+
+// a contiguous run of blocks, phys_block == 0 denotes a hole or an uninitialized extent
+struct BlockRun {
+    log_block: u64,
+    phys_block: u64,
+    count: u64,
+}
+
+pub(crate) struct ExtFs<'a> {
+    disk: &'a mut Disk,
+    // byte offset of the partition on disk
+    offset: u64,
+    block_size: u64,
+    inodes_per_group: u32,
+    inode_size: u64,
+    feature_incompat: u32,
+    // inode table block for every block group
+    inode_tables: Vec<u64>,
+    label: String,
+    fs_type: &'static str,
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+    u16::from(buffer[offset]) | (u16::from(buffer[offset + 1]) << 8)
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    u32::from(read_u16(buffer, offset)) | (u32::from(read_u16(buffer, offset + 2)) << 16)
+}
+
+impl<'a> ExtFs<'a> {
+    pub fn from_disk(disk: &'a mut Disk, part: &PartInfo) -> Result<ExtFs<'a>, MigError> {
+        let offset = part.start_lba * disk.block_size;
+        let mut sb: [u8; SUPERBLOCK_SIZE] = [0; SUPERBLOCK_SIZE];
+        disk.disk.fill(offset + SUPERBLOCK_OFFSET, &mut sb)?;
+
+        if read_u16(&sb, 56) != EXT_MAGIC {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("No ext file system found on partition {}", part.index),
+            ));
+        }
+
+        let feature_incompat = read_u32(&sb, 96);
+        if (feature_incompat & INCOMPAT_JOURNAL_DEV) != 0
+            || (feature_incompat & !INCOMPAT_SUPPORTED) != 0
+        {
+            return Err(MigError::from_remark(
+                MigErrorKind::NotImpl,
+                &format!(
+                    "Unsupported ext file system features {:x} on partition {}",
+                    feature_incompat & !INCOMPAT_SUPPORTED,
+                    part.index
+                ),
+            ));
+        }
+
+        if (feature_incompat & INCOMPAT_RECOVER) != 0 {
+            warn!(
+                "The ext file system on partition {} needs journal recovery, data might be stale",
+                part.index
+            );
+        }
+
+        let log_block_size = read_u32(&sb, 24);
+        if log_block_size > 6 {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("Invalid ext block size on partition {}", part.index),
+            ));
+        }
+        let block_size = 1024u64 << log_block_size;
+
+        let blocks_count = if (feature_incompat & INCOMPAT_64BIT) != 0 {
+            u64::from(read_u32(&sb, 4)) | (u64::from(read_u32(&sb, 336)) << 32)
+        } else {
+            u64::from(read_u32(&sb, 4))
+        };
+        let first_data_block = u64::from(read_u32(&sb, 20));
+        let blocks_per_group = u64::from(read_u32(&sb, 32));
+        let inodes_per_group = read_u32(&sb, 40);
+        let inode_size = if read_u32(&sb, 76) == 0 {
+            128
+        } else {
+            u64::from(read_u16(&sb, 88))
+        };
+        let desc_size = if (feature_incompat & INCOMPAT_64BIT) != 0 {
+            u64::from(read_u16(&sb, 254))
+        } else {
+            32
+        };
+
+        if blocks_per_group == 0 || inodes_per_group == 0 || inode_size < 128 || desc_size < 32 {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("Invalid ext superblock on partition {}", part.index),
+            ));
+        }
+
+        let num_groups =
+            ((blocks_count - first_data_block + blocks_per_group - 1) / blocks_per_group) as usize;
+
+        debug!(
+            "from_disk: partition {}: block size {}, {} groups, inode size {}",
+            part.index, block_size, num_groups, inode_size
+        );
+
+        // group descriptors start in the block following the superblock
+        let mut gdt: Vec<u8> = vec![0; num_groups * desc_size as usize];
+        disk.disk
+            .fill(offset + (first_data_block + 1) * block_size, &mut gdt)?;
+
+        let inode_tables: Vec<u64> = gdt
+            .chunks(desc_size as usize)
+            .map(|desc| {
+                let lo = u64::from(read_u32(desc, 8));
+                if desc_size >= 64 {
+                    lo | (u64::from(read_u32(desc, 0x28)) << 32)
+                } else {
+                    lo
+                }
+            })
+            .collect();
+
+        let label = String::from_utf8_lossy(&sb[120..136])
+            .trim_end_matches('\0')
+            .to_string();
+
+        // tell ext2/3/4 apart by their features like blkid does
+        let fs_type = if (feature_incompat & !INCOMPAT_EXT3) != 0
+            || (read_u32(&sb, 100) & !RO_COMPAT_EXT3) != 0
+        {
+            "ext4"
+        } else if (read_u32(&sb, 92) & COMPAT_HAS_JOURNAL) != 0 {
+            "ext3"
+        } else {
+            "ext2"
+        };
+
+        Ok(ExtFs {
+            disk,
+            offset,
+            block_size,
+            inodes_per_group,
+            inode_size,
+            feature_incompat,
+            inode_tables,
+            label,
+            fs_type,
+        })
+    }
+
+    pub fn get_label(&self) -> &str {
+        &self.label
+    }
+
+    // ext2, ext3 or ext4
+    pub fn get_fs_type(&self) -> &'static str {
+        self.fs_type
+    }
+
+    // list the contents of a directory, path is relative to the file system root
+    pub fn read_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<ExtDirEntry>, MigError> {
+        let path = path.as_ref();
+        let inode_num = self.lookup(path)?;
+        let inode = self.read_inode(inode_num)?;
+        if inode.get_file_type() != ExtFileType::Dir {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("'{}' is not a directory", path.display()),
+            ));
+        }
+
+        let dir_data = self.read_data(&inode)?;
+        Ok(self
+            .parse_dir(&dir_data)
+            .into_iter()
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .collect())
+    }
+
+    // read a file, path is relative to the file system root, symlinks are followed
+    pub fn read_file<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<u8>, MigError> {
+        let path = path.as_ref();
+        let inode_num = self.lookup(path)?;
+        let inode = self.read_inode(inode_num)?;
+        if inode.get_file_type() != ExtFileType::File {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("'{}' is not a regular file", path.display()),
+            ));
+        }
+        self.read_data(&inode)
+    }
+
+    pub fn read_to_string<P: AsRef<Path>>(&mut self, path: P) -> Result<String, MigError> {
+        let path = path.as_ref();
+        match String::from_utf8(self.read_file(path)?) {
+            Ok(content) => Ok(content),
+            Err(_why) => Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("'{}' does not contain valid UTF-8", path.display()),
+            )),
+        }
+    }
+
+    // read /etc/os-release into a key / value map, quotes are removed from values
+    pub fn get_os_release(&mut self) -> Result<HashMap<String, String>, MigError> {
+        let mut os_release: HashMap<String, String> = HashMap::new();
+        for line in self.read_to_string(OS_RELEASE_PATH)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(pos) = line.find('=') {
+                let value = line[pos + 1..].trim().trim_matches('"').trim_matches('\'');
+                os_release.insert(String::from(&line[0..pos]), String::from(value));
+            }
+        }
+        Ok(os_release)
+    }
+
+    // the kernel version is taken from the modules directory
+    pub fn get_kernel_version(&mut self) -> Result<Option<String>, MigError> {
+        Ok(self
+            .read_dir(MODULES_PATH)?
+            .into_iter()
+            .find(|entry| entry.file_type == ExtFileType::Dir)
+            .map(|entry| entry.name))
+    }
+
+    // resolve path to an inode number, following symlinks
+    fn lookup(&mut self, path: &Path) -> Result<u32, MigError> {
+        trace!("lookup: entered with '{}'", path.display());
+        let mut components: VecDeque<String> = VecDeque::new();
+        ExtFs::push_components(&mut components, path)?;
+
+        let mut curr = ROOT_INODE;
+        let mut link_count: usize = 0;
+
+        while let Some(name) = components.pop_front() {
+            if name == "/" {
+                curr = ROOT_INODE;
+                continue;
+            }
+
+            let dir_inode = self.read_inode(curr)?;
+            if dir_inode.get_file_type() != ExtFileType::Dir {
+                return Err(MigError::from_remark(
+                    MigErrorKind::NotFound,
+                    &format!("'{}' not found", path.display()),
+                ));
+            }
+
+            let dir_data = self.read_data(&dir_inode)?;
+            let entry = match self
+                .parse_dir(&dir_data)
+                .into_iter()
+                .find(|entry| entry.name == name)
+            {
+                Some(entry) => entry,
+                None => {
+                    return Err(MigError::from_remark(
+                        MigErrorKind::NotFound,
+                        &format!("'{}' not found", path.display()),
+                    ));
+                }
+            };
+
+            let inode = self.read_inode(entry.inode)?;
+            if inode.get_file_type() == ExtFileType::Symlink {
+                link_count += 1;
+                if link_count > MAX_SYMLINKS {
+                    return Err(MigError::from_remark(
+                        MigErrorKind::InvState,
+                        &format!("Too many levels of symbolic links in '{}'", path.display()),
+                    ));
+                }
+                let target = self.read_link(&inode)?;
+                debug!("lookup: following symlink '{}' -> '{}'", name, target);
+                // the link target replaces the link, relative links resolve in the current dir
+                let mut link_components: VecDeque<String> = VecDeque::new();
+                ExtFs::push_components(&mut link_components, Path::new(&target))?;
+                while let Some(component) = link_components.pop_back() {
+                    components.push_front(component);
+                }
+            } else {
+                curr = entry.inode;
+            }
+        }
+
+        Ok(curr)
+    }
+
+    fn push_components(components: &mut VecDeque<String>, path: &Path) -> Result<(), MigError> {
+        for component in path.components() {
+            match component {
+                Component::RootDir => components.push_back(String::from("/")),
+                Component::CurDir => (),
+                Component::ParentDir => components.push_back(String::from("..")),
+                Component::Normal(name) => components.push_back(name.to_string_lossy().to_string()),
+                Component::Prefix(_) => {
+                    return Err(MigError::from_remark(
+                        MigErrorKind::InvParam,
+                        &format!("Unsupported path '{}'", path.display()),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_link(&mut self, inode: &Inode) -> Result<String, MigError> {
+        // fast symlinks store the target in the block array
+        let data = if inode.size < 60 && (inode.flags & INODE_FLAG_EXTENTS) == 0 {
+            inode.block[0..inode.size as usize].to_vec()
+        } else {
+            self.read_data(inode)?
+        };
+        Ok(String::from_utf8_lossy(&data).to_string())
+    }
+
+    fn read_inode(&mut self, inode_num: u32) -> Result<Inode, MigError> {
+        if inode_num == 0 {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                "Invalid inode number 0",
+            ));
+        }
+
+        let group = ((inode_num - 1) / self.inodes_per_group) as usize;
+        let index = u64::from((inode_num - 1) % self.inodes_per_group);
+        let inode_table = match self.inode_tables.get(group) {
+            Some(inode_table) => *inode_table,
+            None => {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!("Inode {} is out of range", inode_num),
+                ));
+            }
+        };
+
+        let mut buffer: [u8; 128] = [0; 128];
+        self.disk.disk.fill(
+            self.offset + inode_table * self.block_size + index * self.inode_size,
+            &mut buffer,
+        )?;
+
+        let mut block: [u8; 60] = [0; 60];
+        block.copy_from_slice(&buffer[40..100]);
+
+        Ok(Inode {
+            mode: read_u16(&buffer, 0),
+            size: u64::from(read_u32(&buffer, 4)) | (u64::from(read_u32(&buffer, 108)) << 32),
+            flags: read_u32(&buffer, 32),
+            block,
+        })
+    }
+
+    fn read_block(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), MigError> {
+        self.disk
+            .disk
+            .fill(self.offset + block * self.block_size, buffer)
+    }
+
+    fn read_data(&mut self, inode: &Inode) -> Result<Vec<u8>, MigError> {
+        if (inode.flags & INODE_FLAG_INLINE_DATA) != 0 {
+            return Err(MigError::from_remark(
+                MigErrorKind::NotImpl,
+                "Inline data is not supported",
+            ));
+        }
+
+        let num_blocks = (inode.size + self.block_size - 1) / self.block_size;
+        let runs = if (inode.flags & INODE_FLAG_EXTENTS) != 0 {
+            let mut runs: Vec<BlockRun> = Vec::new();
+            self.read_extents(&inode.block, EXTENT_MAX_DEPTH, &mut runs)?;
+            runs
+        } else {
+            self.read_block_map(inode, num_blocks)?
+        };
+
+        let mut data: Vec<u8> = vec![0; (num_blocks * self.block_size) as usize];
+        for run in runs {
+            if run.phys_block == 0 || run.log_block >= num_blocks {
+                // holes & uninitialized extents read as zeros
+                continue;
+            }
+            let count = if run.log_block + run.count > num_blocks {
+                num_blocks - run.log_block
+            } else {
+                run.count
+            };
+            let start = (run.log_block * self.block_size) as usize;
+            let end = start + (count * self.block_size) as usize;
+            self.read_block(run.phys_block, &mut data[start..end])?;
+        }
+
+        data.truncate(inode.size as usize);
+        Ok(data)
+    }
+
+    fn read_extents(
+        &mut self,
+        node: &[u8],
+        max_depth: u16,
+        runs: &mut Vec<BlockRun>,
+    ) -> Result<(), MigError> {
+        if read_u16(node, 0) != EXTENT_MAGIC {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvState,
+                "Invalid extent header magic",
+            ));
+        }
+
+        let entries = read_u16(node, 2) as usize;
+        let depth = read_u16(node, 6);
+        if depth > max_depth || (entries + 1) * 12 > node.len() {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvState,
+                "Invalid extent tree",
+            ));
+        }
+
+        for index in 0..entries {
+            let entry = &node[(index + 1) * 12..(index + 2) * 12];
+            if depth == 0 {
+                let raw_len = read_u16(entry, 4);
+                let (len, uninit) = if raw_len > EXTENT_UNINIT_LEN {
+                    (raw_len - EXTENT_UNINIT_LEN, true)
+                } else {
+                    (raw_len, false)
+                };
+                runs.push(BlockRun {
+                    log_block: u64::from(read_u32(entry, 0)),
+                    phys_block: if uninit {
+                        0
+                    } else {
+                        (u64::from(read_u16(entry, 6)) << 32) | u64::from(read_u32(entry, 8))
+                    },
+                    count: u64::from(len),
+                });
+            } else {
+                let leaf = u64::from(read_u32(entry, 4)) | (u64::from(read_u16(entry, 8)) << 32);
+                let mut child: Vec<u8> = vec![0; self.block_size as usize];
+                self.read_block(leaf, &mut child)?;
+                self.read_extents(&child, depth - 1, runs)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // classic ext2/3 block map: 12 direct, 1 indirect, 1 double & 1 triple indirect block
+    fn read_block_map(
+        &mut self,
+        inode: &Inode,
+        num_blocks: u64,
+    ) -> Result<Vec<BlockRun>, MigError> {
+        let mut blocks: Vec<u64> = Vec::new();
+        for index in 0..12 {
+            blocks.push(u64::from(read_u32(&inode.block, index * 4)));
+        }
+
+        for level in 1..=3 {
+            if blocks.len() as u64 >= num_blocks {
+                break;
+            }
+            let root = u64::from(read_u32(&inode.block, (11 + level) * 4));
+            self.read_indirect(root, level, num_blocks, &mut blocks)?;
+        }
+
+        Ok(blocks
+            .into_iter()
+            .take(num_blocks as usize)
+            .enumerate()
+            .map(|(log_block, phys_block)| BlockRun {
+                log_block: log_block as u64,
+                phys_block,
+                count: 1,
+            })
+            .collect())
+    }
+
+    fn read_indirect(
+        &mut self,
+        block: u64,
+        level: usize,
+        num_blocks: u64,
+        blocks: &mut Vec<u64>,
+    ) -> Result<(), MigError> {
+        let ptrs_per_block = self.block_size / 4;
+        if block == 0 {
+            // a hole covering all blocks referenced by this indirect block
+            let span = ptrs_per_block.pow(level as u32);
+            for _ in 0..span {
+                if blocks.len() as u64 >= num_blocks {
+                    break;
+                }
+                blocks.push(0);
+            }
+            return Ok(());
+        }
+
+        let mut buffer: Vec<u8> = vec![0; self.block_size as usize];
+        self.read_block(block, &mut buffer)?;
+        for index in 0..ptrs_per_block as usize {
+            if blocks.len() as u64 >= num_blocks {
+                break;
+            }
+            let ptr = u64::from(read_u32(&buffer, index * 4));
+            if level == 1 {
+                blocks.push(ptr);
+            } else {
+                self.read_indirect(ptr, level - 1, num_blocks, blocks)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_dir(&self, dir_data: &[u8]) -> Vec<ExtDirEntry> {
+        let mut entries: Vec<ExtDirEntry> = Vec::new();
+        let has_file_type = (self.feature_incompat & INCOMPAT_FILETYPE) != 0;
+        let mut offset: usize = 0;
+
+        while offset + 8 <= dir_data.len() {
+            let inode = read_u32(dir_data, offset);
+            let rec_len = read_u16(dir_data, offset + 4) as usize;
+            let (name_len, file_type) = if has_file_type {
+                (dir_data[offset + 6] as usize, dir_data[offset + 7])
+            } else {
+                (read_u16(dir_data, offset + 6) as usize, 0)
+            };
+
+            if rec_len < 8 || offset + rec_len > dir_data.len() || 8 + name_len > rec_len {
+                warn!("Invalid directory entry found at offset {}", offset);
+                break;
+            }
+
+            if inode != 0 && name_len > 0 {
+                entries.push(ExtDirEntry {
+                    name: String::from_utf8_lossy(&dir_data[offset + 8..offset + 8 + name_len])
+                        .to_string(),
+                    inode,
+                    file_type: match file_type {
+                        1 => ExtFileType::File,
+                        FT_DIR => ExtFileType::Dir,
+                        FT_SYMLINK => ExtFileType::Symlink,
+                        _ => ExtFileType::Other,
+                    },
+                });
+            }
+
+            offset += rec_len;
+        }
+
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_rootfs(image: &str, fs_type: &str) {
+        let mut disk = Disk::from_gzip_img(image).unwrap();
+        let part = PartInfo {
+            index: 2,
+            ptype: 0x83,
+            status: 0,
+            start_lba: 0,
+            num_sectors: 8192,
+            gpt_info: None,
+        };
+
+        let mut ext_fs = ExtFs::from_disk(&mut disk, &part).unwrap();
+        assert_eq!(ext_fs.get_fs_type(), fs_type);
+
+        // /etc/os-release is a relative symlink to /usr/lib/os-release
+        let os_release = ext_fs.read_to_string("/etc/os-release").unwrap();
+        assert!(os_release.contains("VERSION_ID=\"2.38.0\""));
+
+        let modules = ext_fs.read_dir("/lib/modules").unwrap();
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules[0].name, "4.19.71");
+        assert_eq!(modules[0].file_type, ExtFileType::Dir);
+
+        // large enough to need indirect blocks / multiple extents
+        let big_file = ext_fs.read_file("/usr/share/big.bin").unwrap();
+        assert_eq!(big_file.len(), 300_000);
+        assert!(big_file
+            .iter()
+            .enumerate()
+            .all(|(idx, byte)| *byte == (idx % 251) as u8));
+
+        let os_release = ext_fs.get_os_release().unwrap();
+        assert_eq!(os_release.get("VARIANT_ID").unwrap(), "prod");
+        assert_eq!(
+            ext_fs.get_kernel_version().unwrap(),
+            Some(String::from("4.19.71"))
+        );
+
+        assert!(ext_fs.read_file("/etc/missing").is_err());
+        assert!(ext_fs.read_file("/etc").is_err());
+    }
+
+    #[test]
+    fn read_gzipped_ext4() {
+        check_rootfs("./test_data/ext4.img.gz", "ext4");
+    }
+
+    #[test]
+    fn read_gzipped_ext2() {
+        check_rootfs("./test_data/ext2.img.gz", "ext2");
+    }
+}
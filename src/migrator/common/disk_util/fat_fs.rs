@@ -0,0 +1,541 @@
+use log::{debug, trace};
+use std::path::{Component, Path};
+
+use crate::common::{
+    disk_util::{Disk, PartInfo},
+    MigError, MigErrorKind,
+};
+
+// *************************************************************************************************
+// * Read-only FAT12/16/32 file system access
+// * Works on a partition of a Disk, so files can be read from plain or gzipped images without
+// * mounting them.
+// *************************************************************************************************
+
+const DIR_ENTRY_SIZE: usize = 32;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const DELETED_ENTRY: u8 = 0xE5;
+const LFN_LAST_ENTRY: u8 = 0x40;
+const LFN_CHARS_PER_ENTRY: usize = 13;
+// NT reserved byte flags for lower case short names
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct FatDirEntry {
+    pub name: String,
+    pub short_name: String,
+    pub is_dir: bool,
+    pub size: u32,
+    first_cluster: u32,
+}
+
+pub(crate) struct FatFs<'a> {
+    disk: &'a mut Disk,
+    // byte offset of the partition on disk
+    offset: u64,
+    fat_type: FatType,
+    cluster_size: u64,
+    // FAT12/16 root directory location & size in bytes, relative to offset
+    root_dir_offset: u64,
+    root_dir_size: u64,
+    // FAT32 root directory cluster
+    root_cluster: u32,
+    data_offset: u64,
+    max_cluster: u32,
+    fat: Vec<u8>,
+    label: String,
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+    u16::from(buffer[offset]) | (u16::from(buffer[offset + 1]) << 8)
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    u32::from(read_u16(buffer, offset)) | (u32::from(read_u16(buffer, offset + 2)) << 16)
+}
+
+fn lfn_checksum(short_name: &[u8]) -> u8 {
+    short_name.iter().fold(0u8, |sum, byte| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*byte)
+    })
+}
+
+impl<'a> FatFs<'a> {
+    pub fn from_disk(disk: &'a mut Disk, part: &PartInfo) -> Result<FatFs<'a>, MigError> {
+        let offset = part.start_lba * disk.block_size;
+        let mut boot_sect: [u8; 512] = [0; 512];
+        disk.disk.fill(offset, &mut boot_sect)?;
+
+        if boot_sect[510] != 0x55 || boot_sect[511] != 0xAA {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "No FAT boot sector signature found on partition {}",
+                    part.index
+                ),
+            ));
+        }
+
+        let bytes_per_sector = u64::from(read_u16(&boot_sect, 11));
+        let sectors_per_cluster = u64::from(boot_sect[13]);
+        let reserved_sectors = u64::from(read_u16(&boot_sect, 14));
+        let num_fats = u64::from(boot_sect[16]);
+        let root_entries = u64::from(read_u16(&boot_sect, 17));
+        let total_sectors = match read_u16(&boot_sect, 19) {
+            0 => u64::from(read_u32(&boot_sect, 32)),
+            sectors => u64::from(sectors),
+        };
+        let fat_sectors = match read_u16(&boot_sect, 22) {
+            0 => u64::from(read_u32(&boot_sect, 36)),
+            sectors => u64::from(sectors),
+        };
+
+        if !bytes_per_sector.is_power_of_two()
+            || bytes_per_sector < 512
+            || !sectors_per_cluster.is_power_of_two()
+            || num_fats == 0
+            || fat_sectors == 0
+        {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "Invalid FAT boot sector parameters on partition {}",
+                    part.index
+                ),
+            ));
+        }
+
+        let root_dir_sectors =
+            (root_entries * DIR_ENTRY_SIZE as u64 + bytes_per_sector - 1) / bytes_per_sector;
+        let data_sector = reserved_sectors + num_fats * fat_sectors + root_dir_sectors;
+        if data_sector >= total_sectors {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("Invalid FAT geometry on partition {}", part.index),
+            ));
+        }
+
+        let num_clusters = (total_sectors - data_sector) / sectors_per_cluster;
+        // see Microsoft FAT specification, the cluster count is the only valid discriminator
+        let fat_type = if num_clusters < 4085 {
+            FatType::Fat12
+        } else if num_clusters < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+
+        debug!(
+            "from_disk: partition {}: {:?}, {} clusters of {} bytes",
+            part.index,
+            fat_type,
+            num_clusters,
+            sectors_per_cluster * bytes_per_sector
+        );
+
+        // the volume label is stored in the extended BPB, its location depends on the FAT type
+        let label_offset = if fat_type == FatType::Fat32 { 71 } else { 43 };
+        let label = String::from_utf8_lossy(&boot_sect[label_offset..label_offset + 11])
+            .trim_end()
+            .to_string();
+
+        let mut fat: Vec<u8> = vec![0; (fat_sectors * bytes_per_sector) as usize];
+        disk.disk
+            .fill(offset + reserved_sectors * bytes_per_sector, &mut fat)?;
+
+        Ok(FatFs {
+            disk,
+            offset,
+            fat_type,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            root_dir_offset: (reserved_sectors + num_fats * fat_sectors) * bytes_per_sector,
+            root_dir_size: root_dir_sectors * bytes_per_sector,
+            root_cluster: if fat_type == FatType::Fat32 {
+                read_u32(&boot_sect, 44)
+            } else {
+                0
+            },
+            data_offset: data_sector * bytes_per_sector,
+            max_cluster: (num_clusters + 1) as u32,
+            fat,
+            label,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn get_fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    pub fn get_label(&self) -> &str {
+        &self.label
+    }
+
+    // list the contents of a directory, path is relative to the file system root
+    #[allow(dead_code)]
+    pub fn read_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<FatDirEntry>, MigError> {
+        let path = path.as_ref();
+        let dir_data = match self.lookup(path)? {
+            Some(entry) => {
+                if !entry.is_dir {
+                    return Err(MigError::from_remark(
+                        MigErrorKind::InvParam,
+                        &format!("'{}' is not a directory", path.display()),
+                    ));
+                }
+                self.read_chain(entry.first_cluster, None)?
+            }
+            None => self.read_root_dir()?,
+        };
+        FatFs::parse_dir(&dir_data)
+    }
+
+    // read a file, path is relative to the file system root
+    pub fn read_file<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<u8>, MigError> {
+        let path = path.as_ref();
+        match self.lookup(path)? {
+            Some(entry) => {
+                if entry.is_dir {
+                    Err(MigError::from_remark(
+                        MigErrorKind::InvParam,
+                        &format!("'{}' is a directory", path.display()),
+                    ))
+                } else if entry.size == 0 {
+                    Ok(Vec::new())
+                } else {
+                    self.read_chain(entry.first_cluster, Some(u64::from(entry.size)))
+                }
+            }
+            None => Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("'{}' is a directory", path.display()),
+            )),
+        }
+    }
+
+    pub fn read_to_string<P: AsRef<Path>>(&mut self, path: P) -> Result<String, MigError> {
+        let path = path.as_ref();
+        match String::from_utf8(self.read_file(path)?) {
+            Ok(content) => Ok(content),
+            Err(_why) => Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("'{}' does not contain valid UTF-8", path.display()),
+            )),
+        }
+    }
+
+    // find the directory entry for path, None denotes the root directory
+    fn lookup(&mut self, path: &Path) -> Result<Option<FatDirEntry>, MigError> {
+        let mut curr: Option<FatDirEntry> = None;
+        for component in path.components() {
+            let name = match component {
+                Component::RootDir | Component::CurDir => continue,
+                Component::Normal(name) => name.to_string_lossy(),
+                _ => {
+                    return Err(MigError::from_remark(
+                        MigErrorKind::InvParam,
+                        &format!("Unsupported path '{}'", path.display()),
+                    ));
+                }
+            };
+
+            let dir_data = match curr {
+                Some(ref entry) => {
+                    if !entry.is_dir {
+                        return Err(MigError::from_remark(
+                            MigErrorKind::NotFound,
+                            &format!("'{}' not found", path.display()),
+                        ));
+                    }
+                    self.read_chain(entry.first_cluster, None)?
+                }
+                None => self.read_root_dir()?,
+            };
+
+            // FAT file names are case insensitive
+            let lc_name = name.to_lowercase();
+            curr = Some(
+                match FatFs::parse_dir(&dir_data)?.into_iter().find(|entry| {
+                    entry.name.to_lowercase() == lc_name || entry.short_name.to_lowercase() == lc_name
+                }) {
+                    Some(entry) => entry,
+                    None => {
+                        return Err(MigError::from_remark(
+                            MigErrorKind::NotFound,
+                            &format!("'{}' not found", path.display()),
+                        ));
+                    }
+                },
+            );
+        }
+        Ok(curr)
+    }
+
+    fn read_root_dir(&mut self) -> Result<Vec<u8>, MigError> {
+        if self.fat_type == FatType::Fat32 {
+            let root_cluster = self.root_cluster;
+            self.read_chain(root_cluster, None)
+        } else {
+            let mut buffer: Vec<u8> = vec![0; self.root_dir_size as usize];
+            self.disk
+                .disk
+                .fill(self.offset + self.root_dir_offset, &mut buffer)?;
+            Ok(buffer)
+        }
+    }
+
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, MigError> {
+        let idx = cluster as usize;
+        let (next, eoc) = match self.fat_type {
+            FatType::Fat12 => {
+                let val = u32::from(read_u16(&self.fat, idx + idx / 2));
+                let next = if (cluster & 1) != 0 {
+                    val >> 4
+                } else {
+                    val & 0x0FFF
+                };
+                (next, 0x0FF8)
+            }
+            FatType::Fat16 => (u32::from(read_u16(&self.fat, idx * 2)), 0xFFF8),
+            FatType::Fat32 => (read_u32(&self.fat, idx * 4) & 0x0FFF_FFFF, 0x0FFF_FFF8),
+        };
+
+        if next >= eoc {
+            Ok(None)
+        } else if next < 2 || next > self.max_cluster {
+            Err(MigError::from_remark(
+                MigErrorKind::InvState,
+                &format!(
+                    "Invalid FAT entry {:x} found for cluster {}",
+                    next, cluster
+                ),
+            ))
+        } else {
+            Ok(Some(next))
+        }
+    }
+
+    // read a cluster chain, optionally limited to size bytes
+    fn read_chain(&mut self, first_cluster: u32, size: Option<u64>) -> Result<Vec<u8>, MigError> {
+        trace!(
+            "read_chain: entered with cluster {}, size {:?}",
+            first_cluster,
+            size
+        );
+        let mut data: Vec<u8> = Vec::new();
+        let mut cluster = first_cluster;
+        let mut count: u32 = 0;
+
+        loop {
+            if cluster < 2 || cluster > self.max_cluster || count > self.max_cluster {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvState,
+                    &format!("Invalid cluster chain starting at {}", first_cluster),
+                ));
+            }
+
+            let to_read = if let Some(size) = size {
+                let left = size - data.len() as u64;
+                if left < self.cluster_size {
+                    left
+                } else {
+                    self.cluster_size
+                }
+            } else {
+                self.cluster_size
+            };
+
+            let start = data.len();
+            data.resize(start + to_read as usize, 0);
+            let cluster_offset =
+                self.offset + self.data_offset + u64::from(cluster - 2) * self.cluster_size;
+            self.disk.disk.fill(cluster_offset, &mut data[start..])?;
+
+            if let Some(size) = size {
+                if data.len() as u64 >= size {
+                    break;
+                }
+            }
+
+            count += 1;
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None => {
+                    if let Some(size) = size {
+                        return Err(MigError::from_remark(
+                            MigErrorKind::InvState,
+                            &format!(
+                                "Cluster chain starting at {} ends after {} of {} bytes",
+                                first_cluster,
+                                data.len(),
+                                size
+                            ),
+                        ));
+                    }
+                    break;
+                }
+            };
+        }
+
+        Ok(data)
+    }
+
+    fn parse_dir(dir_data: &[u8]) -> Result<Vec<FatDirEntry>, MigError> {
+        let mut entries: Vec<FatDirEntry> = Vec::new();
+        let mut lfn_parts: Vec<u16> = Vec::new();
+        let mut lfn_checksum_val: Option<u8> = None;
+
+        for raw in dir_data.chunks(DIR_ENTRY_SIZE) {
+            if raw.len() < DIR_ENTRY_SIZE || raw[0] == 0 {
+                // end of directory
+                break;
+            }
+
+            if raw[0] == DELETED_ENTRY {
+                lfn_checksum_val = None;
+                continue;
+            }
+
+            let attr = raw[11];
+            if attr & 0x3F == ATTR_LONG_NAME {
+                let ord = raw[0];
+                let seq = usize::from(ord & 0x1F);
+                if seq == 0 {
+                    lfn_checksum_val = None;
+                    continue;
+                }
+                if (ord & LFN_LAST_ENTRY) != 0 {
+                    lfn_parts = vec![0xFFFF; seq * LFN_CHARS_PER_ENTRY];
+                    lfn_checksum_val = Some(raw[13]);
+                } else if lfn_checksum_val != Some(raw[13])
+                    || seq * LFN_CHARS_PER_ENTRY > lfn_parts.len()
+                {
+                    lfn_checksum_val = None;
+                    continue;
+                }
+
+                let base = (seq - 1) * LFN_CHARS_PER_ENTRY;
+                let char_offsets = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+                for (idx, offset) in char_offsets.iter().enumerate() {
+                    lfn_parts[base + idx] = read_u16(raw, *offset);
+                }
+                continue;
+            }
+
+            if (attr & ATTR_VOLUME_ID) != 0 {
+                lfn_checksum_val = None;
+                continue;
+            }
+
+            let short_name = FatFs::get_short_name(raw);
+            if short_name == "." || short_name == ".." {
+                lfn_checksum_val = None;
+                continue;
+            }
+
+            let name = if lfn_checksum_val == Some(lfn_checksum(&raw[0..11])) {
+                let name_utf16: Vec<u16> = lfn_parts
+                    .iter()
+                    .cloned()
+                    .take_while(|c| *c != 0 && *c != 0xFFFF)
+                    .collect();
+                String::from_utf16_lossy(&name_utf16)
+            } else {
+                short_name.clone()
+            };
+            lfn_checksum_val = None;
+
+            entries.push(FatDirEntry {
+                name,
+                short_name,
+                is_dir: (attr & ATTR_DIRECTORY) != 0,
+                size: read_u32(raw, 28),
+                first_cluster: (u32::from(read_u16(raw, 20)) << 16) | u32::from(read_u16(raw, 26)),
+            });
+        }
+
+        Ok(entries)
+    }
+
+    fn get_short_name(raw: &[u8]) -> String {
+        let mut base: Vec<u8> = raw[0..8].to_vec();
+        if base[0] == 0x05 {
+            // 0xE5 is a valid first character, stored as 0x05
+            base[0] = DELETED_ENTRY;
+        }
+
+        let base = String::from_utf8_lossy(&base).trim_end().to_string();
+        let ext = String::from_utf8_lossy(&raw[8..11]).trim_end().to_string();
+
+        let base = if (raw[12] & NT_LOWER_BASE) != 0 {
+            base.to_lowercase()
+        } else {
+            base
+        };
+        let ext = if (raw[12] & NT_LOWER_EXT) != 0 {
+            ext.to_lowercase()
+        } else {
+            ext
+        };
+
+        if ext.is_empty() {
+            base
+        } else {
+            format!("{}.{}", base, ext)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_gzipped_fat() {
+        let mut disk = Disk::from_gzip_img("./test_data/fat.img.gz").unwrap();
+        let part = PartInfo {
+            index: 1,
+            ptype: 0x0e,
+            status: 0,
+            start_lba: 0,
+            num_sectors: 8192,
+            gpt_info: None,
+        };
+
+        let mut fat_fs = FatFs::from_disk(&mut disk, &part).unwrap();
+        assert_eq!(fat_fs.get_fat_type(), FatType::Fat16);
+        assert_eq!(fat_fs.get_label(), "RESIN-BOOT");
+
+        let root = fat_fs.read_dir("/").unwrap();
+        assert_eq!(root.len(), 3);
+        assert!(root
+            .iter()
+            .any(|entry| entry.name == "device-type.json" && !entry.is_dir));
+        assert!(root.iter().any(|entry| entry.name == "overlays" && entry.is_dir));
+
+        assert_eq!(
+            fat_fs.read_to_string("/os-release").unwrap(),
+            "ID=balena-os\nVERSION_ID=2.38.0\n"
+        );
+
+        let device_type = fat_fs.read_to_string("device-type.json").unwrap();
+        assert_eq!(device_type.len(), 5000);
+        assert!(device_type.starts_with("{\"slug\": \"raspberrypi3\""));
+
+        // case insensitive lookup via long & short names
+        let overlay = fat_fs.read_file("/OVERLAYS/a-long-overlay-name.dtbo").unwrap();
+        assert_eq!(overlay, vec![0xD0, 0x0D, 0xFE, 0xED]);
+
+        assert!(fat_fs.read_file("/missing.txt").is_err());
+    }
+}
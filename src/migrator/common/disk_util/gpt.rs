@@ -0,0 +1,368 @@
+use log::{debug, warn};
+use std::fmt;
+
+use crate::common::{disk_util::image_file::ImageFile, MigError, MigErrorKind};
+
+// *************************************************************************************************
+// * GUID Partition Table parser
+// * Reads the primary GPT header & partition entries, validates their CRCs and falls back to
+// * the backup header at the end of the disk if the primary copy is damaged.
+// *************************************************************************************************
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+// sanity limit, the spec default is 128 entries
+const GPT_MAX_ENTRIES: usize = 1024;
+const GPT_NAME_OFFSET: usize = 56;
+const GPT_NAME_SIZE: usize = 72;
+
+// protective MBR partition type
+pub(crate) const GPT_PROTECTIVE_PTYPE: u8 = 0xEE;
+
+// MBR partition types reported for GPT partitions, so GPT & DOS partitions can be handled alike
+const PTYPE_EFI: u8 = 0xEF;
+const PTYPE_FAT: u8 = 0x0C;
+const PTYPE_LINUX: u8 = 0x83;
+const PTYPE_OTHER: u8 = 0xDA;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Guid([u8; 16]);
+
+pub(crate) const GUID_EMPTY: Guid = Guid([0; 16]);
+
+// C12A7328-F81F-11D2-BA4B-00A0C93EC93B
+pub(crate) const GUID_EFI_SYSTEM: Guid = Guid([
+    0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B,
+]);
+// EBD0A0A2-B9E5-4433-87C0-68B6B72699C7
+pub(crate) const GUID_BASIC_DATA: Guid = Guid([
+    0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7,
+]);
+// 0FC63DAF-8483-4772-8E79-3D69D8477DE4
+pub(crate) const GUID_LINUX_FS: Guid = Guid([
+    0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4,
+]);
+
+impl Guid {
+    fn from_slice(buffer: &[u8]) -> Guid {
+        let mut guid: [u8; 16] = [0; 16];
+        guid.copy_from_slice(&buffer[0..16]);
+        Guid(guid)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == GUID_EMPTY
+    }
+}
+
+impl fmt::Display for Guid {
+    // first three fields are stored little endian, the remainder big endian
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            g[3], g[2], g[1], g[0], g[5], g[4], g[7], g[6], g[8], g[9], g[10], g[11], g[12], g[13],
+            g[14], g[15]
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct GptPartInfo {
+    pub type_guid: Guid,
+    pub part_guid: Guid,
+    pub name: String,
+}
+
+impl GptPartInfo {
+    // MBR partition type equivalent for the partition type GUID
+    pub fn get_ptype(&self) -> u8 {
+        if self.type_guid == GUID_EFI_SYSTEM {
+            PTYPE_EFI
+        } else if self.type_guid == GUID_BASIC_DATA {
+            PTYPE_FAT
+        } else if self.type_guid == GUID_LINUX_FS {
+            PTYPE_LINUX
+        } else {
+            PTYPE_OTHER
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct GptEntry {
+    // partition number, the position in the partition table counting from 1
+    pub index: usize,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub info: GptPartInfo,
+}
+
+#[derive(Debug)]
+struct GptHeader {
+    my_lba: u64,
+    alternate_lba: u64,
+    disk_guid: Guid,
+    part_entry_lba: u64,
+    num_part_entries: usize,
+    part_entry_size: usize,
+    part_entries_crc32: u32,
+}
+
+#[derive(Debug)]
+pub(crate) struct Gpt {
+    pub disk_guid: Guid,
+    pub entries: Vec<GptEntry>,
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    let mut value: u32 = 0;
+    for byte in buffer[offset..offset + 4].iter().rev() {
+        value = (value << 8) | u32::from(*byte);
+    }
+    value
+}
+
+fn read_u64(buffer: &[u8], offset: usize) -> u64 {
+    let mut value: u64 = 0;
+    for byte in buffer[offset..offset + 8].iter().rev() {
+        value = (value << 8) | u64::from(*byte);
+    }
+    value
+}
+
+// CRC32 as used by GPT (IEEE 802.3, reflected polynomial 0xEDB88320)
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if (crc & 1) != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+impl Gpt {
+    // backup_lba is taken from the protective MBR if available - the GPT header might be damaged
+    pub fn from_image(
+        image: &mut dyn ImageFile,
+        block_size: u64,
+        backup_lba: Option<u64>,
+    ) -> Result<Gpt, MigError> {
+        let (header, entries) = match Gpt::read_table(image, block_size, 1) {
+            Ok((header, entries)) => {
+                debug!("from_image: primary GPT header is valid");
+                (header, entries)
+            }
+            Err(primary_err) => {
+                warn!(
+                    "The primary GPT header is invalid, trying backup, error: {}",
+                    primary_err
+                );
+                if let Some(backup_lba) = backup_lba {
+                    match Gpt::read_table(image, block_size, backup_lba) {
+                        Ok((header, entries)) => (header, entries),
+                        Err(backup_err) => {
+                            return Err(MigError::from_remark(
+                                MigErrorKind::InvParam,
+                                &format!(
+                                    "Both GPT headers are invalid, primary: {}, backup: {}",
+                                    primary_err, backup_err
+                                ),
+                            ));
+                        }
+                    }
+                } else {
+                    return Err(MigError::from_remark(
+                        MigErrorKind::InvParam,
+                        &format!(
+                            "The primary GPT header is invalid and no backup header location is known, error: {}",
+                            primary_err
+                        ),
+                    ));
+                }
+            }
+        };
+
+        Ok(Gpt {
+            disk_guid: header.disk_guid,
+            entries,
+        })
+    }
+
+    fn read_table(
+        image: &mut dyn ImageFile,
+        block_size: u64,
+        header_lba: u64,
+    ) -> Result<(GptHeader, Vec<GptEntry>), MigError> {
+        let header = Gpt::read_header(image, block_size, header_lba)?;
+
+        let table_size = header.num_part_entries * header.part_entry_size;
+        let mut buffer: Vec<u8> = vec![0; table_size];
+        image.fill(header.part_entry_lba * block_size, &mut buffer)?;
+
+        let entries_crc32 = crc32(&buffer);
+        if entries_crc32 != header.part_entries_crc32 {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "Invalid GPT partition entries CRC32 at LBA {}, expected {:08x}, found {:08x}",
+                    header.part_entry_lba, header.part_entries_crc32, entries_crc32
+                ),
+            ));
+        }
+
+        let mut entries: Vec<GptEntry> = Vec::new();
+        for index in 0..header.num_part_entries {
+            let entry =
+                &buffer[index * header.part_entry_size..(index + 1) * header.part_entry_size];
+            let type_guid = Guid::from_slice(&entry[0..16]);
+            if type_guid.is_empty() {
+                continue;
+            }
+
+            let name_utf16: Vec<u16> = entry[GPT_NAME_OFFSET..GPT_NAME_OFFSET + GPT_NAME_SIZE]
+                .chunks(2)
+                .map(|c| u16::from(c[0]) | (u16::from(c[1]) << 8))
+                .take_while(|c| *c != 0)
+                .collect();
+
+            let first_lba = read_u64(entry, 32);
+            let last_lba = read_u64(entry, 40);
+            if last_lba < first_lba {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!(
+                        "Invalid GPT partition entry {}, first LBA {} > last LBA {}",
+                        index, first_lba, last_lba
+                    ),
+                ));
+            }
+
+            entries.push(GptEntry {
+                index: index + 1,
+                first_lba,
+                last_lba,
+                info: GptPartInfo {
+                    type_guid,
+                    part_guid: Guid::from_slice(&entry[16..32]),
+                    name: String::from_utf16_lossy(&name_utf16),
+                },
+            });
+        }
+
+        Ok((header, entries))
+    }
+
+    fn read_header(
+        image: &mut dyn ImageFile,
+        block_size: u64,
+        header_lba: u64,
+    ) -> Result<GptHeader, MigError> {
+        let mut buffer: Vec<u8> = vec![0; block_size as usize];
+        image.fill(header_lba * block_size, &mut buffer)?;
+
+        if &buffer[0..8] != GPT_SIGNATURE {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!("No GPT signature found at LBA {}", header_lba),
+            ));
+        }
+
+        let header_size = read_u32(&buffer, 12) as usize;
+        if header_size < GPT_MIN_HEADER_SIZE || header_size > buffer.len() {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "Invalid GPT header size {} at LBA {}",
+                    header_size, header_lba
+                ),
+            ));
+        }
+
+        // CRC32 is calculated with the CRC field set to 0
+        let header_crc32 = read_u32(&buffer, 16);
+        for byte in &mut buffer[16..20] {
+            *byte = 0;
+        }
+        let calc_crc32 = crc32(&buffer[0..header_size]);
+        if calc_crc32 != header_crc32 {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "Invalid GPT header CRC32 at LBA {}, expected {:08x}, found {:08x}",
+                    header_lba, header_crc32, calc_crc32
+                ),
+            ));
+        }
+
+        let header = GptHeader {
+            my_lba: read_u64(&buffer, 24),
+            alternate_lba: read_u64(&buffer, 32),
+            disk_guid: Guid::from_slice(&buffer[56..72]),
+            part_entry_lba: read_u64(&buffer, 72),
+            num_part_entries: read_u32(&buffer, 80) as usize,
+            part_entry_size: read_u32(&buffer, 84) as usize,
+            part_entries_crc32: read_u32(&buffer, 88),
+        };
+
+        if header.my_lba != header_lba {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "GPT header at LBA {} claims to be at LBA {}",
+                    header_lba, header.my_lba
+                ),
+            ));
+        }
+
+        if header.part_entry_size < GPT_MIN_ENTRY_SIZE
+            || (header.part_entry_size % 8) != 0
+            || header.num_part_entries > GPT_MAX_ENTRIES
+        {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "Unsupported GPT partition table at LBA {}, {} entries of size {}",
+                    header_lba, header.num_part_entries, header.part_entry_size
+                ),
+            ));
+        }
+
+        debug!(
+            "read_header: LBA {}, alternate LBA {}, entries at LBA {}",
+            header.my_lba, header.alternate_lba, header.part_entry_lba
+        );
+
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_ok() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn guid_display() {
+        assert_eq!(
+            GUID_EFI_SYSTEM.to_string(),
+            "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+        );
+        assert_eq!(
+            GUID_LINUX_FS.to_string(),
+            "0FC63DAF-8483-4772-8E79-3D69D8477DE4"
+        );
+    }
+}
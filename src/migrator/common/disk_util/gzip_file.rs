@@ -1,138 +1,281 @@
-use flate2::read::GzDecoder;
-use log::{debug, trace};
+use flate2::bufread::GzDecoder;
+use log::{debug, trace, warn};
 use std::fs::{File, OpenOptions};
-use std::io::Read;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 
-const DEF_READ_BUFFER: usize = 1024 * 1024;
-
 use crate::common::{disk_util::image_file::ImageFile, MigError, MigErrorKind};
 
+mod gzip_index;
+use gzip_index::{Checkpoint, GZipIndex};
+
+// distance between checkpoints in uncompressed data
+const DEF_INDEX_SPAN: u64 = 16 * 1024 * 1024;
+// extension of the cached index file
+const INDEX_EXTENSION: &str = ".gzidx";
+const IN_BUFFER_SIZE: usize = 64 * 1024;
+const SKIP_BUFFER_SIZE: usize = 1024 * 1024;
+// first byte of a gzip member
+const GZIP_ID1: u8 = 0x1F;
+
+// *************************************************************************************************
+// * Random access to gzipped images.
+// * Decompression is left to flate2 which can only start at the beginning of a gzip member, so
+// * checkpoints are recorded at member boundaries at least index span bytes apart. Reads going
+// * backwards restart at the closest checkpoint or at the start of the file if there is none, as
+// * for images compressed into a single member. Share one GZipFile between readers of the same
+// * image to keep reads going forward.
+// * If an index directory is given the index is saved there when new checkpoints were found and
+// * reused when the image is opened again.
+// *************************************************************************************************
+
 pub(crate) struct GZipFile {
     path: PathBuf,
-    decoder: GzDecoder<File>,
-    bytes_read: u64,
+    // None once the end of data was reached
+    decoder: Option<GzDecoder<BufReader<File>>>,
+    // current offset in uncompressed data
+    position: u64,
+    index: GZipIndex,
+    index_path: Option<PathBuf>,
+    index_dirty: bool,
 }
 
 impl GZipFile {
     pub fn new(path: &Path) -> Result<GZipFile, MigError> {
-        trace!("new: entered with '{}'", path.display());
-        let file = match OpenOptions::new()
-            .write(false)
-            .read(true)
-            .create(false)
-            .open(path)
-        {
-            Ok(file) => file,
-            Err(why) => {
-                return Err(MigError::from_remark(
-                    MigErrorKind::Upstream,
-                    &format!(
-                        "failed to open file for reading: '{}', error {:?}",
-                        path.display(),
-                        why
-                    ),
-                ));
+        GZipFile::with_index(path, DEF_INDEX_SPAN, None)
+    }
+
+    // cache the index in index_dir, typically the work dir
+    pub fn with_index_dir(path: &Path, index_dir: &Path) -> Result<GZipFile, MigError> {
+        GZipFile::with_index(path, DEF_INDEX_SPAN, Some(index_dir))
+    }
+
+    // create a GZipFile with checkpoints every span bytes, cache the index in index_dir if given
+    pub fn with_index(
+        path: &Path,
+        span: u64,
+        index_dir: Option<&Path>,
+    ) -> Result<GZipFile, MigError> {
+        trace!(
+            "with_index: entered with '{}', span: {}, index_dir: {:?}",
+            path.display(),
+            span,
+            index_dir
+        );
+
+        // the index records size & mtime of the image, an index of a different image with the
+        // same name is not used
+        let index_path = if let (Some(index_dir), Some(file_name)) = (index_dir, path.file_name()) {
+            let mut index_name = file_name.to_owned();
+            index_name.push(INDEX_EXTENSION);
+            Some(index_dir.join(index_name))
+        } else {
+            None
+        };
+
+        let index = if let Some(ref index_path) = index_path {
+            if index_path.exists() {
+                match GZipIndex::load(index_path, path, span) {
+                    Ok(Some(index)) => index,
+                    Ok(None) => GZipIndex::new(span),
+                    Err(why) => {
+                        warn!(
+                            "Failed to load gzip index '{}', error: {:?}",
+                            index_path.display(),
+                            why
+                        );
+                        GZipIndex::new(span)
+                    }
+                }
+            } else {
+                GZipIndex::new(span)
             }
+        } else {
+            GZipIndex::new(span)
         };
 
         Ok(GZipFile {
             path: path.to_path_buf(),
-            decoder: GzDecoder::new(file),
-            bytes_read: 0,
+            decoder: Some(GZipFile::open_at(path, 0)?),
+            position: 0,
+            index,
+            index_path,
+            index_dirty: false,
         })
     }
 
-    fn reset(&mut self) -> Result<(), MigError> {
-        trace!("reset: entered");
-        let file = match OpenOptions::new()
+    // start decoding the gzip member found at offset in the compressed file
+    fn open_at(path: &Path, offset: u64) -> Result<GzDecoder<BufReader<File>>, MigError> {
+        let mut file = match OpenOptions::new()
             .write(false)
             .read(true)
             .create(false)
-            .open(&self.path)
+            .open(path)
         {
             Ok(file) => file,
             Err(why) => {
                 return Err(MigError::from_remark(
                     MigErrorKind::Upstream,
                     &format!(
-                        "failed to reopen file for reading: '{}', error {:?}",
-                        self.path.display(),
+                        "failed to open file for reading: '{}', error {:?}",
+                        path.display(),
                         why
                     ),
                 ));
             }
         };
 
-        self.decoder = GzDecoder::new(file);
-        self.bytes_read = 0;
+        if let Err(why) = file.seek(SeekFrom::Start(offset)) {
+            return Err(MigError::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "failed to seek to offset {} in file: '{}', error {:?}",
+                    offset,
+                    path.display(),
+                    why
+                ),
+            ));
+        }
+
+        Ok(GzDecoder::new(BufReader::with_capacity(
+            IN_BUFFER_SIZE,
+            file,
+        )))
+    }
+
+    fn io_error(&self, why: &std::io::Error) -> MigError {
+        MigError::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "failed to read from file: '{}' at offset {}, error {:?}",
+                self.path.display(),
+                self.position,
+                why
+            ),
+        )
+    }
+
+    fn restore(&mut self, checkpoint: &Checkpoint) -> Result<(), MigError> {
+        self.decoder = Some(GZipFile::open_at(&self.path, checkpoint.in_offset)?);
+        self.position = checkpoint.out_offset;
         Ok(())
     }
 
-    fn seek(&mut self, offset: u64) -> Result<(), MigError> {
-        trace!(
-            "seek: entered with offset {}, bytes_read: {}",
-            offset,
-            self.bytes_read
-        );
-        let mut to_read = if offset < self.bytes_read {
-            self.reset()?;
-            offset
-        } else {
-            offset - self.bytes_read
-        };
+    // read uncompressed data, continuing with the next gzip member at the end of a member
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, MigError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
 
-        trace!("to_read: {}", to_read);
+        loop {
+            let bytes_read = if let Some(ref mut decoder) = self.decoder {
+                decoder.read(buffer)
+            } else {
+                return Ok(0);
+            };
 
-        if to_read == 0 {
-            Ok(())
-        } else {
-            let mut buffer: [u8; DEF_READ_BUFFER] = [0; DEF_READ_BUFFER];
-            if to_read > (DEF_READ_BUFFER as u64) {
-                loop {
-                    match self.decoder.read(&mut buffer) {
-                        Ok(bytes_read) => {
-                            to_read -= bytes_read as u64;
-                            // debug!("bytes_read: {}, to_read:{}", bytes_read, to_read);
-                            if to_read < DEF_READ_BUFFER as u64 {
-                                debug!("done with DEF_BUFFER, to_read: {}", to_read);
-                                break;
-                            }
-                        }
-                        Err(why) => {
-                            return Err(MigError::from_remark(
-                                MigErrorKind::Upstream,
-                                &format!(
-                                    "failed to reopen file for reading: '{}', error {:?}",
-                                    self.path.display(),
-                                    why
-                                ),
-                            ));
-                        }
-                    }
+            match bytes_read {
+                Ok(0) => (),
+                Ok(bytes_read) => {
+                    self.position += bytes_read as u64;
+                    return Ok(bytes_read);
                 }
+                Err(why) => return Err(self.io_error(&why)),
             }
 
-            if to_read > 0 {
-                debug!("last buffer, to_read: {}", to_read);
-                match self.decoder.read_exact(&mut buffer[0..to_read as usize]) {
-                    Ok(_) => {
-                        self.bytes_read = offset;
-                        Ok(())
+            // the decoder consumed the member including its trailer
+            let mut reader = if let Some(decoder) = self.decoder.take() {
+                decoder.into_inner()
+            } else {
+                return Ok(0);
+            };
+
+            // data following the last member, like zero padding, is ignored
+            match reader.fill_buf() {
+                Ok(data) => {
+                    if data.first() != Some(&GZIP_ID1) {
+                        return Ok(0);
                     }
-                    Err(why) => Err(MigError::from_remark(
-                        MigErrorKind::Upstream,
-                        &format!(
-                            "failed to reopen file for reading: '{}', error {:?}",
-                            self.path.display(),
-                            why
-                        ),
-                    )),
                 }
-            } else {
-                self.bytes_read = offset;
-                Ok(())
+                Err(why) => return Err(self.io_error(&why)),
             }
+
+            let in_offset = match reader.get_mut().stream_position() {
+                Ok(offset) => offset - reader.buffer().len() as u64,
+                Err(why) => return Err(self.io_error(&why)),
+            };
+
+            if self.position >= self.index.next_checkpoint()
+                && self.index.add(Checkpoint {
+                    out_offset: self.position,
+                    in_offset,
+                })
+            {
+                debug!(
+                    "read: added checkpoint at offset {}, compressed offset {}",
+                    self.position, in_offset
+                );
+                self.index_dirty = true;
+            }
+
+            self.decoder = Some(GzDecoder::new(reader));
+        }
+    }
+
+    // skip count bytes of uncompressed data, returns the number of bytes skipped
+    fn skip(&mut self, count: u64) -> Result<u64, MigError> {
+        let mut buffer: Vec<u8> = vec![0; SKIP_BUFFER_SIZE.min(count as usize)];
+        let mut skipped: u64 = 0;
+        while skipped < count {
+            let size = buffer.len().min((count - skipped) as usize);
+            let bytes_read = self.read(&mut buffer[0..size])?;
+            if bytes_read == 0 {
+                break;
+            }
+            skipped += bytes_read as u64;
+        }
+        Ok(skipped)
+    }
+
+    fn seek(&mut self, offset: u64) -> Result<(), MigError> {
+        trace!(
+            "seek: entered with offset {}, position: {}",
+            offset,
+            self.position
+        );
+
+        if let Some(checkpoint) = self.index.find(offset).cloned() {
+            if self.position > offset || checkpoint.out_offset > self.position {
+                debug!(
+                    "seek: restarting at checkpoint with offset {}",
+                    checkpoint.out_offset
+                );
+                self.restore(&checkpoint)?;
+            }
+        } else if self.position > offset {
+            debug!("seek: restarting at the start of the file");
+            self.restore(&Checkpoint {
+                out_offset: 0,
+                in_offset: 0,
+            })?;
+        }
+
+        let to_skip = offset - self.position;
+        trace!("seek: to_skip: {}", to_skip);
+        let skipped = self.skip(to_skip)?;
+
+        if skipped < to_skip {
+            Err(MigError::from_remark(
+                MigErrorKind::InvParam,
+                &format!(
+                    "failed to seek to offset {} in file: '{}', end of data at {}",
+                    offset,
+                    self.path.display(),
+                    self.position
+                ),
+            ))
+        } else {
+            Ok(())
         }
     }
 }
@@ -146,24 +289,183 @@ impl ImageFile for GZipFile {
         );
         self.seek(offset)?;
 
-        trace!("fill: bytes_read after seek {}", self.bytes_read);
-
-        match self.decoder.read_exact(buffer) {
-            Ok(_) => {
-                self.bytes_read = offset + buffer.len() as u64;
-                Ok(())
+        let mut filled: usize = 0;
+        while filled < buffer.len() {
+            let bytes_read = self.read(&mut buffer[filled..])?;
+            if bytes_read == 0 {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!(
+                        "failed to read from file: '{}', unexpected end of data at {}",
+                        self.path.display(),
+                        offset + filled as u64
+                    ),
+                ));
             }
-            Err(why) => Err(MigError::from_remark(
-                MigErrorKind::Upstream,
-                &format!(
-                    "failed to read from file: '{}', error {:?}",
-                    self.path.display(),
-                    why
-                ),
-            )),
+            filled += bytes_read;
         }
+        Ok(())
     }
+
     fn get_path(&self) -> PathBuf {
         self.path.clone()
     }
 }
+
+impl Drop for GZipFile {
+    fn drop(&mut self) {
+        if self.index_dirty {
+            if let Some(ref index_path) = self.index_path {
+                // the index dir might well be on a read-only file system
+                if let Err(why) = self.index.save(index_path, &self.path) {
+                    debug!(
+                        "Failed to save gzip index '{}', error: {:?}",
+                        index_path.display(),
+                        why
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+
+    // compressible but not trivial test data
+    fn make_data(size: usize) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::with_capacity(size);
+        let mut seed: u32 = 0x1234_5678;
+        while data.len() < size {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let word = format!("block-{:04} ", (seed >> 16) % 1000);
+            data.extend_from_slice(word.as_bytes());
+            if (seed >> 8) % 7 == 0 {
+                data.push((seed >> 24) as u8);
+            }
+        }
+        data.truncate(size);
+        data
+    }
+
+    // write data as concatenated gzip members of member_size bytes
+    fn make_gzip(path: &Path, data: &[u8], member_size: usize) {
+        let mut file = File::create(path).unwrap();
+        for (idx, part) in data.chunks(member_size).enumerate() {
+            let level = if idx % 2 == 0 { 1 } else { 9 };
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+            encoder.write_all(part).unwrap();
+            file.write_all(&encoder.finish().unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn gzip_random_access() {
+        let tmp_dir = std::env::temp_dir().join(format!("gzip_file_test_{}", std::process::id()));
+        fs::create_dir_all(&tmp_dir).unwrap();
+        let image = tmp_dir.join("random.img.gz");
+        let index_dir = tmp_dir.join("work");
+        fs::create_dir_all(&index_dir).unwrap();
+
+        let data = make_data(3 * 1024 * 1024 + 517);
+        make_gzip(&image, &data, 100_000);
+
+        let span: u64 = 256 * 1024;
+        {
+            let mut gzip_file = GZipFile::with_index(&image, span, Some(&index_dir)).unwrap();
+            // first pass builds the index
+            let mut buffer: Vec<u8> = vec![0; 100_000];
+            let mut offset: usize = 0;
+            while offset < data.len() {
+                let size = buffer.len().min(data.len() - offset);
+                gzip_file.fill(offset as u64, &mut buffer[0..size]).unwrap();
+                assert_eq!(&buffer[0..size], &data[offset..offset + size]);
+                offset += size;
+            }
+            assert!(gzip_file.index.len() >= 8);
+
+            // read past the end
+            assert!(gzip_file
+                .fill(data.len() as u64 - 10, &mut buffer[0..20])
+                .is_err());
+        }
+
+        // the index is cached in the index dir only
+        let mut index_name = image.file_name().unwrap().to_owned();
+        index_name.push(INDEX_EXTENSION);
+        assert!(index_dir.join(&index_name).exists());
+        assert!(!tmp_dir.join(&index_name).exists());
+        let gzip_file = GZipFile::new(&image).unwrap();
+        assert_eq!(gzip_file.index.len(), 0);
+
+        // reopen using the cached index, read backwards
+        let mut gzip_file = GZipFile::with_index(&image, span, Some(&index_dir)).unwrap();
+        assert!(gzip_file.index.len() >= 8);
+        let mut buffer: [u8; 4096] = [0; 4096];
+        for offset in &[2_900_000, 1_500_123, 1_048_576, 300_001, 12, 0, 3_000_000] {
+            gzip_file.fill(*offset as u64, &mut buffer).unwrap();
+            assert_eq!(&buffer[..], &data[*offset..*offset + buffer.len()]);
+        }
+
+        // a different span ignores the cached index
+        let gzip_file = GZipFile::with_index(&image, span * 2, Some(&index_dir)).unwrap();
+        assert_eq!(gzip_file.index.len(), 0);
+
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+
+    #[test]
+    fn gzip_trailer_check() {
+        let tmp_dir =
+            std::env::temp_dir().join(format!("gzip_trailer_test_{}", std::process::id()));
+        fs::create_dir_all(&tmp_dir).unwrap();
+        let image = tmp_dir.join("trailer.img.gz");
+
+        let data = make_data(200_000);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let gzip = encoder.finish().unwrap();
+
+        let read_all = |gzip: &[u8]| -> Result<Vec<u8>, MigError> {
+            fs::write(&image, gzip).unwrap();
+            let mut gzip_file = GZipFile::new(&image)?;
+            let mut out: Vec<u8> = vec![0; data.len()];
+            gzip_file.fill(0, &mut out)?;
+            // the trailer follows the last byte of data
+            let mut buffer: [u8; 1] = [0; 1];
+            gzip_file.read(&mut buffer)?;
+            Ok(out)
+        };
+
+        assert_eq!(read_all(&gzip).unwrap(), data);
+
+        // CRC32 and ISIZE make up the last 8 bytes
+        for offset in &[gzip.len() - 8, gzip.len() - 1] {
+            let mut damaged = gzip.clone();
+            damaged[*offset] ^= 0x01;
+            assert!(read_all(&damaged).is_err());
+        }
+
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+
+    #[test]
+    fn gzip_test_image() {
+        let mut expected: Vec<u8> = Vec::new();
+        flate2::read::GzDecoder::new(File::open("./test_data/part.img.gz").unwrap())
+            .read_to_end(&mut expected)
+            .unwrap();
+
+        let mut gzip_file =
+            GZipFile::with_index(Path::new("./test_data/part.img.gz"), 1024 * 1024, None).unwrap();
+        let mut buffer: [u8; 512] = [0; 512];
+        for offset in &[4_194_304, 0, 10_485_760 - 512, 1_048_576] {
+            gzip_file.fill(*offset as u64, &mut buffer).unwrap();
+            assert_eq!(&buffer[..], &expected[*offset..*offset + buffer.len()]);
+        }
+    }
+}
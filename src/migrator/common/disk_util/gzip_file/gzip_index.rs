@@ -0,0 +1,219 @@
+use log::debug;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::common::{MigError, MigErrorKind};
+
+// *************************************************************************************************
+// * Checkpoint index for a gzipped image, cached next to the image file.
+// * The cache file records size & modification time of the image and is ignored if either differs.
+// *************************************************************************************************
+
+const INDEX_MAGIC: &[u8; 8] = b"BMGZIDX2";
+
+// the start of a gzip member where decompression can be restarted
+#[derive(Debug, Clone)]
+pub(crate) struct Checkpoint {
+    // offset in uncompressed data
+    pub out_offset: u64,
+    // offset in compressed data
+    pub in_offset: u64,
+}
+
+pub(crate) struct GZipIndex {
+    span: u64,
+    checkpoints: Vec<Checkpoint>,
+}
+
+fn image_stamp(image_path: &Path) -> Result<(u64, u64), MigError> {
+    let metadata = match fs::metadata(image_path) {
+        Ok(metadata) => metadata,
+        Err(why) => {
+            return Err(MigError::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "Failed to retrieve metadata for '{}', error: {:?}",
+                    image_path.display(),
+                    why
+                ),
+            ));
+        }
+    };
+
+    let mtime = match metadata.modified() {
+        Ok(modified) => match modified.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs(),
+            Err(_) => 0,
+        },
+        Err(_) => 0,
+    };
+
+    Ok((metadata.len(), mtime))
+}
+
+fn io_error(path: &Path, why: &std::io::Error) -> MigError {
+    MigError::from_remark(
+        MigErrorKind::Upstream,
+        &format!(
+            "Failed to access index '{}', error: {:?}",
+            path.display(),
+            why
+        ),
+    )
+}
+
+fn read_u64<R: Read>(reader: &mut R, path: &Path) -> Result<u64, MigError> {
+    let mut buffer: [u8; 8] = [0; 8];
+    reader
+        .read_exact(&mut buffer)
+        .map_err(|why| io_error(path, &why))?;
+    Ok(u64::from_le_bytes(buffer))
+}
+
+impl GZipIndex {
+    pub fn new(span: u64) -> GZipIndex {
+        GZipIndex {
+            span,
+            checkpoints: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    // the last checkpoint at or before offset
+    pub fn find(&self, offset: u64) -> Option<&Checkpoint> {
+        match self
+            .checkpoints
+            .binary_search_by_key(&offset, |checkpoint| checkpoint.out_offset)
+        {
+            Ok(index) => Some(&self.checkpoints[index]),
+            Err(0) => None,
+            Err(index) => Some(&self.checkpoints[index - 1]),
+        }
+    }
+
+    // the offset after which the next checkpoint is due
+    pub fn next_checkpoint(&self) -> u64 {
+        if let Some(checkpoint) = self.checkpoints.last() {
+            checkpoint.out_offset + self.span
+        } else {
+            self.span
+        }
+    }
+
+    // add a checkpoint, returns false if it is not beyond the last known checkpoint
+    pub fn add(&mut self, checkpoint: Checkpoint) -> bool {
+        if let Some(last) = self.checkpoints.last() {
+            if checkpoint.out_offset <= last.out_offset {
+                return false;
+            }
+        }
+        self.checkpoints.push(checkpoint);
+        true
+    }
+
+    // load a cached index, returns None if it does not match the image or span
+    pub fn load(
+        index_path: &Path,
+        image_path: &Path,
+        span: u64,
+    ) -> Result<Option<GZipIndex>, MigError> {
+        let mut reader =
+            BufReader::new(File::open(index_path).map_err(|why| io_error(index_path, &why))?);
+
+        let mut magic: [u8; 8] = [0; 8];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|why| io_error(index_path, &why))?;
+        if &magic != INDEX_MAGIC {
+            debug!("load: invalid magic in '{}'", index_path.display());
+            return Ok(None);
+        }
+
+        let (image_size, image_mtime) = image_stamp(image_path)?;
+        if read_u64(&mut reader, index_path)? != image_size
+            || read_u64(&mut reader, index_path)? != image_mtime
+        {
+            debug!("load: index '{}' is outdated", index_path.display());
+            return Ok(None);
+        }
+
+        if read_u64(&mut reader, index_path)? != span {
+            debug!(
+                "load: index '{}' uses a different span",
+                index_path.display()
+            );
+            return Ok(None);
+        }
+
+        let mut index = GZipIndex::new(span);
+        let count = read_u64(&mut reader, index_path)?;
+        for _ in 0..count {
+            let out_offset = read_u64(&mut reader, index_path)?;
+            let in_offset = read_u64(&mut reader, index_path)?;
+            if in_offset >= image_size {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!("Invalid checkpoint in index '{}'", index_path.display()),
+                ));
+            }
+            if !index.add(Checkpoint {
+                out_offset,
+                in_offset,
+            }) {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!("Unordered checkpoints in index '{}'", index_path.display()),
+                ));
+            }
+        }
+
+        debug!(
+            "load: loaded {} checkpoints from '{}'",
+            index.len(),
+            index_path.display()
+        );
+        Ok(Some(index))
+    }
+
+    pub fn save(&self, index_path: &Path, image_path: &Path) -> Result<(), MigError> {
+        let (image_size, image_mtime) = image_stamp(image_path)?;
+
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(INDEX_MAGIC);
+        data.extend_from_slice(&image_size.to_le_bytes());
+        data.extend_from_slice(&image_mtime.to_le_bytes());
+        data.extend_from_slice(&self.span.to_le_bytes());
+        data.extend_from_slice(&(self.checkpoints.len() as u64).to_le_bytes());
+
+        // write to a temporary file first, a truncated index must never be picked up
+        let mut tmp_name = index_path.as_os_str().to_owned();
+        tmp_name.push(".tmp");
+        let tmp_path = Path::new(&tmp_name);
+
+        let mut writer =
+            BufWriter::new(File::create(tmp_path).map_err(|why| io_error(tmp_path, &why))?);
+        writer
+            .write_all(&data)
+            .map_err(|why| io_error(tmp_path, &why))?;
+        for checkpoint in &self.checkpoints {
+            writer
+                .write_all(&checkpoint.out_offset.to_le_bytes())
+                .and_then(|_| writer.write_all(&checkpoint.in_offset.to_le_bytes()))
+                .map_err(|why| io_error(tmp_path, &why))?;
+        }
+        writer.flush().map_err(|why| io_error(tmp_path, &why))?;
+
+        fs::rename(tmp_path, index_path).map_err(|why| io_error(index_path, &why))?;
+        debug!(
+            "save: saved {} checkpoints to '{}'",
+            self.checkpoints.len(),
+            index_path.display()
+        );
+        Ok(())
+    }
+}
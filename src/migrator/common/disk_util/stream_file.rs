@@ -0,0 +1,101 @@
+use log::{debug, trace};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::common::{
+    disk_util::{compression::Compression, image_file::ImageFile},
+    MigError, MigErrorKind,
+};
+
+const DEF_READ_BUFFER: usize = 1024 * 1024;
+
+// *************************************************************************************************
+// * Sequential access to images in compression formats that do not allow seeking (xz, zstd, zip).
+// * Reading backwards restarts decompression at the start of the file.
+// *************************************************************************************************
+
+pub(crate) struct StreamFile {
+    path: PathBuf,
+    compression: Compression,
+    reader: Box<dyn Read>,
+    bytes_read: u64,
+}
+
+impl StreamFile {
+    pub fn new(path: &Path, compression: Compression) -> Result<StreamFile, MigError> {
+        trace!(
+            "new: entered with '{}', compression: {}",
+            path.display(),
+            compression.get_descr()
+        );
+        Ok(StreamFile {
+            path: path.to_path_buf(),
+            compression,
+            reader: compression.open_reader(path)?,
+            bytes_read: 0,
+        })
+    }
+
+    fn reset(&mut self) -> Result<(), MigError> {
+        trace!("reset: entered");
+        self.reader = self.compression.open_reader(&self.path)?;
+        self.bytes_read = 0;
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), MigError> {
+        match self.reader.read_exact(buffer) {
+            Ok(_) => {
+                self.bytes_read += buffer.len() as u64;
+                Ok(())
+            }
+            Err(why) => Err(MigError::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "failed to read from file: '{}', error {:?}",
+                    self.path.display(),
+                    why
+                ),
+            )),
+        }
+    }
+
+    fn seek(&mut self, offset: u64) -> Result<(), MigError> {
+        trace!(
+            "seek: entered with offset {}, bytes_read: {}",
+            offset,
+            self.bytes_read
+        );
+
+        if offset < self.bytes_read {
+            debug!(
+                "seek: restarting decompression of '{}'",
+                self.path.display()
+            );
+            self.reset()?;
+        }
+
+        let mut buffer: Vec<u8> = vec![0; DEF_READ_BUFFER];
+        while self.bytes_read < offset {
+            let to_read = ((offset - self.bytes_read) as usize).min(buffer.len());
+            self.read_exact(&mut buffer[0..to_read])?;
+        }
+        Ok(())
+    }
+}
+
+impl ImageFile for StreamFile {
+    fn fill(&mut self, offset: u64, buffer: &mut [u8]) -> Result<(), MigError> {
+        trace!(
+            "fill: entered with offset {}, size {}",
+            offset,
+            buffer.len()
+        );
+        self.seek(offset)?;
+        self.read_exact(buffer)
+    }
+
+    fn get_path(&self) -> PathBuf {
+        self.path.clone()
+    }
+}
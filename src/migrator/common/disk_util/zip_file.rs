@@ -0,0 +1,276 @@
+use flate2::read::DeflateDecoder;
+use log::{debug, trace};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::common::{MigError, MigErrorKind};
+
+// *************************************************************************************************
+// * Locate the single entry of a zip archive as handed out by balena-cloud.
+// * Only stored & deflated entries are supported, zip64 extensions are handled for large images.
+// *************************************************************************************************
+
+const EOCD_SIG: u32 = 0x0605_4B50;
+const EOCD_SIZE: usize = 22;
+const MAX_COMMENT_SIZE: usize = 0xFFFF;
+const ZIP64_LOCATOR_SIG: u32 = 0x0706_4B50;
+const ZIP64_LOCATOR_SIZE: usize = 20;
+const ZIP64_EOCD_SIG: u32 = 0x0606_4B50;
+const ZIP64_EOCD_SIZE: usize = 56;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+const CENTRAL_HEADER_SIG: u32 = 0x0201_4B50;
+const CENTRAL_HEADER_SIZE: usize = 46;
+const LOCAL_HEADER_SIG: u32 = 0x0403_4B50;
+const LOCAL_HEADER_SIZE: usize = 30;
+
+const FLAG_ENCRYPTED: u16 = 0x0001;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+    u16::from(buffer[offset]) | (u16::from(buffer[offset + 1]) << 8)
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    u32::from(read_u16(buffer, offset)) | (u32::from(read_u16(buffer, offset + 2)) << 16)
+}
+
+fn read_u64(buffer: &[u8], offset: usize) -> u64 {
+    u64::from(read_u32(buffer, offset)) | (u64::from(read_u32(buffer, offset + 4)) << 32)
+}
+
+fn read_at(file: &mut File, path: &Path, offset: u64, buffer: &mut [u8]) -> Result<(), MigError> {
+    if let Err(why) = file
+        .seek(SeekFrom::Start(offset))
+        .and_then(|_| file.read_exact(buffer))
+    {
+        return Err(MigError::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "Failed to read {} bytes at offset {} from '{}', error: {:?}",
+                buffer.len(),
+                offset,
+                path.display(),
+                why
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn zip_error(path: &Path, msg: &str) -> MigError {
+    MigError::from_remark(
+        MigErrorKind::InvParam,
+        &format!("Invalid zip archive '{}': {}", path.display(), msg),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ZipEntry {
+    pub method: u16,
+    pub compressed_size: u64,
+    // offset of the entry data in the archive
+    pub data_offset: u64,
+}
+
+impl ZipEntry {
+    // find the one and only entry of the archive
+    pub fn from_file(path: &Path) -> Result<ZipEntry, MigError> {
+        trace!("from_file: entered with '{}'", path.display());
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(why) => {
+                return Err(MigError::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!(
+                        "failed to open file for reading: '{}', error {:?}",
+                        path.display(),
+                        why
+                    ),
+                ));
+            }
+        };
+
+        let file_size = match file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(why) => {
+                return Err(MigError::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!(
+                        "Failed to retrieve metadata for '{}', error: {:?}",
+                        path.display(),
+                        why
+                    ),
+                ));
+            }
+        };
+
+        // the end of central directory record is followed by a variable size comment
+        let tail_size = file_size.min((EOCD_SIZE + MAX_COMMENT_SIZE) as u64) as usize;
+        if tail_size < EOCD_SIZE {
+            return Err(zip_error(path, "file is too small"));
+        }
+        let tail_offset = file_size - tail_size as u64;
+        let mut tail: Vec<u8> = vec![0; tail_size];
+        read_at(&mut file, path, tail_offset, &mut tail)?;
+
+        let eocd_pos = if let Some(pos) = (0..=tail_size - EOCD_SIZE)
+            .rev()
+            .find(|pos| read_u32(&tail, *pos) == EOCD_SIG)
+        {
+            pos
+        } else {
+            return Err(zip_error(path, "end of central directory not found"));
+        };
+
+        let eocd = &tail[eocd_pos..eocd_pos + EOCD_SIZE];
+        let mut num_entries = u64::from(read_u16(eocd, 10));
+        let mut cd_offset = u64::from(read_u32(eocd, 16));
+
+        if num_entries == 0xFFFF || cd_offset == 0xFFFF_FFFF {
+            // zip64, the locator immediately precedes the end of central directory record
+            let locator_offset = (tail_offset + eocd_pos as u64)
+                .checked_sub(ZIP64_LOCATOR_SIZE as u64)
+                .ok_or_else(|| zip_error(path, "zip64 locator not found"))?;
+            let mut locator: [u8; ZIP64_LOCATOR_SIZE] = [0; ZIP64_LOCATOR_SIZE];
+            read_at(&mut file, path, locator_offset, &mut locator)?;
+            if read_u32(&locator, 0) != ZIP64_LOCATOR_SIG {
+                return Err(zip_error(path, "zip64 locator not found"));
+            }
+
+            let mut eocd64: [u8; ZIP64_EOCD_SIZE] = [0; ZIP64_EOCD_SIZE];
+            read_at(&mut file, path, read_u64(&locator, 8), &mut eocd64)?;
+            if read_u32(&eocd64, 0) != ZIP64_EOCD_SIG {
+                return Err(zip_error(path, "zip64 end of central directory not found"));
+            }
+            num_entries = read_u64(&eocd64, 32);
+            cd_offset = read_u64(&eocd64, 48);
+        }
+
+        if num_entries != 1 {
+            return Err(zip_error(
+                path,
+                &format!("expected a single entry, found {}", num_entries),
+            ));
+        }
+
+        let mut header: [u8; CENTRAL_HEADER_SIZE] = [0; CENTRAL_HEADER_SIZE];
+        read_at(&mut file, path, cd_offset, &mut header)?;
+        if read_u32(&header, 0) != CENTRAL_HEADER_SIG {
+            return Err(zip_error(path, "invalid central directory header"));
+        }
+
+        let flags = read_u16(&header, 8);
+        let method = read_u16(&header, 10);
+        let mut compressed_size = u64::from(read_u32(&header, 20));
+        let mut size = u64::from(read_u32(&header, 24));
+        let name_len = read_u16(&header, 28) as usize;
+        let extra_len = read_u16(&header, 30) as usize;
+        let mut local_offset = u64::from(read_u32(&header, 42));
+
+        if flags & FLAG_ENCRYPTED != 0 {
+            return Err(zip_error(path, "encrypted entries are not supported"));
+        }
+
+        if method != METHOD_STORED && method != METHOD_DEFLATE {
+            return Err(zip_error(
+                path,
+                &format!("unsupported compression method {}", method),
+            ));
+        }
+
+        let mut name_extra: Vec<u8> = vec![0; name_len + extra_len];
+        read_at(
+            &mut file,
+            path,
+            cd_offset + CENTRAL_HEADER_SIZE as u64,
+            &mut name_extra,
+        )?;
+        let name = String::from_utf8_lossy(&name_extra[0..name_len]).to_string();
+
+        // zip64 extended information, values are only present if their 32 bit field is maxed out
+        let extra = &name_extra[name_len..];
+        let mut pos: usize = 0;
+        while pos + 4 <= extra.len() {
+            let id = read_u16(extra, pos);
+            let len = read_u16(extra, pos + 2) as usize;
+            let data = &extra[pos + 4..(pos + 4 + len).min(extra.len())];
+            if id == ZIP64_EXTRA_ID {
+                let mut field: usize = 0;
+                for value in &mut [&mut size, &mut compressed_size, &mut local_offset] {
+                    if **value == 0xFFFF_FFFF {
+                        if field + 8 > data.len() {
+                            return Err(zip_error(path, "truncated zip64 extra field"));
+                        }
+                        **value = read_u64(data, field);
+                        field += 8;
+                    }
+                }
+            }
+            pos += 4 + len;
+        }
+
+        let mut local_header: [u8; LOCAL_HEADER_SIZE] = [0; LOCAL_HEADER_SIZE];
+        read_at(&mut file, path, local_offset, &mut local_header)?;
+        if read_u32(&local_header, 0) != LOCAL_HEADER_SIG {
+            return Err(zip_error(path, "invalid local file header"));
+        }
+        let data_offset = local_offset
+            + LOCAL_HEADER_SIZE as u64
+            + u64::from(read_u16(&local_header, 26))
+            + u64::from(read_u16(&local_header, 28));
+
+        if data_offset + compressed_size > file_size {
+            return Err(zip_error(path, "entry exceeds the archive size"));
+        }
+
+        debug!(
+            "from_file: found entry '{}', method: {}, size: {}, compressed: {}, offset: {}",
+            name, method, size, compressed_size, data_offset
+        );
+
+        Ok(ZipEntry {
+            method,
+            compressed_size,
+            data_offset,
+        })
+    }
+
+    // open a reader delivering the uncompressed entry data
+    pub fn open_reader(&self, path: &Path) -> Result<Box<dyn Read>, MigError> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(why) => {
+                return Err(MigError::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!(
+                        "failed to open file for reading: '{}', error {:?}",
+                        path.display(),
+                        why
+                    ),
+                ));
+            }
+        };
+
+        if let Err(why) = file.seek(SeekFrom::Start(self.data_offset)) {
+            return Err(MigError::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "Failed to seek to offset {} in '{}', error: {:?}",
+                    self.data_offset,
+                    path.display(),
+                    why
+                ),
+            ));
+        }
+
+        let data = file.take(self.compressed_size);
+        if self.method == METHOD_DEFLATE {
+            Ok(Box::new(DeflateDecoder::new(data)))
+        } else {
+            Ok(Box::new(data))
+        }
+    }
+}
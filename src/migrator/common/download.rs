@@ -0,0 +1,616 @@
+use digest::Digest;
+use failure::ResultExt;
+use log::{debug, error, info, trace, warn};
+use sha2::Sha256;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{remove_file, rename, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::common::{
+    bmap::to_hex_string,
+    config::{balena_config::FileRef, migrate_config::DownloadCfg},
+    file_digest::{check_digest, HashInfo},
+    file_exists, file_size, format_size_with_unit, path_append, MigErrCtx, MigError, MigErrorKind,
+};
+
+// *************************************************************************************************
+// * Download files referenced by URL into the working directory.
+// * Interrupted downloads are kept as <file>.part and resumed using range requests, proxies are
+// * taken from the http_proxy / https_proxy / no_proxy environment variables.
+// * A file named like one downloaded before from a different URL gets a prefix derived from its URL.
+// *************************************************************************************************
+
+const DEFAULT_MAX_SIZE: u64 = 8 * 1024 * 1024 * 1024;
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const BUFFER_SIZE: usize = 1024 * 1024;
+const REPORT_INTERVAL_SECS: u64 = 10;
+const PART_EXT: &str = "part";
+// hex digits of the URL digest prefixed to clashing file names
+const URL_PREFIX_LEN: usize = 8;
+
+pub(crate) struct Downloader {
+    max_size: u64,
+    timeout: Duration,
+    // file names handed out and the URLs they were downloaded from
+    file_names: RefCell<HashMap<String, Url>>,
+}
+
+// FileRef paths starting with http:// or https:// are downloaded
+pub(crate) fn get_url(file_ref: &FileRef) -> Option<Url> {
+    let path_str = file_ref.path.to_string_lossy();
+    if path_str.starts_with("http://") || path_str.starts_with("https://") {
+        Url::parse(&path_str).ok()
+    } else {
+        None
+    }
+}
+
+fn get_env(name: &str) -> Option<String> {
+    if let Ok(val) = env::var(name) {
+        if !val.is_empty() {
+            return Some(val);
+        }
+    }
+
+    if let Ok(val) = env::var(name.to_uppercase()) {
+        if !val.is_empty() {
+            return Some(val);
+        }
+    }
+    None
+}
+
+fn get_proxy(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+
+    if let Some(no_proxy) = get_env("no_proxy") {
+        for entry in no_proxy.split(',').map(|entry| entry.trim()) {
+            if entry == "*" {
+                return None;
+            }
+
+            let entry = entry.trim_start_matches('.');
+            if !entry.is_empty() && (host == entry || host.ends_with(&format!(".{}", entry))) {
+                return None;
+            }
+        }
+    }
+
+    get_env(&format!("{}_proxy", url.scheme()))
+        .map(|proxy| String::from(proxy.trim_end_matches('/')))
+}
+
+// first byte of a Content-Range header value like 'bytes 100-299/300'
+fn get_range_start(content_range: &str) -> Option<u64> {
+    let mut words = content_range.trim().splitn(2, ' ');
+    if words.next() == Some("bytes") {
+        words.next()?.split('-').next()?.trim().parse::<u64>().ok()
+    } else {
+        None
+    }
+}
+
+impl Downloader {
+    pub fn new(config: Option<&DownloadCfg>) -> Downloader {
+        let (max_size, timeout) = if let Some(config) = config {
+            (
+                config.max_size.unwrap_or(DEFAULT_MAX_SIZE),
+                config.timeout.unwrap_or(DEFAULT_TIMEOUT_SECS),
+            )
+        } else {
+            (DEFAULT_MAX_SIZE, DEFAULT_TIMEOUT_SECS)
+        };
+
+        Downloader {
+            max_size,
+            timeout: Duration::from_secs(timeout),
+            file_names: RefCell::new(HashMap::new()),
+        }
+    }
+
+    // the last path segment of the URL, prefixed with a digest of the URL if a different URL
+    // used the same name before
+    fn get_file_name(&self, url: &Url) -> Result<String, MigError> {
+        let file_name = if let Some(file_name) = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|name| !name.is_empty())
+        {
+            String::from(file_name)
+        } else {
+            error!("Unable to derive a file name from URL '{}'", url);
+            return Err(MigError::displayed());
+        };
+
+        let mut file_names = self.file_names.borrow_mut();
+        let file_name = match file_names.get(&file_name) {
+            Some(other_url) if other_url != url => {
+                let mut hasher = Sha256::default();
+                hasher.input(url.as_str().as_bytes());
+                let url_digest = to_hex_string(&hasher.result());
+                let unique_name = format!("{}-{}", &url_digest[0..URL_PREFIX_LEN], file_name);
+                warn!(
+                    "'{}' and '{}' share the file name '{}', downloading the latter to '{}'",
+                    other_url, url, file_name, unique_name
+                );
+                unique_name
+            }
+            _ => file_name,
+        };
+
+        file_names.insert(file_name.clone(), url.clone());
+        Ok(file_name)
+    }
+
+    // download the file if it is referenced by URL, return a reference to the local file
+    pub fn fetch<P: AsRef<Path>>(
+        &self,
+        file_ref: &FileRef,
+        work_dir: P,
+    ) -> Result<FileRef, MigError> {
+        let url = if let Some(url) = get_url(file_ref) {
+            url
+        } else {
+            return Ok(file_ref.clone());
+        };
+
+        let target = path_append(work_dir, &self.get_file_name(&url)?);
+
+        if file_exists(&target) {
+            if let Some(ref hash_info) = file_ref.hash {
+                if check_digest(&target, hash_info)? {
+                    info!(
+                        "The file '{}' was downloaded before, the digest matches",
+                        target.display()
+                    );
+                    return Ok(FileRef {
+                        path: target,
+                        hash: file_ref.hash.clone(),
+                    });
+                }
+                warn!(
+                    "The digest of existing file '{}' does not match, downloading it again",
+                    target.display()
+                );
+            }
+        }
+
+        self.download(&url, &target, file_ref.hash.as_ref())?;
+
+        Ok(FileRef {
+            path: target,
+            hash: file_ref.hash.clone(),
+        })
+    }
+
+    fn download(
+        &self,
+        url: &Url,
+        target: &Path,
+        hash_info: Option<&HashInfo>,
+    ) -> Result<(), MigError> {
+        trace!("download: entered with '{}' -> '{}'", url, target.display());
+
+        let part_path = PathBuf::from(format!("{}.{}", target.display(), PART_EXT));
+        let agent = ureq::agent();
+
+        let mut restarted = false;
+        let (response, mut offset) = loop {
+            let offset = if file_exists(&part_path) {
+                file_size(&part_path)?
+            } else {
+                0
+            };
+
+            let mut request = agent.get(url.as_str());
+            request
+                .timeout_connect(self.timeout.as_millis() as u64)
+                .timeout_read(self.timeout.as_millis() as u64);
+
+            if offset > 0 {
+                info!(
+                    "Resuming download of '{}' at {}",
+                    url,
+                    format_size_with_unit(offset)
+                );
+                request.set("Range", &format!("bytes={}-", offset));
+            }
+
+            if let Some(proxy) = get_proxy(url) {
+                debug!("download: using proxy '{}'", proxy);
+                match ureq::Proxy::new(&proxy) {
+                    Ok(proxy) => {
+                        request.set_proxy(proxy);
+                    }
+                    Err(why) => {
+                        error!("Invalid proxy '{}', error: {:?}", proxy, why);
+                        return Err(MigError::displayed());
+                    }
+                }
+            }
+
+            let response = request.call();
+            if let Some(why) = response.synthetic_error() {
+                error!("Failed to download '{}', error: {}", url, why);
+                return Err(MigError::displayed());
+            }
+
+            match response.status() {
+                206 if offset > 0 => {
+                    let range_start = response.header("Content-Range").and_then(get_range_start);
+                    if range_start == Some(offset) {
+                        break (response, offset);
+                    }
+                    // appending a different range would corrupt the file
+                    warn!(
+                        "The server returned range {:?} instead of {} for '{}', restarting download",
+                        response.header("Content-Range"),
+                        offset,
+                        url
+                    );
+                    remove_file(&part_path).context(MigErrCtx::from_remark(
+                        MigErrorKind::Upstream,
+                        &format!("Failed to remove '{}'", part_path.display()),
+                    ))?;
+                }
+                200 => {
+                    if offset > 0 {
+                        info!("The server does not support resuming, restarting download");
+                    }
+                    break (response, 0);
+                }
+                416 if offset > 0 && !restarted => {
+                    // the partial file does not fit the remote file any more
+                    warn!(
+                        "Unable to resume download of '{}', restarting download",
+                        url
+                    );
+                    remove_file(&part_path).context(MigErrCtx::from_remark(
+                        MigErrorKind::Upstream,
+                        &format!("Failed to remove '{}'", part_path.display()),
+                    ))?;
+                    restarted = true;
+                }
+                status => {
+                    error!(
+                        "Failed to download '{}', server returned {} {}",
+                        url,
+                        status,
+                        response.status_text()
+                    );
+                    return Err(MigError::displayed());
+                }
+            }
+        };
+
+        if let Some(length) = response
+            .header("Content-Length")
+            .and_then(|length| length.parse::<u64>().ok())
+        {
+            if offset + length > self.max_size {
+                error!(
+                    "The file '{}' is {}, larger than the maximum download size of {}",
+                    url,
+                    format_size_with_unit(offset + length),
+                    format_size_with_unit(self.max_size)
+                );
+                return Err(MigError::displayed());
+            }
+            info!(
+                "Downloading '{}' to '{}', {} remaining",
+                url,
+                target.display(),
+                format_size_with_unit(length)
+            );
+        } else {
+            info!("Downloading '{}' to '{}'", url, target.display());
+        }
+
+        let mut part_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(offset > 0)
+            .truncate(offset == 0)
+            .open(&part_path)
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed to open '{}' for writing", part_path.display()),
+            ))?;
+
+        let mut reader = response.into_reader();
+        let mut buffer: Vec<u8> = vec![0; BUFFER_SIZE];
+        let start_time = Instant::now();
+        let mut last_report = start_time;
+        loop {
+            let bytes_read = reader.read(&mut buffer).context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed to read from '{}'", url),
+            ))?;
+
+            if bytes_read == 0 {
+                break;
+            }
+
+            offset += bytes_read as u64;
+            if offset > self.max_size {
+                error!(
+                    "The download of '{}' exceeds the maximum download size of {}",
+                    url,
+                    format_size_with_unit(self.max_size)
+                );
+                return Err(MigError::displayed());
+            }
+
+            part_file
+                .write_all(&buffer[0..bytes_read])
+                .context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!("Failed to write to '{}'", part_path.display()),
+                ))?;
+
+            if last_report.elapsed().as_secs() >= REPORT_INTERVAL_SECS {
+                last_report = Instant::now();
+                info!(
+                    "{} downloaded in {} seconds",
+                    format_size_with_unit(offset),
+                    start_time.elapsed().as_secs()
+                );
+            }
+        }
+
+        part_file.flush().context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to write to '{}'", part_path.display()),
+        ))?;
+
+        if let Some(hash_info) = hash_info {
+            if !check_digest(&part_path, hash_info)? {
+                // a corrupt download can not be resumed
+                let _res = remove_file(&part_path);
+                error!(
+                    "The digest of the file downloaded from '{}' does not match {:?}",
+                    url, hash_info
+                );
+                return Err(MigError::displayed());
+            }
+            debug!("download: digest of '{}' is ok", part_path.display());
+        }
+
+        rename(&part_path, target).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "Failed to rename '{}' to '{}'",
+                part_path.display(),
+                target.display()
+            ),
+        ))?;
+
+        info!(
+            "Downloaded {} from '{}' to '{}'",
+            format_size_with_unit(offset),
+            url,
+            target.display()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, read, remove_dir_all, write};
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    const TEST_CONTENT_SIZE: usize = 300_000;
+    // md5 digest of TEST_CONTENT_SIZE bytes created by test_content
+    const TEST_CONTENT_MD5: &str = "34fadf2975834e9a357ec41d3e6df067";
+
+    fn test_content() -> Vec<u8> {
+        (0..TEST_CONTENT_SIZE)
+            .map(|idx| (idx % 251) as u8)
+            .collect()
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Ranges {
+        Ignore,
+        Honour,
+        // answer range requests with the whole file in a 206 response
+        WholeFile,
+    }
+
+    // serve content to a number of requests, handling range requests as told by ranges
+    fn serve(content: Vec<u8>, requests: usize, ranges: Ranges) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut range = None;
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim().to_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if line.starts_with("range: bytes=") {
+                        range = Some(
+                            line["range: bytes=".len()..]
+                                .trim_end_matches('-')
+                                .parse::<usize>()
+                                .unwrap(),
+                        );
+                    }
+                }
+
+                let offset = match (range, ranges) {
+                    (Some(offset), Ranges::Honour) => Some(offset),
+                    (Some(_), Ranges::WholeFile) => Some(0),
+                    _ => None,
+                };
+
+                let header = if let Some(offset) = offset {
+                    format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+                        content.len() - offset,
+                        offset,
+                        content.len() - 1,
+                        content.len()
+                    )
+                } else {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        content.len()
+                    )
+                };
+                stream.write_all(header.as_bytes()).unwrap();
+                // the client may hang up early
+                let _res = stream.write_all(&content[offset.unwrap_or(0)..]);
+            }
+        });
+        format!("http://127.0.0.1:{}/files/balena.img", port)
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = path_append(env::temp_dir(), name);
+        let _res = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn download_and_resume() {
+        let work_dir = test_dir("balena-migrate-download-test");
+        let content = test_content();
+        // leave part of the file from an interrupted download
+        write(
+            path_append(&work_dir, "balena.img.part"),
+            &content[0..100_000],
+        )
+        .unwrap();
+
+        let url = serve(content.clone(), 1, Ranges::Honour);
+        let file_ref = FileRef {
+            path: PathBuf::from(url),
+            hash: Some(HashInfo::Md5(String::from(TEST_CONTENT_MD5))),
+        };
+
+        let local_ref = Downloader::new(None).fetch(&file_ref, &work_dir).unwrap();
+        assert_eq!(local_ref.path, path_append(&work_dir, "balena.img"));
+        assert_eq!(read(&local_ref.path).unwrap(), content);
+        assert!(!file_exists(path_append(&work_dir, "balena.img.part")));
+
+        // the existing file is not downloaded again, the server is gone
+        let local_ref = Downloader::new(None).fetch(&file_ref, &work_dir).unwrap();
+        assert_eq!(local_ref.path, path_append(&work_dir, "balena.img"));
+        remove_dir_all(&work_dir).unwrap();
+    }
+
+    #[test]
+    fn download_fails() {
+        let work_dir = test_dir("balena-migrate-download-fail-test");
+        let url = serve(test_content(), 2, Ranges::Ignore);
+
+        let file_ref = FileRef {
+            path: PathBuf::from(&url),
+            hash: Some(HashInfo::Md5(String::from(
+                "00000000000000000000000000000000",
+            ))),
+        };
+        assert!(Downloader::new(None).fetch(&file_ref, &work_dir).is_err());
+        assert!(!file_exists(path_append(&work_dir, "balena.img")));
+
+        let file_ref = FileRef {
+            path: PathBuf::from(&url),
+            hash: None,
+        };
+        let config = DownloadCfg {
+            max_size: Some(1000),
+            timeout: None,
+        };
+        assert!(Downloader::new(Some(&config))
+            .fetch(&file_ref, &work_dir)
+            .is_err());
+        remove_dir_all(&work_dir).unwrap();
+    }
+
+    #[test]
+    fn resume_wrong_range() {
+        let work_dir = test_dir("balena-migrate-download-range-test");
+        let content = test_content();
+        write(
+            path_append(&work_dir, "balena.img.part"),
+            &content[0..100_000],
+        )
+        .unwrap();
+
+        let url = serve(content.clone(), 2, Ranges::WholeFile);
+
+        let file_ref = FileRef {
+            path: PathBuf::from(url),
+            hash: Some(HashInfo::Md5(String::from(TEST_CONTENT_MD5))),
+        };
+        let local_ref = Downloader::new(None).fetch(&file_ref, &work_dir).unwrap();
+        assert_eq!(read(&local_ref.path).unwrap(), content);
+        remove_dir_all(&work_dir).unwrap();
+    }
+
+    #[test]
+    fn same_file_name() {
+        let work_dir = test_dir("balena-migrate-download-name-test");
+        let content = test_content();
+        let other_content: Vec<u8> = content.iter().rev().cloned().collect();
+        let urls = vec![
+            serve(content.clone(), 1, Ranges::Ignore),
+            serve(other_content.clone(), 1, Ranges::Ignore),
+        ];
+
+        let downloader = Downloader::new(None);
+        let paths: Vec<PathBuf> = urls
+            .iter()
+            .map(|url| {
+                let file_ref = FileRef {
+                    path: PathBuf::from(url),
+                    hash: None,
+                };
+                downloader.fetch(&file_ref, &work_dir).unwrap().path
+            })
+            .collect();
+
+        assert_eq!(paths[0], path_append(&work_dir, "balena.img"));
+        assert_ne!(paths[0], paths[1]);
+        assert_eq!(read(&paths[0]).unwrap(), content);
+        assert_eq!(read(&paths[1]).unwrap(), other_content);
+        remove_dir_all(&work_dir).unwrap();
+    }
+
+    #[test]
+    fn content_range() {
+        assert_eq!(get_range_start("bytes 100-299/300"), Some(100));
+        assert_eq!(get_range_start("bytes 0-299/*"), Some(0));
+        assert_eq!(get_range_start("bytes */300"), None);
+        assert_eq!(get_range_start("items 100-299/300"), None);
+    }
+
+    #[test]
+    fn local_file_ref() {
+        let file_ref = FileRef {
+            path: PathBuf::from("balena.img.gz"),
+            hash: None,
+        };
+        assert!(get_url(&file_ref).is_none());
+        assert_eq!(
+            Downloader::new(None).fetch(&file_ref, "/tmp").unwrap(),
+            file_ref
+        );
+    }
+}
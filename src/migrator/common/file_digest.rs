@@ -1,31 +1,417 @@
 use digest::Digest;
 use failure::ResultExt;
-use log::debug;
+use log::{debug, error, info, warn};
 use md5::Md5;
 use serde::{Deserialize, Serialize};
 use sha1::Sha1;
-use std::fs::File;
+use sha2::{Sha256, Sha512};
+use std::collections::HashMap;
+use std::fs::{read_to_string, write, File};
 use std::io::Read;
-use std::path::Path;
+use std::mem::discriminant;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
-use crate::common::{MigErrCtx, MigError, MigErrorKind};
+use crate::common::{
+    bmap::to_hex_string, config::balena_config::FileRef, download::get_url, file_exists,
+    path_append, MigErrCtx, MigError, MigErrorKind,
+};
 
 const BUFFER_SIZE: usize = 1024 * 1024;
 
+// manifests looked for in the work dir if none is configured
+const DEFAULT_MANIFESTS: &[&str] = &["SHA256SUMS", "SHA512SUMS"];
+
+// digests computed in stage 1 are kept in the work dir
+const DIGEST_CACHE_FILE: &str = ".balena-migrate-digests.json";
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub(crate) enum HashInfo {
     #[serde(rename = "sha1")]
     Sha1(String),
     #[serde(rename = "md5")]
     Md5(String),
+    #[serde(rename = "sha256")]
+    Sha256(String),
+    #[serde(rename = "sha512")]
+    Sha512(String),
 }
 
-pub(crate) fn check_digest<P: AsRef<Path>>(path: P, digest: &HashInfo) -> Result<bool, MigError> {
-    //let path= path.as_ref();
-    let computed = match digest {
+impl HashInfo {
+    // derive the digest type from the length of a hex digest
+    fn from_hex(digest: &str) -> Option<HashInfo> {
+        if !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let digest = digest.to_lowercase();
+        match digest.len() {
+            32 => Some(HashInfo::Md5(digest)),
+            40 => Some(HashInfo::Sha1(digest)),
+            64 => Some(HashInfo::Sha256(digest)),
+            128 => Some(HashInfo::Sha512(digest)),
+            _ => None,
+        }
+    }
+}
+
+// checksum manifest as created by sha256sum & co, lines of '<digest>  <file>' or '<digest> *<file>'
+#[derive(Debug)]
+pub(crate) struct DigestManifest {
+    path: PathBuf,
+    digests: HashMap<String, HashInfo>,
+}
+
+impl DigestManifest {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<DigestManifest, MigError> {
+        let path = path.as_ref();
+        let content = read_to_string(path).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to read digest manifest '{}'", path.display()),
+        ))?;
+        DigestManifest::from_str(path, &content)
+    }
+
+    pub fn from_str<P: AsRef<Path>>(path: P, content: &str) -> Result<DigestManifest, MigError> {
+        let path = path.as_ref();
+        let mut digests: HashMap<String, HashInfo> = HashMap::new();
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let parsed = if let Some(pos) = line.find(char::is_whitespace) {
+                let (digest, file) = line.split_at(pos);
+                let file = file.trim_start();
+                let file = file.trim_start_matches('*').trim_start_matches("./");
+                if let Some(hash_info) = HashInfo::from_hex(digest) {
+                    if !file.is_empty() {
+                        Some((String::from(file), hash_info))
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                }
+            } else {
+                None
+            };
+
+            if let Some((file, hash_info)) = parsed {
+                digests.insert(file, hash_info);
+            } else {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!(
+                        "Invalid entry in digest manifest '{}' line {}",
+                        path.display(),
+                        idx + 1
+                    ),
+                ));
+            }
+        }
+
+        debug!(
+            "DigestManifest::from_str: read {} digests from '{}'",
+            digests.len(),
+            path.display()
+        );
+
+        Ok(DigestManifest {
+            path: path.to_path_buf(),
+            digests,
+        })
+    }
+
+    // load the configured manifest or the first default manifest found in the work dir
+    pub fn find<P: AsRef<Path>>(
+        work_dir: P,
+        manifest: Option<&Path>,
+    ) -> Result<Option<DigestManifest>, MigError> {
+        let work_dir = work_dir.as_ref();
+        if let Some(manifest) = manifest {
+            Ok(Some(DigestManifest::from_file(path_append(
+                work_dir, manifest,
+            ))?))
+        } else if let Some(path) = DEFAULT_MANIFESTS
+            .iter()
+            .map(|name| path_append(work_dir, name))
+            .find(|path| file_exists(path))
+        {
+            info!("Using digest manifest '{}'", path.display());
+            Ok(Some(DigestManifest::from_file(path)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // digests are looked up by relative path first, then by file name
+    pub fn get_digest(&self, file_ref: &FileRef) -> Option<&HashInfo> {
+        let file_path = if let Some(url) = get_url(file_ref) {
+            PathBuf::from(url.path().trim_start_matches('/'))
+        } else {
+            file_ref.path.clone()
+        };
+
+        let rel_path = file_path.to_string_lossy();
+        if let Some(hash_info) = self.digests.get(rel_path.trim_start_matches("./")) {
+            return Some(hash_info);
+        }
+
+        let file_name = file_path.file_name()?.to_string_lossy();
+        self.digests.get(file_name.as_ref())
+    }
+
+    // fill in the digest from the manifest, a configured digest takes precedence
+    pub fn fill(&self, file_ref: &FileRef) -> FileRef {
+        if file_ref.hash.is_some() {
+            if let Some(hash_info) = self.get_digest(file_ref) {
+                if Some(hash_info) != file_ref.hash.as_ref() {
+                    warn!(
+                        "The digest configured for '{}' differs from manifest '{}'",
+                        file_ref.path.display(),
+                        self.path.display()
+                    );
+                }
+            }
+            file_ref.clone()
+        } else {
+            FileRef {
+                path: file_ref.path.clone(),
+                hash: self.get_digest(file_ref).cloned(),
+            }
+        }
+    }
+
+    // like fill but the manifest is authoritative, files it does not cover are refused
+    pub fn require(&self, file_ref: &FileRef) -> Result<FileRef, MigError> {
+        if let Some(hash_info) = self.get_digest(file_ref) {
+            if let Some(ref configured) = file_ref.hash {
+                if configured != hash_info {
+                    error!(
+                        "The digest configured for '{}' differs from manifest '{}'",
+                        file_ref.path.display(),
+                        self.path.display()
+                    );
+                    return Err(MigError::displayed());
+                }
+            }
+            Ok(FileRef {
+                path: file_ref.path.clone(),
+                hash: Some(hash_info.clone()),
+            })
+        } else {
+            error!(
+                "The file '{}' is not covered by manifest '{}'",
+                file_ref.path.display(),
+                self.path.display()
+            );
+            Err(MigError::displayed())
+        }
+    }
+}
+
+// incremental digest of the same type as a given digest, used to hash data while copying it
+pub(crate) enum DigestHasher {
+    Sha1(Sha1),
+    Md5(Md5),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl DigestHasher {
+    pub fn new(digest: &HashInfo) -> DigestHasher {
+        match digest {
+            HashInfo::Sha1(_) => DigestHasher::Sha1(Sha1::default()),
+            HashInfo::Md5(_) => DigestHasher::Md5(Md5::default()),
+            HashInfo::Sha256(_) => DigestHasher::Sha256(Sha256::default()),
+            HashInfo::Sha512(_) => DigestHasher::Sha512(Sha512::default()),
+        }
+    }
+
+    pub fn input(&mut self, data: &[u8]) {
+        match self {
+            DigestHasher::Sha1(hasher) => hasher.input(data),
+            DigestHasher::Md5(hasher) => hasher.input(data),
+            DigestHasher::Sha256(hasher) => hasher.input(data),
+            DigestHasher::Sha512(hasher) => hasher.input(data),
+        }
+    }
+
+    pub fn result(self) -> HashInfo {
+        match self {
+            DigestHasher::Sha1(hasher) => HashInfo::Sha1(to_hex_string(&hasher.result())),
+            DigestHasher::Md5(hasher) => HashInfo::Md5(to_hex_string(&hasher.result())),
+            DigestHasher::Sha256(hasher) => HashInfo::Sha256(to_hex_string(&hasher.result())),
+            DigestHasher::Sha512(hasher) => HashInfo::Sha512(to_hex_string(&hasher.result())),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    digests: Vec<HashInfo>,
+}
+
+// digests keyed by path, size and modification time, so unchanged files are not read again.
+// A cache loaded from a work dir is saved there whenever a digest is added
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct DigestCache {
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    entries: HashMap<String, CacheEntry>,
+}
+
+impl DigestCache {
+    // load the digest cache kept in work_dir, start a new one if there is none
+    pub fn load<P: AsRef<Path>>(work_dir: P) -> DigestCache {
+        let cache_path = path_append(work_dir, DIGEST_CACHE_FILE);
+        let cache = if file_exists(&cache_path) {
+            let cache = read_to_string(&cache_path)
+                .ok()
+                .and_then(|content| serde_json::from_str::<DigestCache>(&content).ok());
+            if cache.is_none() {
+                warn!(
+                    "Failed to read digest cache '{}', starting a new one",
+                    cache_path.display()
+                );
+            }
+            cache
+        } else {
+            None
+        };
+
+        let mut cache = cache.unwrap_or_default();
+        debug!(
+            "DigestCache::load: using '{}' with {} entries",
+            cache_path.display(),
+            cache.entries.len()
+        );
+        cache.path = Some(cache_path);
+        cache
+    }
+
+    // the default digest, taken from the cache if the file did not change
+    pub fn get_default_digest<P: AsRef<Path>>(&mut self, path: P) -> Result<HashInfo, MigError> {
+        let path = path.as_ref();
+        let digest = HashInfo::Sha256(String::new());
+        if let Some(cached) = self.get(path, &digest) {
+            debug!(
+                "get_default_digest: using cached digest for '{}'",
+                path.display()
+            );
+            return Ok(cached);
+        }
+
+        let computed = compute_digest(path, &digest)?;
+        self.insert(path, &computed);
+        Ok(computed)
+    }
+
+    // configured and signed digests are always checked against the file, the cache lives in the
+    // work dir and can be written by anyone who can write the files it describes
+    pub fn check_digest<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        digest: &HashInfo,
+    ) -> Result<bool, MigError> {
+        let path = path.as_ref();
+        let computed = compute_digest(path, digest)?;
+        self.insert(path, &computed);
+
+        debug!("check_digest: provided digest is: {:?}", digest);
+        debug!("check_digest: computed digest is: {:?}", computed);
+        Ok(computed == *digest)
+    }
+
+    // identify a file by canonical path, size and modification time
+    fn get_key(path: &Path) -> Option<(String, u64, u64, u32)> {
+        let abs_path = path.canonicalize().ok()?;
+        let metadata = abs_path.metadata().ok()?;
+        let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some((
+            String::from(abs_path.to_string_lossy()),
+            metadata.len(),
+            mtime.as_secs(),
+            mtime.subsec_nanos(),
+        ))
+    }
+
+    fn get(&self, path: &Path, digest: &HashInfo) -> Option<HashInfo> {
+        let (key, size, mtime_secs, mtime_nanos) = DigestCache::get_key(path)?;
+        let entry = self.entries.get(&key)?;
+        if entry.size == size && entry.mtime_secs == mtime_secs && entry.mtime_nanos == mtime_nanos
+        {
+            entry
+                .digests
+                .iter()
+                .find(|cached| discriminant(*cached) == discriminant(digest))
+                .cloned()
+        } else {
+            None
+        }
+    }
+
+    fn insert(&mut self, path: &Path, digest: &HashInfo) {
+        if let Some((key, size, mtime_secs, mtime_nanos)) = DigestCache::get_key(path) {
+            let entry = self.entries.entry(key).or_insert(CacheEntry {
+                size,
+                mtime_secs,
+                mtime_nanos,
+                digests: Vec::new(),
+            });
+
+            if entry.size != size
+                || entry.mtime_secs != mtime_secs
+                || entry.mtime_nanos != mtime_nanos
+            {
+                *entry = CacheEntry {
+                    size,
+                    mtime_secs,
+                    mtime_nanos,
+                    digests: Vec::new(),
+                };
+            }
+
+            entry
+                .digests
+                .retain(|cached| discriminant(cached) != discriminant(digest));
+            entry.digests.push(digest.clone());
+
+            // the cache is only an optimization, failing to write it is not an error
+            if let Some(ref cache_path) = self.path {
+                match serde_json::to_string(&*self) {
+                    Ok(content) => {
+                        if let Err(why) = write(cache_path, content) {
+                            warn!(
+                                "Failed to write digest cache '{}', error: {:?}",
+                                cache_path.display(),
+                                why
+                            );
+                        }
+                    }
+                    Err(why) => warn!("Failed to serialize digest cache, error: {:?}", why),
+                }
+            }
+        }
+    }
+}
+
+fn compute_digest(path: &Path, digest: &HashInfo) -> Result<HashInfo, MigError> {
+    Ok(match digest {
         HashInfo::Sha1(_) => HashInfo::Sha1(process_digest::<Sha1, _>(path)?),
         HashInfo::Md5(_) => HashInfo::Md5(process_digest::<Md5, _>(path)?),
-    };
+        HashInfo::Sha256(_) => HashInfo::Sha256(process_digest::<Sha256, _>(path)?),
+        HashInfo::Sha512(_) => HashInfo::Sha512(process_digest::<Sha512, _>(path)?),
+    })
+}
+
+pub(crate) fn check_digest<P: AsRef<Path>>(path: P, digest: &HashInfo) -> Result<bool, MigError> {
+    let computed = compute_digest(path.as_ref(), digest)?;
 
     debug!("check_digest: provided digest is: {:?}", digest);
     debug!("check_digest: computed digest is: {:?}", computed);
@@ -33,7 +419,7 @@ pub(crate) fn check_digest<P: AsRef<Path>>(path: P, digest: &HashInfo) -> Result
 }
 
 pub(crate) fn get_default_digest<P: AsRef<Path>>(path: P) -> Result<HashInfo, MigError> {
-    Ok(HashInfo::Md5(process_digest::<Md5, _>(path)?))
+    compute_digest(path.as_ref(), &HashInfo::Sha256(String::new()))
 }
 
 fn process_digest<D: Digest + Default, P: AsRef<Path>>(path: P) -> Result<String, MigError> {
@@ -55,10 +441,129 @@ fn process_digest<D: Digest + Default, P: AsRef<Path>>(path: P) -> Result<String
             break;
         }
     }
-    let digest = sh.result();
-    let mut res = String::from("");
-    for byte in &digest {
-        res.push_str(&format!("{:02x}", byte));
+    Ok(to_hex_string(&sh.result()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn sha2_digests() {
+        assert!(check_digest(
+            "./test_data/part.img.zst",
+            &HashInfo::Sha512(String::from(
+                "3744bdd0d8418ade2e4eb32d86a8126fade5f52aace50d6fe440c5c1b1abd6fb8e51f82d35be7cfd5827f79a7d98b023e2c3092be9051b2cb875b7a0198a4169"
+            ))
+        )
+        .unwrap());
+        assert!(!check_digest(
+            "./test_data/part.img.zst",
+            &HashInfo::Md5(String::from("00000000000000000000000000000000"))
+        )
+        .unwrap());
+        assert_eq!(
+            get_default_digest("./test_data/part.img.gz").unwrap(),
+            HashInfo::Sha256(String::from(
+                "9be2a59a8286c8acacebd69a6ac1ff3caa02f3950f91ba2df1514f9f69f9495e"
+            ))
+        );
+    }
+
+    #[test]
+    fn digest_manifest() {
+        let manifest = DigestManifest::find("./test_data", None).unwrap().unwrap();
+
+        let file_ref = manifest.fill(&FileRef {
+            path: PathBuf::from("part.img.xz"),
+            hash: None,
+        });
+        assert!(check_digest("./test_data/part.img.xz", file_ref.hash.as_ref().unwrap()).unwrap());
+
+        let file_ref = manifest.fill(&FileRef {
+            path: PathBuf::from("https://files.example.com/images/part.img.gz"),
+            hash: None,
+        });
+        assert!(check_digest("./test_data/part.img.gz", file_ref.hash.as_ref().unwrap()).unwrap());
+
+        let file_ref = FileRef {
+            path: PathBuf::from("part.img.gz"),
+            hash: Some(HashInfo::Md5(String::from(
+                "6365c141f0f15d405f38690447e3738f",
+            ))),
+        };
+        assert_eq!(manifest.fill(&file_ref), file_ref);
+        assert_eq!(
+            manifest.get_digest(&FileRef {
+                path: PathBuf::from("part.img.zst"),
+                hash: None,
+            }),
+            None
+        );
+    }
+
+    // a work dir of its own for each test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = path_append(
+            std::env::temp_dir(),
+            format!("balena-migrate-{}-{}", name, std::process::id()),
+        );
+        let _res = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn digest_cache() {
+        let work_dir = test_dir("digest-cache");
+        let file = path_append(&work_dir, "part.img.gz");
+        std::fs::copy("./test_data/part.img.gz", &file).unwrap();
+
+        let mut cache = DigestCache::load(&work_dir);
+        let sha256 = cache.get_default_digest(&file).unwrap();
+        assert_eq!(sha256, get_default_digest(&file).unwrap());
+        assert_eq!(cache.get(&file, &sha256), Some(sha256.clone()));
+        assert_eq!(cache.get(&file, &HashInfo::Md5(String::new())), None);
+
+        let saved = DigestCache::load(&work_dir);
+        assert_eq!(saved.entries.len(), 1);
+        assert_eq!(saved.get(&file, &sha256), Some(sha256.clone()));
+
+        // a modified file is hashed again
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&file)
+            .unwrap()
+            .write_all(b"modified")
+            .unwrap();
+        assert_eq!(cache.get(&file, &sha256), None);
+        assert_ne!(cache.get_default_digest(&file).unwrap(), sha256);
+
+        std::fs::remove_dir_all(&work_dir).unwrap();
+    }
+
+    #[test]
+    fn check_digest_ignores_cache() {
+        let work_dir = test_dir("forged-cache");
+        let file = path_append(&work_dir, "part.img.gz");
+        std::fs::copy("./test_data/part.img.gz", &file).unwrap();
+
+        // a forged entry claiming the file has another digest
+        let forged = HashInfo::Sha256(String::from(
+            "0000000000000000000000000000000000000000000000000000000000000000",
+        ));
+        DigestCache::load(&work_dir).insert(&file, &forged);
+
+        let mut cache = DigestCache::load(&work_dir);
+        assert!(!cache.check_digest(&file, &forged).unwrap());
+        let sha256 = HashInfo::Sha256(String::from(
+            "9be2a59a8286c8acacebd69a6ac1ff3caa02f3950f91ba2df1514f9f69f9495e",
+        ));
+        assert!(cache.check_digest(&file, &sha256).unwrap());
+        // the checked digest replaced the forged one
+        assert_eq!(cache.get_default_digest(&file).unwrap(), sha256);
+
+        std::fs::remove_dir_all(&work_dir).unwrap();
     }
-    Ok(res)
 }
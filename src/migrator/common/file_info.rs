@@ -14,7 +14,7 @@ use std::path::{Path, PathBuf};
 
 use crate::common::{
     config::balena_config::FileRef,
-    file_digest::{check_digest, get_default_digest, HashInfo},
+    file_digest::{DigestCache, HashInfo},
     //file_digest::check_digest
     file_exists,
     MigErrCtx,
@@ -42,9 +42,11 @@ pub(crate) struct RelFileInfo {
 // TODO: make this detect file formats used by migrate, eg: kernel, initramfs, json file, disk image
 
 impl FileInfo {
+    // digests are taken from and added to digests
     pub fn new<P: AsRef<Path>>(
         file_ref: &FileRef,
         work_dir: P,
+        digests: &mut DigestCache,
     ) -> Result<Option<FileInfo>, MigError> {
         let file_path = &file_ref.path;
         let work_path = work_dir.as_ref();
@@ -86,7 +88,7 @@ impl FileInfo {
         };
 
         let hash_info = if let Some(ref hash_info) = file_ref.hash {
-            if !check_digest(&file_ref.path, hash_info)? {
+            if !digests.check_digest(&file_ref.path, hash_info)? {
                 error!(
                     "Failed to check file digest for file '{}': {:?}",
                     file_ref.path.display(),
@@ -98,7 +100,7 @@ impl FileInfo {
             }
         } else {
             debug!("Created digest for file: '{}'", file_ref.path.display());
-            get_default_digest(&file_ref.path)?
+            digests.get_default_digest(&file_ref.path)?
         };
 
         Ok(Some(FileInfo {
@@ -0,0 +1,227 @@
+use log::{debug, error, trace};
+use std::io::Read;
+use std::path::Path;
+use std::str;
+
+use crate::{
+    common::{disk_util::Compression, MigError, MigErrorKind},
+    defs::FileType,
+};
+
+// *************************************************************************************************
+// * Determine file types from their contents.
+// * Compressed files are identified by their magic numbers and the start of the uncompressed
+// * data is inspected for the file type.
+// *************************************************************************************************
+
+// amount of (uncompressed) data inspected
+const SNIFF_SIZE: usize = 4096;
+
+const BOOT_SIG_OFFSET: usize = 510;
+const BOOT_SIG: &[u8] = &[0x55, 0xAA];
+const CPIO_MAGIC: &[u8] = b"07070";
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+const BZIMAGE_MAGIC_OFFSET: usize = 0x202;
+const BZIMAGE_MAGIC: &[u8] = b"HdrS";
+const ZIMAGE_MAGIC_OFFSET: usize = 0x24;
+const ZIMAGE_MAGIC: &[u8] = &[0x18, 0x28, 0x6F, 0x01];
+const ARM64_MAGIC_OFFSET: usize = 0x38;
+const ARM64_MAGIC: &[u8] = b"ARM\x64";
+const PE_MAGIC: &[u8] = b"MZ";
+const DTB_MAGIC: &[u8] = &[0xD0, 0x0D, 0xFE, 0xED];
+
+// balena OS image types accepted for flashing
+pub(crate) const OS_IMAGE_TYPES: &[FileType] = &[
+    FileType::GZipOSImage,
+    FileType::XzOSImage,
+    FileType::ZstdOSImage,
+    FileType::ZipOSImage,
+];
+
+fn has_magic(buffer: &[u8], offset: usize, magic: &[u8]) -> bool {
+    buffer.len() >= offset + magic.len() && &buffer[offset..offset + magic.len()] == magic
+}
+
+fn is_boot_sector(buffer: &[u8]) -> bool {
+    has_magic(buffer, BOOT_SIG_OFFSET, BOOT_SIG)
+}
+
+// printable text, a multi byte character might be cut off at the end of buffer
+fn is_text(buffer: &[u8]) -> bool {
+    if buffer.is_empty() || buffer.contains(&0) {
+        return false;
+    }
+    match str::from_utf8(buffer) {
+        Ok(_) => true,
+        Err(why) => why.error_len().is_none(),
+    }
+}
+
+// read the start of the uncompressed file contents
+fn read_head(path: &Path, compression: Compression) -> Result<Vec<u8>, MigError> {
+    let mut reader = compression.open_reader(path)?;
+    let mut buffer: Vec<u8> = vec![0; SNIFF_SIZE];
+    let mut buffer_len: usize = 0;
+    while buffer_len < buffer.len() {
+        match reader.read(&mut buffer[buffer_len..]) {
+            Ok(0) => break,
+            Ok(bytes_read) => buffer_len += bytes_read,
+            Err(why) => {
+                return Err(MigError::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!(
+                        "Failed to read {} data from '{}', error: {:?}",
+                        compression.get_descr(),
+                        path.display(),
+                        why
+                    ),
+                ));
+            }
+        }
+    }
+    buffer.truncate(buffer_len);
+    Ok(buffer)
+}
+
+fn check_type(compression: Compression, head: &[u8], ftype: &FileType) -> bool {
+    match ftype {
+        FileType::OSImage => compression == Compression::None && is_boot_sector(head),
+        FileType::GZipOSImage => compression == Compression::GZip && is_boot_sector(head),
+        FileType::XzOSImage => compression == Compression::Xz && is_boot_sector(head),
+        FileType::ZstdOSImage => compression == Compression::Zstd && is_boot_sector(head),
+        FileType::ZipOSImage => compression == Compression::Zip && is_boot_sector(head),
+        FileType::InitRD => compression == Compression::GZip && head.starts_with(CPIO_MAGIC),
+        FileType::GZipTar => {
+            compression == Compression::GZip && has_magic(head, TAR_MAGIC_OFFSET, TAR_MAGIC)
+        }
+        FileType::Json | FileType::Text => compression == Compression::None && is_text(head),
+        FileType::KernelAMD64 => {
+            compression == Compression::None
+                && (has_magic(head, BZIMAGE_MAGIC_OFFSET, BZIMAGE_MAGIC) || is_boot_sector(head))
+        }
+        FileType::KernelARMHF => {
+            compression == Compression::None && has_magic(head, ZIMAGE_MAGIC_OFFSET, ZIMAGE_MAGIC)
+        }
+        FileType::KernelAARCH64 => {
+            compression == Compression::None
+                && (head.starts_with(PE_MAGIC) || has_magic(head, ARM64_MAGIC_OFFSET, ARM64_MAGIC))
+        }
+        // anything binary used to be accepted as DTB
+        FileType::DTB => {
+            compression == Compression::None && (head.starts_with(DTB_MAGIC) || !is_text(head))
+        }
+    }
+}
+
+pub(crate) fn is_file_type<P: AsRef<Path>>(file: P, ftype: &FileType) -> Result<bool, MigError> {
+    let file = file.as_ref();
+    trace!(
+        "is_file_type: entered with '{}', type: {}",
+        file.display(),
+        ftype.get_descr()
+    );
+    let compression = Compression::from_file(file)?;
+    let head = read_head(file, compression)?;
+    let res = check_type(compression, &head, ftype);
+    debug!(
+        "is_file_type: '{}' is {}, looking for: {}, result: {}",
+        file.display(),
+        compression.get_descr(),
+        ftype.get_descr(),
+        res
+    );
+    Ok(res)
+}
+
+pub(crate) fn expect_type<P: AsRef<Path>>(file: P, ftype: &FileType) -> Result<(), MigError> {
+    if !is_file_type(file.as_ref(), ftype)? {
+        error!(
+            "Could not determine expected file type '{}' for file '{}'",
+            ftype.get_descr(),
+            file.as_ref().display()
+        );
+        Err(MigError::displayed())
+    } else {
+        Ok(())
+    }
+}
+
+// find the balena OS image type of file, plain images are included
+pub(crate) fn get_os_image_type<P: AsRef<Path>>(file: P) -> Result<Option<FileType>, MigError> {
+    let file = file.as_ref();
+    let compression = Compression::from_file(file)?;
+    let head = read_head(file, compression)?;
+    Ok(OS_IMAGE_TYPES
+        .iter()
+        .chain(&[FileType::OSImage])
+        .find(|ftype| check_type(compression, &head, ftype))
+        .cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff_os_images() {
+        for (image, ftype) in &[
+            ("./test_data/part.img.gz", FileType::GZipOSImage),
+            ("./test_data/part.img.xz", FileType::XzOSImage),
+            ("./test_data/part.img.zst", FileType::ZstdOSImage),
+            ("./test_data/part.img.zip", FileType::ZipOSImage),
+        ] {
+            assert!(is_file_type(image, ftype).unwrap());
+            assert!(!is_file_type(image, &FileType::OSImage).unwrap());
+            assert!(!is_file_type(image, &FileType::GZipTar).unwrap());
+            assert_eq!(
+                get_os_image_type(image).unwrap().unwrap().get_descr(),
+                ftype.get_descr()
+            );
+        }
+    }
+
+    #[test]
+    fn sniff_contents() {
+        let mut mbr: Vec<u8> = vec![0; 512];
+        mbr[510] = 0x55;
+        mbr[511] = 0xAA;
+        assert!(check_type(Compression::None, &mbr, &FileType::OSImage));
+        assert!(check_type(Compression::None, &mbr, &FileType::KernelAMD64));
+        assert!(!check_type(Compression::GZip, &mbr, &FileType::OSImage));
+
+        let json = b"{\"deviceType\": \"raspberrypi3\", \"name\": \"\xc3\xa4\"}";
+        assert!(check_type(Compression::None, json, &FileType::Json));
+        assert!(check_type(
+            Compression::None,
+            &json[0..json.len() - 3],
+            &FileType::Text
+        ));
+        assert!(!check_type(Compression::None, json, &FileType::DTB));
+        assert!(!check_type(Compression::None, b"", &FileType::Text));
+
+        assert!(check_type(
+            Compression::None,
+            &[0xD0, 0x0D, 0xFE, 0xED, 0, 0, 0x10, 0],
+            &FileType::DTB
+        ));
+        assert!(check_type(
+            Compression::GZip,
+            b"070701000a1b2c",
+            &FileType::InitRD
+        ));
+
+        let mut zimage: Vec<u8> = vec![0; 64];
+        zimage[0x24..0x28].copy_from_slice(&[0x18, 0x28, 0x6F, 0x01]);
+        assert!(check_type(
+            Compression::None,
+            &zimage,
+            &FileType::KernelARMHF
+        ));
+        assert!(!check_type(
+            Compression::None,
+            &zimage,
+            &FileType::KernelAARCH64
+        ));
+    }
+}
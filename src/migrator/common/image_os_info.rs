@@ -0,0 +1,142 @@
+use log::debug;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+use crate::common::{
+    disk_util::{Disk, ExtFs, PartInfo, PartitionIterator, PartitionType},
+    MigError, MigErrorKind,
+};
+
+// *************************************************************************************************
+// * balena OS version, variant and kernel version as found on resin-rootA of a balena OS image
+// *************************************************************************************************
+
+const UNKNOWN: &str = "unknown";
+// VARIANT_ID of development images, older images only set VARIANT
+const DEV_VARIANT_ID: &str = "dev";
+const DEV_VARIANT: &str = "Development";
+
+#[derive(Debug, Clone)]
+pub(crate) struct ImageOSInfo {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub variant: Option<String>,
+    pub kernel_version: Option<String>,
+}
+
+impl ImageOSInfo {
+    // read the OS information from resin-rootA of a plain or compressed image
+    pub fn from_disk(disk: &mut Disk) -> Result<ImageOSInfo, MigError> {
+        // resin-boot comes first, resin-rootA second
+        let partitions: Vec<PartInfo> = PartitionIterator::new(disk)?
+            .filter(|part| {
+                matches!(
+                    PartitionType::from_ptype(part.ptype),
+                    PartitionType::Fat | PartitionType::Linux
+                )
+            })
+            .collect();
+
+        let root_a = if let Some(root_a) = partitions.get(1) {
+            root_a
+        } else {
+            return Err(MigError::from_remark(
+                MigErrorKind::NotFound,
+                "The image does not contain a root partition",
+            ));
+        };
+
+        ImageOSInfo::from_ext_fs(&mut ExtFs::from_disk(disk, root_a)?)
+    }
+
+    pub fn from_ext_fs(ext_fs: &mut ExtFs) -> Result<ImageOSInfo, MigError> {
+        let os_release = ext_fs.get_os_release()?;
+        let os_info = ImageOSInfo::from_os_release(&os_release, ext_fs.get_kernel_version()?);
+        debug!("from_ext_fs: found {:?}", os_info);
+        Ok(os_info)
+    }
+
+    fn from_os_release(
+        os_release: &HashMap<String, String>,
+        kernel_version: Option<String>,
+    ) -> ImageOSInfo {
+        ImageOSInfo {
+            name: os_release.get("NAME").cloned(),
+            version: os_release.get("VERSION_ID").cloned(),
+            variant: os_release
+                .get("VARIANT_ID")
+                .or_else(|| os_release.get("VARIANT"))
+                .cloned(),
+            kernel_version,
+        }
+    }
+
+    pub fn is_development(&self) -> bool {
+        if let Some(ref variant) = self.variant {
+            variant == DEV_VARIANT_ID || variant == DEV_VARIANT
+        } else {
+            false
+        }
+    }
+}
+
+impl Display for ImageOSInfo {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} version {}, variant {}, kernel version {}",
+            self.name
+                .as_ref()
+                .map_or("unknown OS", |name| name.as_str()),
+            self.version
+                .as_ref()
+                .map_or(UNKNOWN, |version| version.as_str()),
+            self.variant
+                .as_ref()
+                .map_or(UNKNOWN, |variant| variant.as_str()),
+            self.kernel_version
+                .as_ref()
+                .map_or(UNKNOWN, |version| version.as_str())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_os_info() {
+        let mut disk = Disk::from_gzip_img("./test_data/ext4.img.gz").unwrap();
+        let part = PartInfo {
+            index: 2,
+            ptype: 0x83,
+            status: 0,
+            start_lba: 0,
+            num_sectors: 8192,
+            gpt_info: None,
+        };
+
+        let os_info =
+            ImageOSInfo::from_ext_fs(&mut ExtFs::from_disk(&mut disk, &part).unwrap()).unwrap();
+        assert_eq!(os_info.version, Some(String::from("2.38.0")));
+        assert_eq!(os_info.variant, Some(String::from("prod")));
+        assert_eq!(os_info.kernel_version, Some(String::from("4.19.71")));
+        assert!(!os_info.is_development());
+    }
+
+    #[test]
+    fn development_variant() {
+        let mut os_release: HashMap<String, String> = HashMap::new();
+        os_release.insert(String::from("VARIANT_ID"), String::from("dev"));
+        assert!(ImageOSInfo::from_os_release(&os_release, None).is_development());
+
+        // older images only have VARIANT
+        os_release.clear();
+        os_release.insert(String::from("VARIANT"), String::from("Development"));
+        assert!(ImageOSInfo::from_os_release(&os_release, None).is_development());
+
+        os_release.clear();
+        assert!(!ImageOSInfo::from_os_release(&os_release, None).is_development());
+    }
+}
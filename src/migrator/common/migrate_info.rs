@@ -2,18 +2,26 @@ use log::{debug, error, info, trace, warn};
 
 use crate::{
     common::{
+        bmap::{find_bmap_file, BlockMap},
+        bundle::SignedBundle,
         config::{
             balena_config::FileRef,
             balena_config::{ImageType, PartDump},
             MigrateWifis,
         },
         device_info::DeviceInfo,
+        device_type_json::DeviceTypeJson,
+        disk_util::Disk,
+        download::Downloader,
+        file_digest::{check_digest, DigestCache, DigestManifest},
         file_info::RelFileInfo,
+        file_type::OS_IMAGE_TYPES,
+        image_os_info::ImageOSInfo,
         os_api::OSApi,
         path_info::PathInfo,
-        stage2_config::{CheckedFSDump, CheckedImageType, CheckedPartDump},
+        stage2_config::{check_stage2_schema, CheckedFSDump, CheckedImageType, CheckedPartDump},
         wifi_config::WifiConfig,
-        Config, FileInfo, MigError, MigErrorKind,
+        format_size_with_unit, path_append, Config, FileInfo, MigError, MigErrorKind,
     },
     defs::FileType,
     defs::OSArch,
@@ -41,13 +49,24 @@ pub(crate) struct MigrateInfo {
     pub wifis: Vec<WifiConfig>,
 
     pub image_file: CheckedImageType,
-    pub config_file: BalenaCfgJson,
+    // device-type.json read from the flasher image boot partition
+    pub image_dev_type: Option<DeviceTypeJson>,
+    // bmaptool block map found next to the flasher image
+    pub bmap_file: Option<RelFileInfo>,
+    // not set until generated if config.json is generated from migrate config
+    config_file: Option<BalenaCfgJson>,
 
     pub kernel_file: FileInfo,
 
     pub initrd_file: FileInfo,
 
     pub dtb_file: Vec<FileInfo>,
+
+    // signed bundle, passed on to stage 2
+    pub bundle: Option<SignedBundle>,
+
+    // digests of files in the work dir
+    pub digest_cache: DigestCache,
 }
 
 // TODO: sort out error reporting with Displayed
@@ -68,6 +87,9 @@ impl MigrateInfo {
             work_path.device_info.device.display()
         );
 
+        // files in work_dir are not hashed again unless they changed since the last run
+        let mut digest_cache = DigestCache::load(work_dir);
+
         let log_path = if let Some(log_dev) = config.migrate.get_log_device() {
             if log_dev.exists() {
                 Some(os_api.device_info_from_partition(log_dev)?)
@@ -82,15 +104,144 @@ impl MigrateInfo {
             None
         };
 
+        // digests missing from the config are taken from a manifest in the working directory,
+        // files referenced by URL are downloaded to the working directory first
+        let manifest = DigestManifest::find(work_dir, config.migrate.get_digest_manifest())?;
+
+        // a signed bundle overrides all other digests, files it does not cover are refused
+        let bundle = SignedBundle::load(work_dir, config.migrate.get_bundle())?;
+        let signed = if let Some(ref bundle) = bundle {
+            Some(bundle.verify()?)
+        } else {
+            None
+        };
+
+        if let Some(ref signed) = signed {
+            if let Some(config_path) = config.get_config_file() {
+                let config_ref = signed.require(&FileRef {
+                    path: config_path.to_path_buf(),
+                    hash: None,
+                })?;
+                if let Some(ref hash_info) = config_ref.hash {
+                    if !check_digest(config_path, hash_info)? {
+                        error!(
+                            "The migrate config '{}' does not match the signed bundle",
+                            config_path.display()
+                        );
+                        return Err(MigError::displayed());
+                    }
+                }
+            }
+        }
+
+        let downloader = Downloader::new(config.migrate.get_download());
+        let fetch = |file_ref: &FileRef| -> Result<FileRef, MigError> {
+            if let Some(ref signed) = signed {
+                downloader.fetch(&signed.require(file_ref)?, work_dir)
+            } else if let Some(ref manifest) = manifest {
+                downloader.fetch(&manifest.fill(file_ref), work_dir)
+            } else {
+                downloader.fetch(file_ref, work_dir)
+            }
+        };
+
+        let mut image_dev_type: Option<DeviceTypeJson> = None;
+        let mut image_os_info: Option<ImageOSInfo> = None;
+        let mut bmap_file: Option<RelFileInfo> = None;
+
         let os_image = match config.balena.get_image_path() {
             ImageType::Flasher(ref flasher_img) => {
                 let checked_ref = MigrateInfo::check_file(
-                    &flasher_img,
-                    &FileType::GZipOSImage,
+                    &fetch(flasher_img)?,
+                    OS_IMAGE_TYPES,
                     &work_path,
                     os_api,
+                    &mut digest_cache,
                 )?;
 
+                let image_path = path_append(work_dir, &checked_ref.rel_path);
+                // both are read from one disk, reads into the image mostly move forward
+                match Disk::from_image_file(&image_path) {
+                    Ok(mut disk) => {
+                        match DeviceTypeJson::from_disk(&mut disk) {
+                            Ok(dev_type) => {
+                                info!(
+                                    "The balena OS image was built for device type '{}', architecture '{}'",
+                                    dev_type.slug, dev_type.arch
+                                );
+                                image_dev_type = Some(dev_type);
+                            }
+                            Err(why) => {
+                                warn!(
+                                    "Failed to read the device type from the balena OS image '{}', not checking it, error: {}",
+                                    image_path.display(),
+                                    why
+                                );
+                            }
+                        }
+
+                        match ImageOSInfo::from_disk(&mut disk) {
+                            Ok(os_info) => {
+                                info!("The balena OS image contains {}", os_info);
+                                image_os_info = Some(os_info);
+                            }
+                            Err(why) => {
+                                warn!(
+                                    "Failed to read the OS information from the balena OS image '{}', not checking it, error: {}",
+                                    image_path.display(),
+                                    why
+                                );
+                            }
+                        }
+                    }
+                    Err(why) => {
+                        warn!(
+                            "Failed to open the balena OS image '{}', not checking device type and OS information, error: {}",
+                            image_path.display(),
+                            why
+                        );
+                    }
+                }
+
+                if let Some(bmap_path) = find_bmap_file(&image_path) {
+                    let bmap = match BlockMap::from_file(&bmap_path) {
+                        Ok(bmap) => bmap,
+                        Err(why) => {
+                            error!(
+                                "Failed to read the block map '{}', error: {}",
+                                bmap_path.display(),
+                                why
+                            );
+                            return Err(MigError::displayed());
+                        }
+                    };
+
+                    info!(
+                        "Found block map '{}', {} of {} are mapped",
+                        bmap_path.display(),
+                        format_size_with_unit(bmap.get_mapped_size()),
+                        format_size_with_unit(bmap.image_size)
+                    );
+
+                    let bmap_ref = FileRef {
+                        path: bmap_path,
+                        hash: None,
+                    };
+                    let bmap_ref = if let Some(ref signed) = signed {
+                        signed.require(&bmap_ref)?
+                    } else {
+                        bmap_ref
+                    };
+
+                    bmap_file = Some(MigrateInfo::check_file(
+                        &bmap_ref,
+                        &[FileType::Text],
+                        &work_path,
+                        os_api,
+                        &mut digest_cache,
+                    )?);
+                }
+
                 CheckedImageType::Flasher(checked_ref)
             }
             ImageType::FileSystems(ref fs_dump) => {
@@ -102,32 +253,68 @@ impl MigrateInfo {
                     mkfs_direct: fs_dump.mkfs_direct,
                     extended_blocks: fs_dump.extended_blocks,
                     boot: CheckedPartDump {
-                        archive: MigrateInfo::check_dump(&fs_dump.boot, &work_path, os_api)?,
+                        archive: MigrateInfo::check_dump(
+                            &fs_dump.boot,
+                            &work_path,
+                            &fetch,
+                            os_api,
+                            &mut digest_cache,
+                        )?,
                         blocks: fs_dump.boot.blocks,
                     },
                     root_a: CheckedPartDump {
-                        archive: MigrateInfo::check_dump(&fs_dump.root_a, &work_path, os_api)?,
+                        archive: MigrateInfo::check_dump(
+                            &fs_dump.root_a,
+                            &work_path,
+                            &fetch,
+                            os_api,
+                            &mut digest_cache,
+                        )?,
                         blocks: fs_dump.root_a.blocks,
                     },
                     root_b: CheckedPartDump {
-                        archive: MigrateInfo::check_dump(&fs_dump.root_b, &work_path, os_api)?,
+                        archive: MigrateInfo::check_dump(
+                            &fs_dump.root_b,
+                            &work_path,
+                            &fetch,
+                            os_api,
+                            &mut digest_cache,
+                        )?,
                         blocks: fs_dump.root_b.blocks,
                     },
                     state: CheckedPartDump {
-                        archive: MigrateInfo::check_dump(&fs_dump.state, &work_path, os_api)?,
+                        archive: MigrateInfo::check_dump(
+                            &fs_dump.state,
+                            &work_path,
+                            &fetch,
+                            os_api,
+                            &mut digest_cache,
+                        )?,
                         blocks: fs_dump.state.blocks,
                     },
                     data: CheckedPartDump {
-                        archive: MigrateInfo::check_dump(&fs_dump.data, &work_path, os_api)?,
+                        archive: MigrateInfo::check_dump(
+                            &fs_dump.data,
+                            &work_path,
+                            &fetch,
+                            os_api,
+                            &mut digest_cache,
+                        )?,
                         blocks: fs_dump.data.blocks,
                     },
                 })
             }
         };
 
-        let config_file = if let Some(file_info) =
-            FileInfo::new(config.balena.get_config_path(), &work_dir)?
-        {
+        let config_file = if config.balena.get_config_params().is_some() {
+            // config.json is generated once the device slug is known
+            info!("The balena config will be generated from the migrate config");
+            None
+        } else if let Some(file_info) = FileInfo::new(
+            &fetch(config.balena.get_config_path())?,
+            &work_dir,
+            &mut digest_cache,
+        )? {
             if file_info.rel_path.is_none() {
                 error!("The balena OS config was found outside of the working directory. This setup is not supported");
                 return Err(MigError::displayed());
@@ -158,15 +345,31 @@ impl MigrateInfo {
                 balena_cfg.get_rel_path().display()
             );
             //balena_cfg.check()
-            balena_cfg
+            Some(balena_cfg)
         } else {
-            error!("The balena config has not been specified or cannot be accessed. Automatic download is not yet implemented, so you need to specify and supply all required files");
+            error!("The balena config has not been specified or cannot be accessed. Supply the file in the working directory or reference it by URL");
             return Err(MigError::displayed());
         };
 
+        if let Some(ref os_info) = image_os_info {
+            // a generated config.json is never in development mode
+            let development_mode = if let Some(ref balena_cfg) = config_file {
+                balena_cfg.is_development_mode()
+            } else {
+                false
+            };
+
+            if os_info.is_development() && !development_mode {
+                error!("The balena OS image is a development image but the balena config is not in development mode, refusing to migrate to a development image");
+                return Err(MigError::displayed());
+            }
+        }
+
         let kernel_info = config.migrate.get_kernel_path();
 
-        let kernel_file = if let Some(file_info) = FileInfo::new(&kernel_info, work_dir)? {
+        let kernel_file = if let Some(file_info) =
+            FileInfo::new(&fetch(kernel_info)?, work_dir, &mut digest_cache)?
+        {
             // TODO: check later, when target arch is known
             info!(
                 "The balena migrate kernel looks ok: '{}'",
@@ -174,28 +377,33 @@ impl MigrateInfo {
             );
             file_info
         } else {
-            error!("The migrate kernel has not been specified or cannot be accessed. Automatic download is not yet implemented, so you need to specify and supply all required files");
+            error!("The migrate kernel has not been specified or cannot be accessed. Supply the file in the working directory or reference it by URL");
             return Err(MigError::displayed());
         };
 
-        let initrd_file = if let Some(file_info) =
-            FileInfo::new(config.migrate.get_initrd_path(), work_dir)?
-        {
+        let initrd_file = if let Some(file_info) = FileInfo::new(
+            &fetch(config.migrate.get_initrd_path())?,
+            work_dir,
+            &mut digest_cache,
+        )? {
             os_api.expect_type(&file_info.path, &FileType::InitRD)?;
+            check_stage2_schema(&file_info.path)?;
             info!(
                 "The balena migrate initramfs looks ok: '{}'",
                 file_info.path.display()
             );
             file_info
         } else {
-            error!("The migrate initramfs has not been specified or cannot be accessed. Automatic download is not yet implemented, so you need to specify and supply all required files");
+            error!("The migrate initramfs has not been specified or cannot be accessed. Supply the file in the working directory or reference it by URL");
             return Err(MigError::displayed());
         };
 
         let dtb_files = if let Some(dtb_refs) = config.migrate.get_dtb_refs() {
             let mut dtb_files: Vec<FileInfo> = Vec::new();
             for dtb_ref in dtb_refs {
-                if let Some(file_info) = FileInfo::new(dtb_ref, work_dir)? {
+                if let Some(file_info) =
+                    FileInfo::new(&fetch(dtb_ref)?, work_dir, &mut digest_cache)?
+                {
                     os_api.expect_type(&file_info.path, &FileType::DTB)?;
                     info!(
                         "The balena migrate device tree blob looks ok: '{}'",
@@ -203,7 +411,7 @@ impl MigrateInfo {
                     );
                     dtb_files.push(file_info);
                 } else {
-                    error!("The migrate device tree blob '{}' cannot be accessed. Automatic download is not yet implemented, so you need to specify and supply all required files", dtb_ref.path.display());
+                    error!("The migrate device tree blob '{}' cannot be accessed. Supply the file in the working directory or reference it by URL", dtb_ref.path.display());
                     return Err(MigError::displayed());
                 }
             }
@@ -221,6 +429,7 @@ impl MigrateInfo {
                     hash: None,
                 },
                 &work_dir,
+                &mut digest_cache,
             )? {
                 os_api.expect_type(&file_info.path, &FileType::Text)?;
                 info!(
@@ -279,12 +488,16 @@ impl MigrateInfo {
             work_path,
             log_path,
             image_file: os_image,
+            image_dev_type,
+            bmap_file,
             kernel_file,
             initrd_file,
             dtb_file: dtb_files,
             nwmgr_files,
             config_file,
             wifis,
+            bundle,
+            digest_cache,
         };
 
         debug!("MigrateInfo: {:?}", result);
@@ -292,26 +505,46 @@ impl MigrateInfo {
         Ok(result)
     }
 
+    pub fn set_config_file(&mut self, config_file: BalenaCfgJson) {
+        self.config_file = Some(config_file);
+    }
+
+    // config.json is not known until it has been read or generated
+    pub fn get_config_file(&self) -> Result<&BalenaCfgJson, MigError> {
+        if let Some(ref config_file) = self.config_file {
+            Ok(config_file)
+        } else {
+            Err(MigError::from_remark(
+                MigErrorKind::InvState,
+                "config.json has not been read or generated",
+            ))
+        }
+    }
+
     fn check_dump(
         dump: &PartDump,
         work_path: &PathInfo,
+        fetch: &dyn Fn(&FileRef) -> Result<FileRef, MigError>,
         os_api: &impl OSApi,
+        digests: &mut DigestCache,
     ) -> Result<RelFileInfo, MigError> {
         Ok(MigrateInfo::check_file(
-            &dump.archive,
-            &FileType::GZipTar,
+            &fetch(&dump.archive)?,
+            &[FileType::GZipTar],
             work_path,
             os_api,
+            digests,
         )?)
     }
 
     fn check_file(
         file_ref: &FileRef,
-        expected_type: &FileType,
+        expected_types: &[FileType],
         work_path: &PathInfo,
         os_api: &impl OSApi,
+        digests: &mut DigestCache,
     ) -> Result<RelFileInfo, MigError> {
-        if let Some(file_info) = FileInfo::new(&file_ref, &work_path.path)? {
+        if let Some(file_info) = FileInfo::new(&file_ref, &work_path.path, digests)? {
             // make sure files are present and in /workdir, generate total size and partitioning config in miginfo
             let rel_path = if let Some(ref rel_path) = file_info.rel_path {
                 rel_path.clone()
@@ -327,21 +560,33 @@ impl MigrateInfo {
             }
 
             // ensure expected type
-            match os_api.expect_type(&file_info.path, expected_type) {
-                Ok(_) => {
-                    info!("The file '{}' image looks ok", file_info.path.display());
-                }
-                Err(_why) => {
-                    // TODO: try gzip non compressed OS image
-                    error!(
-                        "The file '{}' does not match the expected type: '{:?}'",
-                        file_ref.path.display(),
-                        expected_type
-                    );
-                    return Err(MigError::displayed());
+            let mut found_type: Option<&FileType> = None;
+            for expected_type in expected_types {
+                if os_api.is_file_type(&file_info.path, expected_type)? {
+                    found_type = Some(expected_type);
+                    break;
                 }
             }
 
+            if let Some(found_type) = found_type {
+                info!(
+                    "The file '{}' looks ok, found {}",
+                    file_info.path.display(),
+                    found_type.get_descr()
+                );
+            } else {
+                error!(
+                    "The file '{}' does not match the expected type: '{}'",
+                    file_ref.path.display(),
+                    expected_types
+                        .iter()
+                        .map(|ftype| ftype.get_descr())
+                        .collect::<Vec<&str>>()
+                        .join("' or '")
+                );
+                return Err(MigError::displayed());
+            }
+
             Ok(RelFileInfo {
                 rel_path,
                 size: file_info.size,
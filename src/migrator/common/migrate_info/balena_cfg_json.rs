@@ -4,16 +4,28 @@ use serde::{
     de::{self, Unexpected},
     Deserialize, Deserializer,
 };
-use serde_json;
+use serde_json::{self, json};
 use std::fmt;
-use std::fs::File;
-use std::io::BufReader;
-use std::path::PathBuf;
-
-use crate::common::{
-    check_tcp_connect, file_info::RelFileInfo, Config, FileInfo, MigErrCtx, MigError, MigErrorKind,
+use std::fs::{read_to_string, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    common::{
+        check_tcp_connect,
+        config::balena_config::{ConfigJsonParams, FileRef},
+        file_digest::DigestCache,
+        file_info::RelFileInfo,
+        path_append, Config, FileInfo, MigErrCtx, MigError, MigErrorKind,
+    },
+    defs::{
+        DEFAULT_API_ENDPOINT, DEFAULT_DELTA_ENDPOINT, DEFAULT_LISTEN_PORT, DEFAULT_POLL_INTERVAL,
+        DEFAULT_REGISTRY_ENDPOINT, DEFAULT_VPN_ENDPOINT, DEFAULT_VPN_PORT, GENERATED_CONFIG_FILE,
+    },
 };
 
+mod validate;
+
 struct DeserializeU64OrStringVisitor;
 
 impl<'de> de::Visitor<'de> for DeserializeU64OrStringVisitor {
@@ -48,6 +60,15 @@ where
     deserializer.deserialize_any(DeserializeU64OrStringVisitor)
 }
 
+fn deserialize_opt_u64_or_string<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Some(
+        deserializer.deserialize_any(DeserializeU64OrStringVisitor)?,
+    ))
+}
+
 struct DeserializeU16OrStringVisitor;
 
 impl<'de> de::Visitor<'de> for DeserializeU16OrStringVisitor {
@@ -96,6 +117,20 @@ where
 
 // TODO: make u16 work
 
+// the keys BalenaConfig can not do without, all other fields are optional
+const REQUIRED_KEYS: &[&str] = &[
+    "applicationName",
+    "applicationId",
+    "deviceType",
+    "appUpdatePollInterval",
+    "listenPort",
+    "vpnPort",
+    "apiEndpoint",
+    "vpnEndpoint",
+    "registryEndpoint",
+    "deltaEndpoint",
+];
+
 #[derive(Debug, Deserialize, Clone)]
 struct BalenaConfig {
     #[serde(rename = "applicationName")]
@@ -106,9 +141,9 @@ struct BalenaConfig {
     #[serde(rename = "deviceType")]
     pub device_type: String,
     #[serde(rename = "userId")]
-    #[serde(deserialize_with = "deserialize_u64_or_string")]
-    pub user_id: u64,
-    pub username: String,
+    #[serde(default, deserialize_with = "deserialize_opt_u64_or_string")]
+    pub user_id: Option<u64>,
+    pub username: Option<String>,
     #[serde(rename = "appUpdatePollInterval")]
     #[serde(deserialize_with = "deserialize_u64_or_string")]
     pub app_poll_interval: u64,
@@ -127,15 +162,17 @@ struct BalenaConfig {
     #[serde(rename = "deltaEndpoint")]
     pub delta_endpoint: String,
     #[serde(rename = "pubnubSubscribeKey")]
-    pub pubnub_subscr_key: String,
+    pub pubnub_subscr_key: Option<String>,
     #[serde(rename = "pubnubPublishKey")]
-    pub pubnub_publish_key: String,
+    pub pubnub_publish_key: Option<String>,
     #[serde(rename = "mixpanelToken")]
-    pub mixpanel_token: String,
+    pub mixpanel_token: Option<String>,
     #[serde(rename = "apiKey")]
     pub api_key: Option<String>,
     #[serde(rename = "deviceApiKey")]
     pub device_api_key: Option<String>,
+    #[serde(rename = "developmentMode")]
+    pub development_mode: Option<bool>,
 }
 
 #[derive(Debug, Clone)]
@@ -146,14 +183,34 @@ pub(crate) struct BalenaCfgJson {
 
 impl BalenaCfgJson {
     pub fn new(cfg_file: FileInfo) -> Result<BalenaCfgJson, MigError> {
+        let cfg_str = read_to_string(&cfg_file.path).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("new: cannot read file '{}'", cfg_file.path.display()),
+        ))?;
+
+        let problems = validate::validate(&cfg_str);
+        for warning in &problems.warnings {
+            warn!(
+                "The balena config file '{}': {}",
+                cfg_file.path.display(),
+                warning
+            );
+        }
+
+        if !problems.errors.is_empty() {
+            error!(
+                "The balena config file '{}' failed validation with {} problem(s):",
+                cfg_file.path.display(),
+                problems.errors.len()
+            );
+            for problem in problems.errors {
+                error!("  {}", problem);
+            }
+            return Err(MigError::displayed());
+        }
+
         Ok(BalenaCfgJson {
-            config: serde_json::from_reader(BufReader::new(File::open(&cfg_file.path).context(
-                MigErrCtx::from_remark(
-                    MigErrorKind::Upstream,
-                    &format!("new: cannot open file '{}'", cfg_file.path.display()),
-                ),
-            )?))
-            .context(MigErrCtx::from_remark(
+            config: serde_json::from_str(&cfg_str).context(MigErrCtx::from_remark(
                 MigErrorKind::Upstream,
                 &format!("new: failed to parse '{}'", cfg_file.path.display()),
             ))?,
@@ -161,6 +218,92 @@ impl BalenaCfgJson {
         })
     }
 
+    // Generate config.json in work_dir from the parameters given in the migrate config.
+    // The generated file is parsed by BalenaCfgJson::new like a supplied one.
+    pub fn generate(
+        params: &ConfigJsonParams,
+        device_slug: &str,
+        work_dir: &Path,
+        digests: &mut DigestCache,
+    ) -> Result<BalenaCfgJson, MigError> {
+        let cfg_path = path_append(work_dir, GENERATED_CONFIG_FILE);
+        let cfg_str = BalenaCfgJson::params_to_string(params, device_slug)?;
+
+        File::create(&cfg_path)
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "generate: failed to open file for writing: '{}'",
+                    cfg_path.display()
+                ),
+            ))?
+            .write_all(cfg_str.as_bytes())
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("generate: failed to write to '{}'", cfg_path.display()),
+            ))?;
+
+        info!("Generated balena config in '{}'", cfg_path.display());
+
+        if let Some(file_info) = FileInfo::new(
+            &FileRef {
+                path: cfg_path.clone(),
+                hash: None,
+            },
+            work_dir,
+            digests,
+        )? {
+            BalenaCfgJson::new(file_info)
+        } else {
+            error!(
+                "The generated balena config '{}' could not be accessed",
+                cfg_path.display()
+            );
+            Err(MigError::displayed())
+        }
+    }
+
+    fn params_to_string(params: &ConfigJsonParams, device_slug: &str) -> Result<String, MigError> {
+        let device_type = if let Some(ref device_type) = params.device_type {
+            device_type.as_str()
+        } else {
+            device_slug
+        };
+
+        let mut cfg_json = json!({
+            "applicationName": params.app_name,
+            "applicationId": params.app_id,
+            "deviceType": device_type,
+            "appUpdatePollInterval": params.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL),
+            "listenPort": params.listen_port.unwrap_or(DEFAULT_LISTEN_PORT),
+            "vpnPort": params.vpn_port.unwrap_or(DEFAULT_VPN_PORT),
+            "apiEndpoint": params.api_endpoint.as_ref().map_or(DEFAULT_API_ENDPOINT, |s| s.as_str()),
+            "vpnEndpoint": params.vpn_endpoint.as_ref().map_or(DEFAULT_VPN_ENDPOINT, |s| s.as_str()),
+            "registryEndpoint": params.registry_endpoint.as_ref().map_or(DEFAULT_REGISTRY_ENDPOINT, |s| s.as_str()),
+            "deltaEndpoint": params.delta_endpoint.as_ref().map_or(DEFAULT_DELTA_ENDPOINT, |s| s.as_str()),
+            "pubnubSubscribeKey": "",
+            "pubnubPublishKey": "",
+            "mixpanelToken": "",
+            "apiKey": params.api_key,
+        });
+
+        if let Some(cfg_map) = cfg_json.as_object_mut() {
+            if let Some(user_id) = params.user_id {
+                cfg_map.insert(String::from("userId"), json!(user_id));
+            }
+            if let Some(ref username) = params.username {
+                cfg_map.insert(String::from("username"), json!(username));
+            }
+        }
+
+        Ok(
+            serde_json::to_string_pretty(&cfg_json).context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                "generate: failed to serialize config.json",
+            ))?,
+        )
+    }
+
     pub fn check(&self, config: &Config, xpctd_dev_type: &str) -> Result<(), MigError> {
         info!("Configured for application: {}", self.config.app_name);
 
@@ -199,6 +342,10 @@ impl BalenaCfgJson {
         self.file.size
     }
 
+    pub fn is_development_mode(&self) -> bool {
+        self.config.development_mode == Some(true)
+    }
+
     pub fn get_rel_path(&self) -> &PathBuf {
         &self.file.rel_path
     }
@@ -249,10 +396,87 @@ mod tests {
         assert_eq!(config.device_api_key, None);
     }
 
+    #[test]
+    fn generate_conf_ok() {
+        let params = ConfigJsonParams {
+            app_name: String::from("TestApp"),
+            app_id: 1_284_711,
+            device_type: None,
+            user_id: None,
+            username: None,
+            api_key: String::from("1xf6r2oNmJJt4M1xf6r2oNmJJt4M"),
+            poll_interval: None,
+            api_endpoint: None,
+            vpn_endpoint: None,
+            registry_endpoint: None,
+            delta_endpoint: None,
+            listen_port: None,
+            vpn_port: Some(444),
+        };
+
+        let cfg_str = BalenaCfgJson::params_to_string(&params, "raspberrypi3").unwrap();
+        let config: BalenaConfig = serde_json::from_str(&cfg_str).unwrap();
+        assert_eq!(config.app_name, "TestApp");
+        assert_eq!(config.app_id, 1_284_711);
+        assert_eq!(config.device_type, "raspberrypi3");
+        assert_eq!(config.user_id, None);
+        assert_eq!(config.app_poll_interval, DEFAULT_POLL_INTERVAL);
+        assert_eq!(config.vpn_port, 444);
+        assert_eq!(config.api_endpoint, DEFAULT_API_ENDPOINT);
+        assert_eq!(config.api_key.unwrap(), "1xf6r2oNmJJt4M1xf6r2oNmJJt4M");
+    }
+
     #[test]
     fn read_conf_ok3() {
         let config: BalenaConfig = serde_json::from_str(CONFIG3).unwrap();
         assert_eq!(config.api_key, None);
         assert_eq!(config.device_api_key.unwrap(), "aaaaaaaaaaaa");
     }
+
+    #[test]
+    fn required_keys() {
+        // BalenaConfig fails to parse without a required key and only then
+        let cfg_map: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(CONFIG1).unwrap();
+        for key in cfg_map.keys() {
+            let mut partial = cfg_map.clone();
+            partial.remove(key);
+            let res = serde_json::from_value::<BalenaConfig>(serde_json::Value::Object(partial));
+            assert_eq!(
+                res.is_err(),
+                REQUIRED_KEYS.contains(&key.as_str()),
+                "{}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn validate_conf_ok() {
+        assert!(validate::validate(CONFIG1).is_empty());
+        assert!(validate::validate(CONFIG2).is_empty());
+        assert!(validate::validate(CONFIG3).is_empty());
+    }
+
+    #[test]
+    fn validate_generated_conf_ok() {
+        let params = ConfigJsonParams {
+            app_name: String::from("TestApp"),
+            app_id: 1_284_711,
+            device_type: None,
+            user_id: Some(120_815),
+            username: Some(String::from("g_user")),
+            api_key: String::from("1xf6r2oNmJJt4M1xf6r2oNmJJt4M"),
+            poll_interval: None,
+            api_endpoint: None,
+            vpn_endpoint: None,
+            registry_endpoint: None,
+            delta_endpoint: None,
+            listen_port: None,
+            vpn_port: None,
+        };
+
+        let cfg_str = BalenaCfgJson::params_to_string(&params, "intel-nuc").unwrap();
+        assert!(validate::validate(&cfg_str).is_empty());
+    }
 }
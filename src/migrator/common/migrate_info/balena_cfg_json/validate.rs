@@ -0,0 +1,462 @@
+use serde::{
+    de::{self, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::fmt;
+use url::{Host, Url};
+
+use super::REQUIRED_KEYS;
+use crate::defs::KNOWN_DEVICE_SLUGS;
+
+// *************************************************************************************************
+// * Strict validation of config.json contents.
+// * All problems found are collected and reported at once rather than failing on the first one.
+// * Missing or invalid required keys are errors, anything else balena OS might still cope with
+// * (unknown keys, device types or invalid optional keys) is a warning.
+// *************************************************************************************************
+
+// keys that are not reported as unknown
+const OPTIONAL_KEYS: &[&str] = &[
+    "userId",
+    "username",
+    "pubnubSubscribeKey",
+    "pubnubPublishKey",
+    "mixpanelToken",
+    "apiKey",
+    "deviceApiKey",
+    "deviceApiKeys",
+    "deviceId",
+    "uuid",
+    "registered_at",
+    "os",
+    "hostname",
+    "persistentLogging",
+    "country",
+    "developmentMode",
+    "installer",
+    "balenaRootCA",
+    "dashboardEndpoint",
+];
+
+// keys that need to be parseable as http / https URL
+const URL_KEYS: &[&str] = &["apiEndpoint", "deltaEndpoint", "dashboardEndpoint"];
+// keys that need to be a plain host name
+const HOST_KEYS: &[&str] = &["vpnEndpoint", "registryEndpoint"];
+const PORT_KEYS: &[&str] = &["listenPort", "vpnPort"];
+const NUMBER_KEYS: &[&str] = &[
+    "applicationId",
+    "userId",
+    "deviceId",
+    "appUpdatePollInterval",
+    "registered_at",
+];
+const STRING_KEYS: &[&str] = &[
+    "applicationName",
+    "deviceType",
+    "username",
+    "pubnubSubscribeKey",
+    "pubnubPublishKey",
+    "mixpanelToken",
+    "apiKey",
+    "deviceApiKey",
+    "uuid",
+    "hostname",
+];
+
+#[derive(Debug, Default)]
+pub(crate) struct Problems {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Problems {
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty() && self.warnings.is_empty()
+    }
+
+    // a problem with key is an error if the key is required
+    fn add(&mut self, key: &str, problem: String) {
+        if REQUIRED_KEYS.contains(&key) {
+            self.errors.push(problem);
+        } else {
+            self.warnings.push(problem);
+        }
+    }
+}
+
+// a json value that remembers duplicate keys encountered while parsing
+struct CheckedValue {
+    value: Value,
+    duplicates: Vec<String>,
+}
+
+struct CheckedValueVisitor;
+
+impl<'de> Visitor<'de> for CheckedValueVisitor {
+    type Value = CheckedValue;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any valid JSON value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+        Ok(CheckedValue::plain(Value::from(v)))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        Ok(CheckedValue::plain(Value::from(v)))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(CheckedValue::plain(Value::from(v)))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        Ok(CheckedValue::plain(Value::from(v)))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(CheckedValue::plain(Value::from(v)))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Self::Value, E> {
+        Ok(CheckedValue::plain(Value::from(v)))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(CheckedValue::plain(Value::Null))
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(CheckedValue::plain(Value::Null))
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        CheckedValue::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut values: Vec<Value> = Vec::new();
+        let mut duplicates: Vec<String> = Vec::new();
+        while let Some(elem) = seq.next_element::<CheckedValue>()? {
+            let index = values.len();
+            for dup in elem.duplicates {
+                duplicates.push(format!("[{}].{}", index, dup));
+            }
+            values.push(elem.value);
+        }
+        Ok(CheckedValue {
+            value: Value::Array(values),
+            duplicates,
+        })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut values: Map<String, Value> = Map::new();
+        let mut duplicates: Vec<String> = Vec::new();
+        while let Some(key) = map.next_key::<String>()? {
+            let elem = map.next_value::<CheckedValue>()?;
+            for dup in elem.duplicates {
+                duplicates.push(format!("{}.{}", key, dup));
+            }
+            if values.insert(key.clone(), elem.value).is_some() {
+                duplicates.push(key);
+            }
+        }
+        Ok(CheckedValue {
+            value: Value::Object(values),
+            duplicates,
+        })
+    }
+}
+
+impl CheckedValue {
+    fn plain(value: Value) -> CheckedValue {
+        CheckedValue {
+            value,
+            duplicates: Vec::new(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for CheckedValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CheckedValue, D::Error> {
+        deserializer.deserialize_any(CheckedValueVisitor)
+    }
+}
+
+fn as_number(value: &Value) -> Option<u64> {
+    match value {
+        Value::Number(num) => num.as_u64(),
+        Value::String(num) => num.parse::<u64>().ok(),
+        _ => None,
+    }
+}
+
+fn is_hex_str(value: &str) -> bool {
+    value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Validate the contents of a config.json file, returns all problems found
+pub(crate) fn validate(cfg_str: &str) -> Problems {
+    let mut problems = Problems::default();
+
+    let checked: CheckedValue = match serde_json::from_str(cfg_str) {
+        Ok(checked) => checked,
+        Err(why) => {
+            problems
+                .errors
+                .push(format!("failed to parse JSON: {}", why));
+            return problems;
+        }
+    };
+
+    for dup in &checked.duplicates {
+        problems.add(dup, format!("duplicate key '{}'", dup));
+    }
+
+    let cfg_map = if let Value::Object(ref cfg_map) = checked.value {
+        cfg_map
+    } else {
+        problems
+            .errors
+            .push(String::from("expected a JSON object at top level"));
+        return problems;
+    };
+
+    let known_keys: HashSet<&str> = REQUIRED_KEYS
+        .iter()
+        .chain(OPTIONAL_KEYS.iter())
+        .cloned()
+        .collect();
+
+    for key in cfg_map.keys() {
+        if !known_keys.contains(key.as_str()) {
+            problems.warnings.push(format!("unknown key '{}'", key));
+        }
+    }
+
+    for key in REQUIRED_KEYS {
+        if !cfg_map.contains_key(*key) {
+            problems
+                .errors
+                .push(format!("missing required key '{}'", key));
+        }
+    }
+
+    for key in STRING_KEYS {
+        if let Some(value) = cfg_map.get(*key) {
+            if !value.is_string() {
+                problems.add(
+                    key,
+                    format!("'{}' is expected to be a string, found {}", key, value),
+                );
+            }
+        }
+    }
+
+    for key in NUMBER_KEYS {
+        if let Some(value) = cfg_map.get(*key) {
+            if as_number(value).is_none() {
+                problems.add(
+                    key,
+                    format!(
+                        "'{}' is expected to be an unsigned integer, found {}",
+                        key, value
+                    ),
+                );
+            }
+        }
+    }
+
+    for key in PORT_KEYS {
+        if let Some(value) = cfg_map.get(*key) {
+            match as_number(value) {
+                Some(port) => {
+                    if port == 0 || port > 0xFFFF {
+                        problems.add(key, format!("'{}' is out of range: {}", key, port));
+                    }
+                }
+                None => problems.add(
+                    key,
+                    format!("'{}' is expected to be a port number, found {}", key, value),
+                ),
+            }
+        }
+    }
+
+    for key in URL_KEYS {
+        if let Some(Value::String(value)) = cfg_map.get(*key) {
+            match Url::parse(value) {
+                Ok(url) => {
+                    if url.scheme() != "https" && url.scheme() != "http" {
+                        problems.add(
+                            key,
+                            format!(
+                                "'{}' has an unsupported scheme '{}': '{}'",
+                                key,
+                                url.scheme(),
+                                value
+                            ),
+                        );
+                    }
+                    if url.host().is_none() {
+                        problems.add(key, format!("'{}' has no host: '{}'", key, value));
+                    }
+                }
+                Err(why) => problems.add(
+                    key,
+                    format!("'{}' is not a valid URL: '{}', {}", key, value, why),
+                ),
+            }
+        }
+    }
+
+    for key in HOST_KEYS {
+        if let Some(Value::String(value)) = cfg_map.get(*key) {
+            if value.contains("://") || value.contains('/') {
+                problems.add(
+                    key,
+                    format!("'{}' is expected to be a host name, found '{}'", key, value),
+                );
+            } else if let Err(why) = Host::parse(value) {
+                problems.add(
+                    key,
+                    format!("'{}' is not a valid host name: '{}', {}", key, value, why),
+                );
+            }
+        }
+    }
+
+    // the list of slugs is not complete, new device types show up as warnings only
+    if let Some(Value::String(dev_type)) = cfg_map.get("deviceType") {
+        if !KNOWN_DEVICE_SLUGS.contains(&dev_type.as_str()) {
+            let lc_dev_type = dev_type.to_lowercase();
+            if KNOWN_DEVICE_SLUGS.contains(&lc_dev_type.as_str()) {
+                problems.warnings.push(format!(
+                    "'deviceType' should be lower case: '{}', expected '{}'",
+                    dev_type, lc_dev_type
+                ));
+            } else {
+                problems
+                    .warnings
+                    .push(format!("'deviceType' is not a known slug: '{}'", dev_type));
+            }
+        }
+    }
+
+    if let Some(Value::String(app_name)) = cfg_map.get("applicationName") {
+        if app_name.is_empty() {
+            problems
+                .errors
+                .push(String::from("'applicationName' is empty"));
+        }
+    }
+
+    let has_api_key = cfg_map.contains_key("apiKey");
+    let has_device_api_key = cfg_map.contains_key("deviceApiKey");
+
+    if !has_api_key && !has_device_api_key {
+        problems.warnings.push(String::from(
+            "neither 'apiKey' nor 'deviceApiKey' was found, the device will not be able to register",
+        ));
+    }
+
+    if has_device_api_key {
+        match cfg_map.get("uuid") {
+            Some(Value::String(uuid)) => {
+                if !((uuid.len() == 32 || uuid.len() == 62) && is_hex_str(uuid)) {
+                    problems.warnings.push(format!(
+                        "'uuid' is expected to be 32 or 62 hex digits, found '{}'",
+                        uuid
+                    ));
+                }
+            }
+            Some(_) => (),
+            None => problems.warnings.push(String::from(
+                "'deviceApiKey' is present but 'uuid' is missing",
+            )),
+        }
+    }
+
+    if let Some(os) = cfg_map.get("os") {
+        if !os.is_object() {
+            problems
+                .warnings
+                .push(format!("'os' is expected to be an object, found {}", os));
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG_OK: &str = r###"
+{"applicationName":"TestDev","applicationId":1284711,"deviceType":"raspberrypi3","userId":120815,"username":"g_user","appUpdatePollInterval":600000,"listenPort":48484,"vpnPort":443,"apiEndpoint":"https://api.balena-cloud.com","vpnEndpoint":"vpn.balena-cloud.com","registryEndpoint":"registry2.balena-cloud.com","deltaEndpoint":"https://delta.balena-cloud.com","pubnubSubscribeKey":"","pubnubPublishKey":"","mixpanelToken":"9ef939ea64cb6cd9ef939ea64cb6cd","apiKey":"1xf6r2oNmJJt4M1xf6r2oNmJJt4M"}
+"###;
+
+    const CONFIG_BAD: &str = r###"
+{"applicationName":"TestDev","applicationId":1284711,"deviceType":"RaspberryPi3","appUpdatePollInterval":600000,"listenPort":48484,"vpnPort":70000,"apiEndpoint":"api.balena-cloud.com","vpnEndpoint":"vpn.balena-cloud.com","registryEndpoint":"registry2.balena-cloud.com","deltaEndpoint":"https://delta.balena-cloud.com","deviceApiKey":"aaaaaaaaaaaa","vpnPort":443,"someKey":true}
+"###;
+
+    #[test]
+    fn validate_ok() {
+        assert!(validate(CONFIG_OK).is_empty());
+    }
+
+    #[test]
+    fn validate_reports_all() {
+        let problems = validate(CONFIG_BAD);
+        // duplicate vpnPort, bad api URL
+        assert_eq!(problems.errors.len(), 2);
+        assert!(problems
+            .errors
+            .iter()
+            .any(|p| p.contains("duplicate key 'vpnPort'")));
+        assert!(problems.errors.iter().any(|p| p.contains("'apiEndpoint'")));
+        // unknown someKey, bad casing, missing uuid
+        assert_eq!(problems.warnings.len(), 3);
+        assert!(problems
+            .warnings
+            .iter()
+            .any(|p| p.contains("unknown key 'someKey'")));
+        assert!(problems.warnings.iter().any(|p| p.contains("lower case")));
+        assert!(problems
+            .warnings
+            .iter()
+            .any(|p| p.contains("'uuid' is missing")));
+    }
+
+    #[test]
+    fn validate_unknown_values() {
+        // keys and device types this version does not know about are only warnings
+        let cfg_str = CONFIG_OK.replace("raspberrypi3", "some-new-board").replace(
+            "\"apiKey\"",
+            "\"ntpServers\":\"pool.ntp.org\",\"localMode\":false,\"apiKey\"",
+        );
+        let problems = validate(&cfg_str);
+        assert!(problems.errors.is_empty());
+        assert_eq!(problems.warnings.len(), 3);
+
+        // an invalid optional key is a warning, a missing required key an error
+        let problems = validate(&CONFIG_OK.replace("\"userId\":120815", "\"userId\":\"me\""));
+        assert!(problems.errors.is_empty());
+        assert_eq!(problems.warnings.len(), 1);
+        let problems = validate(&CONFIG_OK.replace("\"applicationName\":\"TestDev\",", ""));
+        assert_eq!(problems.errors.len(), 1);
+        assert!(problems.errors[0].contains("missing required key 'applicationName'"));
+    }
+
+    #[test]
+    fn validate_port_range() {
+        let problems = validate(&CONFIG_OK.replace("48484", "0"));
+        assert_eq!(problems.errors.len(), 1);
+        assert!(problems.errors[0].contains("'listenPort' is out of range"));
+    }
+}
@@ -16,4 +16,5 @@ pub(crate) trait OSApi {
         partition: P,
     ) -> Result<DeviceInfo, MigError>;
     fn expect_type<P: AsRef<Path>>(&self, file: P, ftype: &FileType) -> Result<(), MigError>;
+    fn is_file_type<P: AsRef<Path>>(&self, file: P, ftype: &FileType) -> Result<bool, MigError>;
 }
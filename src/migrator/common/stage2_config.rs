@@ -1,7 +1,9 @@
+use digest::Digest;
 use failure::ResultExt;
-use log::{debug, info, Level};
+use log::{debug, error, info, warn, Level};
+use sha2::Sha256;
 use std::fs::{read_to_string, File};
-use std::io::Write;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
@@ -12,13 +14,33 @@ pub const EMPTY_BACKUPS: &[(String, String)] = &[];
 
 const MODULE: &str = "stage2::stage2:config";
 
+// schema of the stage 2 config, increment on any change stage 2 of an older release can not read
+pub(crate) const STAGE2_CFG_SCHEMA: u32 = 1;
+// the schema tagged in the stage 2 binary, stage 1 looks for it in the initramfs
+pub(crate) const STAGE2_CFG_SCHEMA_TAG: &str = "balena-stage2-cfg-schema=1;";
+
+const CHECKSUM_PREFIX: &str = "# checksum: sha256:";
+
+const MAX_SCHEMA_DIGITS: usize = 9;
+const SCAN_BUFFER_SIZE: usize = 64 * 1024;
+
 use crate::{
     common::{
-        config::{balena_config::PartCheck, migrate_config::WatchdogCfg},
+        bmap::to_hex_string,
+        bundle::SignedBundle,
+        config::{
+            balena_config::PartCheck,
+            migrate_config::{HookCfg, ProgressCfg, StatusReportCfg, WatchdogCfg},
+        },
+        disk_util::Compression,
         file_info::RelFileInfo,
+        staging::Staging,
         MigErrCtx, MigError, MigErrorKind,
     },
-    defs::{BootType, DeviceType, FailMode},
+    defs::{
+        BootType, DeviceType, FailMode, DEFAULT_FLASH_RETRIES, DEFAULT_MOUNT_TIMEOUT,
+        DEF_BLOCK_SIZE, PARTITION_ALIGNMENT_KIB, VERSION,
+    },
 };
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -41,6 +63,32 @@ pub(crate) struct CheckedFSDump {
     pub data: CheckedPartDump,
 }
 
+impl CheckedFSDump {
+    // end of the partitions in bytes as laid out by stage 2 without maximizing the data partition
+    pub fn get_layout_end(&self) -> u64 {
+        let alignment_blocks: u64 = PARTITION_ALIGNMENT_KIB * 1024 / DEF_BLOCK_SIZE as u64;
+        let align = |block: u64| -> u64 {
+            if block % alignment_blocks != 0 {
+                (block / alignment_blocks + 1) * alignment_blocks
+            } else {
+                block
+            }
+        };
+
+        let mut start_block = alignment_blocks + self.boot.blocks;
+        start_block = align(start_block) + self.root_a.blocks;
+        start_block = align(start_block) + self.root_b.blocks;
+        start_block = align(start_block);
+        let extended_end = start_block + self.extended_blocks;
+
+        // state and data are placed in the extended partition with an extra alignment block each
+        start_block += alignment_blocks + self.state.blocks;
+        let data_end = align(start_block) + alignment_blocks + self.data.blocks;
+
+        extended_end.max(data_end) * DEF_BLOCK_SIZE as u64
+    }
+}
+
 #[allow(clippy::large_enum_variant)] //TODO refactor to remove clippy warning
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub(crate) enum CheckedImageType {
@@ -105,12 +153,29 @@ pub(crate) enum PathType {
     Mount(MountConfig),
 }
 
+// the fields of the stage 2 config that are checked before the config is parsed
+#[derive(Debug, Deserialize)]
+struct Stage2ConfigHeader {
+    schema: Option<u32>,
+    writer: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct Stage2Config {
-    // what to do on failure
+    // schema of this config, must match STAGE2_CFG_SCHEMA
+    schema: u32,
+    // version of balena-migrate that wrote this config
+    writer: String,
+    // identifies this migration in the stage 2 journal
+    migrate_id: String,
+    // what to do on a recoverable failure
     fail_mode: FailMode,
+    // what to do once balena OS is partially written or the boot configuration was not restored
+    fail_mode_non_recoverable: FailMode,
     // no_flash mode - stop after unmounting root if true
     no_flash: bool,
+    // simulation mode - flash to a file or spare device, do not touch the boot configuration or reboot
+    simulate: bool,
     // which device to flash - derive from /root partition if not set (windows)
     force_flash_device: Option<PathBuf>,
     // balena config file
@@ -125,20 +190,42 @@ pub(crate) struct Stage2Config {
     has_backup: bool,
     // use rust internal gzip
     gzip_internal: bool,
+    // read back & compare the flashed image
+    flash_verify: bool,
+    // flash attempts repeated on verification failure
+    flash_retries: u32,
+    // skip writing zero blocks to the discarded target if no block map is present
+    flash_skip_zeros: bool,
+    // block map of the flasher image
+    bmap_file: Option<RelFileInfo>,
     // stage 2 log level
     log_level: String,
     // stage 2 log destination
     log_to: Option<Stage2LogConfig>,
-    // log also to console
-    log_console: bool,
+    // log also to console, not set unless configured
+    log_console: Option<bool>,
+    // stage 1 log file in work_path
+    stage1_log: Option<PathBuf>,
     // device type
     device_type: DeviceType,
     // boot type
     boot_type: BootType,
     // delay migration in stage 2
     migrate_delay: Option<u64>,
+    // seconds to wait for devices to show up when mounting
+    mount_timeout: Option<u64>,
     // watchdogs to kick
     watchdogs: Option<Vec<WatchdogCfg>>,
+    // user hooks in work_path
+    hooks: Option<Vec<HookCfg>>,
+    // post status reports, device_id is set by stage 1
+    status_report: Option<StatusReportCfg>,
+    // progress event sinks
+    progress: Option<ProgressCfg>,
+    // signed bundle checked in stage 1
+    bundle: Option<SignedBundle>,
+    // files staged on the flash device for low memory devices
+    staging: Option<Staging>,
 }
 
 impl<'a> Stage2Config {
@@ -159,7 +246,6 @@ impl<'a> Stage2Config {
     }
 
     pub fn from_config<P: AsRef<Path>>(path: &P) -> Result<Stage2Config, MigError> {
-        // TODO: Dummy, parse from yaml
         let config_str = read_to_string(path).context(MigErrCtx::from_remark(
             MigErrorKind::Upstream,
             &format!(
@@ -169,11 +255,95 @@ impl<'a> Stage2Config {
             ),
         ))?;
 
+        Stage2Config::check_compat(&config_str)?;
         Stage2Config::from_str(&config_str)
     }
 
+    // make sure the config was written for this stage 2 and arrived intact
+    fn check_compat(config_str: &str) -> Result<(), MigError> {
+        let header: Stage2ConfigHeader =
+            serde_yaml::from_str(config_str).context(MigErrCtx::from_remark(
+                MigErrorKind::InvState,
+                "The stage2 config is corrupted, it can not be parsed",
+            ))?;
+
+        let writer = if let Some(ref writer) = header.writer {
+            format!("balena-migrate {}", writer)
+        } else {
+            String::from("an unknown balena-migrate release")
+        };
+
+        match header.schema {
+            Some(schema) if schema == STAGE2_CFG_SCHEMA => (),
+            schema => {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvState,
+                    &format!(
+                        "The stage2 config was written by {} with config schema {}, this stage 2 (balena-migrate {}) requires schema {}. Use the initramfs from the balena-migrate release that ran stage 1",
+                        writer,
+                        if let Some(schema) = schema {
+                            schema.to_string()
+                        } else {
+                            String::from("none")
+                        },
+                        VERSION,
+                        STAGE2_CFG_SCHEMA
+                    ),
+                ));
+            }
+        }
+
+        let (content, checksum) = if let Some(pos) = config_str.rfind(CHECKSUM_PREFIX) {
+            (
+                &config_str[..pos],
+                config_str[pos + CHECKSUM_PREFIX.len()..].trim(),
+            )
+        } else {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvState,
+                &format!(
+                    "The stage2 config written by {} has no checksum, it was truncated or edited. Run stage 1 again to recreate it",
+                    writer
+                ),
+            ));
+        };
+
+        if checksum != cfg_checksum(content) {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvState,
+                &format!(
+                    "The checksum of the stage2 config written by {} does not match, it is corrupted or was edited. Run stage 1 again to recreate it",
+                    writer
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn get_writer(&'a self) -> &'a str {
+        &self.writer
+    }
+
+    pub fn get_migrate_id(&'a self) -> &'a str {
+        &self.migrate_id
+    }
+
     pub fn is_log_console(&self) -> bool {
-        self.log_console
+        self.log_console == Some(true)
+    }
+
+    // logging to the console was switched off in the config
+    pub fn is_log_console_off(&self) -> bool {
+        self.log_console == Some(false)
+    }
+
+    pub fn get_stage1_log(&'a self) -> Option<&'a Path> {
+        if let Some(ref val) = self.stage1_log {
+            Some(val)
+        } else {
+            None
+        }
     }
 
     pub fn get_log_level(&self) -> Level {
@@ -200,10 +370,50 @@ impl<'a> Stage2Config {
         self.no_flash
     }
 
+    pub fn is_simulate(&self) -> bool {
+        self.simulate
+    }
+
     pub fn is_gzip_internal(&self) -> bool {
         self.gzip_internal
     }
 
+    pub fn is_flash_verify(&self) -> bool {
+        self.flash_verify
+    }
+
+    pub fn get_flash_retries(&self) -> u32 {
+        self.flash_retries
+    }
+
+    pub fn is_flash_skip_zeros(&self) -> bool {
+        self.flash_skip_zeros
+    }
+
+    pub fn get_bmap_file(&'a self) -> Option<&'a RelFileInfo> {
+        if let Some(ref bmap_file) = self.bmap_file {
+            Some(bmap_file)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_bundle(&'a self) -> Option<&'a SignedBundle> {
+        if let Some(ref val) = self.bundle {
+            Some(val)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_staging(&'a self) -> Option<&'a Staging> {
+        if let Some(ref val) = self.staging {
+            Some(val)
+        } else {
+            None
+        }
+    }
+
     pub fn get_force_flash_device(&'a self) -> Option<&'a PathBuf> {
         if let Some(ref flash_device) = self.force_flash_device {
             Some(flash_device)
@@ -220,6 +430,14 @@ impl<'a> Stage2Config {
         }
     }
 
+    pub fn get_mount_timeout(&self) -> u64 {
+        if let Some(val) = self.mount_timeout {
+            val
+        } else {
+            DEFAULT_MOUNT_TIMEOUT
+        }
+    }
+
     pub fn get_watchdogs(&self) -> Option<&Vec<WatchdogCfg>> {
         if let Some(ref val) = self.watchdogs {
             Some(val)
@@ -228,6 +446,30 @@ impl<'a> Stage2Config {
         }
     }
 
+    pub fn get_hooks(&'a self) -> &'a [HookCfg] {
+        if let Some(ref val) = self.hooks {
+            val.as_slice()
+        } else {
+            &[]
+        }
+    }
+
+    pub fn get_status_report(&'a self) -> Option<&'a StatusReportCfg> {
+        if let Some(ref val) = self.status_report {
+            Some(val)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_progress(&'a self) -> Option<&'a ProgressCfg> {
+        if let Some(ref val) = self.progress {
+            Some(val)
+        } else {
+            None
+        }
+    }
+
     pub fn get_boot_type(&'a self) -> &'a BootType {
         &self.boot_type
     }
@@ -259,6 +501,10 @@ impl<'a> Stage2Config {
     pub fn get_fail_mode(&'a self) -> &'a FailMode {
         &self.fail_mode
     }
+
+    pub fn get_fail_mode_non_recoverable(&'a self) -> &'a FailMode {
+        &self.fail_mode_non_recoverable
+    }
 }
 
 pub(crate) struct Required<T> {
@@ -326,8 +572,11 @@ impl<T: Clone> Optional<T> {
 }
 
 pub(crate) struct Stage2ConfigBuilder {
+    migrate_id: Required<String>,
     fail_mode: Required<FailMode>,
+    fail_mode_non_recoverable: Required<FailMode>,
     no_flash: Required<bool>,
+    simulate: Required<bool>,
     force_flash_device: Optional<PathBuf>,
     balena_config: Required<PathBuf>,
     balena_image: Required<CheckedImageType>,
@@ -335,20 +584,37 @@ pub(crate) struct Stage2ConfigBuilder {
     boot_bckup: Optional<Vec<(String, String)>>,
     has_backup: Required<bool>,
     gzip_internal: Required<bool>,
+    flash_verify: Required<bool>,
+    flash_retries: Required<u32>,
+    flash_skip_zeros: Required<bool>,
+    bmap_file: Optional<RelFileInfo>,
     log_level: Required<String>,
     log_to: Optional<Stage2LogConfig>,
-    log_console: Required<bool>,
+    log_console: Optional<bool>,
+    stage1_log: Optional<PathBuf>,
     device_type: Required<DeviceType>,
     boot_type: Required<BootType>,
     migrate_delay: Optional<u64>,
+    mount_timeout: Optional<u64>,
     watchdogs: Optional<Vec<WatchdogCfg>>,
+    hooks: Optional<Vec<HookCfg>>,
+    status_report: Optional<StatusReportCfg>,
+    progress: Optional<ProgressCfg>,
+    bundle: Optional<SignedBundle>,
+    staging: Optional<Staging>,
 }
 
 impl<'a> Stage2ConfigBuilder {
     pub fn default() -> Stage2ConfigBuilder {
         Stage2ConfigBuilder {
+            migrate_id: Required::new("migrate_id", None),
             fail_mode: Required::new("fail_mode", Some(&FailMode::Reboot)),
+            fail_mode_non_recoverable: Required::new(
+                "fail_mode_non_recoverable",
+                Some(&FailMode::RescueShell),
+            ),
             no_flash: Required::new("no_flash", Some(&true)),
+            simulate: Required::new("simulate", Some(&false)),
             force_flash_device: Optional::new(None),
             balena_config: Required::new("balena_config", None),
             balena_image: Required::new("balena_image", None),
@@ -356,20 +622,36 @@ impl<'a> Stage2ConfigBuilder {
             boot_bckup: Optional::new(None),
             has_backup: Required::new("has_backup", None),
             gzip_internal: Required::new("gzip_internal", Some(&true)),
+            flash_verify: Required::new("flash_verify", Some(&false)),
+            flash_retries: Required::new("flash_retries", Some(&DEFAULT_FLASH_RETRIES)),
+            flash_skip_zeros: Required::new("flash_skip_zeros", Some(&false)),
+            bmap_file: Optional::new(None),
             log_level: Required::new("log_level", Some(&String::from("warn"))),
             log_to: Optional::new(None),
-            log_console: Required::new("log_console", Some(&false)),
+            log_console: Optional::new(None),
+            stage1_log: Optional::new(None),
             device_type: Required::new("device_type", None),
             boot_type: Required::new("boot_type", None),
             migrate_delay: Optional::new(None),
+            mount_timeout: Optional::new(None),
             watchdogs: Optional::new(None),
+            hooks: Optional::new(None),
+            status_report: Optional::new(None),
+            progress: Optional::new(None),
+            bundle: Optional::new(None),
+            staging: Optional::new(None),
         }
     }
 
     pub fn build(&self) -> Result<Stage2Config, MigError> {
         let result = Stage2Config {
+            schema: STAGE2_CFG_SCHEMA,
+            writer: String::from(VERSION),
+            migrate_id: self.migrate_id.get()?.clone(),
             fail_mode: self.fail_mode.get()?.clone(),
+            fail_mode_non_recoverable: self.fail_mode_non_recoverable.get()?.clone(),
             no_flash: *self.no_flash.get()?,
+            simulate: *self.simulate.get()?,
             force_flash_device: self.force_flash_device.get().clone(),
             balena_config: self.balena_config.get()?.clone(),
             balena_image: self.balena_image.get()?.clone(),
@@ -377,13 +659,24 @@ impl<'a> Stage2ConfigBuilder {
             boot_bckup: self.boot_bckup.get().clone(),
             has_backup: *self.has_backup.get()?,
             gzip_internal: *self.gzip_internal.get()?,
+            flash_verify: *self.flash_verify.get()?,
+            flash_retries: *self.flash_retries.get()?,
+            flash_skip_zeros: *self.flash_skip_zeros.get()?,
+            bmap_file: self.bmap_file.get().clone(),
             log_level: self.log_level.get()?.clone(),
             log_to: self.log_to.get().clone(),
-            log_console: *self.log_console.get()?,
+            log_console: *self.log_console.get(),
+            stage1_log: self.stage1_log.get().clone(),
             device_type: *self.device_type.get()?,
             boot_type: *self.boot_type.get()?,
             migrate_delay: *self.migrate_delay.get(),
+            mount_timeout: *self.mount_timeout.get(),
             watchdogs: self.watchdogs.get().clone(),
+            hooks: self.hooks.get().clone(),
+            status_report: self.status_report.get().clone(),
+            progress: self.progress.get().clone(),
+            bundle: self.bundle.get().clone(),
+            staging: self.staging.get().clone(),
         };
 
         Ok(result)
@@ -399,6 +692,11 @@ impl<'a> Stage2ConfigBuilder {
         let mut cfg_str = String::from("# Balena Migrate Stage2 Config\n");
         cfg_str.push_str("# auto-created by balena migrate - do not edit\n");
         cfg_str.push_str(&self.build()?.to_str()?);
+        if !cfg_str.ends_with('\n') {
+            cfg_str.push('\n');
+        }
+        let checksum = cfg_checksum(&cfg_str);
+        cfg_str.push_str(&format!("{}{}\n", CHECKSUM_PREFIX, checksum));
 
         debug!("write_stage2_cfg_to: config: '{}'", cfg_str);
 
@@ -424,10 +722,22 @@ impl<'a> Stage2ConfigBuilder {
         self.fail_mode.set_ref(val);
     }
 
+    pub fn set_failmode_non_recoverable(&mut self, val: &FailMode) {
+        self.fail_mode_non_recoverable.set_ref(val);
+    }
+
     pub fn set_no_flash(&mut self, val: bool) {
         self.no_flash.set(val);
     }
 
+    pub fn set_migrate_id(&mut self, val: String) {
+        self.migrate_id.set(val);
+    }
+
+    pub fn set_simulate(&mut self, val: bool) {
+        self.simulate.set(val);
+    }
+
     pub fn set_force_flash_device(&mut self, val: PathBuf) {
         self.force_flash_device.set(val);
     }
@@ -457,6 +767,22 @@ impl<'a> Stage2ConfigBuilder {
         self.gzip_internal.set(val);
     }
 
+    pub fn set_flash_verify(&mut self, val: bool) {
+        self.flash_verify.set(val);
+    }
+
+    pub fn set_flash_retries(&mut self, val: u32) {
+        self.flash_retries.set(val);
+    }
+
+    pub fn set_flash_skip_zeros(&mut self, val: bool) {
+        self.flash_skip_zeros.set(val);
+    }
+
+    pub fn set_bmap_file(&mut self, val: &RelFileInfo) {
+        self.bmap_file.set_ref(val);
+    }
+
     pub fn set_device_type(&mut self, dev_type: DeviceType) {
         self.device_type.set(dev_type);
     }
@@ -473,6 +799,10 @@ impl<'a> Stage2ConfigBuilder {
         self.log_console.set(val);
     }
 
+    pub fn set_stage1_log(&mut self, val: PathBuf) {
+        self.stage1_log.set(val);
+    }
+
     pub fn set_boot_type(&mut self, val: BootType) {
         self.boot_type.set(val);
     }
@@ -481,19 +811,154 @@ impl<'a> Stage2ConfigBuilder {
         self.migrate_delay.set_ref(&val);
     }
 
+    pub fn set_mount_timeout(&mut self, val: u64) {
+        self.mount_timeout.set_ref(&val);
+    }
+
     #[allow(clippy::ptr_arg)] //TODO refactor this function to fix the clippy warning
     pub fn set_watchdogs(&mut self, val: &Vec<WatchdogCfg>) {
         self.watchdogs.set_ref(val);
     }
+
+    pub fn set_hooks(&mut self, val: &[HookCfg]) {
+        self.hooks.set(val.to_vec());
+    }
+
+    pub fn set_status_report(&mut self, val: StatusReportCfg) {
+        self.status_report.set(val);
+    }
+
+    pub fn set_progress(&mut self, val: &ProgressCfg) {
+        self.progress.set_ref(val);
+    }
+
+    pub fn set_bundle(&mut self, val: &SignedBundle) {
+        self.bundle.set_ref(val);
+    }
+
+    pub fn set_staging(&mut self, val: Staging) {
+        self.staging.set(val);
+    }
+}
+
+fn cfg_checksum(content: &str) -> String {
+    let mut hasher = Sha256::default();
+    hasher.input(content.as_bytes());
+    to_hex_string(&hasher.result())
+}
+
+// the part of the schema tag in front of the schema number
+fn schema_tag_prefix() -> &'static str {
+    let end = STAGE2_CFG_SCHEMA_TAG.find('=').unwrap_or(0) + 1;
+    &STAGE2_CFG_SCHEMA_TAG[..end]
+}
+
+// collect the schemas tagged in a stream, anything that does not end in digits and ';' is ignored
+fn find_schema_tags(reader: &mut dyn Read) -> Result<Vec<u32>, io::Error> {
+    let prefix = schema_tag_prefix().as_bytes();
+    let tag_len = prefix.len() + MAX_SCHEMA_DIGITS + 1;
+    let mut tags: Vec<u32> = Vec::new();
+    let mut data: Vec<u8> = Vec::new();
+    let mut buffer = vec![0u8; SCAN_BUFFER_SIZE];
+
+    loop {
+        let bytes_read = match reader.read(&mut buffer) {
+            Ok(bytes_read) => bytes_read,
+            Err(ref why) if why.kind() == io::ErrorKind::Interrupted => continue,
+            Err(why) => return Err(why),
+        };
+        data.extend_from_slice(&buffer[..bytes_read]);
+
+        // a tag starting after scan_end might be incomplete, it is scanned after the next read
+        let scan_end = if bytes_read == 0 {
+            data.len()
+        } else {
+            data.len().saturating_sub(tag_len)
+        };
+
+        for pos in 0..scan_end {
+            if data[pos] != prefix[0] || !data[pos..].starts_with(prefix) {
+                continue;
+            }
+            let number = &data[pos + prefix.len()..data.len().min(pos + tag_len)];
+            if let Some(end) = number.iter().position(|byte| *byte == b';') {
+                if end > 0 && number[..end].iter().all(|byte| byte.is_ascii_digit()) {
+                    if let Ok(schema) = String::from_utf8_lossy(&number[..end]).parse::<u32>() {
+                        if !tags.contains(&schema) {
+                            tags.push(schema);
+                        }
+                    }
+                }
+            }
+        }
+
+        if bytes_read == 0 {
+            return Ok(tags);
+        }
+        data.drain(..scan_end);
+    }
+}
+
+// check that the initramfs contains a stage 2 that can read the config written by this stage 1
+pub(crate) fn check_stage2_schema(initrd: &Path) -> Result<(), MigError> {
+    let compression = Compression::from_file(initrd)?;
+    let scanned = match compression.open_reader(initrd) {
+        Ok(mut reader) => find_schema_tags(&mut reader).map_err(|why| format!("{}", why)),
+        Err(why) => Err(format!("{}", why)),
+    };
+
+    let tags = match scanned {
+        Ok(tags) => tags,
+        Err(why) => {
+            warn!(
+                "The stage 2 config schema of the {} initramfs '{}' could not be checked: {}",
+                compression.get_descr(),
+                initrd.display(),
+                why
+            );
+            return Ok(());
+        }
+    };
+
+    if tags.contains(&STAGE2_CFG_SCHEMA) {
+        info!(
+            "The initramfs '{}' contains a stage 2 for config schema {}",
+            initrd.display(),
+            STAGE2_CFG_SCHEMA
+        );
+        Ok(())
+    } else if tags.is_empty() {
+        warn!(
+            "No stage 2 config schema was found in the {} initramfs '{}', make sure it is the initramfs of balena-migrate {}",
+            compression.get_descr(),
+            initrd.display(),
+            VERSION
+        );
+        Ok(())
+    } else {
+        error!(
+            "The initramfs '{}' contains a stage 2 for config schema {:?}, balena-migrate {} writes schema {}. Use the initramfs from the same balena-migrate release",
+            initrd.display(),
+            tags,
+            VERSION,
+            STAGE2_CFG_SCHEMA
+        );
+        Err(MigError::displayed())
+    }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     const TEST_CONFIG: &str = r##"
+schema: 1
+writer: 0.2.0
+migrate_id: 1571320000-1234
 fail_mode: Reboot
+fail_mode_non_recoverable: RescueShell
 no_flash: true
+simulate: false
 force_flash_device: ~
 balena_config: config.json
 balena_image:
@@ -508,19 +973,201 @@ work_path:
 boot_bckup: ~
 has_backup: false
 gzip_internal: true
+flash_verify: true
+flash_retries: 1
+flash_skip_zeros: false
+bmap_file:
+  rel_path: balena-cloud-intel-nuc-2.38.3+rev5-v9.15.7.img.bmap
+  size: 9627
+  hash_info:
+    md5: 0f2b6a0e1ea8a3c1b4c1f4a4d4be4a8e
 log_level: debug
 log_to:
   device: /dev/sdb1
   fstype: vfat
 log_console: false
+stage1_log: stage1.log
 device_type: IntelNuc
 boot_type: Grub
 migrate_delay: 0
-watchdogs: ~'
+mount_timeout: 30
+watchdogs: ~
+hooks:
+  - path: hooks/set-gpio.sh
+    stage: before_flash
+    args:
+      - "17"
+    timeout: 10
+    on_fail: ignore
+  - path: hooks/copy-extra
+    stage: after_flash
+status_report:
+  url: "https://status.example.com/migrations"
+  token: secret
+  device_id: nuc-1
+  timeout: 10
+progress:
+  interval: 5
+  sinks:
+    - log
+    - file: progress.log
+    - serial: /dev/ttyS0
+bundle:
+  manifest: migrate-bundle.sums
+  content: "9be2a59a8286c8acacebd69a6ac1ff3caa02f3950f91ba2df1514f9f69f9495e  part.img.gz\n"
+  signature: 9b1b2d8e3c2a6c1e4ed9b0e4f2a6d9c3a6d4b0e1f7c2a9d8e5b3c1a0f9e8d7c6b5a4938271605f4e3d2c1b0a99887766554433221100ffeeddccbbaaaa998877
+staging:
+  os_end: 2621440000
+  files:
+    - rel_path: backup.tgz
+      size: 3072
+      hash_info:
+        sha256: 9be2a59a8286c8acacebd69a6ac1ff3caa02f3950f91ba2df1514f9f69f9495e
+      extents:
+        - logical: 0
+          physical: 3221225472
+          length: 4096
 "##;
 
+    const FAIL_MODE_CONFIG: &str = r##"
+schema: 1
+writer: 0.2.0
+migrate_id: 1571320000-1235
+fail_mode:
+  Retry: 3
+fail_mode_non_recoverable: RestoreAndReboot
+no_flash: false
+simulate: false
+force_flash_device: ~
+balena_config: config.json
+balena_image:
+  Flasher:
+    rel_path: balena-cloud-intel-nuc-2.38.3+rev5-v9.15.7.img.gz
+    size: 139522865
+    hash_info:
+      md5: c55a19eacc425c3e75a007ae4249b85d
+work_path:
+  Path: /home/thomas/migrate
+boot_bckup: ~
+has_backup: false
+gzip_internal: true
+flash_verify: true
+flash_retries: 1
+flash_skip_zeros: false
+bmap_file: ~
+log_level: debug
+log_to: ~
+log_console: false
+stage1_log: ~
+device_type: IntelNuc
+boot_type: Grub
+migrate_delay: 0
+mount_timeout: 30
+watchdogs: ~
+hooks: []
+status_report: ~
+progress: ~
+bundle: ~
+staging: ~
+"##;
+
+    // a config retrying recoverable failures
+    pub(crate) fn fail_mode_config() -> Stage2Config {
+        Stage2Config::from_str(FAIL_MODE_CONFIG).unwrap()
+    }
+
     #[test]
     fn assert_test_config1() {
         let _config = Stage2Config::from_str(TEST_CONFIG);
     }
+
+    #[test]
+    fn parse_fail_modes() {
+        let config = fail_mode_config();
+        assert_eq!(config.get_fail_mode(), &FailMode::Retry(3));
+        assert_eq!(
+            config.get_fail_mode_non_recoverable(),
+            &FailMode::RestoreAndReboot
+        );
+    }
+
+    fn with_checksum(content: &str) -> String {
+        format!("{}{}{}\n", content, CHECKSUM_PREFIX, cfg_checksum(content))
+    }
+
+    #[test]
+    fn check_config_compat() {
+        let content = "# Balena Migrate Stage2 Config\nschema: 1\nwriter: 0.2.0\nmigrate_id: abc\n";
+        assert!(Stage2Config::check_compat(&with_checksum(content)).is_ok());
+
+        // edited after the checksum was created
+        let edited = with_checksum(content).replace("abc", "abd");
+        assert!(Stage2Config::check_compat(&edited).is_err());
+
+        // truncated
+        assert!(Stage2Config::check_compat(content).is_err());
+
+        // written for a different stage 2
+        let other = with_checksum(&content.replace("schema: 1", "schema: 2"));
+        let why = format!("{}", Stage2Config::check_compat(&other).unwrap_err());
+        assert!(why.contains("balena-migrate 0.2.0 with config schema 2"));
+
+        let older = with_checksum("schema: ~\nmigrate_id: abc\n");
+        let why = format!("{}", Stage2Config::check_compat(&older).unwrap_err());
+        assert!(why.contains("config schema none"));
+    }
+
+    #[test]
+    fn scan_schema_tags() {
+        // the tag straddles a read, false matches are ignored
+        let mut data = vec![b'x'; SCAN_BUFFER_SIZE - 10];
+        data.extend_from_slice(STAGE2_CFG_SCHEMA_TAG.as_bytes());
+        data.extend_from_slice(b"balena-stage2-cfg-schema=;balena-stage2-cfg-schema=7x;");
+        data.extend_from_slice(b"balena-stage2-cfg-schema=12;balena-stage2-cfg-schema=1;");
+        let tags = find_schema_tags(&mut data.as_slice()).unwrap();
+        assert_eq!(tags, vec![STAGE2_CFG_SCHEMA, 12]);
+
+        // a tag at the very end
+        let mut data: &[u8] = b"balena-stage2-cfg-schema=3;";
+        assert_eq!(find_schema_tags(&mut data).unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn check_initramfs_schema() {
+        use flate2::{write::GzEncoder, Compression as GzCompression};
+        use std::fs::{create_dir_all, remove_dir_all};
+
+        let dir = crate::common::path_append(std::env::temp_dir(), "balena-migrate-schema-test");
+        let _res = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+
+        let write_initrd = |name: &str, tag: &str| -> PathBuf {
+            let path = crate::common::path_append(&dir, name);
+            let mut encoder = GzEncoder::new(File::create(&path).unwrap(), GzCompression::fast());
+            encoder.write_all(b"070701 cpio archive ").unwrap();
+            encoder.write_all(tag.as_bytes()).unwrap();
+            encoder.finish().unwrap();
+            path
+        };
+
+        let initrd = write_initrd("current.gz", STAGE2_CFG_SCHEMA_TAG);
+        assert!(check_stage2_schema(&initrd).is_ok());
+
+        let initrd = write_initrd("other.gz", "balena-stage2-cfg-schema=99;");
+        assert!(check_stage2_schema(&initrd).is_err());
+
+        // no tag found can not be decided on, it is only warned about
+        let initrd = write_initrd("untagged.gz", "");
+        assert!(check_stage2_schema(&initrd).is_ok());
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn schema_tag_matches_schema() {
+        assert_eq!(
+            STAGE2_CFG_SCHEMA_TAG,
+            format!("{}{};", schema_tag_prefix(), STAGE2_CFG_SCHEMA)
+        );
+    }
 }
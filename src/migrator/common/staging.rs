@@ -0,0 +1,166 @@
+use failure::ResultExt;
+use serde::{Deserialize, Serialize};
+use std::cmp::min;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::common::{file_digest::HashInfo, MigErrCtx, MigError, MigErrorKind};
+
+// *************************************************************************************************
+// * Files staged on the flash device.
+// * When there is not enough memory to copy the image and backup to the initramfs, stage 1 records
+// * where their blocks are located on the flash device. The blocks have to lie beyond the area
+// * written by balena OS, so stage 2 can read the files from the raw device while flashing.
+// *************************************************************************************************
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub(crate) struct Extent {
+    // offset in file
+    pub logical: u64,
+    // offset on the flash device
+    pub physical: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct StagedFile {
+    // path relative to work_dir
+    pub rel_path: PathBuf,
+    pub size: u64,
+    pub hash_info: HashInfo,
+    pub extents: Vec<Extent>,
+}
+
+impl StagedFile {
+    pub fn open_reader(&self, device: &Path) -> Result<ExtentReader, MigError> {
+        let file = File::open(device).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to open '{}' for reading", device.display()),
+        ))?;
+        Ok(ExtentReader::new(file, self.size, &self.extents))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct Staging {
+    // end of the area balena OS will be written to
+    pub os_end: u64,
+    pub files: Vec<StagedFile>,
+}
+
+impl Staging {
+    pub fn get_file<P: AsRef<Path>>(&self, rel_path: P) -> Option<&StagedFile> {
+        let rel_path = rel_path.as_ref();
+        self.files.iter().find(|file| file.rel_path == rel_path)
+    }
+}
+
+// reads a file from its extents on a device, holes read as zeros
+pub(crate) struct ExtentReader {
+    device: File,
+    size: u64,
+    extents: Vec<Extent>,
+    pos: u64,
+}
+
+impl ExtentReader {
+    pub fn new(device: File, size: u64, extents: &[Extent]) -> ExtentReader {
+        let mut extents = extents.to_vec();
+        extents.sort_by_key(|extent| extent.logical);
+        ExtentReader {
+            device,
+            size,
+            extents,
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ExtentReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let max_read = min(buf.len() as u64, self.size - self.pos);
+        let pos = self.pos;
+        let bytes_read = if let Some(extent) = self
+            .extents
+            .iter()
+            .find(|extent| extent.logical <= pos && pos < extent.logical + extent.length)
+        {
+            let to_read = min(max_read, extent.logical + extent.length - pos) as usize;
+            self.device
+                .seek(SeekFrom::Start(extent.physical + pos - extent.logical))?;
+            let bytes_read = self.device.read(&mut buf[0..to_read])?;
+            if bytes_read == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "extent exceeds the device",
+                ));
+            }
+            bytes_read
+        } else {
+            let next = self
+                .extents
+                .iter()
+                .map(|extent| extent.logical)
+                .filter(|logical| *logical > pos)
+                .min()
+                .unwrap_or(self.size);
+            let to_read = min(max_read, next - pos) as usize;
+            for byte in buf[0..to_read].iter_mut() {
+                *byte = 0;
+            }
+            to_read
+        };
+
+        self.pos += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{remove_file, write};
+
+    #[test]
+    fn read_extents() {
+        let path = std::env::temp_dir().join("balena-migrate-extent-test");
+        let device: Vec<u8> = (0..64u8).collect();
+        write(&path, &device).unwrap();
+
+        // out of order extents with a hole at 8..12, the last extent is longer than the file
+        let extents = vec![
+            Extent {
+                logical: 12,
+                physical: 40,
+                length: 16,
+            },
+            Extent {
+                logical: 0,
+                physical: 16,
+                length: 8,
+            },
+        ];
+
+        let mut reader = ExtentReader::new(File::open(&path).unwrap(), 20, &extents);
+        let mut buffer = [0u8; 5];
+        let mut content: Vec<u8> = Vec::new();
+        loop {
+            let bytes_read = reader.read(&mut buffer).unwrap();
+            if bytes_read == 0 {
+                break;
+            }
+            content.extend_from_slice(&buffer[0..bytes_read]);
+        }
+        remove_file(&path).unwrap();
+
+        let mut expected: Vec<u8> = (16..24u8).collect();
+        expected.extend_from_slice(&[0; 4]);
+        expected.extend(40..48u8);
+        assert_eq!(content, expected);
+    }
+}
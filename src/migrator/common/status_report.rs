@@ -0,0 +1,419 @@
+use failure::ResultExt;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{read_to_string, remove_file, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::common::{
+    config::migrate_config::StatusReportCfg, file_exists, MigErrCtx, MigError, MigErrorKind,
+};
+
+// *************************************************************************************************
+// * Report the migration status to a backend.
+// * Phase transitions and the outcome are posted as json to the configured URL. Reports that can
+// * not be sent are kept in order and sent with the next report, a queue file keeps them across
+// * reboots when it is set.
+// *************************************************************************************************
+
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+const UNKNOWN_DEVICE_ID: &str = "unknown";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) enum ReportStatus {
+    #[serde(rename = "running")]
+    Running,
+    #[serde(rename = "failed")]
+    Failed,
+    #[serde(rename = "success")]
+    Success,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct StatusReport {
+    device_id: String,
+    migrate_id: String,
+    stage: u8,
+    phase: String,
+    status: ReportStatus,
+    error: Option<String>,
+    time: u64,
+}
+
+pub(crate) struct StatusReporter {
+    url: String,
+    token: Option<String>,
+    device_id: String,
+    migrate_id: String,
+    stage: u8,
+    timeout: u64,
+    queue_file: Option<PathBuf>,
+    pending: Vec<StatusReport>,
+    // the last phase reported as running, tells where a displayed error happened
+    last_phase: Option<String>,
+}
+
+impl StatusReporter {
+    pub fn new(config: &StatusReportCfg, stage: u8, migrate_id: &str) -> StatusReporter {
+        StatusReporter {
+            url: config.url.clone(),
+            token: config.token.clone(),
+            device_id: if let Some(ref device_id) = config.device_id {
+                device_id.clone()
+            } else {
+                String::from(UNKNOWN_DEVICE_ID)
+            },
+            migrate_id: String::from(migrate_id),
+            stage,
+            timeout: config.timeout.unwrap_or(DEFAULT_TIMEOUT_SECS) * 1000,
+            queue_file: None,
+            pending: Vec::new(),
+            last_phase: None,
+        }
+    }
+
+    // a short description of an error, details of displayed errors are in the log
+    pub fn error_summary(&self, why: &MigError) -> String {
+        if why.kind() == MigErrorKind::Displayed {
+            if let Some(ref last_phase) = self.last_phase {
+                format!(
+                    "failed after '{}', see the migration log for details",
+                    last_phase
+                )
+            } else {
+                String::from("see the migration log for details")
+            }
+        } else {
+            format!("{}", why)
+        }
+    }
+
+    // report a failure or retry with a summary of why
+    pub fn report_error(&mut self, phase: &str, status: ReportStatus, why: &MigError) {
+        let summary = self.error_summary(why);
+        self.report(phase, status, Some(summary));
+    }
+
+    pub fn report(&mut self, phase: &str, status: ReportStatus, error: Option<String>) {
+        info!(
+            "Reporting stage {} status: {} {:?}",
+            self.stage, phase, status
+        );
+        if status == ReportStatus::Running && error.is_none() {
+            self.last_phase = Some(String::from(phase));
+        }
+        self.pending.push(StatusReport {
+            device_id: self.device_id.clone(),
+            migrate_id: self.migrate_id.clone(),
+            stage: self.stage,
+            phase: String::from(phase),
+            status,
+            error,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        });
+        self.send_pending();
+    }
+
+    // keep unsent reports in queue_file from now on, reports queued there before are sent first
+    pub fn set_queue_file(&mut self, queue_file: &Path) {
+        let mut pending = StatusReporter::read_queue(queue_file);
+        pending.append(&mut self.pending);
+        self.pending = pending;
+        self.queue_file = Some(queue_file.to_path_buf());
+        self.send_pending();
+    }
+
+    // add the reports queued in queue_file without taking over the file
+    pub fn load_queue(&mut self, queue_file: &Path) {
+        let mut pending = StatusReporter::read_queue(queue_file);
+        if !pending.is_empty() {
+            pending.append(&mut self.pending);
+            self.pending = pending;
+            self.send_pending();
+        }
+    }
+
+    pub fn get_pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    // write the reports that could not be sent to queue_file
+    pub fn save_queue(&self, queue_file: &Path) -> Result<(), MigError> {
+        if self.pending.is_empty() {
+            if file_exists(queue_file) {
+                remove_file(queue_file).context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!("Failed to remove '{}'", queue_file.display()),
+                ))?;
+            }
+            return Ok(());
+        }
+
+        let mut content = String::new();
+        for report in &self.pending {
+            content.push_str(
+                &serde_json::to_string(report).context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    "Failed to serialize status report",
+                ))?,
+            );
+            content.push('\n');
+        }
+
+        let mut file = File::create(queue_file).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to open '{}' for writing", queue_file.display()),
+        ))?;
+        file.write_all(content.as_bytes())
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed to write to '{}'", queue_file.display()),
+            ))?;
+        file.sync_all().context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to sync '{}'", queue_file.display()),
+        ))?;
+        debug!(
+            "save_queue: saved {} reports to '{}'",
+            self.pending.len(),
+            queue_file.display()
+        );
+        Ok(())
+    }
+
+    fn read_queue(queue_file: &Path) -> Vec<StatusReport> {
+        if let Ok(content) = read_to_string(queue_file) {
+            let pending: Vec<StatusReport> = content
+                .lines()
+                .filter_map(|line| serde_json::from_str::<StatusReport>(line).ok())
+                .collect();
+            if !pending.is_empty() {
+                info!(
+                    "Found {} queued status reports in '{}'",
+                    pending.len(),
+                    queue_file.display()
+                );
+            }
+            pending
+        } else {
+            Vec::new()
+        }
+    }
+
+    // send reports in order, stop at the first failure and keep the rest
+    fn send_pending(&mut self) {
+        let mut sent = 0;
+        for report in &self.pending {
+            if let Err(why) = self.send(report) {
+                warn!("Failed to send status report, queueing it: {}", why);
+                break;
+            }
+            sent += 1;
+        }
+        self.pending.drain(0..sent);
+
+        if let Some(ref queue_file) = self.queue_file {
+            if let Err(why) = self.save_queue(queue_file) {
+                warn!("Failed to save status reports, error: {:?}", why);
+            }
+        }
+    }
+
+    fn send(&self, report: &StatusReport) -> Result<(), MigError> {
+        let body = serde_json::to_string(report).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            "Failed to serialize status report",
+        ))?;
+
+        let mut request = ureq::post(&self.url);
+        request
+            .timeout_connect(self.timeout)
+            .timeout_read(self.timeout)
+            .timeout_write(self.timeout)
+            .set("Content-Type", "application/json");
+        if let Some(ref token) = self.token {
+            request.set("Authorization", &format!("Bearer {}", token));
+        }
+
+        let response = request.send_string(&body);
+        if let Some(why) = response.synthetic_error() {
+            return Err(MigError::from_remark(
+                MigErrorKind::Upstream,
+                &format!("'{}' could not be reached: {}", self.url, why),
+            ));
+        }
+
+        if response.ok() {
+            debug!("send: sent status report {}", body);
+            Ok(())
+        } else {
+            Err(MigError::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "'{}' returned {} {}",
+                    self.url,
+                    response.status(),
+                    response.status_text()
+                ),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::path_append;
+    use std::env;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    // answer a number of requests with status, pass the token and body of each request on
+    fn serve(requests: usize, status: &'static str) -> (String, Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                let mut token = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim();
+                    if line.is_empty() {
+                        break;
+                    }
+                    let lower = line.to_lowercase();
+                    if lower.starts_with("content-length:") {
+                        length = lower["content-length:".len()..].trim().parse().unwrap();
+                    } else if lower.starts_with("authorization:") {
+                        token = String::from(line["authorization:".len()..].trim());
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                sender
+                    .send((token, String::from_utf8(body).unwrap()))
+                    .unwrap();
+                stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                            status
+                        )
+                        .as_bytes(),
+                    )
+                    .unwrap();
+            }
+        });
+        (format!("http://127.0.0.1:{}/migrations", port), receiver)
+    }
+
+    fn test_config(url: &str) -> StatusReportCfg {
+        StatusReportCfg {
+            url: String::from(url),
+            token: Some(String::from("secret")),
+            device_id: Some(String::from("device-1")),
+            timeout: Some(2),
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = path_append(env::temp_dir(), name);
+        let _res = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn report_status() {
+        let (url, received) = serve(1, "200 OK");
+        let mut reporter = StatusReporter::new(&test_config(&url), 1, "1571320000-1234");
+        reporter.report("started", ReportStatus::Running, None);
+        assert_eq!(reporter.pending.len(), 0);
+
+        let (token, body) = received.recv().unwrap();
+        assert_eq!(token, "Bearer secret");
+        let report: StatusReport = serde_json::from_str(&body).unwrap();
+        assert_eq!(report.device_id, "device-1");
+        assert_eq!(report.migrate_id, "1571320000-1234");
+        assert_eq!(report.stage, 1);
+        assert_eq!(report.phase, "started");
+        assert_eq!(report.status, ReportStatus::Running);
+    }
+
+    #[test]
+    fn queue_offline_reports() {
+        let dir = test_dir("balena-migrate-status-test");
+        let queue_file = path_append(&dir, "status-queue.json");
+
+        // nothing listens on the port once the listener is dropped
+        let offline_url = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!(
+                "http://127.0.0.1:{}/migrations",
+                listener.local_addr().unwrap().port()
+            )
+        };
+
+        let mut reporter = StatusReporter::new(&test_config(&offline_url), 1, "id");
+        reporter.set_queue_file(&queue_file);
+        reporter.report("started", ReportStatus::Running, None);
+        reporter.report("failed", ReportStatus::Failed, Some(String::from("no")));
+        assert_eq!(reporter.pending.len(), 2);
+        assert_eq!(read_to_string(&queue_file).unwrap().lines().count(), 2);
+
+        // a server error keeps the reports queued
+        let (url, _received) = serve(1, "500 Internal Server Error");
+        let mut reporter = StatusReporter::new(&test_config(&url), 2, "id");
+        reporter.load_queue(&queue_file);
+        assert_eq!(reporter.pending.len(), 2);
+
+        // queued reports are sent in order before the new one
+        let (url, received) = serve(3, "200 OK");
+        let mut reporter = StatusReporter::new(&test_config(&url), 2, "id");
+        reporter.set_queue_file(&queue_file);
+        reporter.report("flash_started", ReportStatus::Running, None);
+        assert_eq!(reporter.pending.len(), 0);
+        assert!(!file_exists(&queue_file));
+
+        let phases: Vec<String> = (0..3)
+            .map(|_| {
+                let (_token, body) = received.recv().unwrap();
+                serde_json::from_str::<StatusReport>(&body).unwrap().phase
+            })
+            .collect();
+        assert_eq!(phases, vec!["started", "failed", "flash_started"]);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn summarize_errors() {
+        let (url, _received) = serve(1, "200 OK");
+        let mut reporter = StatusReporter::new(&test_config(&url), 2, "id");
+        assert_eq!(
+            reporter.error_summary(&MigError::displayed()),
+            "see the migration log for details"
+        );
+
+        reporter.report("flash_started", ReportStatus::Running, None);
+        assert_eq!(
+            reporter.error_summary(&MigError::displayed()),
+            "failed after 'flash_started', see the migration log for details"
+        );
+
+        let why = MigError::from_remark(MigErrorKind::InvParam, "bad config");
+        assert_eq!(reporter.error_summary(&why), format!("{}", why));
+    }
+}
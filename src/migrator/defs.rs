@@ -11,6 +11,9 @@ pub const SYSTEM_CONNECTIONS_DIR: &str = "system-connections";
 // Default migrate config name
 pub const DEFAULT_MIGRATE_CONFIG: &str = "balena-migrate.yml";
 
+// Ed25519 key for signed migration bundles, set BALENA_MIGRATE_BUNDLE_KEY at build time to pin it
+pub const BUNDLE_PUBLIC_KEY: Option<&str> = option_env!("BALENA_MIGRATE_BUNDLE_KEY");
+
 pub const MIG_KERNEL_NAME: &str = "balena-migrate.zImage";
 pub const MIG_INITRD_NAME: &str = "balena-migrate.initrd";
 pub const MIG_DTB_NAME: &str = "balena-migrate.dtb";
@@ -34,6 +37,35 @@ pub const BALENA_FILE_TAG_REGEX: &str = r###"^\s*## created by balena-migrate"##
 // check timeout used for API & VPN
 pub const DEFAULT_API_CHECK_TIMEOUT: u64 = 20;
 
+// defaults used when generating config.json from migrate config
+pub const DEFAULT_API_ENDPOINT: &str = "https://api.balena-cloud.com";
+pub const DEFAULT_VPN_ENDPOINT: &str = "vpn.balena-cloud.com";
+pub const DEFAULT_REGISTRY_ENDPOINT: &str = "registry2.balena-cloud.com";
+pub const DEFAULT_DELTA_ENDPOINT: &str = "https://delta.balena-cloud.com";
+pub const DEFAULT_LISTEN_PORT: u16 = 48484;
+pub const DEFAULT_VPN_PORT: u16 = 443;
+pub const DEFAULT_POLL_INTERVAL: u64 = 600_000; // 10 minutes in ms
+
+// name of config.json generated from migrate config in work_dir
+pub const GENERATED_CONFIG_FILE: &str = "config-generated.json";
+
+// device slugs accepted as deviceType in config.json
+pub const KNOWN_DEVICE_SLUGS: &[&str] = &[
+    "beaglebone-green",
+    "beaglebone-green-wifi",
+    "beaglebone-black",
+    "beagleboard-xm",
+    "beaglebone-pocket",
+    "intel-nuc",
+    "genericx86-64-ext",
+    "raspberry-pi",
+    "raspberry-pi2",
+    "raspberrypi3",
+    "raspberrypi3-64",
+    "raspberrypi4-64",
+    "fincm3",
+];
+
 pub const BACKUP_FILE: &str = "backup.tgz";
 
 pub const MIN_DISK_SIZE: u64 = 2 * 1024 * 1024 * 1024; // 2 GiB
@@ -42,6 +74,17 @@ pub const DEF_BLOCK_SIZE: usize = 512;
 
 pub const STAGE1_MEM_THRESHOLD: u64 = 1024 * 1024 * 100; // 100 MB
 
+pub const PARTITION_ALIGNMENT_KIB: u64 = 4096; // KiB
+
+// gap left between the end of balena OS and files staged on the flash device
+pub const STAGING_MARGIN: u64 = 4 * 1024 * 1024; // 4 MiB
+
+// time stage 2 waits for devices to show up when mounting
+pub const DEFAULT_MOUNT_TIMEOUT: u64 = 30; // seconds
+
+// flash attempts repeated on verification failure
+pub const DEFAULT_FLASH_RETRIES: u32 = 1;
+
 // Default balena partition labels and FS types
 pub const BALENA_BOOT_PART: &str = "resin-boot";
 pub const BALENA_BOOT_FSTYPE: &str = "vfat";
@@ -111,17 +154,29 @@ impl Display for OSArch {
 pub(crate) enum FailMode {
     Reboot,
     RescueShell,
+    // run stage 2 again up to n times, waiting longer after each attempt
+    Retry(u32),
+    PowerOff,
+    // restore the former boot configuration if balena OS has not been written yet
+    RestoreAndReboot,
 }
 
 impl FailMode {
     pub(crate) fn get_default() -> &'static FailMode {
         &FailMode::Reboot
     }
+
+    pub(crate) fn get_default_non_recoverable() -> &'static FailMode {
+        &FailMode::RescueShell
+    }
 }
 
 #[derive(Debug, Clone)]
 pub(crate) enum FileType {
     GZipOSImage,
+    XzOSImage,
+    ZstdOSImage,
+    ZipOSImage,
     OSImage,
     KernelAMD64,
     KernelARMHF,
@@ -138,6 +193,9 @@ impl FileType {
     pub fn get_descr(&self) -> &str {
         match self {
             FileType::GZipOSImage => "gzipped balena OS image",
+            FileType::XzOSImage => "xz compressed balena OS image",
+            FileType::ZstdOSImage => "zstd compressed balena OS image",
+            FileType::ZipOSImage => "zip archived balena OS image",
             FileType::OSImage => "balena OS image",
             FileType::KernelAMD64 => "balena migrate kernel image for AMD64",
             FileType::KernelARMHF => "balena migrate kernel image for ARMHF",
@@ -21,17 +21,18 @@ use crate::{
         config::balena_config::{FSDump, FileRef, ImageType, PartDump},
         disk_util::{Disk, PartitionIterator, PartitionReader}, //  , ImageFile, GZipFile, PlainFile },
         file_digest::get_default_digest,
+        file_type::get_os_image_type,
+        image_os_info::ImageOSInfo,
         path_append,
         MigErrCtx,
         MigError,
         MigErrorKind,
     },
-    defs::FileType,
     defs::PART_INFO,
     linux::{
-        linux_common::{is_admin, is_file_type, mktemp, whereis},
+        linux_common::{is_admin, mktemp, whereis},
         linux_defs::NIX_NONE,
-        linux_defs::{FILE_CMD, LOSETUP_CMD, MKTEMP_CMD, TAR_CMD},
+        linux_defs::{LOSETUP_CMD, MKTEMP_CMD, TAR_CMD},
     },
 };
 
@@ -44,7 +45,7 @@ use crate::{
 // mod plain_file;
 // use plain_file::PlainFile;
 
-const REQUIRED_CMDS: &[&str] = &[FILE_CMD, MKTEMP_CMD, TAR_CMD, LOSETUP_CMD];
+const REQUIRED_CMDS: &[&str] = &[MKTEMP_CMD, TAR_CMD, LOSETUP_CMD];
 const DEF_BUFFER_SIZE: usize = 1024 * 1024;
 
 const EXTRACT_FILE_TEMPLATE: &str = "extract.XXXXXXXXXX";
@@ -183,32 +184,18 @@ impl Extractor {
         }
 
         debug!("new: working with file '{}'", image_file.display());
-        if is_file_type(&image_file, &FileType::GZipOSImage)? {
-            match Disk::from_gzip_img(&image_file) {
-                Ok(gzip_img) => {
-                    debug!("new: is gzipped image '{}'", image_file.display());
-                    Ok(Extractor {
-                        work_dir,
-                        disk: gzip_img,
-                        device_slug: extract_device,
-                    })
-                }
-                Err(why) => {
-                    error!(
-                        "Unable to open the gzipped image file '{}', error: {:?}",
+        if let Some(image_type) = get_os_image_type(&image_file)? {
+            // extracting reads the image back and forth, keep the gzip index in the work dir
+            match Disk::from_image_file_with_index(&image_file, &work_dir) {
+                Ok(disk) => {
+                    debug!(
+                        "new: image '{}' is a {}",
                         image_file.display(),
-                        why
+                        image_type.get_descr()
                     );
-                    Err(MigError::displayed())
-                }
-            }
-        } else if is_file_type(&image_file, &FileType::OSImage)? {
-            match Disk::from_drive_file(&image_file, None) {
-                Ok(plain_img) => {
-                    debug!("new: is plain image '{}'", image_file.display());
                     Ok(Extractor {
                         work_dir,
-                        disk: plain_img,
+                        disk,
                         device_slug: extract_device,
                     })
                 }
@@ -232,6 +219,12 @@ impl Extractor {
 
     pub fn do_extract(&mut self, output_path: Option<&Path>) -> Result<ImageType, MigError> {
         trace!("extract: entered");
+
+        match ImageOSInfo::from_disk(&mut self.disk) {
+            Ok(os_info) => info!("Image contains {}", os_info),
+            Err(why) => warn!("Failed to read OS information from image, error: {:?}", why),
+        }
+
         let work_dir = &self.work_dir;
 
         let mountpoint = match mktemp(true, Some(MOUNTPOINT_TEMPLATE), Some(work_dir)) {
@@ -0,0 +1,398 @@
+use clap::{App, Arg};
+use failure::ResultExt;
+use log::{debug, error, trace, warn};
+use mod_logger::{Level, LogDestination, Logger, NO_STREAM};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    common::{
+        device_type_json::DeviceTypeJson,
+        disk_util::{Disk, ExtFs, FatFs, PartInfo, PartitionIterator, PartitionType},
+        file_digest::{get_default_digest, HashInfo},
+        file_size, format_size_with_unit,
+        image_os_info::ImageOSInfo,
+        MigErrCtx, MigError, MigErrorKind,
+    },
+    defs::PART_INFO,
+};
+
+// *************************************************************************************************
+// * Inspect a balena OS image without mounting it.
+// * Reports the partition table, file system labels compared against PART_INFO, the device type
+// * from resin-boot, the OS release from resin-rootA and the image digest.
+// *************************************************************************************************
+
+#[derive(Debug, Serialize)]
+struct PartReport {
+    index: usize,
+    ptype: String,
+    start_lba: u64,
+    num_sectors: u64,
+    size: u64,
+    // GPT partition name & GUID
+    name: Option<String>,
+    part_guid: Option<String>,
+    label: Option<String>,
+    fs_type: Option<String>,
+    expected_label: Option<String>,
+    expected_fs_type: Option<String>,
+    matches: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ImageReport {
+    image: PathBuf,
+    compression: String,
+    file_size: u64,
+    digest: Option<HashInfo>,
+    label_type: String,
+    disk_id: Option<String>,
+    partitions: Vec<PartReport>,
+    device_type: Option<String>,
+    os_name: Option<String>,
+    os_version: Option<String>,
+    os_variant: Option<String>,
+    kernel_version: Option<String>,
+}
+
+pub fn inspect() -> Result<(), MigError> {
+    Logger::create();
+    Logger::set_color(true);
+    Logger::set_log_dest(&LogDestination::BufferStderr, NO_STREAM).context(
+        MigErrCtx::from_remark(MigErrorKind::Upstream, "failed to set up logging"),
+    )?;
+
+    let arg_matches = App::new("balena-inspect")
+        .version("0.1")
+        .author("Thomas Runte <thomasr@balena.io>")
+        .about("Inspects balena OS Images")
+        .arg(
+            Arg::with_name("image")
+                .required(true)
+                .help("balena OS image to inspect"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .short("j")
+                .long("json")
+                .help("print results as JSON"),
+        )
+        .arg(
+            Arg::with_name("no-digest")
+                .long("no-digest")
+                .help("do not compute the image digest"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .multiple(true)
+                .help("Sets the level of verbosity"),
+        )
+        .get_matches();
+
+    match arg_matches.occurrences_of("verbose") {
+        0 => Logger::set_default_level(&Level::Warn),
+        1 => Logger::set_default_level(&Level::Info),
+        2 => Logger::set_default_level(&Level::Debug),
+        _ => Logger::set_default_level(&Level::Trace),
+    }
+
+    let image_file = if let Some(value) = arg_matches.value_of("image") {
+        PathBuf::from(value)
+    } else {
+        error!("No image file was specified.");
+        return Err(MigError::displayed());
+    };
+
+    if !image_file.exists() {
+        error!("Could not find image file: '{}'", image_file.display());
+        return Err(MigError::displayed());
+    }
+
+    let report = inspect_image(&image_file, !arg_matches.is_present("no-digest"))?;
+
+    if arg_matches.is_present("json") {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                "Failed to serialize report to JSON",
+            ))?
+        );
+    } else {
+        print_report(&report);
+    }
+
+    Logger::flush();
+    Ok(())
+}
+
+fn inspect_image(image_file: &Path, with_digest: bool) -> Result<ImageReport, MigError> {
+    trace!("inspect_image: entered with '{}'", image_file.display());
+
+    let mut disk = Disk::from_image_file(image_file)?;
+    let compression = disk.get_compression();
+    let block_size = disk.get_block_size();
+
+    let label_type = format!("{:?}", disk.get_label()?);
+
+    let (disk_id, partitions) = {
+        let mut iterator = PartitionIterator::new(&mut disk)?;
+        let disk_id = if let Some(guid) = iterator.get_disk_guid() {
+            Some(guid.to_string())
+        } else {
+            iterator
+                .get_disk_id()
+                .map(|disk_id| format!("0x{:08x}", disk_id))
+        };
+        let partitions: Vec<PartInfo> = iterator.by_ref().collect();
+        (disk_id, partitions)
+    };
+
+    let mut report = ImageReport {
+        image: image_file.to_path_buf(),
+        compression: String::from(compression.get_descr()),
+        file_size: file_size(image_file)?,
+        digest: None,
+        label_type,
+        disk_id,
+        partitions: Vec::new(),
+        device_type: None,
+        os_name: None,
+        os_version: None,
+        os_variant: None,
+        kernel_version: None,
+    };
+
+    let mut data_part_idx: usize = 0;
+    for part in &partitions {
+        let is_data_part = matches!(
+            PartitionType::from_ptype(part.ptype),
+            PartitionType::Fat | PartitionType::Linux
+        );
+
+        let (label, fs_type) = if is_data_part {
+            get_fs_info(&mut disk, part)
+        } else {
+            (None, None)
+        };
+
+        let (expected_label, expected_fs_type) = if is_data_part {
+            data_part_idx += 1;
+            if let Some((exp_label, exp_fs_type)) = PART_INFO.get(data_part_idx - 1) {
+                (
+                    Some(String::from(*exp_label)),
+                    Some(String::from(*exp_fs_type)),
+                )
+            } else {
+                (None, None)
+            }
+        } else {
+            (None, None)
+        };
+
+        let matches = !is_data_part
+            || (expected_label.is_some() && label == expected_label && fs_type == expected_fs_type);
+
+        report.partitions.push(PartReport {
+            index: part.index,
+            ptype: if let Some(ref gpt_info) = part.gpt_info {
+                gpt_info.type_guid.to_string()
+            } else {
+                format!("0x{:02x}", part.ptype)
+            },
+            start_lba: part.start_lba,
+            num_sectors: part.num_sectors,
+            size: part.num_sectors * block_size,
+            name: part.gpt_info.as_ref().map(|gpt_info| gpt_info.name.clone()),
+            part_guid: part
+                .gpt_info
+                .as_ref()
+                .map(|gpt_info| gpt_info.part_guid.to_string()),
+            label,
+            fs_type,
+            expected_label,
+            expected_fs_type,
+            matches,
+        });
+
+        if is_data_part && data_part_idx == 1 {
+            report.device_type = get_device_type(&mut disk, part);
+        } else if is_data_part && data_part_idx == 2 {
+            match ExtFs::from_disk(&mut disk, part) {
+                Ok(mut ext_fs) => match ImageOSInfo::from_ext_fs(&mut ext_fs) {
+                    Ok(os_info) => {
+                        report.os_name = os_info.name;
+                        report.os_version = os_info.version;
+                        report.os_variant = os_info.variant;
+                        report.kernel_version = os_info.kernel_version;
+                    }
+                    Err(why) => warn!("Failed to read OS information, error: {:?}", why),
+                },
+                Err(why) => warn!(
+                    "Failed to open root file system on partition {}, error: {:?}",
+                    part.index, why
+                ),
+            }
+        }
+    }
+
+    if with_digest {
+        report.digest = Some(get_default_digest(image_file)?);
+    }
+
+    Ok(report)
+}
+
+// get file system label & type, trying FAT first
+fn get_fs_info(disk: &mut Disk, part: &PartInfo) -> (Option<String>, Option<String>) {
+    if let Ok(fat_fs) = FatFs::from_disk(disk, part) {
+        return (
+            Some(String::from(fat_fs.get_label())),
+            Some(String::from("vfat")),
+        );
+    }
+
+    match ExtFs::from_disk(disk, part) {
+        Ok(ext_fs) => (
+            Some(String::from(ext_fs.get_label())),
+            Some(String::from(ext_fs.get_fs_type())),
+        ),
+        Err(why) => {
+            debug!(
+                "get_fs_info: no known file system on partition {}, error: {:?}",
+                part.index, why
+            );
+            (None, None)
+        }
+    }
+}
+
+fn get_device_type(disk: &mut Disk, part: &PartInfo) -> Option<String> {
+    match DeviceTypeJson::from_partition(disk, part) {
+        Ok(dev_type) => Some(dev_type.slug),
+        Err(why) => {
+            warn!(
+                "Failed to read device type from partition {}, error: {:?}",
+                part.index, why
+            );
+            None
+        }
+    }
+}
+
+fn print_report(report: &ImageReport) {
+    let unknown = String::from("unknown");
+    println!("image:          {}", report.image.display());
+    println!(
+        "file size:      {} ({})",
+        format_size_with_unit(report.file_size),
+        report.compression
+    );
+    if let Some(ref digest) = report.digest {
+        match digest {
+            HashInfo::Md5(digest) => println!("md5:            {}", digest),
+            HashInfo::Sha1(digest) => println!("sha1:           {}", digest),
+            HashInfo::Sha256(digest) => println!("sha256:         {}", digest),
+            HashInfo::Sha512(digest) => println!("sha512:         {}", digest),
+        }
+    }
+    println!("label type:     {}", report.label_type);
+    println!(
+        "disk id:        {}",
+        report.disk_id.as_ref().unwrap_or(&unknown)
+    );
+    println!(
+        "device type:    {}",
+        report.device_type.as_ref().unwrap_or(&unknown)
+    );
+    println!(
+        "os:             {} {} ({})",
+        report.os_name.as_ref().unwrap_or(&unknown),
+        report.os_version.as_ref().unwrap_or(&unknown),
+        report.os_variant.as_ref().unwrap_or(&unknown)
+    );
+    println!(
+        "kernel:         {}",
+        report.kernel_version.as_ref().unwrap_or(&unknown)
+    );
+    println!("partitions:");
+    for part in &report.partitions {
+        println!(
+            "  {:>2} {:>12} {:>10} {:>10} {:<14} {:<6} {}",
+            part.index,
+            part.ptype,
+            part.start_lba,
+            format_size_with_unit(part.size),
+            part.label.as_ref().map_or("", |label| label.as_str()),
+            part.fs_type.as_ref().map_or("", |fs_type| fs_type.as_str()),
+            if part.matches {
+                String::from("")
+            } else {
+                format!(
+                    "expected: {} {}",
+                    part.expected_label
+                        .as_ref()
+                        .map_or("-", |label| label.as_str()),
+                    part.expected_fs_type
+                        .as_ref()
+                        .map_or("-", |fs_type| fs_type.as_str())
+                )
+            }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inspect_test_image() {
+        let report = inspect_image(Path::new("./test_data/balena.img.gz"), true).unwrap();
+        assert_eq!(report.compression, "gzip");
+        assert_eq!(report.label_type, "Dos");
+        assert!(report.digest.is_some());
+
+        let fs_types: Vec<Option<&str>> = report
+            .partitions
+            .iter()
+            .map(|part| part.fs_type.as_ref().map(|fs_type| fs_type.as_str()))
+            .collect();
+        assert_eq!(
+            fs_types,
+            vec![
+                Some("vfat"),
+                Some("ext4"),
+                Some("ext4"),
+                None,
+                Some("ext4"),
+                Some("ext4")
+            ]
+        );
+        assert_eq!(report.partitions[0].label, Some(String::from("RESIN-BOOT")));
+        assert_eq!(report.partitions[1].size, 8192 * 512);
+        // the extended partition has no file system to compare
+        assert!(report.partitions[3].matches);
+        assert_eq!(
+            report.partitions[5].expected_label,
+            Some(String::from("resin-data"))
+        );
+
+        assert_eq!(report.os_version, Some(String::from("2.38.0")));
+        assert_eq!(report.os_variant, Some(String::from("prod")));
+        assert_eq!(report.kernel_version, Some(String::from("4.19.71")));
+    }
+
+    #[test]
+    fn inspect_gpt_image() {
+        let report = inspect_image(Path::new("./test_data/gpt.img.gz"), false).unwrap();
+        assert_eq!(report.label_type, "GPT");
+        assert!(report.disk_id.is_some());
+        assert_eq!(report.partitions.len(), 3);
+        assert_eq!(report.partitions[0].name, Some(String::from("resin-boot")));
+        assert_eq!(report.partitions[0].size, 2048 * 512);
+        assert!(report.digest.is_none());
+    }
+}
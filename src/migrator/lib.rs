@@ -21,6 +21,10 @@ mod linux;
 
 #[cfg(target_os = "linux")]
 mod extract;
+
+#[cfg(target_os = "linux")]
+mod inspect;
+
 #[cfg(target_os = "linux")]
 use linux::stage2::Stage2;
 
@@ -43,6 +47,11 @@ pub fn extract() -> Result<(), MigError> {
     extract::extract()
 }
 
+#[cfg(target_os = "linux")]
+pub fn inspect() -> Result<(), MigError> {
+    inspect::inspect()
+}
+
 // TODO: move to stage 2 - leave only wrapper as above
 #[cfg(target_os = "linux")]
 pub fn stage2() -> Result<(), MigError> {
@@ -59,12 +68,19 @@ pub fn stage2() -> Result<(), MigError> {
             }
         };
 
-        match stage2.migrate() {
-            Ok(_res) => {
-                error!("stage2::migrate() is not expected to return on success");
-            }
-            Err(why) => {
-                error!("Failed to complete stage2::migrate Error: {}", why);
+        loop {
+            match stage2.migrate() {
+                Ok(_res) => {
+                    error!("stage2::migrate() is not expected to return on success");
+                    break;
+                }
+                Err(why) => {
+                    error!("Failed to complete stage2::migrate Error: {}", why);
+                    if !stage2.prepare_retry(&why) {
+                        stage2.report_failure(&why);
+                        break;
+                    }
+                }
             }
         }
 
@@ -1,21 +1,24 @@
 use failure::{Fail, ResultExt};
 use log::{debug, error, info, trace, warn};
 use nix::unistd::sync;
-use std::fs::{copy, create_dir, read_dir};
+use std::fs::{copy, create_dir, read_dir, read_to_string};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // TODO: Require files to be in work_dir: balena-image, balena-config, system-connections
 
 use crate::{
     common::{
         backup, call,
-        config::balena_config::ImageType,
+        config::{balena_config::ImageType, migrate_config::StatusReportCfg},
         device::Device,
         dir_exists, format_size_with_unit,
-        migrate_info::MigrateInfo,
+        migrate_info::{BalenaCfgJson, MigrateInfo},
         path_append,
         stage2_config::{PathType, Stage2ConfigBuilder, Stage2LogConfig},
+        status_report::{ReportStatus, StatusReporter},
         Config, MigErrCtx, MigError, MigErrorKind, MigMode,
     },
     defs::{
@@ -25,7 +28,8 @@ use crate::{
 
 pub(crate) mod linux_defs;
 use linux_defs::{
-    CHMOD_CMD, DF_CMD, FILE_CMD, LSBLK_CMD, MKTEMP_CMD, MOUNT_CMD, REBOOT_CMD, TAR_CMD, UNAME_CMD,
+    CHMOD_CMD, DF_CMD, KERNEL_HOSTNAME_PATH, LSBLK_CMD, MKTEMP_CMD, MOUNT_CMD, REBOOT_CMD,
+    STAGE1_LOG_FILE, STATUS_QUEUE_FILE, TAR_CMD, UNAME_CMD,
 };
 
 pub(crate) mod device_impl;
@@ -41,6 +45,9 @@ pub(crate) mod lsblk_info;
 //pub(crate) use lsblk_info::LsblkInfo;
 
 pub(crate) mod linux_common;
+
+pub(crate) mod staging;
+
 use crate::common::file_size;
 use crate::common::stage2_config::MountConfig;
 use crate::defs::VERSION;
@@ -51,7 +58,7 @@ use mod_logger::{LogDestination, Logger};
 
 const REQUIRED_CMDS: &[&str] = &[
     // TODO: check this
-    DF_CMD, LSBLK_CMD, FILE_CMD, UNAME_CMD, MOUNT_CMD, REBOOT_CMD, CHMOD_CMD, MKTEMP_CMD, TAR_CMD,
+    DF_CMD, LSBLK_CMD, UNAME_CMD, MOUNT_CMD, REBOOT_CMD, CHMOD_CMD, MKTEMP_CMD, TAR_CMD,
 ];
 
 pub(crate) struct LinuxMigrator {
@@ -77,18 +84,72 @@ impl<'a> LinuxMigrator {
 
         match config.migrate.get_mig_mode() {
             _ => {
-                let mut migrator = LinuxMigrator::try_init(config)?;
-                let res = match migrator.config.migrate.get_mig_mode() {
-                    MigMode::Immediate => migrator.do_migrate(),
-                    MigMode::Pretend => Ok(()),
-                    //MigMode::Agent => Err(MigError::from(MigErrorKind::NotImpl)),
+                // tells journal entries and status reports of this migration from those of
+                // earlier attempts
+                let start_time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                let migrate_id = format!("{}-{}", start_time, std::process::id());
+
+                let status_cfg = LinuxMigrator::get_status_cfg(&config);
+                let mut status = if let Some(ref status_cfg) = status_cfg {
+                    let mut status = StatusReporter::new(status_cfg, 1, &migrate_id);
+                    status.set_queue_file(&path_append(
+                        config.migrate.get_work_dir(),
+                        STATUS_QUEUE_FILE,
+                    ));
+                    status.report("started", ReportStatus::Running, None);
+                    Some(status)
+                } else {
+                    None
+                };
+
+                let res = match LinuxMigrator::try_init(config) {
+                    Ok(mut migrator) => {
+                        migrator.stage2_config.set_migrate_id(migrate_id);
+                        if let Some(status_cfg) = status_cfg {
+                            migrator.stage2_config.set_status_report(status_cfg);
+                        }
+
+                        match migrator.config.migrate.get_mig_mode() {
+                            MigMode::Immediate => migrator.do_migrate(status.as_mut()),
+                            MigMode::Pretend => Ok(()),
+                            //MigMode::Agent => Err(MigError::from(MigErrorKind::NotImpl)),
+                        }
+                    }
+                    Err(why) => Err(why),
                 };
+
+                if let Err(ref why) = res {
+                    if let Some(ref mut status) = status {
+                        status.report_error("failed", ReportStatus::Failed, why);
+                    }
+                }
+
                 Logger::flush();
                 res
             }
         }
     }
 
+    // status reports are only sent when migrating, the host name identifies the device by default
+    fn get_status_cfg(config: &Config) -> Option<StatusReportCfg> {
+        if let MigMode::Immediate = config.migrate.get_mig_mode() {
+            if let Some(status_cfg) = config.migrate.get_status_report() {
+                let mut status_cfg = status_cfg.clone();
+                if status_cfg.device_id.is_none() {
+                    match read_to_string(KERNEL_HOSTNAME_PATH) {
+                        Ok(hostname) => status_cfg.device_id = Some(String::from(hostname.trim())),
+                        Err(why) => warn!("Failed to read host name, error: {:?}", why),
+                    }
+                }
+                return Some(status_cfg);
+            }
+        }
+        None
+    }
+
     // **********************************************************************
     // ** Initialise migrator
     // **********************************************************************
@@ -118,7 +179,7 @@ impl<'a> LinuxMigrator {
 
         let lsblk_info = LsblkInfo::all()?;
         let linux_api = LinuxAPI::new(&lsblk_info);
-        let mig_info = match MigrateInfo::new(&config, &linux_api) {
+        let mut mig_info = match MigrateInfo::new(&config, &linux_api) {
             Ok(mig_info) => {
                 info!(
                     "OS Architecture is {}, OS Name is '{}'",
@@ -168,18 +229,38 @@ impl<'a> LinuxMigrator {
             }
         };
 
-        match mig_info
-            .config_file
-            .check(&config, device.get_device_slug())
-        {
+        if let Some(cfg_params) = config.balena.get_config_params() {
+            match BalenaCfgJson::generate(
+                cfg_params,
+                device.get_device_slug(),
+                &mig_info.work_path.path,
+                &mut mig_info.digest_cache,
+            ) {
+                Ok(balena_cfg) => mig_info.set_config_file(balena_cfg),
+                Err(why) => {
+                    return match why.kind() {
+                        MigErrorKind::Displayed => Err(why),
+                        _ => {
+                            error!("Failed to generate config.json: {:?}", why);
+                            Err(MigError::from(
+                                why.context(MigErrCtx::from(MigErrorKind::Displayed)),
+                            ))
+                        }
+                    };
+                }
+            }
+        }
+
+        let balena_cfg = mig_info.get_config_file()?;
+        match balena_cfg.check(&config, device.get_device_slug()) {
             Ok(_dummy) => info!(
                 "The sanity check on '{}' passed",
-                mig_info.config_file.get_rel_path().display()
+                balena_cfg.get_rel_path().display()
             ),
             Err(why) => {
                 let message = format!(
                     "The sanity check on '{}' failed: {:?}",
-                    mig_info.config_file.get_rel_path().display(),
+                    balena_cfg.get_rel_path().display(),
                     why
                 );
                 error!("{}", message);
@@ -215,6 +296,25 @@ impl<'a> LinuxMigrator {
             }
         }
 
+        if let Some(ref image_dev_type) = mig_info.image_dev_type {
+            if image_dev_type.slug != device.get_device_slug() {
+                error!(
+                    "The balena OS image was built for device type '{}' but the device was detected as '{}', refusing to flash an image for different hardware",
+                    image_dev_type.slug,
+                    device.get_device_slug()
+                );
+                return Err(MigError::from(MigErrorKind::Displayed));
+            }
+
+            if !image_dev_type.is_arch_compatible(&mig_info.os_arch) {
+                error!(
+                    "The balena OS image architecture '{}' does not match the device architecture '{}'",
+                    image_dev_type.arch, mig_info.os_arch
+                );
+                return Err(MigError::from(MigErrorKind::Displayed));
+            }
+        }
+
         // TODO: check available space for work files here if work is not on a distinct partition
 
         // **********************************************************************
@@ -243,11 +343,11 @@ impl<'a> LinuxMigrator {
     // **********************************************************************
 
     #[allow(clippy::cognitive_complexity)] //TODO refactor this function to fix the clippy warning
-    fn do_migrate(&mut self) -> Result<(), MigError> {
+    fn do_migrate(&mut self, status: Option<&mut StatusReporter>) -> Result<(), MigError> {
         // TODO: prepare logging
 
         let work_dir = &self.mig_info.work_path.path;
-        let log_file = path_append(work_dir, "stage1.log");
+        let log_file = path_append(work_dir, STAGE1_LOG_FILE);
 
         Logger::set_log_file(&LogDestination::Stderr, &log_file, true).context(
             MigErrCtx::from_remark(
@@ -276,6 +376,30 @@ impl<'a> LinuxMigrator {
                 )));
         }
 
+        // hooks are run from the work dir in stage 2, make sure they can be
+        for hook in self.config.migrate.get_hooks() {
+            let hook_path = path_append(work_dir, &hook.path);
+            let is_executable = if let Ok(metadata) = hook_path.metadata() {
+                metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+            } else {
+                false
+            };
+
+            if !is_executable {
+                error!(
+                    "The {} hook '{}' is not an executable file",
+                    hook.stage.as_str(),
+                    hook_path.display()
+                );
+                return Err(MigError::displayed());
+            }
+            info!(
+                "Running '{}' in stage 2 {}",
+                hook.path.display(),
+                hook.stage.as_str()
+            );
+        }
+
         let backup_path = path_append(work_dir, BACKUP_FILE);
 
         let has_backup =
@@ -338,12 +462,20 @@ impl<'a> LinuxMigrator {
 
         let mut required_size: u64 = self.mig_info.image_file.get_required_space();
 
-        required_size += self.mig_info.config_file.get_size();
+        required_size += self.mig_info.get_config_file()?.get_size();
+
+        if let Some(ref bmap_file) = self.mig_info.bmap_file {
+            required_size += bmap_file.size;
+        }
 
         if has_backup {
             required_size += file_size(&backup_path)?;
         }
 
+        for hook in self.config.migrate.get_hooks() {
+            required_size += file_size(path_append(&self.mig_info.work_path.path, &hook.path))?;
+        }
+
         if dir_exists(&nwmgr_path)? {
             let read_dir = read_dir(&nwmgr_path).context(MigErrCtx::from_remark(
                 MigErrorKind::Upstream,
@@ -367,8 +499,27 @@ impl<'a> LinuxMigrator {
             format_size_with_unit(required_size)
         );
 
-        if mem_tot - required_size < STAGE1_MEM_THRESHOLD {
-            warn!("The memory used to copy files to initramfs might not be available.");
+        if mem_tot < required_size + STAGE1_MEM_THRESHOLD {
+            if self.mig_info.work_path.device_info.drive == boot_device.device_info.drive
+                && self.config.migrate.get_force_flash_device().is_none()
+            {
+                warn!("Not enough memory to copy files to initramfs, staging them on the flash device");
+                let staging = staging::stage_files(
+                    &self.mig_info.work_path,
+                    &mut self.mig_info.image_file,
+                    self.mig_info.bmap_file.as_ref(),
+                    has_backup,
+                )?;
+                info!(
+                    "Staged {} files beyond offset {} on '{}'",
+                    staging.files.len(),
+                    staging.os_end,
+                    boot_device.device_info.drive.display()
+                );
+                self.stage2_config.set_staging(staging);
+            } else {
+                warn!("The memory used to copy files to initramfs might not be available.");
+            }
         }
 
         trace!("device setup");
@@ -377,8 +528,13 @@ impl<'a> LinuxMigrator {
         // TODO: make setup take no s2_cfg or immutable s2_cfg and return boot_backup instead
         // TODO: make setup undoable in case something bad happens later on
 
-        self.device
-            .setup(&mut self.mig_info, &self.config, &mut self.stage2_config)?;
+        // stage 2 is started by hand when simulating, the boot configuration is left alone
+        if self.config.debug.is_simulate() {
+            info!("Simulation mode, not setting up the boot configuration");
+        } else {
+            self.device
+                .setup(&mut self.mig_info, &self.config, &mut self.stage2_config)?;
+        }
 
         trace!("stage2 config");
 
@@ -395,21 +551,45 @@ impl<'a> LinuxMigrator {
         self.stage2_config
             .set_failmode(self.config.migrate.get_fail_mode());
 
+        self.stage2_config
+            .set_failmode_non_recoverable(self.config.migrate.get_fail_mode_non_recoverable());
+
         self.stage2_config
             .set_no_flash(self.config.debug.is_no_flash());
 
+        if self.config.debug.is_simulate() {
+            if self.config.migrate.get_force_flash_device().is_none() {
+                error!("Simulation mode requires migrate.force_flash_device to be set");
+                return Err(MigError::displayed());
+            }
+            warn!("Stage 2 will run in simulation mode");
+            self.stage2_config.set_simulate(true);
+        }
+
         self.stage2_config
             .set_migrate_delay(self.config.migrate.get_delay());
 
+        self.stage2_config
+            .set_mount_timeout(self.config.migrate.get_mount_timeout());
+
         if let Some(watchdogs) = self.config.migrate.get_watchdogs() {
             self.stage2_config.set_watchdogs(watchdogs);
         }
 
+        let hooks = self.config.migrate.get_hooks();
+        if !hooks.is_empty() {
+            self.stage2_config.set_hooks(hooks);
+        }
+
+        if let Some(progress) = self.config.migrate.get_progress() {
+            self.stage2_config.set_progress(progress);
+        }
+
         self.stage2_config
             .set_balena_image(self.mig_info.image_file.clone());
 
         self.stage2_config
-            .set_balena_config(self.mig_info.config_file.get_rel_path().clone());
+            .set_balena_config(self.mig_info.get_config_file()?.get_rel_path().clone());
 
         // TODO: setpath if on / mount else set mount
 
@@ -417,7 +597,29 @@ impl<'a> LinuxMigrator {
             .set_gzip_internal(self.config.migrate.is_gzip_internal());
 
         self.stage2_config
-            .set_log_console(self.config.migrate.get_log_console());
+            .set_flash_verify(self.config.migrate.is_flash_verify());
+
+        self.stage2_config
+            .set_flash_retries(self.config.migrate.get_flash_retries());
+
+        self.stage2_config
+            .set_flash_skip_zeros(self.config.migrate.is_flash_skip_zeros());
+
+        if let Some(ref bmap_file) = self.mig_info.bmap_file {
+            self.stage2_config.set_bmap_file(bmap_file);
+        }
+
+        if let Some(ref bundle) = self.mig_info.bundle {
+            self.stage2_config.set_bundle(bundle);
+        }
+
+        if let Some(log_console) = self.config.migrate.get_log_console() {
+            self.stage2_config.set_log_console(log_console);
+        }
+
+        // stage 2 keeps the stage 1 log with its own
+        self.stage2_config
+            .set_stage1_log(PathBuf::from(STAGE1_LOG_FILE));
 
         self.stage2_config
             .set_log_level(String::from(self.config.migrate.get_log_level()));
@@ -446,7 +648,16 @@ impl<'a> LinuxMigrator {
         let s2_path = path_append(&boot_device.mountpoint, STAGE2_CFG_FILE);
         self.stage2_config.write_stage2_cfg_to(&s2_path)?;
 
-        if let Some(delay) = self.config.migrate.get_reboot() {
+        // stage 2 sends the reports still queued in the work dir
+        if let Some(status) = status {
+            status.report("stage2_configured", ReportStatus::Running, None);
+        }
+
+        if self.config.debug.is_simulate() {
+            info!(
+                "Migration stage 1 was successfull, simulation mode - not rebooting, run balena-stage2 to continue"
+            );
+        } else if let Some(delay) = self.config.migrate.get_reboot() {
             println!(
                 "Migration stage 1 was successfull, rebooting system in {} seconds",
                 *delay
@@ -5,7 +5,7 @@ use crate::{
     common::{device_info::DeviceInfo, os_api::OSApi, path_info::PathInfo, MigError},
     defs::{FileType, OSArch},
     linux::{
-        linux_common::{expect_type, get_os_arch, get_os_name},
+        linux_common::{expect_type, get_os_arch, get_os_name, is_file_type},
         lsblk_info::LsblkInfo,
     },
 };
@@ -49,4 +49,8 @@ impl OSApi for LinuxAPI<'_> {
     fn expect_type<P: AsRef<Path>>(&self, file: P, ftype: &FileType) -> Result<(), MigError> {
         expect_type(file.as_ref(), ftype)
     }
+
+    fn is_file_type<P: AsRef<Path>>(&self, file: P, ftype: &FileType) -> Result<bool, MigError> {
+        is_file_type(file.as_ref(), ftype)
+    }
 }
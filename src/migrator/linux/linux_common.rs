@@ -10,16 +10,17 @@ use libc::getuid;
 
 use crate::{
     common::{call, file_exists, parse_file, path_append, MigErrCtx, MigError, MigErrorKind},
-    defs::FileType,
     defs::{OSArch, DISK_BY_LABEL_PATH, DISK_BY_PARTUUID_PATH, DISK_BY_UUID_PATH},
     linux::linux_defs::{
-        DF_CMD, FILE_CMD, KERNEL_CMDLINE_PATH, MKTEMP_CMD, MOKUTIL_CMD, SYS_UEFI_DIR, UNAME_CMD,
-        WHEREIS_CMD,
+        DF_CMD, KERNEL_CMDLINE_PATH, MKTEMP_CMD, MOKUTIL_CMD, SYS_UEFI_DIR, UNAME_CMD, WHEREIS_CMD,
     },
 };
 
 use crate::common::dir_exists;
 
+// file types are determined from their contents, see common::file_type
+pub(crate) use crate::common::file_type::{expect_type, is_file_type};
+
 const MOKUTIL_ARGS_SB_STATE: [&str; 1] = ["--sb-state"];
 
 const UNAME_ARGS_OS_ARCH: [&str; 1] = ["-m"];
@@ -29,26 +30,6 @@ const BIN_DIRS: &[&str] = &["/bin", "/usr/bin", "/sbin", "/usr/sbin"];
 const OS_RELEASE_FILE: &str = "/etc/os-release";
 const OS_NAME_REGEX: &str = r#"^PRETTY_NAME="([^"]+)"$"#;
 
-// file on ubuntu-14.04 reports x86 boot sector for image and kernel files
-
-const OS_IMG_FTYPE_REGEX: &str = r#"^(DOS/MBR boot sector|x86 boot sector)$"#;
-const GZIP_OS_IMG_FTYPE_REGEX: &str =
-    r#"^(DOS/MBR boot sector|x86 boot sector).*\(gzip compressed data.*\)$"#;
-
-const INITRD_FTYPE_REGEX: &str = r#"^ASCII cpio archive.*\(gzip compressed data.*\)$"#;
-const OS_CFG_FTYPE_REGEX: &str = r#"^(ASCII text|JSON data).*$"#;
-const KERNEL_AMD64_FTYPE_REGEX: &str =
-    r#"^(Linux kernel x86 boot executable bzImage|x86 boot sector).*$"#;
-const KERNEL_ARMHF_FTYPE_REGEX: &str = r#"^Linux kernel ARM boot executable zImage.*$"#;
-//const KERNEL_I386_FTYPE_REGEX: &str = r#"^Linux kernel i386 boot executable bzImage.*$"#;
-const KERNEL_AARCH64_FTYPE_REGEX: &str = r#"^MS-DOS executable.*$"#;
-
-const TEXT_FTYPE_REGEX: &str = r#"^ASCII text.*$"#;
-
-const DTB_FTYPE_REGEX: &str = r#"^(Device Tree Blob|data).*$"#;
-
-const GZIP_TAR_FTYPE_REGEX: &str = r#"^(POSIX tar archive \(GNU\)).*\(gzip compressed data.*\)$"#;
-
 pub(crate) fn is_admin() -> Result<bool, MigError> {
     trace!("LinuxMigrator::is_admin: entered");
     let admin = Some(unsafe { getuid() } == 0);
@@ -355,7 +336,7 @@ pub(crate) fn to_std_device_path(device: &Path) -> Result<PathBuf, MigError> {
 }
 
 pub(crate) fn drive_to_partition(drive: &Path, part_num: usize) -> Result<PathBuf, MigError> {
-    const PART2DRIVE_REGEX: &str = r#"^(/dev/(([hs]d[a-z])|(nvme\d+n\d+|mmcblk\d+)))$"#;
+    const PART2DRIVE_REGEX: &str = r#"^(/dev/(([hs]d[a-z])|(nvme\d+n\d+|mmcblk\d+|loop\d+)))$"#;
     lazy_static! {
         static ref PART2DRIVE_RE: Regex = Regex::new(PART2DRIVE_REGEX).unwrap();
     }
@@ -385,27 +366,6 @@ pub(crate) fn drive_to_partition(drive: &Path, part_num: usize) -> Result<PathBu
     }
 }
 
-pub(crate) fn drive_from_partition(partition: &Path) -> Result<PathBuf, MigError> {
-    const DRIVE2PART_REGEX: &str = r#"^(/dev/([hs]d[a-z]|nvme\d+n\d+|mmcblk\d+))(p?\d+)$"#;
-    lazy_static! {
-        static ref DRIVE2PART_RE: Regex = Regex::new(DRIVE2PART_REGEX).unwrap();
-    }
-
-    if let Some(captures) =
-        DRIVE2PART_RE.captures(&to_std_device_path(partition)?.to_string_lossy())
-    {
-        Ok(PathBuf::from(captures.get(1).unwrap().as_str()))
-    } else {
-        Err(MigError::from_remark(
-            MigErrorKind::InvParam,
-            &format!(
-                "Failed to derive drive name from partition name: '{}'",
-                partition.display()
-            ),
-        ))
-    }
-}
-
 /*
 pub(crate) fn get_os_release() -> Result<OSRelease, MigError> {
     let os_info =
@@ -512,6 +472,7 @@ pub(crate) fn get_kernel_root_info() -> Result<(PathBuf, Option<String>), MigErr
     const ROOT_DEVICE_REGEX: &str = r#"\sroot=(\S+)\s"#;
     const ROOT_PARTUUID_REGEX: &str = r#"^PARTUUID=(\S+)$"#;
     const ROOT_UUID_REGEX: &str = r#"^UUID=(\S+)$"#;
+    const ROOT_LABEL_REGEX: &str = r#"^LABEL=(\S+)$"#;
     const ROOT_FSTYPE_REGEX: &str = r#"\srootfstype=(\S+)\s"#;
 
     trace!("get_root_info: entered");
@@ -554,12 +515,24 @@ pub(crate) fn get_kernel_root_info() -> Result<(PathBuf, Option<String>), MigErr
                 DISK_BY_UUID_PATH,
                 captures.get(1).unwrap().as_str(),
             ))
+        } else if let Some(captures) = RegexBuilder::new(ROOT_LABEL_REGEX)
+            .case_insensitive(true)
+            .build()
+            .unwrap()
+            .captures(root_dev)
+        {
+            debug!("Got root device LABEL: {:?}", captures.get(1));
+            Some(path_append(
+                DISK_BY_LABEL_PATH,
+                captures.get(1).unwrap().as_str(),
+            ))
         } else {
             debug!("Got plain root device UUID: {:?}", captures.get(1));
             None
         } {
-            debug!("trying device path: '{}'", uuid_part.display());
-            to_std_device_path(&uuid_part)?
+            // the link is resolved when mounting, it might not have been created yet
+            debug!("Using device path: '{}'", uuid_part.display());
+            uuid_part
         } else {
             debug!("Got plain root device '{}'", root_dev);
             PathBuf::from(root_dev)
@@ -591,61 +564,24 @@ pub(crate) fn get_kernel_root_info() -> Result<(PathBuf, Option<String>), MigErr
     Ok((root_device, root_fs_type))
 }
 
-pub(crate) fn expect_type<P: AsRef<Path>>(file: P, ftype: &FileType) -> Result<(), MigError> {
-    if !is_file_type(file.as_ref(), ftype)? {
-        error!(
-            "Could not determine expected file type '{}' for file '{}'",
-            ftype.get_descr(),
-            file.as_ref().display()
-        );
-        Err(MigError::displayed())
-    } else {
-        Ok(())
-    }
-}
-
-pub(crate) fn is_file_type<P: AsRef<Path>>(file: P, ftype: &FileType) -> Result<bool, MigError> {
-    let path_str = file.as_ref().to_string_lossy();
-    let args: Vec<&str> = vec!["-bz", &path_str];
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let cmd_res = call(FILE_CMD, &args, true)?;
-    if !cmd_res.status.success() || cmd_res.stdout.is_empty() {
-        return Err(MigError::from_remark(
-            MigErrorKind::InvParam,
-            &format!("new: failed determine type for file {}", path_str),
-        ));
-    }
-
-    lazy_static! {
-        static ref OS_IMG_FTYPE_RE: Regex = Regex::new(OS_IMG_FTYPE_REGEX).unwrap();
-        static ref GZIP_OS_IMG_FTYPE_RE: Regex = Regex::new(GZIP_OS_IMG_FTYPE_REGEX).unwrap();
-        static ref INITRD_FTYPE_RE: Regex = Regex::new(INITRD_FTYPE_REGEX).unwrap();
-        static ref OS_CFG_FTYPE_RE: Regex = Regex::new(OS_CFG_FTYPE_REGEX).unwrap();
-        static ref TEXT_FTYPE_RE: Regex = Regex::new(TEXT_FTYPE_REGEX).unwrap();
-        static ref KERNEL_AMD64_FTYPE_RE: Regex = Regex::new(KERNEL_AMD64_FTYPE_REGEX).unwrap();
-        static ref KERNEL_ARMHF_FTYPE_RE: Regex = Regex::new(KERNEL_ARMHF_FTYPE_REGEX).unwrap();
-        static ref KERNEL_AARCH64_FTYPE_RE: Regex = Regex::new(KERNEL_AARCH64_FTYPE_REGEX).unwrap();
-        //static ref KERNEL_I386_FTYPE_RE: Regex = Regex::new(KERNEL_I386_FTYPE_REGEX).unwrap();
-        static ref DTB_FTYPE_RE: Regex = Regex::new(DTB_FTYPE_REGEX).unwrap();
-        static ref GZIP_TAR_FTYPE_RE: Regex = Regex::new(GZIP_TAR_FTYPE_REGEX).unwrap();
-    }
-
-    debug!(
-        "FileInfo::is_type: looking for: {}, found {}",
-        ftype.get_descr(),
-        cmd_res.stdout
-    );
-    match ftype {
-        FileType::GZipOSImage => Ok(GZIP_OS_IMG_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::OSImage => Ok(OS_IMG_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::InitRD => Ok(INITRD_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::KernelARMHF => Ok(KERNEL_ARMHF_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::KernelAMD64 => Ok(KERNEL_AMD64_FTYPE_RE.is_match(&cmd_res.stdout)),
-        //FileType::KernelI386 => Ok(KERNEL_I386_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::KernelAARCH64 => Ok(KERNEL_AARCH64_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::Json => Ok(OS_CFG_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::Text => Ok(TEXT_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::DTB => Ok(DTB_FTYPE_RE.is_match(&cmd_res.stdout)),
-        FileType::GZipTar => Ok(GZIP_TAR_FTYPE_RE.is_match(&cmd_res.stdout)),
+    #[test]
+    fn partition_from_drive() {
+        assert_eq!(
+            drive_to_partition(Path::new("/dev/sda"), 1).unwrap(),
+            PathBuf::from("/dev/sda1")
+        );
+        assert_eq!(
+            drive_to_partition(Path::new("/dev/mmcblk0"), 2).unwrap(),
+            PathBuf::from("/dev/mmcblk0p2")
+        );
+        assert_eq!(
+            drive_to_partition(Path::new("/dev/loop3"), 5).unwrap(),
+            PathBuf::from("/dev/loop3p5")
+        );
+        assert!(drive_to_partition(Path::new("/tmp/disk.img"), 1).is_err());
     }
 }
@@ -3,6 +3,12 @@ pub const BOOT_PATH: &str = "/boot";
 pub const ROOT_PATH: &str = "/";
 
 pub const MIGRATE_LOG_FILE: &str = "migrate.log";
+pub const STAGE1_LOG_FILE: &str = "stage1.log";
+pub const STAGE2_LOG_FILE: &str = "stage2.log";
+// directory on resin-data / resin-boot the logs are kept in after the migration
+pub const MIGRATE_LOG_DIR: &str = "balena-migrate";
+pub const MIGRATE_JOURNAL_FILE: &str = "migrate.journal";
+pub const STATUS_QUEUE_FILE: &str = "status-queue.json";
 
 pub const DEFAULT_UNAME_STR: &str = "4.1.4.53-balenaOS-2.38.0+rev1";
 pub const MLO_FILE_NAME: &str = "MLO";
@@ -11,6 +17,7 @@ pub const UBOOT_FILE_NAME: &str = "u-boot.img";
 
 pub const KERNEL_CMDLINE_PATH: &str = "/proc/cmdline";
 pub const KERNEL_OSRELEASE_PATH: &str = "/proc/sys/kernel/osrelease";
+pub const KERNEL_HOSTNAME_PATH: &str = "/proc/sys/kernel/hostname";
 
 pub const GRUB_CONFIG_DIR: &str = "/etc/grub.d";
 pub const GRUB_CONFIG_FILE: &str = "/etc/grub.d/43_balena-migrate";
@@ -30,7 +37,6 @@ pub const CHMOD_CMD: &str = "chmod";
 pub const DD_CMD: &str = "dd";
 pub const DF_CMD: &str = "df";
 pub const SFDISK_CMD: &str = "sfdisk";
-pub const FILE_CMD: &str = "file";
 pub const LSBLK_CMD: &str = "lsblk";
 // pub const BLKID_CMD: &str = "blkid";
 pub const GRUB_REBOOT_CMD: &str = "grub-reboot";
@@ -43,9 +49,13 @@ pub const LOSETUP_CMD: &str = "losetup";
 //pub const PARTED_CMD: &str = "parted";
 pub const PARTPROBE_CMD: &str = "partprobe";
 pub const REBOOT_CMD: &str = "reboot";
+pub const POWEROFF_CMD: &str = "poweroff";
 pub const TAR_CMD: &str = "tar";
 pub const UDEVADM_CMD: &str = "udevadm";
 pub const UNAME_CMD: &str = "uname";
+pub const UNZIP_CMD: &str = "unzip";
+pub const XZ_CMD: &str = "xz";
+pub const ZSTD_CMD: &str = "zstd";
 pub const EXT_FMT_CMD: &str = "mkfs.ext4";
 pub const FAT_FMT_CMD: &str = "mkfs.vfat";
 
@@ -3,28 +3,35 @@ use log::{debug, error, info, trace, warn, Level};
 use mod_logger::{LogDestination, Logger, NO_STREAM};
 use nix::unistd::sync;
 
-use std::fs::{copy, create_dir, read_dir, read_to_string};
+use std::fs::{copy, create_dir, create_dir_all, read_dir, read_to_string, remove_dir_all};
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use crate::{
     common::{
-        call, dir_exists,
-        file_digest::check_digest,
+        call,
+        config::{balena_config::FileRef, migrate_config::HookStage},
+        dir_exists,
+        file_digest::{check_digest, DigestManifest, HashInfo},
         file_exists,
         file_info::RelFileInfo,
         file_size, format_size_with_unit, path_append,
-        stage2_config::{CheckedImageType, Stage2Config},
+        stage2_config::{CheckedImageType, Stage2Config, STAGE2_CFG_SCHEMA_TAG},
+        staging::Staging,
+        status_report::{ReportStatus, StatusReporter},
         MigErrCtx, MigError, MigErrorKind,
     },
-    defs::{FailMode, BACKUP_FILE, SYSTEM_CONNECTIONS_DIR, VERSION},
+    defs::{FailMode, BACKUP_FILE, BUNDLE_PUBLIC_KEY, SYSTEM_CONNECTIONS_DIR, VERSION},
     linux::{
         device_impl,
         linux_common::{get_mem_info, whereis},
-        linux_defs::{KERNEL_OSRELEASE_PATH, REBOOT_CMD},
-        linux_defs::{MIGRATE_LOG_FILE, STAGE2_MEM_THRESHOLD},
+        linux_defs::{KERNEL_OSRELEASE_PATH, POWEROFF_CMD, REBOOT_CMD},
+        linux_defs::{
+            MIGRATE_LOG_DIR, MIGRATE_LOG_FILE, STAGE1_LOG_FILE, STAGE2_LOG_FILE,
+            STAGE2_MEM_THRESHOLD, STATUS_QUEUE_FILE,
+        },
     },
 };
 
@@ -35,16 +42,30 @@ use crate::{
 mod fs_writer;
 
 mod flasher;
+use flasher::ImageSource;
 
 mod watchdog;
 use watchdog::WatchdogHandler;
 
+mod journal;
+use journal::{Checkpoint, Journal};
+
+mod progress;
+use progress::{copy_and_hash, copy_file, copy_staged, hash_staged, Phase, Progress};
+
+mod block_devices;
+
+mod hooks;
+
 pub(crate) mod mounts;
 use mounts::Mounts;
 
 use std::cell::RefCell;
 
 const REBOOT_DELAY: u64 = 3;
+// delay before the first retry, doubled for each further retry
+const RETRY_DELAY: u64 = 10;
+const MAX_RETRY_DELAY: u64 = 300;
 const S2_REV: u32 = 5;
 
 // TODO: set this to Info once mature
@@ -57,6 +78,7 @@ const MIGRATE_TEMP_DIR: &str = "/migrate_tmp";
 
 const BALENA_IMAGE_FILE: &str = "balenaOS.img.gz";
 const BALENA_CONFIG_FILE: &str = "config.json";
+const BALENA_BMAP_FILE: &str = "balenaOS.img.bmap";
 
 const BALENA_BOOT_FS_FILE: &str = "resin-boot.tgz";
 const BALENA_ROOTA_FS_FILE: &str = "resin-rootA.tgz";
@@ -66,6 +88,7 @@ const BALENA_DATA_FS_FILE: &str = "resin-data.tgz";
 
 const LOG_STDERR: bool = true; // mute / unmute the start until config is read
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub(crate) enum FlashResult {
     Ok,
     FailRecoverable,
@@ -75,6 +98,17 @@ pub(crate) enum FlashResult {
 pub(crate) struct Stage2 {
     pub mounts: RefCell<Mounts>,
     config: Stage2Config,
+    progress: RefCell<Progress>,
+    journal: Journal,
+    // the stage 2 log file on the log device or in the work dir
+    log_file: Option<PathBuf>,
+    // the stage 1 log, copied with the other files
+    stage1_log: Option<PathBuf>,
+    status: Option<StatusReporter>,
+    // retries done for FailMode::Retry
+    retries: u32,
+    // all files were copied to MIGRATE_TEMP_DIR, a retry after flashing has started can use them
+    files_in_tmp: bool,
     pub recoverable_state: bool,
 }
 
@@ -99,6 +133,8 @@ impl<'a> Stage2 {
                     "Balena Migrate Stage 2 version {} rev {} initializing",
                     VERSION, S2_REV
                 );
+                // also keeps the schema tag in the binary for stage 1 to find
+                debug!("Stage 2 config schema: '{}'", STAGE2_CFG_SCHEMA_TAG);
             }
             Err(_why) => {
                 println!("failed to initalize logger");
@@ -136,8 +172,9 @@ impl<'a> Stage2 {
         let stage2_cfg = match Stage2Config::from_config(&stage2_cfg_file) {
             Ok(s2_cfg) => {
                 info!(
-                    "Successfully read stage 2 config file from {}",
-                    stage2_cfg_file.display()
+                    "Successfully read stage 2 config file from {}, written by balena-migrate {}",
+                    stage2_cfg_file.display(),
+                    s2_cfg.get_writer()
                 );
                 s2_cfg
             }
@@ -155,13 +192,31 @@ impl<'a> Stage2 {
         if let Some(device) = stage2_cfg.get_force_flash_device() {
             if device != mounts.get_flash_device() {
                 warn!("Forcibly setting flash device to '{}'", device.display());
-                mounts.set_force_flash_device(device);
+                if let Err(why) = mounts.set_force_flash_device(device) {
+                    error!(
+                        "Failed to set flash device to '{}', error: {:?}",
+                        device.display(),
+                        why
+                    );
+                    return Err(MigError::displayed());
+                }
+            } else if stage2_cfg.is_simulate() {
+                error!(
+                    "Refusing to simulate migration on the boot device '{}'",
+                    device.display()
+                );
+                return Err(MigError::displayed());
             }
+        } else if stage2_cfg.is_simulate() {
+            error!("Simulation mode requires a forced flash device");
+            return Err(MigError::displayed());
         }
 
         info!("Setting log level to {:?}", stage2_cfg.get_log_level());
         Logger::set_default_level(&stage2_cfg.get_log_level());
 
+        mounts.set_mount_timeout(Duration::from_secs(stage2_cfg.get_mount_timeout()));
+
         // Mount all remaining drives - work and log
         match mounts.mount_from_config(&stage2_cfg) {
             Ok(_) => {
@@ -206,12 +261,54 @@ impl<'a> Stage2 {
                 }
             }
         } else {
-            let _res = Logger::set_log_dest(&LogDestination::Stderr, NO_STREAM);
+            // keep the log buffered until it can be written to the balena partitions, the console
+            // is the only output until then unless it was switched off
+            let log_dest = if stage2_cfg.is_log_console_off() {
+                LogDestination::Buffer
+            } else {
+                LogDestination::BufferStderr
+            };
+            let _res = Logger::set_log_dest(&log_dest, NO_STREAM);
         }
 
+        let progress = Progress::new(stage2_cfg.get_progress(), mounts.get_log_path());
+
+        // keep the journal on the log device if there is one, on the boot partition otherwise
+        let journal = if let Some(log_path) = mounts.get_log_path() {
+            Journal::new(log_path, stage2_cfg.get_migrate_id())
+        } else {
+            info!("No log device configured, the journal on the boot partition can not be used to resume once flashing has started");
+            Journal::new(mounts.get_boot_mountpoint(), stage2_cfg.get_migrate_id())
+        };
+
+        // reports that could not be sent are waiting in the work dir, on the log device once
+        // flashing has started
+        let status = if let Some(status_cfg) = stage2_cfg.get_status_report() {
+            let mut status = StatusReporter::new(status_cfg, 2, stage2_cfg.get_migrate_id());
+            let queue_dir = if journal.is_done(Checkpoint::FlashStarted) {
+                mounts.get_log_path()
+            } else {
+                mounts.get_work_path()
+            };
+            if let Some(queue_dir) = queue_dir {
+                status.load_queue(&path_append(queue_dir, STATUS_QUEUE_FILE));
+            }
+            status.report("started", ReportStatus::Running, None);
+            Some(status)
+        } else {
+            None
+        };
+
         Ok(Stage2 {
             mounts: RefCell::new(mounts),
             config: stage2_cfg,
+            progress: RefCell::new(progress),
+            journal,
+            log_file: log_path,
+            stage1_log: None,
+            status,
+            retries: 0,
+            files_in_tmp: false,
             recoverable_state: false,
         })
     }
@@ -222,8 +319,8 @@ impl<'a> Stage2 {
     pub fn migrate(&mut self) -> Result<(), MigError> {
         trace!("migrate: entered");
 
-        let device_type = self.config.get_device_type();
-        let boot_type = self.config.get_boot_type();
+        let device_type = *self.config.get_device_type();
+        let boot_type = *self.config.get_boot_type();
 
         // Recover device type and restore original boot configuration
 
@@ -244,7 +341,7 @@ impl<'a> Stage2 {
         };
 
         let migrate_delay = self.config.get_migrate_delay();
-        if migrate_delay > 0 {
+        if migrate_delay > 0 && self.retries == 0 {
             let start_time = Instant::now();
             let max_wait = Duration::from_secs(migrate_delay);
             info!("Taking a break for {} seconds", migrate_delay);
@@ -259,13 +356,33 @@ impl<'a> Stage2 {
             info!("Done waiting, continuing now");
         }
 
-        let device = device_impl::from_config(*device_type, *boot_type)?;
-        if device.restore_boot(&self.mounts.borrow(), &self.config) {
-            info!("Boot configuration was restored sucessfully");
-            // boot config restored can reboot
+        if self.journal.is_done(Checkpoint::DataCopied) {
+            info!("A previous attempt completed the migration, rebooting");
+            if self.config.is_simulate() {
+                self.simulation_exit(true);
+            }
+            Stage2::exit(&FailMode::Reboot)?;
+            return Ok(());
+        }
+
+        if self.journal.is_done(Checkpoint::BootRestored) {
+            info!("The boot configuration was restored by a previous attempt");
+            // the boot device is not bootable anymore once writing balena OS has started
+            self.recoverable_state = !self.journal.is_done(Checkpoint::FlashStarted);
+        } else if self.config.is_simulate() {
+            info!("Simulation mode, not restoring the boot configuration");
             self.recoverable_state = true;
+            self.record(Checkpoint::BootRestored);
         } else {
-            warn!("Failed to restore boot configuration - trying to migrate anyway.",);
+            let device = device_impl::from_config(device_type, boot_type)?;
+            if device.restore_boot(&self.mounts.borrow(), &self.config) {
+                info!("Boot configuration was restored sucessfully");
+                // boot config restored can reboot
+                self.recoverable_state = true;
+                self.record(Checkpoint::BootRestored);
+            } else {
+                warn!("Failed to restore boot configuration - trying to migrate anyway.",);
+            }
         }
 
         sync();
@@ -274,14 +391,36 @@ impl<'a> Stage2 {
 
         info!("migrating {:?} boot type: {:?}", device_type, &boot_type);
 
-        let work_path = if let Some(work_path) = self.mounts.borrow().get_work_path() {
-            work_path.to_path_buf()
-        } else {
-            error!("The working directory was not mounted - aborting migration");
+        // the work directory is gone once writing balena OS has started, a retry uses the files
+        // copied by the previous attempt
+        let reuse_files = self.files_in_tmp && self.journal.is_done(Checkpoint::FlashStarted);
+
+        let work_path = match self.mounts.borrow().get_work_path() {
+            Some(work_path) => work_path.to_path_buf(),
+            None if reuse_files => PathBuf::from(MIGRATE_TEMP_DIR),
+            None => {
+                error!("The working directory was not mounted - aborting migration");
+                return Err(MigError::displayed());
+            }
+        };
+
+        // check the signed bundle again, files are compared to it once copied
+        let signed = if let Some(bundle) = self.config.get_bundle() {
+            Some(bundle.verify()?)
+        } else if BUNDLE_PUBLIC_KEY.is_some() {
+            error!("A public key is compiled in but stage 1 passed on no signed bundle, refusing to migrate");
             return Err(MigError::displayed());
+        } else {
+            None
         };
 
-        let mig_tmp_dir = if !self.mounts.borrow().is_work_no_copy() {
+        // files read from the flash device instead of being copied
+        let mut staging: Option<Staging> = None;
+
+        let mig_tmp_dir = if reuse_files {
+            info!("Using the files copied by the previous attempt");
+            Path::new(MIGRATE_TEMP_DIR)
+        } else if !self.mounts.borrow().is_work_no_copy() {
             // check if we have enough space to copy files to initramfs
             let mig_tmp_dir = match get_mem_info() {
                 Ok((mem_tot, mem_avail)) => {
@@ -300,6 +439,14 @@ impl<'a> Stage2 {
                         required_size += file_size(path_append(&work_path, BACKUP_FILE))?;
                     }
 
+                    if let Some(bmap_file) = self.config.get_bmap_file() {
+                        required_size += bmap_file.size;
+                    }
+
+                    for hook in self.config.get_hooks() {
+                        required_size += file_size(path_append(&work_path, &hook.path))?;
+                    }
+
                     let src_nwmgr_dir = path_append(&work_path, SYSTEM_CONNECTIONS_DIR);
                     if dir_exists(&src_nwmgr_dir)? {
                         let paths = read_dir(&src_nwmgr_dir).context(MigErrCtx::from_remark(
@@ -321,8 +468,17 @@ impl<'a> Stage2 {
 
                     if mem_avail > required_size + STAGE2_MEM_THRESHOLD {
                         Path::new(MIGRATE_TEMP_DIR)
+                    } else if let Some(staged) = self.config.get_staging().filter(|staged| {
+                        let staged_size: u64 = staged.files.iter().map(|file| file.size).sum();
+                        mem_avail > required_size.saturating_sub(staged_size) + STAGE2_MEM_THRESHOLD
+                    }) {
+                        info!(
+                            "Not enough memory available for copying all files, reading {} staged files from the flash device",
+                            staged.files.len()
+                        );
+                        staging = Some(staged.clone());
+                        Path::new(MIGRATE_TEMP_DIR)
                     } else {
-                        // TODO: create temporary storage on disk instead by resizing
                         error!("Not enough memory available for copying files");
                         return Err(MigError::from_remark(
                             MigErrorKind::InvState,
@@ -348,67 +504,136 @@ impl<'a> Stage2 {
 
             match self.config.get_balena_image() {
                 CheckedImageType::Flasher(ref image_file) => {
-                    let src = path_append(&work_path, &image_file.rel_path);
-                    let tgt = path_append(mig_tmp_dir, BALENA_IMAGE_FILE);
-                    copy(&src, &tgt).context(MigErrCtx::from_remark(
-                        MigErrorKind::Upstream,
-                        &format!(
-                            "failed to copy balena image to migrate temp directory, '{}' -> '{}'",
-                            src.display(),
-                            tgt.display()
-                        ),
-                    ))?;
-                    info!("Checking digest on copied file '{}'", tgt.display());
-                    if !check_digest(&tgt, &image_file.hash_info)? {
-                        return Err(MigError::from_remark(
-                            MigErrorKind::InvParam,
+                    if let Some(ref staging) = staging {
+                        self.check_staged(staging, &image_file.rel_path, signed.as_ref())?;
+                    } else {
+                        let src = path_append(&work_path, &image_file.rel_path);
+                        let tgt = path_append(mig_tmp_dir, BALENA_IMAGE_FILE);
+                        let digest = copy_and_hash(
+                            &src,
+                            &tgt,
+                            &mut self.progress.borrow_mut(),
+                            &image_file.hash_info,
+                        )
+                        .context(MigErrCtx::from_remark(
+                            MigErrorKind::Upstream,
                             &format!(
-                                "Failed to check digest on copied file: '{}', {:?} ",
-                                tgt.display(),
-                                image_file.hash_info
+                                "failed to copy balena image to migrate temp directory, '{}' -> '{}'",
+                                src.display(),
+                                tgt.display()
                             ),
-                        ));
+                        ))?;
+                        if digest != image_file.hash_info {
+                            return Err(MigError::from_remark(
+                                MigErrorKind::InvParam,
+                                &format!(
+                                    "Failed to check digest on copied file: '{}', {:?} ",
+                                    tgt.display(),
+                                    image_file.hash_info
+                                ),
+                            ));
+                        }
+
+                        self.check_signed(
+                            signed.as_ref(),
+                            &image_file.rel_path,
+                            &tgt,
+                            Some(&image_file.hash_info),
+                        )?;
+
+                        info!("copied balena OS image to '{}'", tgt.display());
+                        // check digest
                     }
 
-                    info!("copied balena OS image to '{}'", tgt.display());
-                    // check digest
+                    if let Some(bmap_file) = self.config.get_bmap_file() {
+                        let src = path_append(&work_path, &bmap_file.rel_path);
+                        let tgt = path_append(mig_tmp_dir, BALENA_BMAP_FILE);
+                        let digest = copy_and_hash(
+                            &src,
+                            &tgt,
+                            &mut self.progress.borrow_mut(),
+                            &bmap_file.hash_info,
+                        )
+                        .context(MigErrCtx::from_remark(
+                            MigErrorKind::Upstream,
+                            &format!(
+                                "failed to copy block map to migrate temp directory, '{}' -> '{}'",
+                                src.display(),
+                                tgt.display()
+                            ),
+                        ))?;
+                        if digest != bmap_file.hash_info {
+                            return Err(MigError::from_remark(
+                                MigErrorKind::InvParam,
+                                &format!(
+                                    "Failed to check digest on copied file: '{}', {:?} ",
+                                    tgt.display(),
+                                    bmap_file.hash_info
+                                ),
+                            ));
+                        }
+                        self.check_signed(
+                            signed.as_ref(),
+                            &bmap_file.rel_path,
+                            &tgt,
+                            Some(&bmap_file.hash_info),
+                        )?;
+                        info!("copied block map to '{}'", tgt.display());
+                    }
                 }
                 CheckedImageType::FileSystems(ref fs_dump) => {
-                    self.copy_and_check(
-                        &work_path,
-                        &fs_dump.boot.archive,
-                        mig_tmp_dir,
-                        "boot",
-                        BALENA_BOOT_FS_FILE,
-                    )?;
-                    self.copy_and_check(
-                        &work_path,
-                        &fs_dump.root_a.archive,
-                        mig_tmp_dir,
-                        "rootA",
-                        BALENA_ROOTA_FS_FILE,
-                    )?;
-                    self.copy_and_check(
-                        &work_path,
-                        &fs_dump.root_b.archive,
-                        mig_tmp_dir,
-                        "rootB",
-                        BALENA_ROOTB_FS_FILE,
-                    )?;
-                    self.copy_and_check(
-                        &work_path,
-                        &fs_dump.state.archive,
-                        mig_tmp_dir,
-                        "state",
-                        BALENA_STATE_FS_FILE,
-                    )?;
-                    self.copy_and_check(
-                        &work_path,
-                        &fs_dump.data.archive,
-                        mig_tmp_dir,
-                        "data",
-                        BALENA_DATA_FS_FILE,
-                    )?;
+                    if let Some(ref staging) = staging {
+                        for archive in &[
+                            &fs_dump.boot.archive,
+                            &fs_dump.root_a.archive,
+                            &fs_dump.root_b.archive,
+                            &fs_dump.state.archive,
+                            &fs_dump.data.archive,
+                        ] {
+                            self.check_staged(staging, &archive.rel_path, signed.as_ref())?;
+                        }
+                    } else {
+                        self.copy_and_check(
+                            &work_path,
+                            &fs_dump.boot.archive,
+                            mig_tmp_dir,
+                            signed.as_ref(),
+                            "boot",
+                            BALENA_BOOT_FS_FILE,
+                        )?;
+                        self.copy_and_check(
+                            &work_path,
+                            &fs_dump.root_a.archive,
+                            mig_tmp_dir,
+                            signed.as_ref(),
+                            "rootA",
+                            BALENA_ROOTA_FS_FILE,
+                        )?;
+                        self.copy_and_check(
+                            &work_path,
+                            &fs_dump.root_b.archive,
+                            mig_tmp_dir,
+                            signed.as_ref(),
+                            "rootB",
+                            BALENA_ROOTB_FS_FILE,
+                        )?;
+                        self.copy_and_check(
+                            &work_path,
+                            &fs_dump.state.archive,
+                            mig_tmp_dir,
+                            signed.as_ref(),
+                            "state",
+                            BALENA_STATE_FS_FILE,
+                        )?;
+                        self.copy_and_check(
+                            &work_path,
+                            &fs_dump.data.archive,
+                            mig_tmp_dir,
+                            signed.as_ref(),
+                            "data",
+                            BALENA_DATA_FS_FILE,
+                        )?;
+                    }
                 }
             };
 
@@ -423,6 +648,17 @@ impl<'a> Stage2 {
                 ),
             ))?;
 
+            // a generated config.json is not part of the bundle
+            if let Some(ref signed) = signed {
+                let cfg_ref = FileRef {
+                    path: self.config.get_balena_config().to_path_buf(),
+                    hash: None,
+                };
+                if signed.get_digest(&cfg_ref).is_some() {
+                    self.check_signed(Some(signed), &cfg_ref.path, &tgt, None)?;
+                }
+            }
+
             info!("copied balena OS config to '{}'", tgt.display());
 
             let src_nwmgr_dir = path_append(&work_path, SYSTEM_CONNECTIONS_DIR);
@@ -466,31 +702,83 @@ impl<'a> Stage2 {
                 }
             }
 
-            if self.config.has_backup() {
-                // TODO: check available memory / disk space
-                let target_path = path_append(mig_tmp_dir, BACKUP_FILE);
-                let source_path = path_append(&work_path, BACKUP_FILE);
+            if let Some(log_file) = self.config.get_stage1_log() {
+                let src = path_append(&work_path, log_file);
+                let tgt = path_append(mig_tmp_dir, STAGE1_LOG_FILE);
+                match copy(&src, &tgt) {
+                    Ok(_) => {
+                        info!("copied stage 1 log to '{}'", tgt.display());
+                        self.stage1_log = Some(tgt);
+                    }
+                    Err(why) => warn!(
+                        "Failed to copy stage 1 log '{}' to '{}', error: {:?}",
+                        src.display(),
+                        tgt.display(),
+                        why
+                    ),
+                }
+            }
 
-                copy(&source_path, &target_path).context(MigErrCtx::from_remark(
+            for hook in self.config.get_hooks() {
+                let src = path_append(&work_path, &hook.path);
+                let tgt = path_append(mig_tmp_dir, &hook.path);
+                if let Some(parent) = tgt.parent() {
+                    create_dir_all(parent).context(MigErrCtx::from_remark(
+                        MigErrorKind::Upstream,
+                        &format!("Failed to create directory '{}'", parent.display()),
+                    ))?;
+                }
+                copy(&src, &tgt).context(MigErrCtx::from_remark(
                     MigErrorKind::Upstream,
                     &format!(
-                        "Failed copy backup file to migrate temp directory '{}' -> '{}'",
-                        source_path.display(),
-                        target_path.display()
+                        "Failed copy hook to migrate temp directory '{}' -> '{}'",
+                        src.display(),
+                        tgt.display()
                     ),
                 ))?;
+                self.check_signed(signed.as_ref(), &hook.path, &tgt, None)?;
+                info!("copied hook to '{}'", tgt.display());
+            }
+
+            if let Some(staging) = staging.as_ref().filter(|_| self.config.has_backup()) {
+                self.check_staged(staging, Path::new(BACKUP_FILE), None)?;
+            } else if self.config.has_backup() {
+                // TODO: check available memory / disk space
+                let target_path = path_append(mig_tmp_dir, BACKUP_FILE);
+                let source_path = path_append(&work_path, BACKUP_FILE);
+
+                copy_file(&source_path, &target_path, &mut self.progress.borrow_mut()).context(
+                    MigErrCtx::from_remark(
+                        MigErrorKind::Upstream,
+                        &format!(
+                            "Failed copy backup file to migrate temp directory '{}' -> '{}'",
+                            source_path.display(),
+                            target_path.display()
+                        ),
+                    ),
+                )?;
                 info!("copied backup  to '{}'", target_path.display());
             }
 
             info!("Files copied to RAMFS");
+            self.files_in_tmp = staging.is_none();
             mig_tmp_dir
         } else {
             info!("Files were not copied, work dir is on a separate drive");
+            self.stage1_log = self
+                .config
+                .get_stage1_log()
+                .map(|log_file| path_append(&work_path, log_file));
+            if let Some(ref signed) = signed {
+                self.check_signed_in_place(signed, &work_path)?;
+            }
             // TODO: adapt path for no copy mode
             // TODO: check digest anyway ?
             &work_path
         };
 
+        self.record(Checkpoint::FilesCopied);
+
         // Write our buffered log to workdir before unmounting if we are not flashing anyway
 
         if self.config.is_no_flash() {
@@ -511,6 +799,15 @@ impl<'a> Stage2 {
 
         info!("Unmounted file systems");
 
+        // the journal was kept on the boot partition
+        let journal_gone = {
+            let mounts = self.mounts.borrow();
+            mounts.get_log_path().is_none() && !mounts.is_boot_mounted()
+        };
+        if journal_gone {
+            self.journal.detach();
+        }
+
         // ************************************************************************************
         // * write the gzipped image to disk
         // * from migrate:
@@ -529,6 +826,9 @@ impl<'a> Stage2 {
         }
 
         if self.config.is_no_flash() {
+            if self.config.is_simulate() {
+                self.simulation_exit(true);
+            }
             Stage2::exit(&FailMode::Reboot)?;
         }
 
@@ -536,19 +836,153 @@ impl<'a> Stage2 {
         // Exit in Rescue Shell  Mode to call external script
         // Call external script
 
+        if self.journal.is_done(Checkpoint::FlashFinished) {
+            info!("balena OS was written by a previous attempt, not writing it again");
+            if let Err(why) = self.mounts.borrow_mut().mount_balena(false) {
+                self.recoverable_state = false;
+                error!("Failed to mount balena partitions, error: {:?}", why);
+                return Err(MigError::displayed());
+            }
+        } else {
+            if self.journal.get_last() == Some(Checkpoint::FlashStarted) {
+                warn!("A previous attempt was interrupted while writing balena OS, starting over");
+            }
+            self.run_hooks(HookStage::BeforeFlash, mig_tmp_dir)?;
+            self.record(Checkpoint::FlashStarted);
+            self.write_balena_os(&target_path, mig_tmp_dir, staging.as_ref())?;
+            self.record(Checkpoint::FlashFinished);
+        }
+
+        info!("Mounting balena file systems");
+        sync();
+
+        // TODO: check fingerprints ?
+
+        let boot_mountpoint =
+            if let Some(mountpoint) = self.mounts.borrow().get_balena_boot_mountpoint() {
+                mountpoint.to_path_buf()
+            } else {
+                self.recoverable_state = false;
+                error!("Unable to retrieve balena boot mountpoint");
+                return Err(MigError::displayed());
+            };
+
+        copy_balena_config(mig_tmp_dir, &boot_mountpoint)?;
+
+        // we can hope to successfully reboot again after writing config.json and system-connections
+        self.recoverable_state = true;
+        self.record(Checkpoint::ConfigWritten);
+
+        if let Some(data_mountpoint) = self.mounts.borrow().get_balena_data_mountpoint() {
+            // TODO: copy log, backup to data_path
+            if self.config.has_backup() {
+                // TODO: check available disk space
+                let backup_path = path_append(&data_mountpoint, BACKUP_FILE);
+
+                if let Some(staged) = staging
+                    .as_ref()
+                    .and_then(|staging| staging.get_file(BACKUP_FILE))
+                {
+                    copy_staged(
+                        staged,
+                        &target_path,
+                        &backup_path,
+                        &mut self.progress.borrow_mut(),
+                    )?;
+                } else {
+                    let source_path = path_append(&mig_tmp_dir, BACKUP_FILE);
+                    copy_file(&source_path, &backup_path, &mut self.progress.borrow_mut())
+                        .context(MigErrCtx::from_remark(
+                            MigErrorKind::Upstream,
+                            &format!(
+                                "Failed copy backup file to data partition '{}' -> '{}'",
+                                source_path.display(),
+                                backup_path.display()
+                            ),
+                        ))?;
+                }
+                info!("copied backup  to '{}'", backup_path.display());
+            }
+        }
+
+        self.run_hooks(HookStage::AfterFlash, mig_tmp_dir)?;
+
+        self.record(Checkpoint::DataCopied);
+
+        // the hook output is persisted with the logs
+        self.run_hooks(HookStage::BeforeReboot, mig_tmp_dir)?;
+
+        // unsent reports are kept with the logs
+        if let Some(ref mut status) = self.status {
+            status.report("finished", ReportStatus::Success, None);
+        }
+
+        self.persist_logs();
+
+        let _res = self.mounts.borrow_mut().unmount_balena();
+
+        info!(
+            "Migration stage 2 was successful, rebooting in {} seconds!",
+            REBOOT_DELAY
+        );
+
+        let _res = self.mounts.borrow_mut().unmount_log();
+
+        if let Some(ref mut wd_handler) = watchdog_handler {
+            debug!("sending term signal to watchdog handler");
+            wd_handler.stop();
+            debug!("watchdog handler has stopped");
+        }
+
+        if self.config.is_simulate() {
+            self.simulation_exit(true);
+        }
+
+        thread::sleep(Duration::new(REBOOT_DELAY, 0));
+
+        Logger::flush(); // superfluous
+        sync();
+
+        Stage2::exit(&FailMode::Reboot)?;
+
+        Ok(())
+    }
+
+    // flash the image or write the file systems to the target device
+    fn write_balena_os(
+        &mut self,
+        target_path: &Path,
+        mig_tmp_dir: &Path,
+        staging: Option<&Staging>,
+    ) -> Result<(), MigError> {
         match self.config.get_balena_image() {
             CheckedImageType::Flasher(ref image_file) => {
                 // TODO: move some, if not most of this into flasher
 
-                let image_path = if self.mounts.borrow().is_work_no_copy() {
+                let (image_path, bmap_path) = if self.mounts.borrow().is_work_no_copy() {
                     if let Some(work_dir) = self.mounts.borrow().get_work_path() {
-                        path_append(work_dir, &image_file.rel_path)
+                        (
+                            path_append(work_dir, &image_file.rel_path),
+                            self.config
+                                .get_bmap_file()
+                                .map(|bmap_file| path_append(work_dir, &bmap_file.rel_path)),
+                        )
                     } else {
                         warn!("Work path not found in no_copy mode, trying mig temp");
-                        path_append(mig_tmp_dir, BALENA_IMAGE_FILE)
+                        (
+                            path_append(mig_tmp_dir, BALENA_IMAGE_FILE),
+                            self.config
+                                .get_bmap_file()
+                                .map(|_| path_append(mig_tmp_dir, BALENA_BMAP_FILE)),
+                        )
                     }
                 } else {
-                    path_append(mig_tmp_dir, BALENA_IMAGE_FILE)
+                    (
+                        path_append(mig_tmp_dir, BALENA_IMAGE_FILE),
+                        self.config
+                            .get_bmap_file()
+                            .map(|_| path_append(mig_tmp_dir, BALENA_BMAP_FILE)),
+                    )
                 };
 
                 info!(
@@ -557,18 +991,26 @@ impl<'a> Stage2 {
                     target_path.display()
                 );
 
-                if !file_exists(&image_path) {
+                let image = if let Some(staged) =
+                    staging.and_then(|staging| staging.get_file(&image_file.rel_path))
+                {
+                    ImageSource::Staged(staged, target_path)
+                } else if file_exists(&image_path) {
+                    ImageSource::File(&image_path)
+                } else {
                     return Err(MigError::from_remark(
                         MigErrorKind::NotFound,
                         &format!("Could not locate OS image: '{}'", image_path.display()),
                     ));
-                }
+                };
 
                 match flasher::flash_balena_os(
-                    &target_path,
+                    target_path,
                     &mut self.mounts.borrow_mut(),
                     &self.config,
-                    &image_path,
+                    &image,
+                    bmap_path.as_ref().map(|path| path.as_path()),
+                    &mut self.progress.borrow_mut(),
                 ) {
                     FlashResult::Ok => {}
                     FlashResult::FailRecoverable => {
@@ -600,10 +1042,12 @@ impl<'a> Stage2 {
                 };
 
                 match fs_writer::write_balena_os(
-                    &target_path,
+                    target_path,
                     &mut self.mounts.borrow_mut(),
                     &self.config,
                     &base_path,
+                    staging,
+                    &mut self.progress.borrow_mut(),
                 ) {
                     FlashResult::Ok => (),
                     FlashResult::FailNonRecoverable => {
@@ -619,123 +1063,6 @@ impl<'a> Stage2 {
                 }
             }
         }
-
-        info!("Mounting balena file systems");
-        sync();
-
-        // TODO: check fingerprints ?
-
-        let boot_mountpoint =
-            if let Some(mountpoint) = self.mounts.borrow().get_balena_boot_mountpoint() {
-                mountpoint.to_path_buf()
-            } else {
-                self.recoverable_state = false;
-                error!("Unable to retrieve balena boot mountpoint");
-                return Err(MigError::displayed());
-            };
-
-        let src = path_append(mig_tmp_dir, BALENA_CONFIG_FILE);
-        let tgt = path_append(&boot_mountpoint, BALENA_CONFIG_FILE);
-
-        copy(&src, &tgt).context(MigErrCtx::from_remark(
-            MigErrorKind::Upstream,
-            &format!(
-                "failed to copy balena config to boot mount dir, '{}' -> '{}'",
-                src.display(),
-                tgt.display()
-            ),
-        ))?;
-
-        info!("copied balena OS config to '{}'", tgt.display());
-
-        // copy system connections
-        let nwmgr_dir = path_append(mig_tmp_dir, SYSTEM_CONNECTIONS_DIR);
-        if dir_exists(&nwmgr_dir)? {
-            let tgt_path = path_append(&boot_mountpoint, SYSTEM_CONNECTIONS_DIR);
-            for path in read_dir(&nwmgr_dir).context(MigErrCtx::from_remark(
-                MigErrorKind::Upstream,
-                &format!("Failed to read directory: '{}'", nwmgr_dir.display()),
-            ))? {
-                if let Ok(ref path) = path {
-                    let tgt = path_append(&tgt_path, path.file_name());
-                    copy(path.path(), &tgt).context(MigErrCtx::from_remark(
-                        MigErrorKind::Upstream,
-                        &format!(
-                            "Failed to copy '{}' to '{}'",
-                            path.path().display(),
-                            tgt.display()
-                        ),
-                    ))?;
-                    info!("copied '{}' to '{}'", path.path().display(), tgt.display());
-                } else {
-                    error!("failed to read path element: {:?}", path);
-                }
-            }
-        } else {
-            warn!("No network manager configurations were copied");
-        }
-
-        // we can hope to successfully reboot again after writing config.json and system-connections
-        self.recoverable_state = true;
-
-        if let Some(data_mountpoint) = self.mounts.borrow().get_balena_data_mountpoint() {
-            // TODO: copy log, backup to data_path
-            if self.config.has_backup() {
-                // TODO: check available disk space
-                let source_path = path_append(&mig_tmp_dir, BACKUP_FILE);
-                let target_path = path_append(&data_mountpoint, BACKUP_FILE);
-
-                copy(&source_path, &target_path).context(MigErrCtx::from_remark(
-                    MigErrorKind::Upstream,
-                    &format!(
-                        "Failed copy backup file to data partition '{}' -> '{}'",
-                        source_path.display(),
-                        target_path.display()
-                    ),
-                ))?;
-                info!("copied backup  to '{}'", target_path.display());
-            }
-
-            if Logger::get_log_dest().is_buffer_dest() {
-                let log_path = path_append(&data_mountpoint, MIGRATE_LOG_FILE);
-                match Logger::set_log_file(&LogDestination::Stderr, &log_path, false) {
-                    Ok(_) => {
-                        info!("Set log file to '{}'", log_path.display());
-                        //Logger::flush();
-                    }
-                    Err(why) => {
-                        warn!(
-                            "Failed to set log file to '{}', error: {:?}",
-                            log_path.display(),
-                            why
-                        );
-                    }
-                }
-            }
-        }
-
-        let _res = self.mounts.borrow_mut().unmount_balena();
-
-        info!(
-            "Migration stage 2 was successful, rebooting in {} seconds!",
-            REBOOT_DELAY
-        );
-
-        let _res = self.mounts.borrow_mut().unmount_log();
-
-        if let Some(ref mut wd_handler) = watchdog_handler {
-            debug!("sending term signal to watchdog handler");
-            wd_handler.stop();
-            debug!("watchdog handler has stopped");
-        }
-
-        thread::sleep(Duration::new(REBOOT_DELAY, 0));
-
-        Logger::flush(); // superfluous
-        sync();
-
-        Stage2::exit(&FailMode::Reboot)?;
-
         Ok(())
     }
 
@@ -756,13 +1083,43 @@ impl<'a> Stage2 {
                     return Err(MigError::displayed());
                 }
             }
+            FailMode::PowerOff => {
+                let poweroff_cmd = whereis(POWEROFF_CMD)?;
+                let cmd_res = call(&poweroff_cmd, &["-f"], true)?;
+                if !cmd_res.status.success() {
+                    error!("Command failed: {}, : '{}'", POWEROFF_CMD, cmd_res.stderr);
+                    return Err(MigError::displayed());
+                }
+            }
             FailMode::RescueShell => {
                 std::process::exit(1);
             }
+            // resolved by error_exit, reboot if they get here anyway
+            FailMode::Retry(_) | FailMode::RestoreAndReboot => {
+                return Stage2::exit(&FailMode::Reboot);
+            }
         }
         Ok(())
     }
 
+    // never reboot in simulation mode, detach the flash target and exit
+    fn simulation_exit(&self, success: bool) -> ! {
+        let _res = self.mounts.borrow_mut().unmount_balena();
+        if let Err(why) = self.mounts.borrow_mut().detach_flash_loop() {
+            warn!("{:?}", why);
+        }
+
+        if success {
+            info!("Simulation was successful, not rebooting");
+        } else {
+            error!("Simulation failed, not rebooting");
+        }
+
+        Logger::flush();
+        sync();
+        std::process::exit(if success { 0 } else { 1 });
+    }
+
     /*
         pub(crate) fn is_recoverable(&self) -> bool {
             self.recoverable_state
@@ -773,26 +1130,251 @@ impl<'a> Stage2 {
         Stage2::exit(FailMode::get_default())
     }
 
+    pub(crate) fn report_failure(&mut self, why: &MigError) {
+        if let Some(ref mut status) = self.status {
+            status.report_error("failed", ReportStatus::Failed, why);
+        }
+    }
+
+    // the configured fail mode for the current state
+    fn get_fail_mode(&self) -> &FailMode {
+        select_fail_mode(&self.config, self.recoverable_state)
+    }
+
+    // prepare to run migrate again if the fail mode asks for it, the journal makes migrate skip
+    // the phases completed before
+    pub(crate) fn prepare_retry(&mut self, why: &MigError) -> bool {
+        // once writing balena OS has started the files are only available if they were copied
+        // or the work directory is on a separate drive
+        let files_available = !self.journal.is_done(Checkpoint::FlashStarted)
+            || self.files_in_tmp
+            || self.mounts.borrow().is_work_no_copy();
+
+        let delay = if let Some(delay) =
+            get_retry_delay(self.get_fail_mode(), self.retries, files_available)
+        {
+            delay
+        } else {
+            return false;
+        };
+
+        self.retries += 1;
+        let max_retries = if let FailMode::Retry(max_retries) = self.get_fail_mode() {
+            *max_retries
+        } else {
+            0
+        };
+
+        if let Some(ref mut status) = self.status {
+            status.report_error("retry", ReportStatus::Running, why);
+        }
+
+        info!(
+            "Retrying migration in {} seconds, attempt {} of {}",
+            delay, self.retries, max_retries
+        );
+        Logger::flush();
+        thread::sleep(Duration::from_secs(delay));
+
+        let mut mounts = self.mounts.borrow_mut();
+        mounts.unmount_balena();
+        if !self.journal.is_done(Checkpoint::FlashStarted) {
+            // the files are copied again, free the memory used by the last attempt
+            if let Err(why) = remove_dir_all(MIGRATE_TEMP_DIR) {
+                debug!("Failed to remove '{}', error: {:?}", MIGRATE_TEMP_DIR, why);
+            }
+            if let Err(why) = mounts.remount_boot() {
+                warn!("Failed to mount the boot partition again, error: {:?}", why);
+            }
+            if mounts.get_work_path().is_none() {
+                if let Err(why) = mounts.mount_from_config(&self.config) {
+                    warn!("Failed to mount the work directory again, error: {:?}", why);
+                }
+            }
+        }
+        true
+    }
+
+    // restore the former boot configuration unless writing balena OS has started
+    fn try_restore_boot(&self) -> bool {
+        if self.journal.is_done(Checkpoint::FlashStarted) {
+            warn!("balena OS was written, the boot configuration can not be restored");
+            return false;
+        }
+
+        if self.journal.is_done(Checkpoint::BootRestored) {
+            return true;
+        }
+
+        if let Err(why) = self.mounts.borrow_mut().remount_boot() {
+            error!("Failed to mount the boot partition, error: {:?}", why);
+            return false;
+        }
+
+        match device_impl::from_config(*self.config.get_device_type(), *self.config.get_boot_type())
+        {
+            Ok(device) => {
+                if device.restore_boot(&self.mounts.borrow(), &self.config) {
+                    info!("Boot configuration was restored sucessfully");
+                    true
+                } else {
+                    error!("Failed to restore boot configuration");
+                    false
+                }
+            }
+            Err(why) => {
+                error!("Failed to create device, error: {:?}", why);
+                false
+            }
+        }
+    }
+
     pub(crate) fn error_exit(&self) -> Result<(), MigError> {
         trace!("error_exit: entered");
-        if self.recoverable_state {
-            Stage2::exit(self.config.get_fail_mode())
-        } else {
-            Stage2::exit(&FailMode::RescueShell)
+        self.persist_logs();
+
+        if self.config.is_simulate() {
+            self.simulation_exit(false);
+        }
+
+        let fail_mode = match self.get_fail_mode() {
+            // retries are exhausted
+            FailMode::Retry(_) => {
+                if self.recoverable_state {
+                    &FailMode::Reboot
+                } else {
+                    &FailMode::RescueShell
+                }
+            }
+            FailMode::RestoreAndReboot => {
+                if self.try_restore_boot() || self.recoverable_state {
+                    &FailMode::Reboot
+                } else {
+                    &FailMode::RescueShell
+                }
+            }
+            fail_mode => fail_mode,
+        };
+
+        Stage2::exit(fail_mode)
+    }
+
+    // record the checkpoint in the journal and report it
+    fn record(&mut self, checkpoint: Checkpoint) {
+        self.journal.record(checkpoint);
+        if let Some(ref mut status) = self.status {
+            status.report(checkpoint.as_str(), ReportStatus::Running, None);
         }
     }
 
+    // write the stage 1 & stage 2 logs to MIGRATE_LOG_DIR on resin-data, on resin-boot if data is
+    // not mounted or in the work dir if flashing has not started
+    fn persist_logs(&self) {
+        let log_dir = {
+            let mounts = self.mounts.borrow();
+            if let Some(log_dir) = get_log_dir(
+                mounts.get_balena_data_mountpoint(),
+                mounts.get_balena_boot_mountpoint(),
+                mounts.get_work_path(),
+                self.journal.is_done(Checkpoint::FlashStarted),
+            ) {
+                log_dir
+            } else {
+                warn!("No file system available to keep the migration logs");
+                return;
+            }
+        };
+
+        if let Some(ref status) = self.status {
+            // stage 1 sends the reports left in the work dir if the migration is started again,
+            // once flashing has started only a stage 2 retry can send them from the log device
+            let mounts = self.mounts.borrow();
+            let queue_dir = if self.journal.is_done(Checkpoint::FlashStarted) {
+                mounts.get_log_path()
+            } else {
+                mounts.get_work_path()
+            };
+            if let Some(queue_dir) = queue_dir {
+                let queue_file = path_append(queue_dir, STATUS_QUEUE_FILE);
+                if let Err(why) = status.save_queue(&queue_file) {
+                    warn!(
+                        "Failed to save status reports to '{}', error: {:?}",
+                        queue_file.display(),
+                        why
+                    );
+                }
+            } else if status.get_pending_count() > 0 {
+                warn!(
+                    "{} status reports could not be sent and are dropped, no log device is configured",
+                    status.get_pending_count()
+                );
+            }
+        }
+
+        if let Some(ref stage1_log) = self.stage1_log {
+            copy_stage1_log(stage1_log, &log_dir);
+        }
+
+        let log_path = path_append(&log_dir, STAGE2_LOG_FILE);
+        if Logger::get_log_dest().is_buffer_dest() {
+            // switching to a log file writes out the buffered log
+            let log_dest = if self.config.is_log_console_off() {
+                LogDestination::Stream
+            } else {
+                LogDestination::StreamStderr
+            };
+
+            match Logger::set_log_file(&log_dest, &log_path, false) {
+                Ok(_) => info!("Set log file to '{}'", log_path.display()),
+                Err(why) => warn!(
+                    "Failed to set log file to '{}', error: {:?}",
+                    log_path.display(),
+                    why
+                ),
+            }
+        } else if let Some(ref log_file) = self.log_file {
+            Logger::flush();
+            match copy(log_file, &log_path) {
+                Ok(_) => info!("copied stage 2 log to '{}'", log_path.display()),
+                Err(why) => warn!(
+                    "Failed to copy stage 2 log '{}' to '{}', error: {:?}",
+                    log_file.display(),
+                    log_path.display(),
+                    why
+                ),
+            }
+        }
+    }
+
+    // check the digest of a copied file, reported as phase verify
+    fn check_digest(&self, path: &Path, hash_info: &HashInfo) -> Result<bool, MigError> {
+        let size = file_size(path)?;
+        let mut progress = self.progress.borrow_mut();
+        progress.start_phase(Phase::Verify, Some(size));
+        let res = check_digest(path, hash_info);
+        progress.update(size);
+        progress.finish_phase();
+        res
+    }
+
     fn copy_and_check(
         &self,
         source_dir: &Path,
         archive: &RelFileInfo,
         target_dir: &Path,
+        signed: Option<&DigestManifest>,
         tag: &str,
         target_name: &str,
     ) -> Result<(), MigError> {
         let src = path_append(&source_dir, &archive.rel_path);
         let tgt = path_append(target_dir, target_name);
-        copy(&src, &tgt).context(MigErrCtx::from_remark(
+        let digest = copy_and_hash(
+            &src,
+            &tgt,
+            &mut self.progress.borrow_mut(),
+            &archive.hash_info,
+        )
+        .context(MigErrCtx::from_remark(
             MigErrorKind::Upstream,
             &format!(
                 "failed to copy balena fs archive to migrate temp directory, '{}' -> '{}'",
@@ -808,12 +1390,7 @@ impl<'a> Stage2 {
             tgt.display()
         );
 
-        info!(
-            "Checking digest on copied file '{}' - {:?}",
-            tgt.display(),
-            archive.hash_info
-        );
-        if !check_digest(&tgt, &archive.hash_info)? {
+        if digest != archive.hash_info {
             return Err(MigError::from_remark(
                 MigErrorKind::InvParam,
                 &format!(
@@ -823,6 +1400,443 @@ impl<'a> Stage2 {
                 ),
             ));
         }
+        self.check_signed(signed, &archive.rel_path, &tgt, Some(&archive.hash_info))
+    }
+
+    // check a file staged on the flash device against its digest and the signed bundle
+    fn check_staged(
+        &self,
+        staging: &Staging,
+        rel_path: &Path,
+        signed: Option<&DigestManifest>,
+    ) -> Result<(), MigError> {
+        let staged = if let Some(staged) = staging.get_file(rel_path) {
+            staged
+        } else {
+            error!(
+                "The file '{}' was not staged on the flash device",
+                rel_path.display()
+            );
+            return Err(MigError::displayed());
+        };
+
+        let device = self.mounts.borrow().get_flash_device().to_path_buf();
+        let digest = hash_staged(
+            staged,
+            &staged.hash_info,
+            &device,
+            &mut self.progress.borrow_mut(),
+        )?;
+        if digest != staged.hash_info {
+            error!(
+                "The file '{}' staged on '{}' does not match its digest, it might have been moved",
+                rel_path.display(),
+                device.display()
+            );
+            return Err(MigError::displayed());
+        }
+
+        // the signed digest is checked against the blocks that are flashed, not the file
+        if let Some(signed) = signed {
+            let file_ref = signed.require(&FileRef {
+                path: rel_path.to_path_buf(),
+                hash: None,
+            })?;
+            if let Some(ref signed_digest) = file_ref.hash {
+                if *signed_digest != staged.hash_info
+                    && hash_staged(
+                        staged,
+                        signed_digest,
+                        &device,
+                        &mut self.progress.borrow_mut(),
+                    )? != *signed_digest
+                {
+                    error!(
+                        "The file '{}' staged on '{}' does not match the signed bundle",
+                        rel_path.display(),
+                        device.display()
+                    );
+                    return Err(MigError::displayed());
+                }
+            }
+        }
+
+        info!(
+            "checked '{}' staged on '{}'",
+            rel_path.display(),
+            device.display()
+        );
+        Ok(())
+    }
+
+    // compare a file to the signed bundle, a digest already checked is not computed again
+    fn check_signed(
+        &self,
+        signed: Option<&DigestManifest>,
+        rel_path: &Path,
+        path: &Path,
+        checked: Option<&HashInfo>,
+    ) -> Result<(), MigError> {
+        if let Some(signed) = signed {
+            let file_ref = signed.require(&FileRef {
+                path: rel_path.to_path_buf(),
+                hash: None,
+            })?;
+            if let Some(ref hash_info) = file_ref.hash {
+                if checked != Some(hash_info) && !self.check_digest(path, hash_info)? {
+                    error!(
+                        "The file '{}' does not match the signed bundle",
+                        rel_path.display()
+                    );
+                    return Err(MigError::displayed());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // files are used from the work dir if they are not copied, check them there
+    fn check_signed_in_place(
+        &self,
+        signed: &DigestManifest,
+        work_path: &Path,
+    ) -> Result<(), MigError> {
+        let mut files: Vec<&RelFileInfo> = match self.config.get_balena_image() {
+            CheckedImageType::Flasher(ref image_file) => vec![image_file],
+            CheckedImageType::FileSystems(ref fs_dump) => vec![
+                &fs_dump.boot.archive,
+                &fs_dump.root_a.archive,
+                &fs_dump.root_b.archive,
+                &fs_dump.state.archive,
+                &fs_dump.data.archive,
+            ],
+        };
+
+        if let Some(bmap_file) = self.config.get_bmap_file() {
+            files.push(bmap_file);
+        }
+
+        for file in files {
+            self.check_signed(
+                Some(signed),
+                &file.rel_path,
+                &path_append(work_path, &file.rel_path),
+                None,
+            )?;
+        }
+
+        for hook in self.config.get_hooks() {
+            self.check_signed(
+                Some(signed),
+                &hook.path,
+                &path_append(work_path, &hook.path),
+                None,
+            )?;
+        }
         Ok(())
     }
+
+    // run the hooks configured for stage from hook_path, a failing hook fails the migration
+    fn run_hooks(&mut self, stage: HookStage, hook_path: &Path) -> Result<(), MigError> {
+        if self.config.get_hooks().is_empty() {
+            return Ok(());
+        }
+
+        let mut env: Vec<(&str, PathBuf)> = vec![
+            (
+                "BALENA_MIGRATE_FLASH_DEVICE",
+                self.mounts.borrow().get_flash_device().to_path_buf(),
+            ),
+            ("BALENA_MIGRATE_WORK_DIR", hook_path.to_path_buf()),
+        ];
+        if let Some(mountpoint) = self.mounts.borrow().get_balena_boot_mountpoint() {
+            env.push(("BALENA_MIGRATE_BOOT_MOUNT", mountpoint.to_path_buf()));
+        }
+        if let Some(mountpoint) = self.mounts.borrow().get_balena_data_mountpoint() {
+            env.push(("BALENA_MIGRATE_DATA_MOUNT", mountpoint.to_path_buf()));
+        }
+
+        match hooks::run_hooks(self.config.get_hooks(), stage, hook_path, &env) {
+            FlashResult::Ok => Ok(()),
+            FlashResult::FailRecoverable => {
+                self.recoverable_state = true;
+                error!("A {} hook failed", stage.as_str());
+                Err(MigError::displayed())
+            }
+            FlashResult::FailNonRecoverable => {
+                self.recoverable_state = false;
+                error!("A {} hook failed", stage.as_str());
+                Err(MigError::displayed())
+            }
+        }
+    }
+}
+
+// MIGRATE_LOG_DIR on resin-data, on resin-boot if data is not mounted or in the work dir if
+// flashing has not started, the directory is created
+fn get_log_dir(
+    data_mountpoint: Option<&Path>,
+    boot_mountpoint: Option<&Path>,
+    work_path: Option<&Path>,
+    flash_started: bool,
+) -> Option<PathBuf> {
+    let log_dir = path_append(
+        data_mountpoint
+            .or(boot_mountpoint)
+            .or_else(|| work_path.filter(|_| !flash_started))?,
+        MIGRATE_LOG_DIR,
+    );
+
+    if let Err(why) = create_dir_all(&log_dir) {
+        warn!(
+            "Failed to create log directory '{}', error: {:?}",
+            log_dir.display(),
+            why
+        );
+        None
+    } else {
+        Some(log_dir)
+    }
+}
+
+fn copy_stage1_log(stage1_log: &Path, log_dir: &Path) {
+    let tgt = path_append(log_dir, STAGE1_LOG_FILE);
+    match copy(stage1_log, &tgt) {
+        Ok(_) => info!("copied stage 1 log to '{}'", tgt.display()),
+        Err(why) => warn!(
+            "Failed to copy stage 1 log '{}' to '{}', error: {:?}",
+            stage1_log.display(),
+            tgt.display(),
+            why
+        ),
+    }
+}
+
+// copy config.json and the network manager configurations to the balena boot partition
+fn copy_balena_config(mig_tmp_dir: &Path, boot_mountpoint: &Path) -> Result<(), MigError> {
+    let src = path_append(mig_tmp_dir, BALENA_CONFIG_FILE);
+    let tgt = path_append(boot_mountpoint, BALENA_CONFIG_FILE);
+
+    copy(&src, &tgt).context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!(
+            "failed to copy balena config to boot mount dir, '{}' -> '{}'",
+            src.display(),
+            tgt.display()
+        ),
+    ))?;
+
+    info!("copied balena OS config to '{}'", tgt.display());
+
+    // copy system connections
+    let nwmgr_dir = path_append(mig_tmp_dir, SYSTEM_CONNECTIONS_DIR);
+    if dir_exists(&nwmgr_dir)? {
+        let tgt_path = path_append(boot_mountpoint, SYSTEM_CONNECTIONS_DIR);
+        for path in read_dir(&nwmgr_dir).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to read directory: '{}'", nwmgr_dir.display()),
+        ))? {
+            if let Ok(ref path) = path {
+                let tgt = path_append(&tgt_path, path.file_name());
+                copy(path.path(), &tgt).context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!(
+                        "Failed to copy '{}' to '{}'",
+                        path.path().display(),
+                        tgt.display()
+                    ),
+                ))?;
+                info!("copied '{}' to '{}'", path.path().display(), tgt.display());
+            } else {
+                error!("failed to read path element: {:?}", path);
+            }
+        }
+    } else {
+        warn!("No network manager configurations were copied");
+    }
+
+    Ok(())
+}
+
+// the configured fail mode for the state
+fn select_fail_mode(config: &Stage2Config, recoverable_state: bool) -> &FailMode {
+    if recoverable_state {
+        config.get_fail_mode()
+    } else {
+        config.get_fail_mode_non_recoverable()
+    }
+}
+
+// the delay before the next retry, None if the fail mode or the state do not allow one
+fn get_retry_delay(fail_mode: &FailMode, retries: u32, files_available: bool) -> Option<u64> {
+    let max_retries = if let FailMode::Retry(max_retries) = fail_mode {
+        *max_retries
+    } else {
+        return None;
+    };
+
+    if retries >= max_retries {
+        warn!("Giving up after {} retries", retries);
+        return None;
+    }
+
+    if !files_available {
+        warn!("The files needed to write balena OS are not available anymore, not retrying");
+        return None;
+    }
+
+    Some(
+        RETRY_DELAY
+            .checked_shl(retries)
+            .unwrap_or(MAX_RETRY_DELAY)
+            .min(MAX_RETRY_DELAY),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::stage2_config::tests::fail_mode_config;
+
+    #[test]
+    fn fail_mode_for_state() {
+        let config = fail_mode_config();
+        assert_eq!(select_fail_mode(&config, true), &FailMode::Retry(3));
+        assert_eq!(
+            select_fail_mode(&config, false),
+            &FailMode::RestoreAndReboot
+        );
+    }
+
+    // flash, mount and configure a file like stage 2 does in simulation mode, needs root, loop
+    // devices and partprobe, run with: sudo cargo test -- --ignored
+    #[test]
+    #[ignore]
+    fn simulate_flash_to_file() {
+        use crate::common::stage2_config::tests::fail_mode_config;
+        use std::fs::{copy, write, OpenOptions};
+
+        let dir = path_append(std::env::temp_dir(), "balena-migrate-simulate-test");
+        let _res = remove_dir_all(&dir);
+        let tmp_dir = path_append(&dir, "migrate_tmp");
+        create_dir_all(path_append(&tmp_dir, SYSTEM_CONNECTIONS_DIR)).unwrap();
+
+        let image_path = path_append(&dir, BALENA_IMAGE_FILE);
+        // boot, root A, root B and an extended partition with state and data
+        copy("./test_data/balena.img.gz", &image_path).unwrap();
+        write(
+            path_append(&tmp_dir, BALENA_CONFIG_FILE),
+            "{\"deviceType\":\"intel-nuc\"}",
+        )
+        .unwrap();
+        write(
+            path_append(path_append(&tmp_dir, SYSTEM_CONNECTIONS_DIR), "wifi"),
+            "[connection]\n",
+        )
+        .unwrap();
+
+        let target = path_append(&dir, "target.img");
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&target)
+            .unwrap()
+            .set_len(32 * 1024 * 1024)
+            .unwrap();
+
+        let config = fail_mode_config();
+        let mut mounts = Mounts::for_flash_target(&target).unwrap();
+        let flash_device = mounts.get_flash_device().to_path_buf();
+        let res = flasher::flash_balena_os(
+            &flash_device,
+            &mut mounts,
+            &config,
+            &ImageSource::File(&image_path),
+            None,
+            &mut Progress::new(None, None),
+        );
+
+        let mounted = res == FlashResult::Ok && {
+            mounts.unmount_balena();
+            mounts.mount_balena(true).unwrap_or(false)
+        };
+
+        let copied =
+            if let (true, Some(boot_mountpoint)) = (mounted, mounts.get_balena_boot_mountpoint()) {
+                let _res = create_dir_all(path_append(boot_mountpoint, SYSTEM_CONNECTIONS_DIR));
+                copy_balena_config(&tmp_dir, boot_mountpoint).is_ok()
+                    && read_to_string(path_append(boot_mountpoint, BALENA_CONFIG_FILE)).unwrap()
+                        == "{\"deviceType\":\"intel-nuc\"}"
+                    && file_exists(path_append(
+                        path_append(boot_mountpoint, SYSTEM_CONNECTIONS_DIR),
+                        "wifi",
+                    ))
+            } else {
+                false
+            };
+
+        let data_mounted = mounts.get_balena_data_mountpoint().is_some();
+        mounts.unmount_balena();
+        mounts.detach_flash_loop().unwrap();
+        remove_dir_all(&dir).unwrap();
+
+        assert_eq!(res, FlashResult::Ok);
+        assert!(mounted && data_mounted);
+        assert!(copied);
+    }
+
+    #[test]
+    fn persist_log_dirs() {
+        use std::fs::write;
+
+        let dir = path_append(std::env::temp_dir(), "balena-migrate-log-dir-test");
+        let _res = remove_dir_all(&dir);
+        let data = path_append(&dir, "data");
+        let boot = path_append(&dir, "boot");
+        let work = path_append(&dir, "work");
+
+        let log_dir = |data: Option<&PathBuf>, boot: Option<&PathBuf>, flash_started: bool| {
+            get_log_dir(
+                data.map(|path| path.as_path()),
+                boot.map(|path| path.as_path()),
+                Some(&work),
+                flash_started,
+            )
+        };
+
+        let data_dir = log_dir(Some(&data), Some(&boot), true).unwrap();
+        assert_eq!(data_dir, path_append(&data, MIGRATE_LOG_DIR));
+        assert_eq!(
+            log_dir(None, Some(&boot), true),
+            Some(path_append(&boot, MIGRATE_LOG_DIR))
+        );
+        assert_eq!(
+            log_dir(None, None, false),
+            Some(path_append(&work, MIGRATE_LOG_DIR))
+        );
+        // the work dir is gone once flashing has started
+        assert_eq!(log_dir(None, None, true), None);
+
+        // the stage 1 log copied from the work dir ends up next to the stage 2 log
+        let stage1_log = path_append(&work, "stage1.log");
+        write(&stage1_log, "stage 1 was here\n").unwrap();
+        copy_stage1_log(&stage1_log, &data_dir);
+        let copied = read_to_string(path_append(&data_dir, STAGE1_LOG_FILE)).unwrap();
+        remove_dir_all(&dir).unwrap();
+        assert_eq!(copied, "stage 1 was here\n");
+    }
+
+    #[test]
+    fn retry_delays() {
+        let retry = FailMode::Retry(7);
+        assert_eq!(get_retry_delay(&retry, 0, true), Some(RETRY_DELAY));
+        assert_eq!(get_retry_delay(&retry, 1, true), Some(RETRY_DELAY * 2));
+        assert_eq!(get_retry_delay(&retry, 6, true), Some(MAX_RETRY_DELAY));
+        // retries exhausted
+        assert_eq!(get_retry_delay(&retry, 7, true), None);
+        // the files needed were on the flashed device
+        assert_eq!(get_retry_delay(&retry, 0, false), None);
+        assert_eq!(get_retry_delay(&FailMode::Reboot, 0, true), None);
+        assert_eq!(get_retry_delay(&FailMode::RestoreAndReboot, 0, true), None);
+    }
 }
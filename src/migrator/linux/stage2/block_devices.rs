@@ -0,0 +1,596 @@
+use failure::ResultExt;
+use log::{debug, trace, warn};
+use std::fs::{read_dir, read_to_string, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    common::{
+        disk_util::{Disk, PartitionIterator},
+        file_exists, path_append, MigErrCtx, MigError, MigErrorKind,
+    },
+    defs::{DISK_BY_LABEL_PATH, DISK_BY_PARTUUID_PATH, DISK_BY_UUID_PATH},
+};
+
+// *************************************************************************************************
+// * Block device discovery for stage 2.
+// * Devices given as /dev/disk/by-uuid, by-partuuid or by-label links are looked up through the
+// * links udev creates. If the links are missing, all block devices found in sysfs are identified
+// * by their partition table entries and file system superblocks instead.
+// *************************************************************************************************
+
+const SYS_BLOCK_DIR: &str = "class/block";
+const DEV_DIR: &str = "/dev";
+
+// devices that never hold the migration files, loop devices are kept if they are the flash target
+const SKIP_DEVICES: &[&str] = &["ram", "zram", "loop", "sr", "fd"];
+
+const PROBE_SIZE: usize = 2048;
+
+const EXT_SB_OFFSET: usize = 1024;
+const EXT_MAGIC: u16 = 0xEF53;
+const EXT_COMPAT_HAS_JOURNAL: u32 = 0x0004;
+const EXT_INCOMPAT_EXT4: u32 = 0x0040 | 0x0080 | 0x0200;
+
+const FAT_NO_LABEL: &str = "NO NAME";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct FsInfo {
+    pub fstype: Option<&'static str>,
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BlockDevice {
+    pub device: PathBuf,
+    // the drive containing the partition, the device itself for unpartitioned drives
+    pub drive: PathBuf,
+    // partition number, start in 512 byte sectors
+    pub partition: Option<(u32, u64)>,
+}
+
+impl BlockDevice {
+    pub fn get_fs_info(&self) -> FsInfo {
+        get_fs_info(&self.device)
+    }
+
+    // PARTUUID as reported by blkid, derived from the drive's partition table
+    pub fn get_partuuid(&self) -> Option<String> {
+        let (number, start) = self.partition?;
+        let mut disk = match Disk::from_drive_file(&self.drive, None) {
+            Ok(disk) => disk,
+            Err(why) => {
+                debug!(
+                    "Failed to open drive '{}', error: {:?}",
+                    self.drive.display(),
+                    why
+                );
+                return None;
+            }
+        };
+
+        let mut part_iter = match PartitionIterator::new(&mut disk) {
+            Ok(part_iter) => part_iter,
+            Err(why) => {
+                debug!(
+                    "Failed to read partition table from '{}', error: {:?}",
+                    self.drive.display(),
+                    why
+                );
+                return None;
+            }
+        };
+
+        if part_iter.get_disk_guid().is_some() {
+            part_iter
+                .find(|part| part.start_lba == start)
+                .and_then(|part| part.gpt_info)
+                .map(|gpt_info| gpt_info.part_guid.to_string().to_lowercase())
+        } else {
+            part_iter
+                .get_disk_id()
+                .map(|disk_id| format!("{:08x}-{:02x}", disk_id, number))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct BlockDevices {
+    sys_root: PathBuf,
+    dev_root: PathBuf,
+    // the configured flash target
+    target: Option<PathBuf>,
+}
+
+impl BlockDevices {
+    pub fn new() -> BlockDevices {
+        BlockDevices::from_roots("/sys", DEV_DIR)
+    }
+
+    fn from_roots<P1: AsRef<Path>, P2: AsRef<Path>>(sys_root: P1, dev_root: P2) -> BlockDevices {
+        BlockDevices {
+            sys_root: sys_root.as_ref().to_path_buf(),
+            dev_root: dev_root.as_ref().to_path_buf(),
+            target: None,
+        }
+    }
+
+    // keep the flash target and its partitions when scanning, even if it is a loop device
+    pub fn with_target(mut self, target: &Path) -> BlockDevices {
+        let target = self
+            .resolve(target)
+            .unwrap_or_else(|| self.to_dev_path(target));
+        self.target = Some(target);
+        self
+    }
+
+    // all devices that might contain a file system, drives with partitions are left out
+    pub fn get_devices(&self) -> Result<Vec<BlockDevice>, MigError> {
+        let sys_block = path_append(&self.sys_root, SYS_BLOCK_DIR);
+        let mut devices: Vec<BlockDevice> = Vec::new();
+        for entry in read_dir(&sys_block).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to list directory '{}'", sys_block.display()),
+        ))? {
+            let entry = entry.context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed to read entry from '{}'", sys_block.display()),
+            ))?;
+
+            let name = entry.file_name().to_string_lossy().to_string();
+            let skip = SKIP_DEVICES.iter().any(|skip| name.starts_with(skip));
+            if skip && self.target.is_none() {
+                trace!("get_devices: skipping '{}'", name);
+                continue;
+            }
+
+            if let Some(device) = self.get_device(&name) {
+                if skip && !self.is_target(&device) {
+                    trace!("get_devices: skipping '{}'", name);
+                    continue;
+                }
+                devices.push(device);
+            }
+        }
+
+        let drives: Vec<PathBuf> = devices
+            .iter()
+            .filter(|device| device.partition.is_some())
+            .map(|device| device.drive.clone())
+            .collect();
+
+        devices.retain(|device| device.partition.is_some() || !drives.contains(&device.drive));
+        devices.sort_by(|dev1, dev2| dev1.device.cmp(&dev2.device));
+        Ok(devices)
+    }
+
+    // the drive a partition belongs to
+    pub fn get_drive(&self, device: &Path) -> Option<PathBuf> {
+        let name = device.file_name()?.to_string_lossy();
+        self.get_device(&name).map(|device| device.drive)
+    }
+
+    // find the device a path refers to, either directly or through a by-uuid, by-partuuid or
+    // by-label link
+    pub fn resolve(&self, device: &Path) -> Option<PathBuf> {
+        let dev_path = self.to_dev_path(device);
+        if file_exists(&dev_path) {
+            return match dev_path.canonicalize() {
+                Ok(dev_path) => Some(dev_path),
+                Err(why) => {
+                    warn!(
+                        "Failed to canonicalize path '{}', error: {:?}",
+                        dev_path.display(),
+                        why
+                    );
+                    None
+                }
+            };
+        }
+
+        let (id_type, id) = if let Ok(id) = device.strip_prefix(DISK_BY_UUID_PATH) {
+            ("UUID", id.to_string_lossy())
+        } else if let Ok(id) = device.strip_prefix(DISK_BY_PARTUUID_PATH) {
+            ("PARTUUID", id.to_string_lossy())
+        } else if let Ok(id) = device.strip_prefix(DISK_BY_LABEL_PATH) {
+            ("LABEL", id.to_string_lossy())
+        } else {
+            return None;
+        };
+
+        debug!(
+            "resolve: '{}' not found, scanning block devices for {}={}",
+            device.display(),
+            id_type,
+            id
+        );
+
+        let devices = match self.get_devices() {
+            Ok(devices) => devices,
+            Err(why) => {
+                warn!("Failed to scan block devices, error: {:?}", why);
+                return None;
+            }
+        };
+
+        devices
+            .into_iter()
+            .find(|blk_device| {
+                let found = match id_type {
+                    "UUID" => blk_device.get_fs_info().uuid,
+                    "PARTUUID" => blk_device.get_partuuid(),
+                    _ => blk_device.get_fs_info().label,
+                };
+                if let Some(found) = found {
+                    if id_type == "LABEL" {
+                        found == id
+                    } else {
+                        found.eq_ignore_ascii_case(&id)
+                    }
+                } else {
+                    false
+                }
+            })
+            .map(|blk_device| {
+                debug!(
+                    "resolve: found {}={} on '{}'",
+                    id_type,
+                    id,
+                    blk_device.device.display()
+                );
+                blk_device.device
+            })
+    }
+
+    fn is_target(&self, device: &BlockDevice) -> bool {
+        if let Some(ref target) = self.target {
+            target.file_name() == device.drive.file_name()
+        } else {
+            false
+        }
+    }
+
+    fn to_dev_path(&self, device: &Path) -> PathBuf {
+        if let Ok(rel_path) = device.strip_prefix(DEV_DIR) {
+            path_append(&self.dev_root, rel_path)
+        } else {
+            device.to_path_buf()
+        }
+    }
+
+    fn get_device(&self, name: &str) -> Option<BlockDevice> {
+        let sys_path = path_append(path_append(&self.sys_root, SYS_BLOCK_DIR), name);
+        let device = path_append(&self.dev_root, name);
+
+        let number = match read_to_string(path_append(&sys_path, "partition")) {
+            Ok(number) => number.trim().parse::<u32>().ok()?,
+            Err(_) => {
+                return if sys_path.exists() {
+                    Some(BlockDevice {
+                        drive: device.clone(),
+                        device,
+                        partition: None,
+                    })
+                } else {
+                    debug!("get_device: no sysfs entry found for '{}'", name);
+                    None
+                };
+            }
+        };
+
+        let start = read_to_string(path_append(&sys_path, "start"))
+            .ok()?
+            .trim()
+            .parse::<u64>()
+            .ok()?;
+
+        // partitions are listed below their drive in sysfs
+        let drive_name = sys_path
+            .canonicalize()
+            .ok()?
+            .parent()?
+            .file_name()?
+            .to_string_lossy()
+            .to_string();
+
+        Some(BlockDevice {
+            device,
+            drive: path_append(&self.dev_root, drive_name),
+            partition: Some((number, start)),
+        })
+    }
+}
+
+// file system type, UUID and label of a device
+pub(crate) fn get_fs_info(device: &Path) -> FsInfo {
+    let mut buffer = [0u8; PROBE_SIZE];
+    match File::open(device) {
+        Ok(mut file) => {
+            if let Err(why) = file.read_exact(&mut buffer) {
+                debug!(
+                    "Failed to read superblock from '{}', error: {:?}",
+                    device.display(),
+                    why
+                );
+                return FsInfo::default();
+            }
+            probe_fs(&buffer)
+        }
+        Err(why) => {
+            debug!(
+                "Failed to open '{}' for reading, error: {:?}",
+                device.display(),
+                why
+            );
+            FsInfo::default()
+        }
+    }
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+    u16::from(buffer[offset]) | (u16::from(buffer[offset + 1]) << 8)
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    u32::from(read_u16(buffer, offset)) | (u32::from(read_u16(buffer, offset + 2)) << 16)
+}
+
+fn to_label(bytes: &[u8]) -> Option<String> {
+    let label = String::from_utf8_lossy(bytes)
+        .trim_end_matches(&['\0', ' '][..])
+        .to_string();
+    if label.is_empty() {
+        None
+    } else {
+        Some(label)
+    }
+}
+
+// file system type, UUID and label from the start of a device
+fn probe_fs(buffer: &[u8]) -> FsInfo {
+    let ext_sb = &buffer[EXT_SB_OFFSET..];
+    if read_u16(ext_sb, 56) == EXT_MAGIC {
+        let fstype = if read_u32(ext_sb, 96) & EXT_INCOMPAT_EXT4 != 0 {
+            "ext4"
+        } else if read_u32(ext_sb, 92) & EXT_COMPAT_HAS_JOURNAL != 0 {
+            "ext3"
+        } else {
+            "ext2"
+        };
+
+        let u = &ext_sb[104..120];
+        return FsInfo {
+            fstype: Some(fstype),
+            uuid: Some(format!(
+                "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+                u[0], u[1], u[2], u[3], u[4], u[5], u[6], u[7], u[8], u[9], u[10], u[11], u[12], u[13],
+                u[14], u[15]
+            )),
+            label: to_label(&ext_sb[120..136]),
+        };
+    }
+
+    if buffer[510] == 0x55 && buffer[511] == 0xAA {
+        // FAT32 and FAT12/16 keep the volume id & label at different offsets
+        let (id_offset, label_offset) = if &buffer[82..87] == b"FAT32" {
+            (67, 71)
+        } else if &buffer[54..57] == b"FAT" {
+            (39, 43)
+        } else if &buffer[3..7] == b"NTFS" {
+            let serial = &buffer[72..80];
+            return FsInfo {
+                fstype: Some("ntfs"),
+                uuid: Some(
+                    serial
+                        .iter()
+                        .rev()
+                        .map(|byte| format!("{:02X}", byte))
+                        .collect(),
+                ),
+                label: None,
+            };
+        } else {
+            return FsInfo::default();
+        };
+
+        let vol_id = read_u32(buffer, id_offset);
+        let label = to_label(&buffer[label_offset..label_offset + 11])
+            .filter(|label| label.as_str() != FAT_NO_LABEL);
+        return FsInfo {
+            fstype: Some("vfat"),
+            uuid: Some(format!("{:04X}-{:04X}", vol_id >> 16, vol_id & 0xFFFF)),
+            label,
+        };
+    }
+
+    FsInfo::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::os::unix::fs::symlink;
+
+    const DISK_ID: u32 = 0x1234_abcd;
+    const EXT_UUID: &str = "01020304-0506-0708-090a-0b0c0d0e0f10";
+
+    // drive sda with an MBR and two partitions, sdb without partitions, loop0, loop1 with a
+    // partition and udev links for sdb only
+    fn make_tree(name: &str) -> PathBuf {
+        let root = path_append(std::env::temp_dir(), name);
+        let _res = remove_dir_all(&root);
+        let sys_devices = path_append(&root, "sys/devices/block");
+        let sys_block = path_append(&root, "sys/class/block");
+        let dev = path_append(&root, "dev");
+        create_dir_all(&sys_block).unwrap();
+        create_dir_all(path_append(&dev, "disk/by-uuid")).unwrap();
+
+        for (name, parent, part) in &[
+            ("sda", None, None),
+            ("sda1", Some("sda"), Some((1, 2048))),
+            ("sda2", Some("sda"), Some((2, 4096))),
+            ("sdb", None, None),
+            ("loop0", None, None),
+            ("loop1", None, None),
+            ("loop1p1", Some("loop1"), Some((1, 2048))),
+        ] {
+            let sys_path = if let Some(parent) = parent {
+                path_append(path_append(&sys_devices, parent), name)
+            } else {
+                path_append(&sys_devices, name)
+            };
+            create_dir_all(&sys_path).unwrap();
+            if let Some((number, start)) = part {
+                write(path_append(&sys_path, "partition"), format!("{}\n", number)).unwrap();
+                write(path_append(&sys_path, "start"), format!("{}\n", start)).unwrap();
+            }
+            symlink(&sys_path, path_append(&sys_block, name)).unwrap();
+        }
+
+        let mut mbr = vec![0u8; 512];
+        mbr[440..444].copy_from_slice(&DISK_ID.to_le_bytes());
+        mbr[450] = 0x83;
+        mbr[510] = 0x55;
+        mbr[511] = 0xAA;
+        write(path_append(&dev, "sda"), &mbr).unwrap();
+
+        // ext4 with a label
+        let mut ext = vec![0u8; PROBE_SIZE];
+        ext[1024 + 56..1024 + 58].copy_from_slice(&EXT_MAGIC.to_le_bytes());
+        ext[1024 + 96] = 0x40;
+        for (idx, byte) in ext[1024 + 104..1024 + 120].iter_mut().enumerate() {
+            *byte = idx as u8 + 1;
+        }
+        ext[1024 + 120..1024 + 132].copy_from_slice(b"resin-rootA\0");
+        write(path_append(&dev, "sda2"), &ext).unwrap();
+
+        // FAT32 without a label
+        let mut fat = vec![0u8; PROBE_SIZE];
+        fat[67..71].copy_from_slice(&0xA1B2_C3D4u32.to_le_bytes());
+        fat[71..82].copy_from_slice(b"NO NAME    ");
+        fat[82..90].copy_from_slice(b"FAT32   ");
+        fat[510] = 0x55;
+        fat[511] = 0xAA;
+        write(path_append(&dev, "sda1"), &fat).unwrap();
+
+        write(path_append(&dev, "sdb"), &vec![0u8; PROBE_SIZE]).unwrap();
+        symlink("../../sdb", path_append(&dev, "disk/by-uuid/sdb-uuid")).unwrap();
+        root
+    }
+
+    #[test]
+    fn scan_devices() {
+        let root = make_tree("balena-migrate-blkdev-scan-test");
+        let dev = path_append(&root, "dev");
+        let blk_devices = BlockDevices::from_roots(path_append(&root, "sys"), &dev);
+
+        let devices = blk_devices.get_devices().unwrap();
+        let names: Vec<PathBuf> = devices.iter().map(|dev| dev.device.clone()).collect();
+        assert_eq!(
+            names,
+            vec![
+                path_append(&dev, "sda1"),
+                path_append(&dev, "sda2"),
+                path_append(&dev, "sdb")
+            ]
+        );
+        assert_eq!(devices[1].drive, path_append(&dev, "sda"));
+        assert_eq!(devices[1].partition, Some((2, 4096)));
+        assert_eq!(devices[2].drive, path_append(&dev, "sdb"));
+
+        assert_eq!(
+            devices[0].get_fs_info(),
+            FsInfo {
+                fstype: Some("vfat"),
+                uuid: Some(String::from("A1B2-C3D4")),
+                label: None,
+            }
+        );
+        assert_eq!(
+            devices[1].get_fs_info(),
+            FsInfo {
+                fstype: Some("ext4"),
+                uuid: Some(String::from(EXT_UUID)),
+                label: Some(String::from("resin-rootA")),
+            }
+        );
+        assert_eq!(devices[2].get_fs_info(), FsInfo::default());
+        assert_eq!(devices[1].get_partuuid(), Some(String::from("1234abcd-02")));
+        assert_eq!(devices[2].get_partuuid(), None);
+
+        assert_eq!(
+            blk_devices.get_drive(Path::new("/dev/sda1")),
+            Some(path_append(&dev, "sda"))
+        );
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn scan_loop_target() {
+        let root = make_tree("balena-migrate-blkdev-loop-test");
+        let dev = path_append(&root, "dev");
+        let blk_devices = BlockDevices::from_roots(path_append(&root, "sys"), &dev)
+            .with_target(Path::new("/dev/loop1"));
+
+        let names: Vec<PathBuf> = blk_devices
+            .get_devices()
+            .unwrap()
+            .into_iter()
+            .map(|dev| dev.device)
+            .collect();
+        // loop0 is not the target
+        assert_eq!(
+            names,
+            vec![
+                path_append(&dev, "loop1p1"),
+                path_append(&dev, "sda1"),
+                path_append(&dev, "sda2"),
+                path_append(&dev, "sdb")
+            ]
+        );
+        remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn resolve_devices() {
+        let root = make_tree("balena-migrate-blkdev-resolve-test");
+        let dev = path_append(&root, "dev").canonicalize().unwrap();
+        let blk_devices = BlockDevices::from_roots(path_append(&root, "sys"), &dev);
+
+        // udev link and plain device
+        assert_eq!(
+            blk_devices.resolve(&path_append(DISK_BY_UUID_PATH, "sdb-uuid")),
+            Some(path_append(&dev, "sdb"))
+        );
+        assert_eq!(
+            blk_devices.resolve(Path::new("/dev/sda1")),
+            Some(path_append(&dev, "sda1"))
+        );
+
+        // missing links are found by scanning
+        assert_eq!(
+            blk_devices.resolve(&path_append(DISK_BY_UUID_PATH, EXT_UUID.to_uppercase())),
+            Some(path_append(&dev, "sda2"))
+        );
+        assert_eq!(
+            blk_devices.resolve(&path_append(DISK_BY_UUID_PATH, "a1b2-c3d4")),
+            Some(path_append(&dev, "sda1"))
+        );
+        assert_eq!(
+            blk_devices.resolve(&path_append(DISK_BY_PARTUUID_PATH, "1234ABCD-01")),
+            Some(path_append(&dev, "sda1"))
+        );
+        assert_eq!(
+            blk_devices.resolve(&path_append(DISK_BY_LABEL_PATH, "resin-rootA")),
+            Some(path_append(&dev, "sda2"))
+        );
+        assert_eq!(
+            blk_devices.resolve(&path_append(DISK_BY_LABEL_PATH, "resin-rootB")),
+            None
+        );
+        assert_eq!(blk_devices.resolve(Path::new("/dev/sdc1")), None);
+        remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,11 +1,12 @@
 // TODO: flash image using DD
 
-use flate2::read::GzDecoder;
-use log::{debug, error, info};
-use mod_logger::Logger;
+use digest::Digest;
+use failure::ResultExt;
+use log::{debug, error, info, warn};
+use md5::Md5;
 use nix::unistd::sync;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
+use std::fs::File;
+use std::io::Read;
 use std::path::Path;
 use std::process::{Command, Stdio};
 use std::str;
@@ -13,14 +14,27 @@ use std::thread;
 use std::time::{Duration, Instant};
 
 use crate::{
-    common::{call, format_size_with_unit, stage2_config::Stage2Config},
+    common::{
+        bmap::{to_hex_string, BlockMap},
+        call,
+        disk_util::Compression,
+        format_size_with_unit,
+        stage2_config::Stage2Config,
+        staging::StagedFile,
+        MigErrCtx, MigError, MigErrorKind,
+    },
     linux::{
-        linux_defs::{DD_CMD, GZIP_CMD, PARTPROBE_CMD, UDEVADM_CMD},
+        linux_defs::{DD_CMD, GZIP_CMD, PARTPROBE_CMD, UDEVADM_CMD, UNZIP_CMD, XZ_CMD, ZSTD_CMD},
         linux_defs::{POST_PARTPROBE_WAIT_SECS, PRE_PARTPROBE_WAIT_SECS},
         stage2::{mounts::Mounts, FlashResult},
     },
 };
 
+mod target_writer;
+use target_writer::{discard_target, drop_cache, Discard, TargetWriter, WriteMode};
+
+use super::progress::{Phase, Progress};
+
 // TODO: minimum recommended size 128K
 const DD_BLOCK_SIZE: usize = 128 * 1024; // 4_194_304;
 const UDEVADM_PARAMS: &[&str] = &["settle", "-t", "10"];
@@ -28,27 +42,336 @@ const UDEVADM_PARAMS: &[&str] = &["settle", "-t", "10"];
 // TODO: replace removed command checks ?
 //const REQUIRED_CMDS: &[&str] = &[DD_CMD, PARTPROBE_CMD, UDEVADM_CMD];
 
+// the image is read from a file or from its extents on the flash device
+pub(crate) enum ImageSource<'a> {
+    File(&'a Path),
+    Staged(&'a StagedFile, &'a Path),
+}
+
+impl<'a> ImageSource<'a> {
+    fn get_descr(&self) -> String {
+        match self {
+            ImageSource::File(path) => path.display().to_string(),
+            ImageSource::Staged(staged, device) => format!(
+                "{} staged on {}",
+                staged.rel_path.display(),
+                device.display()
+            ),
+        }
+    }
+
+    fn get_compression(&self) -> Result<Compression, MigError> {
+        match self {
+            ImageSource::File(path) => Compression::from_file(path),
+            ImageSource::Staged(staged, device) => {
+                Compression::from_reader(&mut staged.open_reader(device)?, &self.get_descr())
+            }
+        }
+    }
+
+    fn open_reader(&self, compression: Compression) -> Result<Box<dyn Read>, MigError> {
+        match self {
+            ImageSource::File(path) => compression.open_reader(path),
+            ImageSource::Staged(staged, device) => {
+                compression.decode_reader(Box::new(staged.open_reader(device)?), &self.get_descr())
+            }
+        }
+    }
+}
+
 // TODO: return something else instead (success, (recoverable / not recoverable))
 
 pub(crate) fn flash_balena_os(
     target_path: &Path,
     mounts: &mut Mounts,
     config: &Stage2Config,
-    image_path: &Path,
+    image: &ImageSource,
+    bmap_path: Option<&Path>,
+    progress: &mut Progress,
 ) -> FlashResult {
-    let res = if config.is_gzip_internal() {
-        flash_gzip_internal(DD_CMD, target_path, image_path)
+    let compression = match image.get_compression() {
+        Ok(compression) => compression,
+        Err(why) => {
+            error!(
+                "Failed to determine the compression of image file '{}', error: {:?}",
+                image.get_descr(),
+                why
+            );
+            return FlashResult::FailRecoverable;
+        }
+    };
+
+    let staged = matches!(image, ImageSource::Staged(_, _));
+
+    // the target is discarded once the image is open, staged files live on the target
+    let mut skip_zeros = false;
+    let mut mode = if let Some(bmap_path) = bmap_path {
+        match BlockMap::from_file(bmap_path) {
+            Ok(bmap) => {
+                info!(
+                    "Using block map '{}', {} of {} are mapped",
+                    bmap_path.display(),
+                    format_size_with_unit(bmap.get_mapped_size()),
+                    format_size_with_unit(bmap.image_size)
+                );
+                WriteMode::BlockMap(bmap)
+            }
+            Err(why) => {
+                warn!(
+                    "Failed to read block map '{}', writing the full image, error: {:?}",
+                    bmap_path.display(),
+                    why
+                );
+                WriteMode::Full
+            }
+        }
+    } else if config.is_flash_skip_zeros() {
+        if staged {
+            warn!("The image is staged on the target which can not be discarded, writing the full image");
+        } else {
+            skip_zeros = true;
+        }
+        WriteMode::Full
     } else {
-        flash_gzip_external(DD_CMD, target_path, image_path)
+        WriteMode::Full
     };
 
-    sync();
+    // TODO: rename gzip_internal in config, it applies to all compression formats
+    let internal = if let (WriteMode::Full, false) = (&mode, skip_zeros) {
+        config.is_gzip_internal()
+    } else {
+        if !config.is_gzip_internal() {
+            info!(
+                "Writing {} requires internal decompression",
+                if skip_zeros {
+                    WriteMode::SkipZeros.get_descr()
+                } else {
+                    mode.get_descr()
+                }
+            );
+        }
+        true
+    };
+
+    let internal = if staged {
+        if !internal {
+            info!("Reading a staged image requires internal decompression");
+        }
+        true
+    } else {
+        internal
+    };
+
+    info!(
+        "Flashing {} image '{}', writing {}",
+        compression.get_descr(),
+        image.get_descr(),
+        if skip_zeros {
+            WriteMode::SkipZeros.get_descr()
+        } else {
+            mode.get_descr()
+        }
+    );
+
+    let max_attempts = if config.is_flash_verify() {
+        config.get_flash_retries() + 1
+    } else {
+        1
+    };
+
+    // failures are recoverable until the target has been written to or discarded
+    let mut fail_res = FlashResult::FailRecoverable;
+    let mut attempt: u32 = 0;
+    let res = loop {
+        attempt += 1;
+        if attempt > 1 {
+            fail_res = FlashResult::FailNonRecoverable;
+            warn!(
+                "Flashing the Balena OS image, attempt {} of {}",
+                attempt, max_attempts
+            );
+        }
+
+        let mut digest = if config.is_flash_verify() {
+            Some(StreamDigest::new())
+        } else {
+            None
+        };
+
+        // the uncompressed size is only known from a block map
+        progress.start_phase(
+            Phase::Flash,
+            if let WriteMode::BlockMap(ref bmap) = mode {
+                Some(bmap.image_size)
+            } else {
+                None
+            },
+        );
+
+        let verified = if internal {
+            // open the image before the target is touched, a failure leaves the target intact
+            let mut decoder = match image.open_reader(compression) {
+                Ok(decoder) => decoder,
+                Err(why) => {
+                    error!(
+                        "Failed to open image file '{}', error: {:?}",
+                        image.get_descr(),
+                        why
+                    );
+                    break fail_res;
+                }
+            };
+
+            if skip_zeros {
+                skip_zeros = false;
+                match discard_target(target_path) {
+                    Ok(Discard::Zeroed) => {
+                        fail_res = FlashResult::FailNonRecoverable;
+                        mode = WriteMode::SkipZeros;
+                    }
+                    Ok(Discard::NotZeroed) => {
+                        warn!(
+                            "The target '{}' could not be zeroed, writing the full image",
+                            target_path.display()
+                        );
+                        fail_res = FlashResult::FailNonRecoverable;
+                    }
+                    Ok(Discard::NotDiscarded) => {
+                        warn!(
+                            "The target '{}' was not discarded, writing the full image",
+                            target_path.display()
+                        );
+                    }
+                    Err(why) => {
+                        warn!(
+                            "Failed to discard '{}', writing the full image, error: {:?}",
+                            target_path.display(),
+                            why
+                        );
+                    }
+                }
+            }
+
+            let mut writer = match TargetWriter::new(target_path, &mode) {
+                Ok(writer) => writer,
+                Err(why) => {
+                    error!(
+                        "Failed to open output file '{}', error: {:?}",
+                        target_path.display(),
+                        why
+                    );
+                    break fail_res;
+                }
+            };
+
+            let res = flash_internal(
+                &mut writer,
+                &mut decoder,
+                image,
+                fail_res,
+                digest.as_mut(),
+                progress,
+            );
+
+            sync();
+            progress.finish_phase();
+
+            if let FlashResult::Ok = res {
+                if !config.is_flash_verify() {
+                    break res;
+                }
+            } else {
+                break res;
+            }
+
+            if let WriteMode::BlockMap(ref bmap) = mode {
+                progress.start_phase(Phase::Verify, Some(bmap.get_mapped_size()));
+                writer.verify_ranges(progress)
+            } else if let Some(ref mut digest) = digest {
+                verify_target(target_path, digest, progress)
+            } else {
+                break FlashResult::FailNonRecoverable;
+            }
+        } else if let ImageSource::File(image_path) = image {
+            let res = flash_external(DD_CMD, target_path, image_path, compression);
+
+            sync();
+            progress.finish_phase();
+
+            if let FlashResult::Ok = res {
+                if !config.is_flash_verify() {
+                    break res;
+                }
+            } else {
+                break res;
+            }
+
+            // external flashing does not see the uncompressed data, decompress once more
+            progress.start_phase(Phase::Verify, None);
+            match StreamDigest::from_image(image_path, compression, progress) {
+                Ok(mut digest) => verify_target(target_path, &mut digest, progress),
+                Err(why) => {
+                    error!(
+                        "Failed to compute the digest of image file '{}', error: {:?}",
+                        image_path.display(),
+                        why
+                    );
+                    break FlashResult::FailNonRecoverable;
+                }
+            }
+        } else {
+            error!("Staged images can only be flashed with internal decompression");
+            break fail_res;
+        };
+
+        progress.finish_phase();
+
+        match verified {
+            Ok(true) => {
+                info!(
+                    "Successfully verified the data written to '{}'",
+                    target_path.display()
+                );
+                break FlashResult::Ok;
+            }
+            Ok(false) => {
+                if attempt < max_attempts {
+                    warn!(
+                        "Verification of '{}' failed, retrying to flash the image",
+                        target_path.display()
+                    );
+                } else {
+                    error!(
+                        "Verification of '{}' failed after {} attempt(s), giving up",
+                        target_path.display(),
+                        attempt
+                    );
+                    break FlashResult::FailNonRecoverable;
+                }
+            }
+            Err(why) => {
+                error!(
+                    "Failed to verify the data written to '{}', error: {:?}",
+                    target_path.display(),
+                    why
+                );
+                break FlashResult::FailNonRecoverable;
+            }
+        }
+    };
+
+    // a failed attempt leaves nothing to mount
+    if res != FlashResult::Ok {
+        return res;
+    }
 
     info!(
         "The Balena OS image has been written to the device '{}'",
         target_path.display()
     );
 
+    progress.start_phase(Phase::Partprobe, None);
+
     thread::sleep(Duration::from_secs(PRE_PARTPROBE_WAIT_SECS));
 
     let _res = call(PARTPROBE_CMD, &[&target_path.to_string_lossy()], true);
@@ -57,28 +380,27 @@ pub(crate) fn flash_balena_os(
 
     let _res = call(UDEVADM_CMD, UDEVADM_PARAMS, true);
 
+    progress.finish_phase();
+
+    progress.start_phase(Phase::Mount, None);
     if let Err(why) = mounts.mount_balena(false) {
         error!("Failed to mount balena partitions, error: {:?}", why);
         return FlashResult::FailNonRecoverable;
     }
+    progress.finish_phase();
 
-    res
+    FlashResult::Ok
 }
 
-fn flash_gzip_internal(_dd_cmd: &str, target_path: &Path, image_path: &Path) -> FlashResult {
-    debug!("opening: '{}'", image_path.display());
-
-    let mut decoder = GzDecoder::new(match File::open(&image_path) {
-        Ok(file) => file,
-        Err(why) => {
-            error!(
-                "Failed to open image file '{}', error: {:?}",
-                image_path.display(),
-                why
-            );
-            return FlashResult::FailRecoverable;
-        }
-    });
+fn flash_internal(
+    writer: &mut TargetWriter,
+    decoder: &mut dyn Read,
+    image: &ImageSource,
+    mut fail_res: FlashResult,
+    mut digest: Option<&mut StreamDigest>,
+    progress: &mut Progress,
+) -> FlashResult {
+    debug!("flashing: '{}'", image.get_descr());
 
     /* debug!("invoking dd");
 
@@ -103,29 +425,9 @@ fn flash_gzip_internal(_dd_cmd: &str, target_path: &Path, image_path: &Path) ->
     };
     */
 
-    debug!("opening output file '{}", target_path.display());
-    let mut out_file = match OpenOptions::new()
-        .write(true)
-        .read(false)
-        .create(false)
-        .open(&target_path)
-    {
-        Ok(file) => file,
-        Err(why) => {
-            error!(
-                "Failed to open output file '{}', error: {:?}",
-                target_path.display(),
-                why
-            );
-            return FlashResult::FailRecoverable;
-        }
-    };
-
     let start_time = Instant::now();
-    let mut last_elapsed = Duration::new(0, 0);
-    let mut write_count: usize = 0;
+    let mut stream_pos: u64 = 0;
 
-    let mut fail_res = FlashResult::FailRecoverable;
     // TODO: might pay to put buffer on page boundary
     let mut buffer: [u8; DD_BLOCK_SIZE] = [0; DD_BLOCK_SIZE];
     loop {
@@ -137,7 +439,7 @@ fn flash_gzip_internal(_dd_cmd: &str, target_path: &Path, image_path: &Path) ->
                 Err(why) => {
                     error!(
                         "Failed to read uncompressed data from '{}', error: {:?}",
-                        image_path.display(),
+                        image.get_descr(),
                         why
                     );
                     return fail_res;
@@ -156,40 +458,21 @@ fn flash_gzip_internal(_dd_cmd: &str, target_path: &Path, image_path: &Path) ->
         if buff_fill > 0 {
             fail_res = FlashResult::FailNonRecoverable;
 
-            let bytes_written = match out_file.write(&buffer[0..buff_fill]) {
-                Ok(bytes_written) => bytes_written,
-                Err(why) => {
-                    error!("Failed to write uncompressed data to dd, error {:?}", why);
-                    return fail_res;
-                }
-            };
-
-            write_count += bytes_written;
-
-            if buff_fill != bytes_written {
-                error!(
-                    "Read/write count mismatch, read {}, wrote {}",
-                    buff_fill, bytes_written
-                );
+            if let Err(why) = writer.write_at(stream_pos, &buffer[0..buff_fill]) {
+                error!("Failed to write uncompressed data, error {:?}", why);
                 return fail_res;
             }
 
-            let curr_elapsed = start_time.elapsed();
-            let since_last = match curr_elapsed.checked_sub(last_elapsed) {
-                Some(dur) => dur,
-                None => Duration::from_secs(0),
-            };
+            if let Some(ref mut digest) = digest {
+                digest.input(&buffer[0..buff_fill]);
+            }
 
-            if since_last.as_secs() >= 10 {
-                last_elapsed = curr_elapsed;
-                let secs_elapsed = curr_elapsed.as_secs();
-                info!(
-                    "{} written @ {}/sec in {} seconds",
-                    format_size_with_unit(write_count as u64),
-                    format_size_with_unit(write_count as u64 / secs_elapsed),
-                    secs_elapsed
-                );
-                Logger::flush();
+            stream_pos += buff_fill as u64;
+            progress.update(stream_pos);
+
+            // the rest of the image is not mapped
+            if writer.is_complete() {
+                break;
             }
 
             if buff_fill < buffer.len() {
@@ -224,58 +507,224 @@ fn flash_gzip_internal(_dd_cmd: &str, target_path: &Path, image_path: &Path) ->
     }
     */
 
+    if let Err(why) = writer.finish() {
+        error!("Failed to complete writing the image, error: {:?}", why);
+        return fail_res;
+    }
+
     let secs_elapsed = start_time.elapsed().as_secs();
     info!(
-        "{} written @ {}/sec in {} seconds",
-        format_size_with_unit(write_count as u64),
-        format_size_with_unit(write_count as u64 / secs_elapsed),
+        "{} processed, {} written @ {}/sec in {} seconds",
+        format_size_with_unit(stream_pos),
+        format_size_with_unit(writer.get_write_count()),
+        format_size_with_unit(stream_pos / secs_elapsed.max(1)),
         secs_elapsed
     );
 
     FlashResult::Ok
 }
 
-fn flash_gzip_external(dd_cmd: &str, target_path: &Path, image_path: &Path) -> FlashResult {
-    let gzip_child = match Command::new(GZIP_CMD)
-        .args(&["-d", "-c", &image_path.to_string_lossy()])
+// *************************************************************************************************
+// * Verify the data written to the target by reading it back after sync.
+// * The page cache of the target is dropped before so the data is actually read from the device.
+// *************************************************************************************************
+
+struct StreamDigest {
+    hasher: Md5,
+    size: u64,
+    hex_digest: String,
+}
+
+impl StreamDigest {
+    fn new() -> StreamDigest {
+        StreamDigest {
+            hasher: Md5::new(),
+            size: 0,
+            hex_digest: String::new(),
+        }
+    }
+
+    // digest of the uncompressed contents of image_path
+    fn from_image(
+        image_path: &Path,
+        compression: Compression,
+        progress: &mut Progress,
+    ) -> Result<StreamDigest, MigError> {
+        let mut digest = StreamDigest::new();
+        digest.input_from(
+            &mut compression.open_reader(image_path)?,
+            None,
+            image_path,
+            progress,
+        )?;
+        Ok(digest)
+    }
+
+    fn input(&mut self, data: &[u8]) {
+        self.hasher.input(data);
+        self.size += data.len() as u64;
+    }
+
+    // digest the data from reader, up to max_size bytes if given
+    fn input_from(
+        &mut self,
+        reader: &mut dyn Read,
+        max_size: Option<u64>,
+        path: &Path,
+        progress: &mut Progress,
+    ) -> Result<(), MigError> {
+        let mut buffer: Vec<u8> = vec![0; DD_BLOCK_SIZE];
+        loop {
+            let to_read = if let Some(max_size) = max_size {
+                ((max_size - self.size) as usize).min(buffer.len())
+            } else {
+                buffer.len()
+            };
+
+            if to_read == 0 {
+                break;
+            }
+
+            let bytes_read =
+                reader
+                    .read(&mut buffer[0..to_read])
+                    .context(MigErrCtx::from_remark(
+                        MigErrorKind::Upstream,
+                        &format!("Failed to read from '{}'", path.display()),
+                    ))?;
+
+            if bytes_read == 0 {
+                break;
+            }
+            self.input(&buffer[0..bytes_read]);
+            progress.update(self.size);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> &str {
+        if self.hex_digest.is_empty() {
+            self.hex_digest = to_hex_string(&self.hasher.result_reset());
+        }
+        &self.hex_digest
+    }
+}
+
+fn verify_target(
+    target_path: &Path,
+    expected: &mut StreamDigest,
+    progress: &mut Progress,
+) -> Result<bool, MigError> {
+    debug!(
+        "verify_target: reading back {} bytes from '{}'",
+        expected.size,
+        target_path.display()
+    );
+
+    let mut target = File::open(target_path).context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to open '{}' for reading", target_path.display()),
+    ))?;
+
+    drop_cache(&target, target_path);
+
+    progress.start_phase(Phase::Verify, Some(expected.size));
+    let start_time = Instant::now();
+    let mut computed = StreamDigest::new();
+    computed.input_from(&mut target, Some(expected.size), target_path, progress)?;
+
+    if computed.size != expected.size {
+        error!(
+            "Could only read {} of {} bytes back from '{}'",
+            computed.size,
+            expected.size,
+            target_path.display()
+        );
+        return Ok(false);
+    }
+
+    let res = computed.finish() == expected.finish();
+    info!(
+        "Read back {} from '{}' in {} seconds, md5 written: {}, md5 read: {}",
+        format_size_with_unit(computed.size),
+        target_path.display(),
+        start_time.elapsed().as_secs(),
+        expected.finish(),
+        computed.finish()
+    );
+    Ok(res)
+}
+
+fn flash_external(
+    dd_cmd: &str,
+    target_path: &Path,
+    image_path: &Path,
+    compression: Compression,
+) -> FlashResult {
+    let image_path_str = image_path.to_string_lossy();
+    let (decomp_cmd, decomp_args) = match compression {
+        Compression::GZip => (GZIP_CMD, vec!["-d", "-c", &image_path_str]),
+        Compression::Xz => (XZ_CMD, vec!["-d", "-c", &image_path_str]),
+        Compression::Zstd => (ZSTD_CMD, vec!["-d", "-c", &image_path_str]),
+        Compression::Zip => (UNZIP_CMD, vec!["-p", &image_path_str]),
+        Compression::None => {
+            // nothing to decompress, let dd read the image directly
+            return run_dd(
+                Command::new(dd_cmd).args(&[
+                    &format!("if={}", &image_path_str),
+                    &format!("of={}", &target_path.to_string_lossy()),
+                    &format!("bs={}", DD_BLOCK_SIZE),
+                ]),
+                dd_cmd,
+            );
+        }
+    };
+
+    let decomp_child = match Command::new(decomp_cmd)
+        .args(&decomp_args)
         .stdout(Stdio::piped())
         .spawn()
     {
-        Ok(gzip_child) => gzip_child,
+        Ok(decomp_child) => decomp_child,
         Err(why) => {
-            error!("Failed to create gzip process, error: {:?}", why);
+            error!("Failed to create {} process, error: {:?}", decomp_cmd, why);
             return FlashResult::FailRecoverable;
         }
     };
 
-    if let Some(stdout) = gzip_child.stdout {
+    if let Some(stdout) = decomp_child.stdout {
         debug!("invoking dd");
-        match Command::new(dd_cmd)
-            .args(&[
-                &format!("of={}", &target_path.to_string_lossy()),
-                &format!("bs={}", DD_BLOCK_SIZE),
-            ])
-            .stdin(stdout)
-            .output()
-        {
-            Ok(dd_cmd_res) => {
-                if dd_cmd_res.status.success() {
-                    FlashResult::Ok
-                } else {
-                    error!(
-                        "dd terminated with exit code: {:?}",
-                        dd_cmd_res.status.code()
-                    );
-                    FlashResult::FailNonRecoverable
-                }
-            }
-            Err(why) => {
-                error!("failed to execute command {}, error: {:?}", dd_cmd, why);
-                FlashResult::FailRecoverable
-            }
-        }
+        run_dd(
+            Command::new(dd_cmd)
+                .args(&[
+                    &format!("of={}", &target_path.to_string_lossy()),
+                    &format!("bs={}", DD_BLOCK_SIZE),
+                ])
+                .stdin(stdout),
+            dd_cmd,
+        )
     } else {
-        error!("failed to retrieved gzip stdout)");
+        error!("failed to retrieved {} stdout)", decomp_cmd);
         FlashResult::FailRecoverable
     }
 }
+
+fn run_dd(dd_command: &mut Command, dd_cmd: &str) -> FlashResult {
+    match dd_command.output() {
+        Ok(dd_cmd_res) => {
+            if dd_cmd_res.status.success() {
+                FlashResult::Ok
+            } else {
+                error!(
+                    "dd terminated with exit code: {:?}",
+                    dd_cmd_res.status.code()
+                );
+                FlashResult::FailNonRecoverable
+            }
+        }
+        Err(why) => {
+            error!("failed to execute command {}, error: {:?}", dd_cmd, why);
+            FlashResult::FailRecoverable
+        }
+    }
+}
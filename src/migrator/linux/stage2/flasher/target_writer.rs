@@ -0,0 +1,525 @@
+use digest::DynDigest;
+use failure::ResultExt;
+use log::{debug, error, info, warn};
+use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+use std::fs::{read_to_string, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    common::{
+        bmap::{to_hex_string, BlockMap},
+        format_size_with_unit, MigErrCtx, MigError, MigErrorKind,
+    },
+    linux::stage2::progress::Progress,
+};
+
+// *************************************************************************************************
+// * Write the uncompressed image stream to the target.
+// * Depending on the mode all data, only blocks containing non-zero data or only the ranges listed
+// * in a block map are written. Block map ranges are checked against their digests while writing.
+// *************************************************************************************************
+
+// granularity of zero block detection
+const ZERO_BLOCK_SIZE: usize = 4096;
+
+const BLK_IOC_MAGIC: u8 = 0x12;
+const BLK_IOC_DISCARD: u8 = 119;
+const BLK_IOC_ZEROOUT: u8 = 127;
+
+ioctl_write_ptr_bad!(
+    blk_discard,
+    request_code_none!(BLK_IOC_MAGIC, BLK_IOC_DISCARD),
+    [u64; 2]
+);
+
+ioctl_write_ptr_bad!(
+    blk_zeroout,
+    request_code_none!(BLK_IOC_MAGIC, BLK_IOC_ZEROOUT),
+    [u64; 2]
+);
+
+pub(crate) enum WriteMode {
+    Full,
+    SkipZeros,
+    BlockMap(BlockMap),
+}
+
+impl WriteMode {
+    pub fn get_descr(&self) -> &'static str {
+        match self {
+            WriteMode::Full => "full image",
+            WriteMode::SkipZeros => "non-zero blocks",
+            WriteMode::BlockMap(_) => "block map ranges",
+        }
+    }
+}
+
+pub(crate) enum Discard {
+    // the target was left untouched
+    NotDiscarded,
+    // the target was discarded or partially zeroed, blocks might not read back as zeros
+    NotZeroed,
+    // the target was zeroed with BLKZEROOUT
+    Zeroed,
+}
+
+pub(crate) struct TargetWriter<'a> {
+    file: File,
+    path: PathBuf,
+    mode: &'a WriteMode,
+    // current position in file
+    position: u64,
+    write_count: u64,
+    // block map range currently written
+    range_idx: usize,
+    range_hasher: Option<Box<dyn DynDigest>>,
+    // digests of the ranges written
+    range_digests: Vec<String>,
+}
+
+// drop cached data so it is read from the device
+pub(crate) fn drop_cache(file: &File, path: &Path) {
+    if let Err(why) = posix_fadvise(
+        file.as_raw_fd(),
+        0,
+        0,
+        PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+    ) {
+        warn!(
+            "Failed to drop cached data of '{}', data might be read from cache, error: {:?}",
+            path.display(),
+            why
+        );
+    }
+}
+
+fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|byte| *byte == 0)
+}
+
+// the device zeroes ranges without writing them, BLKZEROOUT falls back to writing zeros otherwise
+fn supports_write_zeroes(path: &Path) -> bool {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let dev_name = if let Some(dev_name) = path.file_name() {
+        dev_name.to_string_lossy()
+    } else {
+        return false;
+    };
+
+    let sys_path = format!("/sys/class/block/{}/queue/write_zeroes_max_bytes", dev_name);
+    match read_to_string(&sys_path) {
+        Ok(value) => value.trim().parse::<u64>().unwrap_or(0) > 0,
+        Err(why) => {
+            debug!("Failed to read '{}', error: {:?}", sys_path, why);
+            false
+        }
+    }
+}
+
+// zero the whole target if the device can do so without writing it, discard it otherwise.
+// Discarded blocks are not guaranteed to read back as zeros, only zeroed targets allow skipping
+// zero blocks. Errors are only returned before the target was touched
+pub(crate) fn discard_target(path: &Path) -> Result<Discard, MigError> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to open '{}' for writing", path.display()),
+        ))?;
+
+    let size = file.seek(SeekFrom::End(0)).context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to determine the size of '{}'", path.display()),
+    ))?;
+
+    let range: [u64; 2] = [0, size];
+
+    if supports_write_zeroes(path) {
+        debug!(
+            "discard_target: zeroing {} on '{}'",
+            format_size_with_unit(size),
+            path.display()
+        );
+
+        match unsafe { blk_zeroout(file.as_raw_fd(), &range) } {
+            Ok(_) => {
+                drop_cache(&file, path);
+                info!(
+                    "Zeroed {} on '{}'",
+                    format_size_with_unit(size),
+                    path.display()
+                );
+                return Ok(Discard::Zeroed);
+            }
+            Err(why) => {
+                // the target might have been partially zeroed
+                warn!("Failed to zero '{}', error: {:?}", path.display(), why);
+            }
+        }
+    } else {
+        debug!(
+            "discard_target: '{}' does not support write zeroes",
+            path.display()
+        );
+    }
+
+    debug!(
+        "discard_target: discarding {} on '{}'",
+        format_size_with_unit(size),
+        path.display()
+    );
+
+    if let Err(why) = unsafe { blk_discard(file.as_raw_fd(), &range) } {
+        warn!("Failed to discard '{}', error: {:?}", path.display(), why);
+        return Ok(Discard::NotDiscarded);
+    }
+
+    drop_cache(&file, path);
+    info!(
+        "Discarded {} on '{}'",
+        format_size_with_unit(size),
+        path.display()
+    );
+    Ok(Discard::NotZeroed)
+}
+
+impl<'a> TargetWriter<'a> {
+    pub fn new(path: &Path, mode: &'a WriteMode) -> Result<TargetWriter<'a>, MigError> {
+        debug!("opening output file '{}", path.display());
+        let file = OpenOptions::new()
+            .write(true)
+            .read(false)
+            .create(false)
+            .open(path)
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed to open output file '{}'", path.display()),
+            ))?;
+
+        Ok(TargetWriter {
+            file,
+            path: path.to_path_buf(),
+            mode,
+            position: 0,
+            write_count: 0,
+            range_idx: 0,
+            range_hasher: None,
+            range_digests: Vec::new(),
+        })
+    }
+
+    pub fn get_write_count(&self) -> u64 {
+        self.write_count
+    }
+
+    // no more data needs to be written
+    pub fn is_complete(&self) -> bool {
+        if let WriteMode::BlockMap(ref bmap) = self.mode {
+            self.range_idx >= bmap.ranges.len()
+        } else {
+            false
+        }
+    }
+
+    // write data found at offset in the image stream
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), MigError> {
+        match self.mode {
+            WriteMode::Full => self.write_data(offset, data),
+            WriteMode::SkipZeros => self.write_non_zero(offset, data),
+            WriteMode::BlockMap(ref bmap) => self.write_ranges(bmap, offset, data),
+        }
+    }
+
+    pub fn finish(&mut self) -> Result<(), MigError> {
+        if let WriteMode::BlockMap(ref bmap) = self.mode {
+            if self.range_idx < bmap.ranges.len() {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvState,
+                    &format!(
+                        "The image ended before all block map ranges were written, {} of {} written",
+                        self.range_idx,
+                        bmap.ranges.len()
+                    ),
+                ));
+            }
+        }
+
+        self.file.sync_all().context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to sync '{}'", self.path.display()),
+        ))?;
+        Ok(())
+    }
+
+    // read back the block map ranges and compare them to the digests computed while writing
+    pub fn verify_ranges(&self, progress: &mut Progress) -> Result<bool, MigError> {
+        let bmap = if let WriteMode::BlockMap(ref bmap) = self.mode {
+            bmap
+        } else {
+            return Err(MigError::from_remark(
+                MigErrorKind::InvState,
+                "verify_ranges: no block map in use",
+            ));
+        };
+
+        let mut file = File::open(&self.path).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to open '{}' for reading", self.path.display()),
+        ))?;
+
+        drop_cache(&file, &self.path);
+
+        let mut buffer: Vec<u8> = vec![0; bmap.block_size as usize];
+        let mut verified: u64 = 0;
+        for (range, expected) in bmap.ranges.iter().zip(self.range_digests.iter()) {
+            let (start, end) = bmap.get_range_bytes(range);
+            file.seek(SeekFrom::Start(start))
+                .context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!(
+                        "Failed to seek to offset {} in '{}'",
+                        start,
+                        self.path.display()
+                    ),
+                ))?;
+
+            let mut hasher = bmap.checksum_type.new_hasher();
+            let mut pos = start;
+            while pos < end {
+                let to_read = ((end - pos) as usize).min(buffer.len());
+                file.read_exact(&mut buffer[0..to_read])
+                    .context(MigErrCtx::from_remark(
+                        MigErrorKind::Upstream,
+                        &format!(
+                            "Failed to read {} bytes at offset {} from '{}'",
+                            to_read,
+                            pos,
+                            self.path.display()
+                        ),
+                    ))?;
+                hasher.input(&buffer[0..to_read]);
+                pos += to_read as u64;
+                verified += to_read as u64;
+                progress.update(verified);
+            }
+
+            let computed = to_hex_string(&hasher.result());
+            if computed != *expected {
+                error!(
+                    "Block range {}-{} read back from '{}' does not match, expected {} {}, got {}",
+                    range.first,
+                    range.last,
+                    self.path.display(),
+                    bmap.checksum_type.get_descr(),
+                    expected,
+                    computed
+                );
+                return Ok(false);
+            }
+        }
+
+        info!(
+            "Read back {} in {} block ranges from '{}'",
+            format_size_with_unit(bmap.get_mapped_size()),
+            self.range_digests.len(),
+            self.path.display()
+        );
+        Ok(true)
+    }
+
+    fn write_data(&mut self, offset: u64, data: &[u8]) -> Result<(), MigError> {
+        if offset != self.position {
+            self.file
+                .seek(SeekFrom::Start(offset))
+                .context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!(
+                        "Failed to seek to offset {} in '{}'",
+                        offset,
+                        self.path.display()
+                    ),
+                ))?;
+        }
+
+        self.file.write_all(data).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "Failed to write {} bytes at offset {} to '{}'",
+                data.len(),
+                offset,
+                self.path.display()
+            ),
+        ))?;
+
+        self.position = offset + data.len() as u64;
+        self.write_count += data.len() as u64;
+        Ok(())
+    }
+
+    // write runs of blocks containing non-zero data
+    fn write_non_zero(&mut self, offset: u64, data: &[u8]) -> Result<(), MigError> {
+        let mut run_start: Option<usize> = None;
+        for (idx, block) in data.chunks(ZERO_BLOCK_SIZE).enumerate() {
+            let block_start = idx * ZERO_BLOCK_SIZE;
+            if is_zero(block) {
+                if let Some(start) = run_start.take() {
+                    self.write_data(offset + start as u64, &data[start..block_start])?;
+                }
+            } else if run_start.is_none() {
+                run_start = Some(block_start);
+            }
+        }
+
+        if let Some(start) = run_start {
+            self.write_data(offset + start as u64, &data[start..])?;
+        }
+        Ok(())
+    }
+
+    // write the parts of data covered by block map ranges, digest ranges as they are completed
+    fn write_ranges(&mut self, bmap: &BlockMap, offset: u64, data: &[u8]) -> Result<(), MigError> {
+        let data_end = offset + data.len() as u64;
+        while self.range_idx < bmap.ranges.len() {
+            let range = &bmap.ranges[self.range_idx];
+            let (start, end) = bmap.get_range_bytes(range);
+            if start >= data_end {
+                break;
+            }
+
+            let from = start.max(offset);
+            let to = end.min(data_end);
+            if from < to {
+                let slice = &data[(from - offset) as usize..(to - offset) as usize];
+                self.write_data(from, slice)?;
+                self.range_hasher
+                    .get_or_insert_with(|| bmap.checksum_type.new_hasher())
+                    .input(slice);
+            }
+
+            if to < end {
+                break;
+            }
+
+            let computed = if let Some(hasher) = self.range_hasher.take() {
+                to_hex_string(&hasher.result())
+            } else {
+                to_hex_string(&bmap.checksum_type.new_hasher().result())
+            };
+
+            if let Some(ref expected) = range.checksum {
+                if computed != *expected {
+                    return Err(MigError::from_remark(
+                        MigErrorKind::InvParam,
+                        &format!(
+                            "Block range {}-{} of the image does not match the block map, expected {} {}, got {}",
+                            range.first,
+                            range.last,
+                            bmap.checksum_type.get_descr(),
+                            expected,
+                            computed
+                        ),
+                    ));
+                }
+            }
+
+            self.range_digests.push(computed);
+            self.range_idx += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::disk_util::Compression;
+    use std::fs::{remove_file, write};
+
+    fn flash_to_file(mode: &WriteMode, name: &str) -> (Vec<u8>, Vec<u8>, u64) {
+        let mut image: Vec<u8> = Vec::new();
+        Compression::GZip
+            .open_reader(Path::new("./test_data/part.img.gz"))
+            .unwrap()
+            .read_to_end(&mut image)
+            .unwrap();
+
+        let target = std::env::temp_dir().join(name);
+        write(&target, vec![0xFFu8; image.len()]).unwrap();
+
+        let write_count = {
+            let mut writer = TargetWriter::new(&target, mode).unwrap();
+            for (idx, chunk) in image.chunks(128 * 1024).enumerate() {
+                writer.write_at((idx * 128 * 1024) as u64, chunk).unwrap();
+            }
+            writer.finish().unwrap();
+            if let WriteMode::BlockMap(_) = mode {
+                assert!(writer.is_complete());
+                assert!(writer
+                    .verify_ranges(&mut Progress::new(None, None))
+                    .unwrap());
+            }
+            writer.get_write_count()
+        };
+
+        let written = std::fs::read(&target).unwrap();
+        remove_file(&target).unwrap();
+        (image, written, write_count)
+    }
+
+    #[test]
+    fn write_block_map_ranges() {
+        let bmap = BlockMap::from_file("./test_data/part.img.bmap").unwrap();
+        let mapped_size = bmap.get_mapped_size();
+        let mode = WriteMode::BlockMap(bmap.clone());
+        let (image, written, write_count) = flash_to_file(&mode, "balena-migrate-bmap-test.img");
+        assert_eq!(write_count, mapped_size);
+        for range in &bmap.ranges {
+            let (start, end) = bmap.get_range_bytes(range);
+            assert_eq!(
+                &image[start as usize..end as usize],
+                &written[start as usize..end as usize]
+            );
+        }
+        // unmapped blocks are left alone
+        assert_eq!(written[ZERO_BLOCK_SIZE], 0xFF);
+    }
+
+    #[test]
+    fn write_non_zero_blocks() {
+        let (image, written, write_count) =
+            flash_to_file(&WriteMode::SkipZeros, "balena-migrate-skip-zeros-test.img");
+        assert_eq!(write_count, 3 * ZERO_BLOCK_SIZE as u64);
+        for (src, dst) in image
+            .chunks(ZERO_BLOCK_SIZE)
+            .zip(written.chunks(ZERO_BLOCK_SIZE))
+        {
+            if !is_zero(src) {
+                assert_eq!(src, dst);
+            }
+        }
+    }
+
+    #[test]
+    fn reject_corrupt_range() {
+        let mut bmap = BlockMap::from_file("./test_data/part.img.bmap").unwrap();
+        bmap.ranges[1].checksum = Some(String::from("00"));
+        let mode = WriteMode::BlockMap(bmap);
+        let target = std::env::temp_dir().join("balena-migrate-bmap-corrupt-test.img");
+        write(&target, vec![0u8; 10_485_760]).unwrap();
+        let mut writer = TargetWriter::new(&target, &mode).unwrap();
+        let image = vec![0u8; 128 * 1024];
+        let mut res = Ok(());
+        for idx in 0..80 {
+            res = writer.write_at(idx * image.len() as u64, &image);
+            if res.is_err() {
+                break;
+            }
+        }
+        remove_file(&target).unwrap();
+        assert!(res.is_err());
+    }
+}
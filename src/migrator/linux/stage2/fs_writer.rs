@@ -4,9 +4,10 @@ use nix::unistd::sync;
 use regex::Regex;
 use std::collections::HashMap;
 use std::fs::OpenOptions;
+use std::io::copy;
 use std::path::Path;
 use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, Stdio};
 use std::str;
 use std::thread;
 use std::time::{Duration, SystemTime};
@@ -17,14 +18,19 @@ use crate::{
         config::balena_config::PartCheck,
         file_exists, path_append,
         stage2_config::{CheckedFSDump, CheckedImageType, Stage2Config},
+        staging::{StagedFile, Staging},
         MigErrCtx, MigError, MigErrorKind,
     },
-    defs::{DEF_BLOCK_SIZE, PART_INFO},
+    defs::{DEF_BLOCK_SIZE, PARTITION_ALIGNMENT_KIB, PART_INFO},
     linux::{
         linux_common::whereis,
         linux_defs::{EXT_FMT_CMD, FAT_FMT_CMD, LSBLK_CMD, PARTPROBE_CMD, SFDISK_CMD, TAR_CMD},
         lsblk_info::{LsblkDevice, LsblkInfo},
-        stage2::{mounts::Mounts, FlashResult},
+        stage2::{
+            mounts::Mounts,
+            progress::{Phase, Progress},
+            FlashResult,
+        },
     },
 };
 
@@ -32,9 +38,8 @@ use crate::{
 // TODO: write tests for partitioning
 
 const FORMAT_WITH_LABEL: bool = true;
-const DEFAULT_PARTITION_ALIGNMENT_KIB: u64 = 4096; // KiB
-                                                   // should we maximize data partition to fill disk
-                                                   // TODO: true might be the better default but can be very slow in combination with mkfs_direct_io
+// should we maximize data partition to fill disk
+// TODO: true might be the better default but can be very slow in combination with mkfs_direct_io
 const DEFAULT_MAX_DATA: bool = true;
 
 // TODO: replace removed command checks ?
@@ -44,6 +49,8 @@ pub(crate) fn write_balena_os(
     mounts: &mut Mounts,
     config: &Stage2Config,
     base_path: &Path,
+    staging: Option<&Staging>,
+    progress: &mut Progress,
 ) -> FlashResult {
     // make sure we have allrequired commands
     let mut cmd_path: HashMap<&str, String> = HashMap::new();
@@ -82,6 +89,7 @@ pub(crate) fn write_balena_os(
     }
 
     if let CheckedImageType::FileSystems(ref fs_dump) = config.get_balena_image() {
+        progress.start_phase(Phase::Partition, None);
         let res = partition_sfdisk(device, fs_dump, &cmd_path);
         if let FlashResult::Ok = res {
             let lsblk_dev = match part_reread(device, 30, PART_INFO.len(), &cmd_path) {
@@ -96,9 +104,14 @@ pub(crate) fn write_balena_os(
             };
 
             sync();
+            progress.finish_phase();
 
+            progress.start_phase(Phase::Format, None);
             if format(&lsblk_dev, fs_dump, &cmd_path) {
+                progress.finish_phase();
+
                 // TODO: need partprobe ?
+                progress.start_phase(Phase::Mount, None);
                 if let Err(why) = mounts.mount_balena(true) {
                     error!(
                         "write_balena_os: failed mount balena partitions, error: {:?}",
@@ -107,9 +120,13 @@ pub(crate) fn write_balena_os(
                     sync();
                     return FlashResult::FailNonRecoverable;
                 }
+                progress.finish_phase();
 
-                if balena_write(mounts, fs_dump, base_path, &cmd_path) {
+                if balena_write(
+                    device, mounts, fs_dump, base_path, staging, &cmd_path, progress,
+                ) {
                     sync();
+                    progress.finish_phase();
                     match call(
                         cmd_path[LSBLK_CMD].as_str(),
                         &["-o", "name,partuuid", &device.to_string_lossy()],
@@ -150,8 +167,22 @@ pub(crate) fn write_balena_os(
     }
 }
 
-fn sub_write(tar_path: &str, mountpoint: &Path, base_path: &Path, archive: &PathBuf) -> bool {
-    let arch_path = path_append(base_path, archive);
+fn sub_write(
+    tar_path: &str,
+    mountpoint: &Path,
+    base_path: &Path,
+    archive: &PathBuf,
+    staging: Option<&Staging>,
+    device: &Path,
+) -> bool {
+    // staged archives are piped to tar from the flash device
+    let staged = staging.and_then(|staging| staging.get_file(archive));
+    let arch_path = if staged.is_some() {
+        PathBuf::from("-")
+    } else {
+        path_append(base_path, archive)
+    };
+
     let tar_args: &[&str] = &[
         "-xzf",
         &arch_path.to_string_lossy(),
@@ -161,18 +192,41 @@ fn sub_write(tar_path: &str, mountpoint: &Path, base_path: &Path, archive: &Path
 
     debug!("sub_write: invoking '{}' with {:?}", tar_path, tar_args);
 
-    match Command::new(tar_path)
+    let cmd_res = match Command::new(tar_path)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .args(tar_args)
-        .output()
+        .spawn()
     {
+        Ok(mut child) => {
+            if let Some(staged) = staged {
+                if let Err(why) = pipe_staged(staged, device, &mut child) {
+                    error!(
+                        "sub_write: failed to pipe staged archive '{}' to {}, error: {:?}",
+                        archive.display(),
+                        tar_path,
+                        why
+                    );
+                    let _res = child.kill();
+                    let _res = child.wait();
+                    return false;
+                }
+            } else {
+                // close stdin, tar reads the archive from file
+                drop(child.stdin.take());
+            }
+            child.wait_with_output()
+        }
+        Err(why) => Err(why),
+    };
+
+    match cmd_res {
         Ok(cmd_res) => {
             if cmd_res.status.success() {
                 info!(
                     "Successfully wrote '{}' to '{}'",
-                    arch_path.display(),
+                    archive.display(),
                     mountpoint.display()
                 );
                 true
@@ -197,11 +251,34 @@ fn sub_write(tar_path: &str, mountpoint: &Path, base_path: &Path, archive: &Path
     }
 }
 
+fn pipe_staged(staged: &StagedFile, device: &Path, child: &mut Child) -> Result<u64, MigError> {
+    let mut reader = staged.open_reader(device)?;
+    if let Some(mut stdin) = child.stdin.take() {
+        Ok(
+            copy(&mut reader, &mut stdin).context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!(
+                    "Failed to write staged file '{}' to pipe",
+                    staged.rel_path.display()
+                ),
+            ))?,
+        )
+    } else {
+        Err(MigError::from_remark(
+            MigErrorKind::InvState,
+            "No stdin available for child process",
+        ))
+    }
+}
+
 fn balena_write(
+    device: &Path,
     mounts: &Mounts,
     fs_dump: &CheckedFSDump,
     base_path: &Path,
+    staging: Option<&Staging>,
     cmd_path: &HashMap<&str, String>,
+    progress: &mut Progress,
 ) -> bool {
     // TODO: try use device labels instead
 
@@ -212,15 +289,33 @@ fn balena_write(
 
     let tar_path = cmd_path[TAR_CMD].as_str();
 
+    // progress is reported in archive sizes once an archive has been extracted
+    let archives = [
+        &fs_dump.boot.archive,
+        &fs_dump.root_a.archive,
+        &fs_dump.root_b.archive,
+        &fs_dump.state.archive,
+        &fs_dump.data.archive,
+    ];
+    progress.start_phase(
+        Phase::Extract,
+        Some(archives.iter().map(|archive| archive.size).sum()),
+    );
+    let mut extracted: u64 = 0;
+
     if let Some(mountpoint) = mounts.get_balena_boot_mountpoint() {
         if !sub_write(
             tar_path,
             mountpoint,
             base_path,
             &fs_dump.boot.archive.rel_path,
+            staging,
+            device,
         ) {
             return false;
         }
+        extracted += fs_dump.boot.archive.size;
+        progress.update(extracted);
     } else {
         error!("Could not retrieve boot mountpoint");
         return false;
@@ -232,9 +327,13 @@ fn balena_write(
             mountpoint,
             base_path,
             &fs_dump.root_a.archive.rel_path,
+            staging,
+            device,
         ) {
             return false;
         }
+        extracted += fs_dump.root_a.archive.size;
+        progress.update(extracted);
     } else {
         error!("Could not retrieve root_a mountpoint");
         return false;
@@ -246,9 +345,13 @@ fn balena_write(
             mountpoint,
             base_path,
             &fs_dump.root_b.archive.rel_path,
+            staging,
+            device,
         ) {
             return false;
         }
+        extracted += fs_dump.root_b.archive.size;
+        progress.update(extracted);
     } else {
         error!("Could not retrieve root_b mountpoint");
         return false;
@@ -260,21 +363,32 @@ fn balena_write(
             mountpoint,
             base_path,
             &fs_dump.state.archive.rel_path,
+            staging,
+            device,
         ) {
             return false;
         }
+        extracted += fs_dump.state.archive.size;
+        progress.update(extracted);
     } else {
         error!("Could not retrieve state mountpoint");
         return false;
     }
 
     if let Some(mountpoint) = mounts.get_balena_data_mountpoint() {
-        sub_write(
+        if sub_write(
             tar_path,
             mountpoint,
             base_path,
             &fs_dump.data.archive.rel_path,
-        )
+            staging,
+            device,
+        ) {
+            progress.update(extracted + fs_dump.data.archive.size);
+            true
+        } else {
+            false
+        }
     } else {
         error!("Could not retrieve data mountpoint");
         false
@@ -519,10 +633,10 @@ fn partition_sfdisk(
     part_string.push_str("label-id 0x{:x}\n", random_number: u32)
     */
 
-    let alignment_blocks: u64 = DEFAULT_PARTITION_ALIGNMENT_KIB * 1024 / DEF_BLOCK_SIZE as u64;
+    let alignment_blocks: u64 = PARTITION_ALIGNMENT_KIB * 1024 / DEF_BLOCK_SIZE as u64;
     debug!(
         "partition_sfdisk: Alignment '{}'KiB, {} blocks",
-        DEFAULT_PARTITION_ALIGNMENT_KIB, alignment_blocks
+        PARTITION_ALIGNMENT_KIB, alignment_blocks
     );
 
     debug!(
@@ -801,10 +915,10 @@ fn part_reread(
 
         // TODO: configure partition type
 
-        let alignment_blocks: u64 = DEFAULT_PARTITION_ALIGNMENT_KIB * 1024 / DEF_BLOCK_SIZE as u64;
+        let alignment_blocks: u64 = PARTITION_ALIGNMENT_KIB * 1024 / DEF_BLOCK_SIZE as u64;
         debug!(
             "Alignment '{}'KiB, {} blocks",
-            DEFAULT_PARTITION_ALIGNMENT_KIB, alignment_blocks
+            PARTITION_ALIGNMENT_KIB, alignment_blocks
         );
 
         debug!(
@@ -0,0 +1,223 @@
+use failure::ResultExt;
+use log::{debug, error, info, warn};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    common::{
+        config::migrate_config::{HookCfg, HookFailPolicy, HookStage},
+        path_append, MigErrCtx, MigError, MigErrorKind,
+    },
+    linux::stage2::FlashResult,
+};
+
+// *************************************************************************************************
+// * User hooks run in stage 2.
+// * Hooks are scripts or binaries shipped in the work dir and copied to the initramfs with the
+// * other files. They are run at fixed points of the migration with a timeout, a failing hook is
+// * mapped onto a FlashResult by its fail policy.
+// *************************************************************************************************
+
+const HOOK_POLL_INTERVAL: u64 = 100; // milliseconds
+
+// time to wait for the output of a hook that left child processes running
+const HOOK_OUTPUT_TIMEOUT: u64 = 1; // seconds
+
+const HOOK_STAGE_VAR: &str = "BALENA_MIGRATE_STAGE";
+
+pub(crate) fn run_hooks(
+    hooks: &[HookCfg],
+    stage: HookStage,
+    base_path: &Path,
+    env: &[(&str, PathBuf)],
+) -> FlashResult {
+    for hook in hooks.iter().filter(|hook| hook.stage == stage) {
+        let path = path_append(base_path, &hook.path);
+        info!("Running {} hook '{}'", stage.as_str(), path.display());
+
+        match run_hook(&path, hook, env) {
+            Ok(_) => info!("The hook '{}' was successful", path.display()),
+            Err(why) => match hook.get_on_fail() {
+                HookFailPolicy::Ignore => {
+                    warn!("The hook '{}' failed, ignoring: {}", path.display(), why);
+                }
+                HookFailPolicy::Recoverable => {
+                    error!("The hook '{}' failed: {}", path.display(), why);
+                    return FlashResult::FailRecoverable;
+                }
+                HookFailPolicy::NonRecoverable => {
+                    error!("The hook '{}' failed: {}", path.display(), why);
+                    return FlashResult::FailNonRecoverable;
+                }
+            },
+        }
+    }
+    FlashResult::Ok
+}
+
+fn run_hook(path: &Path, hook: &HookCfg, env: &[(&str, PathBuf)]) -> Result<(), MigError> {
+    let mut child = Command::new(path)
+        .args(hook.get_args())
+        .env(HOOK_STAGE_VAR, hook.stage.as_str())
+        .envs(env.iter().map(|(name, value)| (name, value.as_os_str())))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to start '{}'", path.display()),
+        ))?;
+
+    let stdout = read_output(child.stdout.take());
+    let stderr = read_output(child.stderr.take());
+
+    let timeout = Duration::from_secs(hook.get_timeout());
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to wait for '{}'", path.display()),
+        ))? {
+            break status;
+        }
+
+        if start.elapsed() > timeout {
+            let _res = child.kill();
+            let _res = child.wait();
+            return Err(MigError::from_remark(
+                MigErrorKind::Timeout,
+                &format!(
+                    "'{}' did not finish within {} seconds",
+                    path.display(),
+                    timeout.as_secs()
+                ),
+            ));
+        }
+        thread::sleep(Duration::from_millis(HOOK_POLL_INTERVAL));
+    };
+
+    for (name, output) in &[("stdout", stdout), ("stderr", stderr)] {
+        match output.recv_timeout(Duration::from_secs(HOOK_OUTPUT_TIMEOUT)) {
+            Ok(output) => {
+                for line in output.lines() {
+                    debug!("{} {}: {}", path.display(), name, line);
+                }
+            }
+            Err(_) => debug!("{} {}: not available", path.display(), name),
+        }
+    }
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(MigError::from_remark(
+            MigErrorKind::ExecProcess,
+            &format!("'{}' failed with {}", path.display(), status),
+        ))
+    }
+}
+
+// read a pipe in a thread, child processes of a hook might keep it open
+fn read_output<R: Read + Send + 'static>(pipe: Option<R>) -> Receiver<String> {
+    let (sender, receiver) = channel();
+    if let Some(mut pipe) = pipe {
+        thread::spawn(move || {
+            let mut buffer: Vec<u8> = Vec::new();
+            let _res = pipe.read_to_end(&mut buffer);
+            let _res = sender.send(String::from_utf8_lossy(&buffer).to_string());
+        });
+    }
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, read_to_string, remove_dir_all, set_permissions, write};
+    use std::os::unix::fs::PermissionsExt;
+
+    fn make_hook(dir: &Path, name: &str, script: &str) {
+        let path = path_append(dir, name);
+        write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        set_permissions(&path, PermissionsExt::from_mode(0o755)).unwrap();
+    }
+
+    fn hook_cfg(name: &str, stage: HookStage, on_fail: HookFailPolicy) -> HookCfg {
+        HookCfg {
+            path: PathBuf::from(name),
+            stage,
+            args: Some(vec![String::from("arg1")]),
+            timeout: Some(1),
+            on_fail: Some(on_fail),
+        }
+    }
+
+    #[test]
+    fn run_hooks_by_stage() {
+        let dir = path_append(std::env::temp_dir(), "balena-migrate-hooks-test");
+        let _res = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+
+        make_hook(
+            &dir,
+            "record",
+            "echo \"$BALENA_MIGRATE_STAGE $1 $BALENA_MIGRATE_FLASH_DEVICE\" >> \"$(dirname \"$0\")/record.txt\"",
+        );
+        make_hook(&dir, "fail", "exit 3");
+        make_hook(&dir, "hang", "sleep 10");
+
+        let env = vec![("BALENA_MIGRATE_FLASH_DEVICE", PathBuf::from("/dev/sda"))];
+        let hooks = vec![
+            hook_cfg(
+                "record",
+                HookStage::BeforeFlash,
+                HookFailPolicy::Recoverable,
+            ),
+            hook_cfg("fail", HookStage::BeforeFlash, HookFailPolicy::Ignore),
+            hook_cfg(
+                "fail",
+                HookStage::AfterFlash,
+                HookFailPolicy::NonRecoverable,
+            ),
+            hook_cfg("hang", HookStage::BeforeReboot, HookFailPolicy::Recoverable),
+            hook_cfg(
+                "record",
+                HookStage::BeforeReboot,
+                HookFailPolicy::Recoverable,
+            ),
+        ];
+
+        assert_eq!(
+            run_hooks(&hooks, HookStage::BeforeFlash, &dir, &env),
+            FlashResult::Ok
+        );
+        assert_eq!(
+            read_to_string(path_append(&dir, "record.txt")).unwrap(),
+            "before_flash arg1 /dev/sda\n"
+        );
+
+        assert_eq!(
+            run_hooks(&hooks, HookStage::AfterFlash, &dir, &env),
+            FlashResult::FailNonRecoverable
+        );
+
+        // the hook timing out stops the hooks that follow
+        let start = Instant::now();
+        assert_eq!(
+            run_hooks(&hooks, HookStage::BeforeReboot, &dir, &env),
+            FlashResult::FailRecoverable
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            read_to_string(path_append(&dir, "record.txt")).unwrap(),
+            "before_flash arg1 /dev/sda\n"
+        );
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
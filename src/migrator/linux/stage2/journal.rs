@@ -0,0 +1,253 @@
+use failure::ResultExt;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{read_to_string, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    common::{path_append, MigErrCtx, MigError, MigErrorKind},
+    linux::linux_defs::MIGRATE_JOURNAL_FILE,
+};
+
+// *************************************************************************************************
+// * Checkpoint journal for stage 2.
+// * Each checkpoint reached is appended as a json line and synced to disk, so a stage 2 that is
+// * started again after a power loss can tell how far the last attempt got. Entries written for
+// * other migrations and incomplete lines are ignored.
+// * Only a journal on the log device covers all checkpoints. Without a log device the journal is
+// * kept on the boot partition, which is unmounted before flashing when it is on the flash device.
+// * Checkpoints from then on are kept in memory only, so a power loss can be resumed from up to
+// * files_copied but not once flashing has started.
+// *************************************************************************************************
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub(crate) enum Checkpoint {
+    #[serde(rename = "boot_restored")]
+    BootRestored,
+    #[serde(rename = "files_copied")]
+    FilesCopied,
+    #[serde(rename = "flash_started")]
+    FlashStarted,
+    #[serde(rename = "flash_finished")]
+    FlashFinished,
+    #[serde(rename = "config_written")]
+    ConfigWritten,
+    #[serde(rename = "data_copied")]
+    DataCopied,
+}
+
+impl Checkpoint {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Checkpoint::BootRestored => "boot_restored",
+            Checkpoint::FilesCopied => "files_copied",
+            Checkpoint::FlashStarted => "flash_started",
+            Checkpoint::FlashFinished => "flash_finished",
+            Checkpoint::ConfigWritten => "config_written",
+            Checkpoint::DataCopied => "data_copied",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry {
+    id: String,
+    checkpoint: Checkpoint,
+    time: u64,
+}
+
+pub(crate) struct Journal {
+    path: PathBuf,
+    migrate_id: String,
+    last: Option<Checkpoint>,
+    failed: bool,
+}
+
+impl Journal {
+    pub fn new(dir: &Path, migrate_id: &str) -> Journal {
+        let path = path_append(dir, MIGRATE_JOURNAL_FILE);
+        let last = if let Ok(content) = read_to_string(&path) {
+            content
+                .lines()
+                .rev()
+                .filter_map(|line| serde_json::from_str::<JournalEntry>(line).ok())
+                .find(|entry| entry.id == migrate_id)
+                .map(|entry| entry.checkpoint)
+        } else {
+            None
+        };
+
+        if let Some(checkpoint) = last {
+            info!(
+                "Found journal '{}', the last attempt reached {:?}",
+                path.display(),
+                checkpoint
+            );
+        } else {
+            debug!("Journal::new: no checkpoints found in '{}'", path.display());
+        }
+
+        Journal {
+            path,
+            migrate_id: String::from(migrate_id),
+            last,
+            failed: false,
+        }
+    }
+
+    pub fn get_last(&self) -> Option<Checkpoint> {
+        self.last
+    }
+
+    // the file system holding the journal is gone, keep the checkpoints in memory only
+    pub fn detach(&mut self) {
+        if !self.failed {
+            info!(
+                "The journal '{}' is no longer available, checkpoints are not persisted",
+                self.path.display()
+            );
+            self.failed = true;
+        }
+    }
+
+    // true if this or a previous attempt got to the checkpoint
+    pub fn is_done(&self, checkpoint: Checkpoint) -> bool {
+        if let Some(last) = self.last {
+            last >= checkpoint
+        } else {
+            false
+        }
+    }
+
+    pub fn record(&mut self, checkpoint: Checkpoint) {
+        self.last = Some(checkpoint);
+        if self.failed {
+            return;
+        }
+
+        let entry = JournalEntry {
+            id: self.migrate_id.clone(),
+            checkpoint,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0),
+        };
+
+        match self.write_entry(&entry) {
+            Ok(_) => debug!("Journal::record: {:?}", checkpoint),
+            Err(why) => {
+                // the journal is an aid to recovery, failing to write it does not stop migration
+                warn!(
+                    "Failed to write journal '{}', disabling it, error: {:?}",
+                    self.path.display(),
+                    why
+                );
+                self.failed = true;
+            }
+        }
+    }
+
+    fn write_entry(&self, entry: &JournalEntry) -> Result<(), MigError> {
+        let line = serde_json::to_string(entry).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            "Failed to serialize journal entry",
+        ))?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed to open journal '{}'", self.path.display()),
+            ))?;
+
+        // start a new line after a line torn by a power loss
+        let mut last_char = [b'\n'];
+        if file.seek(SeekFrom::End(0)).unwrap_or(0) > 0 {
+            file.seek(SeekFrom::End(-1))
+                .and_then(|_| file.read_exact(&mut last_char))
+                .context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!("Failed to read from journal '{}'", self.path.display()),
+                ))?;
+        }
+        let line = if last_char[0] == b'\n' {
+            line
+        } else {
+            format!("\n{}", line)
+        };
+
+        writeln!(file, "{}", line).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to write to journal '{}'", self.path.display()),
+        ))?;
+
+        // the checkpoint has to be on disk before the next phase starts
+        Ok(file.sync_all().context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to sync journal '{}'", self.path.display()),
+        ))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    #[test]
+    fn resume_from_journal() {
+        let dir = path_append(std::env::temp_dir(), "balena-migrate-journal-test");
+        let _res = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+
+        let mut journal = Journal::new(&dir, "1-1");
+        assert_eq!(journal.get_last(), None);
+        journal.record(Checkpoint::BootRestored);
+        journal.record(Checkpoint::FlashStarted);
+
+        // entries of other migrations and torn lines are ignored
+        let mut journal = Journal::new(&dir, "2-2");
+        journal.record(Checkpoint::BootRestored);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(path_append(&dir, MIGRATE_JOURNAL_FILE))
+            .unwrap();
+        write!(file, "{{\"id\":\"1-1\",\"checkpoint\":\"flash_fin").unwrap();
+
+        let journal = Journal::new(&dir, "1-1");
+        remove_dir_all(&dir).unwrap();
+
+        assert_eq!(journal.get_last(), Some(Checkpoint::FlashStarted));
+        assert!(journal.is_done(Checkpoint::FilesCopied));
+        assert!(!journal.is_done(Checkpoint::FlashFinished));
+    }
+
+    #[test]
+    fn append_after_torn_line() {
+        let dir = path_append(std::env::temp_dir(), "balena-migrate-torn-journal-test");
+        let _res = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+
+        let mut journal = Journal::new(&dir, "1-1");
+        journal.record(Checkpoint::BootRestored);
+        assert!(journal.is_done(Checkpoint::BootRestored));
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(path_append(&dir, MIGRATE_JOURNAL_FILE))
+            .unwrap();
+        write!(file, "{{\"id\":\"1-1\",\"checkp").unwrap();
+
+        let mut journal = Journal::new(&dir, "1-1");
+        journal.record(Checkpoint::FilesCopied);
+
+        let journal = Journal::new(&dir, "1-1");
+        remove_dir_all(&dir).unwrap();
+        assert_eq!(journal.get_last(), Some(Checkpoint::FilesCopied));
+    }
+}
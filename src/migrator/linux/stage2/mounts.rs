@@ -4,7 +4,7 @@ use std::fs::create_dir_all;
 use std::path::{Path, PathBuf};
 use std::str;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use nix::{
     mount::{mount, umount, MsFlags},
@@ -20,16 +20,14 @@ use crate::{
     defs::{
         BALENA_BOOT_FSTYPE, BALENA_BOOT_PART, BALENA_DATA_FSTYPE, BALENA_DATA_PART,
         BALENA_ROOTA_FSTYPE, BALENA_ROOTA_PART, BALENA_ROOTB_FSTYPE, BALENA_ROOTB_PART,
-        BALENA_STATE_FSTYPE, BALENA_STATE_PART, DISK_BY_LABEL_PATH, STAGE2_CFG_FILE,
+        BALENA_STATE_FSTYPE, BALENA_STATE_PART, DEFAULT_MOUNT_TIMEOUT, DISK_BY_LABEL_PATH,
+        STAGE2_CFG_FILE,
     },
     linux::{
-        linux_common::{
-            drive_from_partition, drive_to_partition, get_kernel_root_info, to_std_device_path,
-            whereis,
-        },
+        linux_common::{drive_to_partition, get_kernel_root_info, whereis},
         linux_defs::NIX_NONE,
-        linux_defs::{FAT_CHK_CMD, UDEVADM_CMD},
-        lsblk_info::LsblkInfo,
+        linux_defs::{FAT_CHK_CMD, LOSETUP_CMD, UDEVADM_CMD},
+        stage2::block_devices::{get_fs_info, BlockDevices},
     },
 };
 
@@ -48,10 +46,17 @@ const UDEVADM_PARAMS: &[&str] = &["settle", "-t", "10"];
 
 const TRY_FS_TYPES: &[&str] = &["ext4", "vfat", "ntfs", "ext2", "ext3"];
 
+// delay between attempts to find / mount a device
+const MOUNT_RETRY_DELAY: u64 = 2;
+
 /*
 Attempts to mount the former boot device
 First approach is to extract root & root fs type from kernel command line
-If that fails all relevant block devices are searched for STAGE2_CFG_FILE.
+If that fails all block devices are searched for STAGE2_CFG_FILE.
+
+Devices are waited for until the mount timeout expires, udev links are resolved by scanning
+the block devices if udev has not created them. Loop devices are only scanned if they are the
+configured flash target.
 
 This device will be used to flash:
  drive path in flash_device
@@ -59,15 +64,21 @@ This device will be used to flash:
 
 */
 
-// TODO: test fallback strategy - boot device search
-
 #[derive(Debug)]
 pub(crate) struct Mounts {
     stage2_config: PathBuf,
     boot_device: PathBuf,
     flash_device: PathBuf,
+    // flash_device is a loop device attached to a file
+    flash_loop: bool,
+    // block device scans include the flash target
+    blk_devices: BlockDevices,
     boot_part: PathBuf,
+    boot_fstype: String,
     boot_mountpoint: PathBuf,
+    // false once unmount_boot_devs has unmounted the boot partition
+    boot_mounted: bool,
+    mount_timeout: Duration,
     work_no_copy: bool,
     work_path: Option<PathBuf>,
     work_mountpoint: Option<PathBuf>,
@@ -86,12 +97,6 @@ impl<'a> Mounts {
     // fallback should not be needed except for windows migration
     // as unix device names can not be reliably guessed (so far) in
     // windows. Have to rely on device UUIDs or this fallback
-
-    // TODO: might make sense to further redesign this:
-    // get lsblk info for starters and pick all devices from there
-    // might get us in trouble with devices showing up slowly though
-
-    #[allow(clippy::cognitive_complexity)] //TODO refactor this function to fix the clippy warning
     pub fn new() -> Result<Mounts, MigError> {
         trace!("new: entered");
 
@@ -112,83 +117,42 @@ impl<'a> Mounts {
             kernel_root_device, kernel_root_fs_type,
         );
 
-        // Not sure if this is needed but can't hurt to be patient
-        thread::sleep(Duration::from_secs(3));
+        Mounts::settle_udev();
 
-        info!("calling {} {:?}", UDEVADM_CMD, UDEVADM_PARAMS);
-        match call(UDEVADM_CMD, UDEVADM_PARAMS, true) {
-            Ok(cmd_res) => {
-                if !cmd_res.status.success() {
-                    warn!(
-                        "{} {:?} failed with '{}'",
-                        UDEVADM_CMD, UDEVADM_PARAMS, cmd_res.stderr
-                    );
-                }
-            }
-            Err(why) => {
-                warn!("{} {:?} failed with {:?}", UDEVADM_CMD, UDEVADM_PARAMS, why);
-            }
-        }
+        let blk_devices = BlockDevices::new();
 
         // try mount root from kernel cmd line
-
         if let Some(kernel_root_device) = kernel_root_device {
-            let mut fstypes: Vec<String> = Vec::new();
-            if let Some(ref fstype) = kernel_root_fs_type {
-                fstypes.push(fstype.clone());
-            } else {
-                TRY_FS_TYPES
-                    .iter()
-                    .for_each(|s| fstypes.push(String::from(*s)));
-            }
+            if let Some(device) = Mounts::wait_for_device(
+                &blk_devices,
+                &kernel_root_device,
+                Duration::from_secs(DEFAULT_MOUNT_TIMEOUT),
+            ) {
+                let fstypes: Vec<&str> = if let Some(ref fstype) = kernel_root_fs_type {
+                    vec![fstype.as_str()]
+                } else if let Some(fstype) = get_fs_info(&device).fstype {
+                    vec![fstype]
+                } else {
+                    TRY_FS_TYPES.to_vec()
+                };
 
-            for fstype in &fstypes {
-                match Mounts::mount(BOOTFS_DIR, &kernel_root_device, fstype) {
-                    Ok(boot_mountpoint) => {
-                        let stage2_config = path_append(&boot_mountpoint, STAGE2_CFG_FILE);
-                        if file_exists(&stage2_config) {
-                            let init_device = match drive_from_partition(&kernel_root_device) {
-                                Ok(flash_device) => flash_device,
-                                Err(why) => {
-                                    error!(
-                                        "Failed to extract drive from partition: '{}', error: {:?}",
-                                        kernel_root_device.display(),
-                                        why
-                                    );
-                                    return Err(MigError::displayed());
-                                }
-                            };
-
-                            return Ok(Mounts {
-                                boot_device: init_device.clone(),
-                                flash_device: init_device,
-                                boot_part: to_std_device_path(&kernel_root_device)?,
-                                boot_mountpoint,
-                                stage2_config,
-                                work_no_copy: false,
-                                work_path: None,
-                                work_mountpoint: None,
-                                log_path: None,
-                                balena_boot_mp: None,
-                                balena_root_a_mp: None,
-                                balena_root_b_mp: None,
-                                balena_state_mp: None,
-                                balena_data_mp: None,
-                            });
-                        } else {
-                            let _res = umount(&boot_mountpoint);
-                        }
-                    }
-                    Err(why) => {
-                        error!(
-                            "Mount failed for {} on {} wth fstype: {}, error {:?}",
-                            kernel_root_device.display(),
-                            BOOTFS_DIR,
-                            fstype,
-                            why
-                        );
+                if let Some(drive) = blk_devices.get_drive(&device) {
+                    if let Some(mounts) =
+                        Mounts::try_boot_device(&blk_devices, &device, &drive, &fstypes)
+                    {
+                        return Ok(mounts);
                     }
+                } else {
+                    warn!(
+                        "Failed to find the drive for partition '{}'",
+                        device.display()
+                    );
                 }
+            } else {
+                warn!(
+                    "Root device '{}' from kernel command line was not found",
+                    kernel_root_device.display()
+                );
             }
         }
 
@@ -196,65 +160,27 @@ impl<'a> Mounts {
 
         debug!("Looking for boot device in all block devices",);
 
-        match LsblkInfo::all() {
-            Ok(lsblk_info) => {
-                for blk_device in lsblk_info.get_blk_devices() {
-                    if let Some(ref children) = blk_device.children {
-                        for blk_part in children {
-                            debug!(
-                                "Looking for boot device in all '{}'",
-                                blk_part.get_path().display()
-                            );
-
-                            let mut fstypes: Vec<String> = Vec::new();
-                            if let Some(ref fstype) = blk_part.fstype {
-                                fstypes.push(fstype.clone());
-                            } else {
-                                TRY_FS_TYPES
-                                    .iter()
-                                    .for_each(|s| fstypes.push(String::from(*s)));
-                            }
-
-                            for fstype in fstypes {
-                                let device = blk_part.get_path();
-
-                                debug!(
-                                    "Attempting to mount '{}' with {}",
-                                    device.display(),
-                                    fstype
-                                );
+        match blk_devices.get_devices() {
+            Ok(devices) => {
+                for blk_device in devices {
+                    debug!(
+                        "Looking for boot device in '{}'",
+                        blk_device.device.display()
+                    );
 
-                                match Mounts::mount(BOOTFS_DIR, &device, &fstype) {
-                                    Ok(boot_mountpoint) => {
-                                        let stage2_config =
-                                            path_append(&boot_mountpoint, STAGE2_CFG_FILE);
-                                        if file_exists(&stage2_config) {
-                                            return Ok(Mounts {
-                                                boot_device: blk_device.get_path(),
-                                                flash_device: blk_device.get_path(),
-                                                boot_part: device,
-                                                boot_mountpoint,
-                                                stage2_config,
-                                                work_no_copy: false,
-                                                work_path: None,
-                                                work_mountpoint: None,
-                                                log_path: None,
-                                                balena_boot_mp: None,
-                                                balena_root_a_mp: None,
-                                                balena_root_b_mp: None,
-                                                balena_state_mp: None,
-                                                balena_data_mp: None,
-                                            });
-                                        } else {
-                                            let _res = umount(&boot_mountpoint);
-                                        }
-                                    }
-                                    Err(why) => {
-                                        error!("Mount failed: {:?}", why);
-                                    }
-                                }
-                            }
-                        }
+                    let fstypes: Vec<&str> = if let Some(fstype) = blk_device.get_fs_info().fstype {
+                        vec![fstype]
+                    } else {
+                        TRY_FS_TYPES.to_vec()
+                    };
+
+                    if let Some(mounts) = Mounts::try_boot_device(
+                        &blk_devices,
+                        &blk_device.device,
+                        &blk_device.drive,
+                        &fstypes,
+                    ) {
+                        return Ok(mounts);
                     }
                 }
             }
@@ -271,6 +197,70 @@ impl<'a> Mounts {
         Err(MigError::displayed())
     }
 
+    // mount a partition and check for STAGE2_CFG_FILE
+    fn try_boot_device(
+        blk_devices: &BlockDevices,
+        device: &Path,
+        drive: &Path,
+        fstypes: &[&str],
+    ) -> Option<Mounts> {
+        for fstype in fstypes {
+            match Mounts::mount(
+                blk_devices,
+                BOOTFS_DIR,
+                device,
+                fstype,
+                Duration::from_secs(0),
+            ) {
+                Ok(boot_mountpoint) => {
+                    let stage2_config = path_append(&boot_mountpoint, STAGE2_CFG_FILE);
+                    if file_exists(&stage2_config) {
+                        return Some(Mounts {
+                            boot_device: drive.to_path_buf(),
+                            flash_device: drive.to_path_buf(),
+                            flash_loop: false,
+                            blk_devices: BlockDevices::new(),
+                            boot_part: device.to_path_buf(),
+                            boot_fstype: String::from(*fstype),
+                            boot_mountpoint,
+                            boot_mounted: true,
+                            mount_timeout: Duration::from_secs(DEFAULT_MOUNT_TIMEOUT),
+                            stage2_config,
+                            work_no_copy: false,
+                            work_path: None,
+                            work_mountpoint: None,
+                            log_path: None,
+                            balena_boot_mp: None,
+                            balena_root_a_mp: None,
+                            balena_root_b_mp: None,
+                            balena_state_mp: None,
+                            balena_data_mp: None,
+                        });
+                    } else {
+                        let _res = umount(&boot_mountpoint);
+                        // no point in trying other file system types
+                        return None;
+                    }
+                }
+                Err(why) => {
+                    debug!(
+                        "Mount failed for {} on {} with fstype: {}, error {:?}",
+                        device.display(),
+                        BOOTFS_DIR,
+                        fstype,
+                        why
+                    );
+                }
+            }
+        }
+        None
+    }
+
+    // how long to wait for work, log and balena devices
+    pub fn set_mount_timeout(&mut self, timeout: Duration) {
+        self.mount_timeout = timeout;
+    }
+
     pub fn get_balena_boot_mountpoint(&'a self) -> Option<&'a Path> {
         if let Some(ref mountpoint) = self.balena_boot_mp {
             Some(mountpoint)
@@ -311,16 +301,106 @@ impl<'a> Mounts {
         }
     }
 
+    // only the flash target, for the flash and mount tests
+    #[cfg(test)]
+    pub fn for_flash_target(target: &Path) -> Result<Mounts, MigError> {
+        let mut mounts = Mounts {
+            stage2_config: PathBuf::from(STAGE2_CFG_FILE),
+            boot_device: PathBuf::new(),
+            flash_device: PathBuf::new(),
+            flash_loop: false,
+            blk_devices: BlockDevices::new(),
+            boot_part: PathBuf::new(),
+            boot_fstype: String::new(),
+            boot_mountpoint: PathBuf::new(),
+            boot_mounted: false,
+            mount_timeout: Duration::from_secs(DEFAULT_MOUNT_TIMEOUT),
+            work_no_copy: true,
+            work_path: None,
+            work_mountpoint: None,
+            log_path: None,
+            balena_boot_mp: None,
+            balena_root_a_mp: None,
+            balena_root_b_mp: None,
+            balena_state_mp: None,
+            balena_data_mp: None,
+        };
+        mounts.set_force_flash_device(target)?;
+        Ok(mounts)
+    }
+
     pub fn get_boot_mountpoint(&'a self) -> &'a Path {
         &self.boot_mountpoint
     }
 
+    pub fn is_boot_mounted(&self) -> bool {
+        self.boot_mounted
+    }
+
     pub fn get_stage2_config(&'a self) -> &'a Path {
         &self.stage2_config
     }
 
-    pub fn set_force_flash_device(&mut self, device: &Path) {
-        self.flash_device = device.to_path_buf();
+    // a regular file is attached to a loop device which is used as flash device instead
+    pub fn set_force_flash_device(&mut self, device: &Path) -> Result<(), MigError> {
+        let metadata = device.metadata().context(MigErrCtx::from_remark(
+            MigErrorKind::NotFound,
+            &format!("Failed to retrieve metadata for '{}'", device.display()),
+        ))?;
+
+        if metadata.is_file() {
+            let cmd_res = call(
+                LOSETUP_CMD,
+                &["--find", "--show", "--partscan", &device.to_string_lossy()],
+                true,
+            )?;
+
+            if !cmd_res.status.success() || cmd_res.stdout.is_empty() {
+                return Err(MigError::from_remark(
+                    MigErrorKind::ExecProcess,
+                    &format!(
+                        "Failed to attach '{}' to a loop device: {}",
+                        device.display(),
+                        cmd_res.stderr
+                    ),
+                ));
+            }
+
+            self.flash_device = PathBuf::from(cmd_res.stdout.trim());
+            self.flash_loop = true;
+            info!(
+                "Attached flash target '{}' to loop device '{}'",
+                device.display(),
+                self.flash_device.display()
+            );
+        } else {
+            self.flash_device = device.to_path_buf();
+        }
+        self.blk_devices = BlockDevices::new().with_target(&self.flash_device);
+        Ok(())
+    }
+
+    pub fn detach_flash_loop(&mut self) -> Result<(), MigError> {
+        if self.flash_loop {
+            let cmd_res = call(
+                LOSETUP_CMD,
+                &["-d", &self.flash_device.to_string_lossy()],
+                true,
+            )?;
+            if !cmd_res.status.success() {
+                return Err(MigError::from_remark(
+                    MigErrorKind::ExecProcess,
+                    &format!(
+                        "Failed to detach loop device '{}': {}",
+                        self.flash_device.display(),
+                        cmd_res.stderr
+                    ),
+                ));
+            }
+            debug!("Detached loop device '{}'", self.flash_device.display());
+            self.flash_loop = false;
+        }
+        Ok(())
     }
 
     pub fn get_flash_device(&'a self) -> &'a Path {
@@ -352,8 +432,17 @@ impl<'a> Mounts {
 
         // TODO: ensure nothing is mounted twice, eg: work_mount == log_mount
 
-        if let Some((log_dev, log_fs)) = stage2_config.get_log_device() {
-            self.log_path = match Mounts::mount(LOGFS_DIR, log_dev, log_fs) {
+        if let Some((log_dev, log_fs)) = stage2_config
+            .get_log_device()
+            .filter(|_| self.log_path.is_none())
+        {
+            self.log_path = match Mounts::mount(
+                &self.blk_devices,
+                LOGFS_DIR,
+                log_dev,
+                log_fs,
+                self.mount_timeout,
+            ) {
                 Ok(mountpoint) => Some(mountpoint),
                 Err(why) => {
                     warn!(
@@ -376,21 +465,38 @@ impl<'a> Mounts {
                 debug!("work_no_copy set to {}", self.work_no_copy);
             }
             PathType::Mount(mount_cfg) => {
-                let device = to_std_device_path(mount_cfg.get_device())?;
+                let device = if let Some(device) = Mounts::wait_for_device(
+                    &self.blk_devices,
+                    mount_cfg.get_device(),
+                    self.mount_timeout,
+                ) {
+                    device
+                } else {
+                    error!(
+                        "Work device '{}' was not found",
+                        mount_cfg.get_device().display()
+                    );
+                    return Err(MigError::displayed());
+                };
                 debug!("Work mountpoint is a mount: '{}'", device.display());
-                // TODO: make all mounts retry with timeout
                 if self.boot_part != device {
-                    match Mounts::mount(WORKFS_DIR, &device, mount_cfg.get_fstype()) {
+                    match Mounts::mount(
+                        &self.blk_devices,
+                        WORKFS_DIR,
+                        &device,
+                        mount_cfg.get_fstype(),
+                        self.mount_timeout,
+                    ) {
                         Ok(mountpoint) => {
-                            match drive_from_partition(&device) {
-                                Ok(drive) => {
-                                    self.work_no_copy = drive != self.flash_device;
-                                    debug!("work_no_copy set to {}", self.work_no_copy);
-                                }
-                                Err(why) => {
-                                    warn!("Failed to derive drive from work partition: '{}', error: {:?}", device.display(), why);
-                                }
-                            };
+                            if let Some(drive) = self.blk_devices.get_drive(&device) {
+                                self.work_no_copy = drive != self.flash_device;
+                                debug!("work_no_copy set to {}", self.work_no_copy);
+                            } else {
+                                warn!(
+                                    "Failed to derive drive from work partition: '{}'",
+                                    device.display()
+                                );
+                            }
                             self.work_path = Some(path_append(&mountpoint, mount_cfg.get_path()));
                             self.work_mountpoint = Some(mountpoint);
                         }
@@ -447,14 +553,20 @@ impl<'a> Mounts {
         }
 
         // TODO: make boot mount optional ?
-        if self.boot_device == self.flash_device {
+        if !self.boot_mounted {
+            debug!("The boot device was unmounted before");
+            Ok(())
+        } else if self.boot_device == self.flash_device {
             debug!(
                 "Unmounting boot device: '{}' from '{}'",
                 self.boot_device.display(),
                 self.boot_mountpoint.display()
             );
             match umount(&self.boot_mountpoint) {
-                Ok(_) => Ok(()),
+                Ok(_) => {
+                    self.boot_mounted = false;
+                    Ok(())
+                }
                 Err(why) => {
                     error!(
                         "Failed to unmount former boot device: '{}', error: {:?}",
@@ -474,14 +586,37 @@ impl<'a> Mounts {
         }
     }
 
-    pub fn mount_balena(&mut self, mount_all: bool) -> Result<bool, MigError> {
-        let mut parts_found = true;
-        let mut part_label = path_append(DISK_BY_LABEL_PATH, BALENA_BOOT_PART);
-        if !file_exists(&part_label) {
-            part_label = drive_to_partition(&self.flash_device, 1)?;
+    // mount the former boot partition again after unmount_boot_devs, only useful as long as
+    // balena OS has not been written
+    pub fn remount_boot(&mut self) -> Result<(), MigError> {
+        if !self.boot_mounted {
+            debug!(
+                "Mounting boot partition '{}' again",
+                self.boot_part.display()
+            );
+            self.boot_mountpoint = Mounts::mount(
+                &self.blk_devices,
+                BOOTFS_DIR,
+                &self.boot_part,
+                &self.boot_fstype,
+                self.mount_timeout,
+            )?;
+            self.boot_mounted = true;
         }
+        Ok(())
+    }
 
-        self.balena_boot_mp = match Mounts::mount(BOOT_MNT_DIR, &part_label, BALENA_BOOT_FSTYPE) {
+    pub fn mount_balena(&mut self, mount_all: bool) -> Result<bool, MigError> {
+        let mut parts_found = true;
+        let part_label = self.get_balena_part(BALENA_BOOT_PART, 1)?;
+
+        self.balena_boot_mp = match Mounts::mount(
+            &self.blk_devices,
+            BOOT_MNT_DIR,
+            &part_label,
+            BALENA_BOOT_FSTYPE,
+            self.mount_timeout,
+        ) {
             Ok(mountpoint) => Some(mountpoint),
             Err(why) => {
                 error!(
@@ -493,25 +628,29 @@ impl<'a> Mounts {
             }
         };
 
-        let mut part_label = path_append(DISK_BY_LABEL_PATH, BALENA_ROOTA_PART);
-        if !file_exists(&part_label) {
-            part_label = drive_to_partition(&self.flash_device, 2)?;
-        }
+        let part_label = self.get_balena_part(BALENA_ROOTA_PART, 2)?;
 
         if mount_all {
-            self.balena_root_a_mp =
-                match Mounts::mount(ROOTA_MNT_DIR, &part_label, BALENA_ROOTA_FSTYPE) {
-                    Ok(mountpoint) => Some(mountpoint),
-                    Err(why) => {
-                        error!(
-                            "Failed to mount balena device: '{}', error: {:?}",
-                            part_label.display(),
-                            why
-                        );
-                        return Err(MigError::displayed());
-                    }
-                };
-        } else if !file_exists(&part_label) {
+            self.balena_root_a_mp = match Mounts::mount(
+                &self.blk_devices,
+                ROOTA_MNT_DIR,
+                &part_label,
+                BALENA_ROOTA_FSTYPE,
+                self.mount_timeout,
+            ) {
+                Ok(mountpoint) => Some(mountpoint),
+                Err(why) => {
+                    error!(
+                        "Failed to mount balena device: '{}', error: {:?}",
+                        part_label.display(),
+                        why
+                    );
+                    return Err(MigError::displayed());
+                }
+            };
+        } else if Mounts::wait_for_device(&self.blk_devices, &part_label, self.mount_timeout)
+            .is_none()
+        {
             warn!(
                 "Unable to find labeled partition: '{}'",
                 part_label.display()
@@ -519,25 +658,29 @@ impl<'a> Mounts {
             parts_found = false;
         }
 
-        let mut part_label = path_append(DISK_BY_LABEL_PATH, BALENA_ROOTB_PART);
-        if !file_exists(&part_label) {
-            part_label = drive_to_partition(&self.flash_device, 3)?;
-        }
+        let part_label = self.get_balena_part(BALENA_ROOTB_PART, 3)?;
 
         if mount_all {
-            self.balena_root_b_mp =
-                match Mounts::mount(ROOTB_MNT_DIR, &part_label, BALENA_ROOTB_FSTYPE) {
-                    Ok(mountpoint) => Some(mountpoint),
-                    Err(why) => {
-                        error!(
-                            "Failed to mount balena device: '{}', error: {:?}",
-                            part_label.display(),
-                            why
-                        );
-                        return Err(MigError::displayed());
-                    }
-                };
-        } else if !file_exists(&part_label) {
+            self.balena_root_b_mp = match Mounts::mount(
+                &self.blk_devices,
+                ROOTB_MNT_DIR,
+                &part_label,
+                BALENA_ROOTB_FSTYPE,
+                self.mount_timeout,
+            ) {
+                Ok(mountpoint) => Some(mountpoint),
+                Err(why) => {
+                    error!(
+                        "Failed to mount balena device: '{}', error: {:?}",
+                        part_label.display(),
+                        why
+                    );
+                    return Err(MigError::displayed());
+                }
+            };
+        } else if Mounts::wait_for_device(&self.blk_devices, &part_label, self.mount_timeout)
+            .is_none()
+        {
             warn!(
                 "Unable to find labeled partition: '{}'",
                 part_label.display()
@@ -545,25 +688,29 @@ impl<'a> Mounts {
             parts_found = false;
         }
 
-        let mut part_label = path_append(DISK_BY_LABEL_PATH, BALENA_STATE_PART);
-        if !file_exists(&part_label) {
-            part_label = drive_to_partition(&self.flash_device, 5)?;
-        }
+        let part_label = self.get_balena_part(BALENA_STATE_PART, 5)?;
 
         if mount_all {
-            self.balena_state_mp =
-                match Mounts::mount(STATE_MNT_DIR, &part_label, BALENA_STATE_FSTYPE) {
-                    Ok(mountpoint) => Some(mountpoint),
-                    Err(why) => {
-                        error!(
-                            "Failed to mount balena device: '{}', error: {:?}",
-                            part_label.display(),
-                            why
-                        );
-                        return Err(MigError::displayed());
-                    }
-                };
-        } else if !file_exists(&part_label) {
+            self.balena_state_mp = match Mounts::mount(
+                &self.blk_devices,
+                STATE_MNT_DIR,
+                &part_label,
+                BALENA_STATE_FSTYPE,
+                self.mount_timeout,
+            ) {
+                Ok(mountpoint) => Some(mountpoint),
+                Err(why) => {
+                    error!(
+                        "Failed to mount balena device: '{}', error: {:?}",
+                        part_label.display(),
+                        why
+                    );
+                    return Err(MigError::displayed());
+                }
+            };
+        } else if Mounts::wait_for_device(&self.blk_devices, &part_label, self.mount_timeout)
+            .is_none()
+        {
             warn!(
                 "Unable to find labeled partition: '{}'",
                 part_label.display()
@@ -571,12 +718,15 @@ impl<'a> Mounts {
             parts_found = false;
         }
 
-        let mut part_label = path_append(DISK_BY_LABEL_PATH, BALENA_DATA_PART);
-        if !file_exists(&part_label) {
-            part_label = drive_to_partition(&self.flash_device, 6)?;
-        }
+        let part_label = self.get_balena_part(BALENA_DATA_PART, 6)?;
 
-        self.balena_data_mp = match Mounts::mount(DATA_MNT_DIR, &part_label, BALENA_DATA_FSTYPE) {
+        self.balena_data_mp = match Mounts::mount(
+            &self.blk_devices,
+            DATA_MNT_DIR,
+            &part_label,
+            BALENA_DATA_FSTYPE,
+            self.mount_timeout,
+        ) {
             Ok(mountpoint) => Some(mountpoint),
             Err(why) => {
                 error!(
@@ -597,6 +747,16 @@ impl<'a> Mounts {
         Ok(parts_found)
     }
 
+    // partition labels are not used on loop devices, they might refer to the host's drives
+    fn get_balena_part(&self, label: &str, part_num: usize) -> Result<PathBuf, MigError> {
+        let part_label = path_append(DISK_BY_LABEL_PATH, label);
+        if !self.flash_loop && file_exists(&part_label) {
+            Ok(part_label)
+        } else {
+            drive_to_partition(&self.flash_device, part_num)
+        }
+    }
+
     pub fn unmount_balena(&mut self) -> bool {
         let mut success = true;
 
@@ -676,13 +836,72 @@ impl<'a> Mounts {
         success
     }
 
-    // this could be the function used to mount other drives too (boot, work)
+    fn settle_udev() {
+        debug!("calling {} {:?}", UDEVADM_CMD, UDEVADM_PARAMS);
+        match call(UDEVADM_CMD, UDEVADM_PARAMS, true) {
+            Ok(cmd_res) => {
+                if !cmd_res.status.success() {
+                    warn!(
+                        "{} {:?} failed with '{}'",
+                        UDEVADM_CMD, UDEVADM_PARAMS, cmd_res.stderr
+                    );
+                }
+            }
+            Err(why) => {
+                warn!("{} {:?} failed with {:?}", UDEVADM_CMD, UDEVADM_PARAMS, why);
+            }
+        }
+    }
+
+    // resolve a device, retrying until it shows up or the timeout expires
+    fn wait_for_device(
+        blk_devices: &BlockDevices,
+        device: &Path,
+        timeout: Duration,
+    ) -> Option<PathBuf> {
+        let start = Instant::now();
+        let mut attempt = 1;
+        loop {
+            if let Some(resolved) = blk_devices.resolve(device) {
+                debug!(
+                    "Found device '{}' as '{}' on attempt {}",
+                    device.display(),
+                    resolved.display(),
+                    attempt
+                );
+                return Some(resolved);
+            }
+
+            if start.elapsed() >= timeout {
+                warn!(
+                    "Device '{}' not found within {} seconds",
+                    device.display(),
+                    timeout.as_secs()
+                );
+                return None;
+            }
+
+            debug!(
+                "Device '{}' not found in attempt {}, will retry in {} seconds",
+                device.display(),
+                attempt,
+                MOUNT_RETRY_DELAY
+            );
+            thread::sleep(Duration::from_secs(MOUNT_RETRY_DELAY));
+            Mounts::settle_udev();
+            attempt += 1;
+        }
+    }
+
+    // wait for a device and mount it, failed mounts are retried until the timeout expires
     fn mount<P1: AsRef<Path>, P2: AsRef<Path>>(
+        blk_devices: &BlockDevices,
         dir: P1,
         device: P2,
         fstype: &str,
+        timeout: Duration,
     ) -> Result<PathBuf, MigError> {
-        // TODO: retry with delay
+        let start = Instant::now();
         let device = device.as_ref();
 
         let mountpoint = path_append(MOUNT_DIR, dir.as_ref());
@@ -700,71 +919,75 @@ impl<'a> Mounts {
             ))?;
         }
 
-        for attempt in 1..3 {
-            if file_exists(&device) {
-                let device = to_std_device_path(device)?;
-
-                debug!(
-                    "Found device '{}' on attempt {} mounting on '{}' with fstype: {}",
-                    device.display(),
-                    attempt,
-                    mountpoint.display(),
-                    fstype
-                );
+        let device = if let Some(device) = Mounts::wait_for_device(blk_devices, device, timeout) {
+            device
+        } else {
+            return Err(MigError::from_remark(
+                MigErrorKind::NotFound,
+                &format!("Device '{}' was not found", device.display()),
+            ));
+        };
 
-                if fstype == "vfat" {
-                    debug!("checking fat file system on '{}'", device.display());
-                    if let Ok(path) = whereis(FAT_CHK_CMD) {
-                        match call(&path, &["-a", &device.to_string_lossy()], true) {
-                            Ok(cmd_res) => {
-                                if !cmd_res.status.success() {
-                                    warn!(
-                                        "Failed to check file system '{}': {} ",
-                                        device.display(),
-                                        cmd_res.stderr
-                                    );
-                                }
-                            }
-                            Err(why) => {
-                                warn!(
-                                    "Failed to check file system '{}': {:?} ",
-                                    device.display(),
-                                    why
-                                );
-                            }
+        if fstype == "vfat" {
+            debug!("checking fat file system on '{}'", device.display());
+            if let Ok(path) = whereis(FAT_CHK_CMD) {
+                match call(&path, &["-a", &device.to_string_lossy()], true) {
+                    Ok(cmd_res) => {
+                        if !cmd_res.status.success() {
+                            warn!(
+                                "Failed to check file system '{}': {} ",
+                                device.display(),
+                                cmd_res.stderr
+                            );
                         }
                     }
+                    Err(why) => {
+                        warn!(
+                            "Failed to check file system '{}': {:?} ",
+                            device.display(),
+                            why
+                        );
+                    }
                 }
-
-                mount(
-                    Some(&device),
-                    &mountpoint,
-                    Some(fstype.as_bytes()),
-                    MsFlags::empty(),
-                    NIX_NONE,
-                )
-                .context(MigErrCtx::from_remark(
-                    MigErrorKind::Upstream,
-                    &format!(
-                        "Failed to mount device '{}' to '{}' with fstype: {:?}",
-                        &device.display(),
-                        &mountpoint.display(),
-                        fstype
-                    ),
-                ))?;
-
-                return Ok(mountpoint);
-            } else {
-                debug!(
-                    "Device '{}'  not found in attempt {}, will retry in 3 seconds",
-                    device.display(),
-                    attempt
-                );
-                thread::sleep(Duration::from_secs(3))
             }
         }
 
-        error!("failed to find log device '{}'", device.display());
-        Err(MigError::displayed())
+        let mut attempt = 1;
+        loop {
+            match mount(
+                Some(&device),
+                &mountpoint,
+                Some(fstype.as_bytes()),
+                MsFlags::empty(),
+                NIX_NONE,
+            ) {
+                Ok(_) => return Ok(mountpoint),
+                Err(why) => {
+                    if start.elapsed() >= timeout {
+                        return Err(MigError::from_remark(
+                            MigErrorKind::Upstream,
+                            &format!(
+                                "Failed to mount device '{}' to '{}' with fstype: {:?} in {} attempt(s), error: {:?}",
+                                device.display(),
+                                mountpoint.display(),
+                                fstype,
+                                attempt,
+                                why
+                            ),
+                        ));
+                    }
+                    debug!(
+                        "Failed to mount '{}' in attempt {}, will retry in {} seconds, error: {:?}",
+                        device.display(),
+                        attempt,
+                        MOUNT_RETRY_DELAY,
+                        why
+                    );
+                }
+            }
+            thread::sleep(Duration::from_secs(MOUNT_RETRY_DELAY));
+            Mounts::settle_udev();
+            attempt += 1;
+        }
     }
 }
@@ -0,0 +1,531 @@
+use failure::ResultExt;
+use log::{info, warn};
+use mod_logger::Logger;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::common::{
+    config::migrate_config::{ProgressCfg, ProgressSinkCfg},
+    file_digest::{DigestHasher, HashInfo},
+    format_size_with_unit, path_append,
+    staging::StagedFile,
+    MigErrCtx, MigError, MigErrorKind,
+};
+
+// *************************************************************************************************
+// * Progress events for the phases of stage 2.
+// * Events are sent to the configured sinks, the log and console receive readable text, files and
+// * serial lines receive one json object per line.
+// *************************************************************************************************
+
+const DEFAULT_INTERVAL_SECS: u64 = 10;
+const CONSOLE_DEVICE: &str = "/dev/console";
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub(crate) enum Phase {
+    #[serde(rename = "copy")]
+    Copy,
+    #[serde(rename = "flash")]
+    Flash,
+    #[serde(rename = "verify")]
+    Verify,
+    #[serde(rename = "partition")]
+    Partition,
+    #[serde(rename = "format")]
+    Format,
+    #[serde(rename = "extract")]
+    Extract,
+    #[serde(rename = "partprobe")]
+    Partprobe,
+    #[serde(rename = "mount")]
+    Mount,
+}
+
+impl Phase {
+    pub fn get_descr(&self) -> &'static str {
+        match self {
+            Phase::Copy => "copy",
+            Phase::Flash => "flash",
+            Phase::Verify => "verify",
+            Phase::Partition => "partition",
+            Phase::Format => "format",
+            Phase::Extract => "extract",
+            Phase::Partprobe => "partprobe",
+            Phase::Mount => "mount",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ProgressEvent {
+    pub phase: Phase,
+    pub bytes_done: u64,
+    pub bytes_total: Option<u64>,
+    pub bytes_per_sec: u64,
+    pub eta_secs: Option<u64>,
+    pub elapsed_secs: u64,
+    pub finished: bool,
+}
+
+impl ProgressEvent {
+    fn to_text(&self) -> String {
+        if self.finished {
+            if self.bytes_done > 0 {
+                format!(
+                    "{}: finished, {} in {} seconds @ {}/sec",
+                    self.phase.get_descr(),
+                    format_size_with_unit(self.bytes_done),
+                    self.elapsed_secs,
+                    format_size_with_unit(self.bytes_per_sec)
+                )
+            } else {
+                format!(
+                    "{}: finished in {} seconds",
+                    self.phase.get_descr(),
+                    self.elapsed_secs
+                )
+            }
+        } else if let Some(total) = self.bytes_total {
+            let percent = if total > 0 {
+                self.bytes_done * 100 / total
+            } else {
+                100
+            };
+            format!(
+                "{}: {} of {} ({}%) @ {}/sec, {} seconds elapsed, ETA {} seconds",
+                self.phase.get_descr(),
+                format_size_with_unit(self.bytes_done),
+                format_size_with_unit(total),
+                percent,
+                format_size_with_unit(self.bytes_per_sec),
+                self.elapsed_secs,
+                self.eta_secs.unwrap_or(0)
+            )
+        } else if self.bytes_done > 0 {
+            format!(
+                "{}: {} @ {}/sec, {} seconds elapsed",
+                self.phase.get_descr(),
+                format_size_with_unit(self.bytes_done),
+                format_size_with_unit(self.bytes_per_sec),
+                self.elapsed_secs
+            )
+        } else {
+            format!("{}: started", self.phase.get_descr())
+        }
+    }
+}
+
+trait EventSink {
+    fn emit(&mut self, event: &ProgressEvent);
+}
+
+struct LogSink {}
+
+impl EventSink for LogSink {
+    fn emit(&mut self, event: &ProgressEvent) {
+        info!("{}", event.to_text());
+        Logger::flush();
+    }
+}
+
+// writes text or json lines to a file or device
+struct LineSink {
+    file: File,
+    path: PathBuf,
+    json: bool,
+    failed: bool,
+}
+
+impl LineSink {
+    fn new(path: &Path, json: bool, append: bool) -> Result<LineSink, MigError> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(append)
+            .append(append)
+            .open(path)
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed to open progress sink '{}'", path.display()),
+            ))?;
+        Ok(LineSink {
+            file,
+            path: path.to_path_buf(),
+            json,
+            failed: false,
+        })
+    }
+}
+
+impl EventSink for LineSink {
+    fn emit(&mut self, event: &ProgressEvent) {
+        if self.failed {
+            return;
+        }
+
+        let line = if self.json {
+            match serde_json::to_string(event) {
+                Ok(line) => line,
+                Err(why) => {
+                    warn!("Failed to serialize progress event, error: {:?}", why);
+                    return;
+                }
+            }
+        } else {
+            event.to_text()
+        };
+
+        if let Err(why) = writeln!(self.file, "{}", line) {
+            // do not flood the log with errors from a broken sink
+            warn!(
+                "Failed to write progress to '{}', disabling it, error: {:?}",
+                self.path.display(),
+                why
+            );
+            self.failed = true;
+        }
+    }
+}
+
+pub(crate) struct Progress {
+    sinks: Vec<Box<dyn EventSink>>,
+    interval: Duration,
+    phase: Phase,
+    bytes_total: Option<u64>,
+    bytes_done: u64,
+    start_time: Instant,
+    last_report: Instant,
+}
+
+impl Progress {
+    // file sinks are created on the log device, they are skipped if there is none
+    pub fn new(config: Option<&ProgressCfg>, log_path: Option<&Path>) -> Progress {
+        let mut sinks: Vec<Box<dyn EventSink>> = Vec::new();
+        let mut interval = Duration::from_secs(DEFAULT_INTERVAL_SECS);
+
+        if let Some(config) = config {
+            if let Some(secs) = config.interval {
+                interval = Duration::from_secs(secs);
+            }
+
+            for sink_cfg in &config.sinks {
+                let sink: Result<Box<dyn EventSink>, MigError> = match sink_cfg {
+                    ProgressSinkCfg::Log => Ok(Box::new(LogSink {})),
+                    ProgressSinkCfg::Console => {
+                        LineSink::new(Path::new(CONSOLE_DEVICE), false, false)
+                            .map(|sink| Box::new(sink) as Box<dyn EventSink>)
+                    }
+                    ProgressSinkCfg::File(file) => {
+                        if let Some(log_path) = log_path {
+                            LineSink::new(&path_append(log_path, file), true, true)
+                                .map(|sink| Box::new(sink) as Box<dyn EventSink>)
+                        } else {
+                            warn!(
+                                "No log device is mounted, not writing progress to '{}'",
+                                file.display()
+                            );
+                            continue;
+                        }
+                    }
+                    ProgressSinkCfg::Serial(device) => LineSink::new(device, true, false)
+                        .map(|sink| Box::new(sink) as Box<dyn EventSink>),
+                };
+
+                match sink {
+                    Ok(sink) => sinks.push(sink),
+                    Err(why) => warn!("Failed to set up progress sink, error: {:?}", why),
+                }
+            }
+        } else {
+            sinks.push(Box::new(LogSink {}));
+        }
+
+        let now = Instant::now();
+        Progress {
+            sinks,
+            interval,
+            phase: Phase::Copy,
+            bytes_total: None,
+            bytes_done: 0,
+            start_time: now,
+            last_report: now,
+        }
+    }
+
+    pub fn start_phase(&mut self, phase: Phase, bytes_total: Option<u64>) {
+        self.phase = phase;
+        self.bytes_total = bytes_total;
+        self.bytes_done = 0;
+        self.start_time = Instant::now();
+        self.last_report = self.start_time;
+        self.emit(false);
+    }
+
+    // report bytes processed in the current phase, events are sent at most once per interval
+    pub fn update(&mut self, bytes_done: u64) {
+        self.bytes_done = bytes_done;
+        if self.last_report.elapsed() >= self.interval {
+            self.last_report = Instant::now();
+            self.emit(false);
+        }
+    }
+
+    pub fn finish_phase(&mut self) {
+        self.emit(true);
+    }
+
+    fn create_event(&self, finished: bool) -> ProgressEvent {
+        let elapsed = self.start_time.elapsed();
+        let millis = elapsed.as_millis() as u64;
+        let bytes_per_sec = if millis > 0 {
+            self.bytes_done * 1000 / millis
+        } else {
+            0
+        };
+
+        let eta_secs = if let Some(total) = self.bytes_total {
+            if bytes_per_sec > 0 {
+                Some(total.saturating_sub(self.bytes_done) / bytes_per_sec)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        ProgressEvent {
+            phase: self.phase,
+            bytes_done: self.bytes_done,
+            bytes_total: self.bytes_total,
+            bytes_per_sec,
+            eta_secs,
+            elapsed_secs: elapsed.as_secs(),
+            finished,
+        }
+    }
+
+    fn emit(&mut self, finished: bool) {
+        let event = self.create_event(finished);
+        for sink in self.sinks.iter_mut() {
+            sink.emit(&event);
+        }
+    }
+}
+
+// copy a file reporting progress in phase copy
+pub(crate) fn copy_file(src: &Path, tgt: &Path, progress: &mut Progress) -> Result<u64, MigError> {
+    copy_data(src, tgt, progress, None)
+}
+
+// copy a file computing a digest of the type given on the way, saves reading the copy again
+pub(crate) fn copy_and_hash(
+    src: &Path,
+    tgt: &Path,
+    progress: &mut Progress,
+    digest: &HashInfo,
+) -> Result<HashInfo, MigError> {
+    let mut hasher = DigestHasher::new(digest);
+    copy_data(src, tgt, progress, Some(&mut hasher))?;
+    Ok(hasher.result())
+}
+
+// copy a staged file from the flash device
+pub(crate) fn copy_staged(
+    staged: &StagedFile,
+    device: &Path,
+    tgt: &Path,
+    progress: &mut Progress,
+) -> Result<u64, MigError> {
+    let mut reader = staged.open_reader(device)?;
+    let src = format!("{}:{}", device.display(), staged.rel_path.display());
+    copy_stream(&mut reader, &src, staged.size, tgt, progress, None)
+}
+
+// digest of a staged file read from the flash device, reported as phase verify
+pub(crate) fn hash_staged(
+    staged: &StagedFile,
+    digest: &HashInfo,
+    device: &Path,
+    progress: &mut Progress,
+) -> Result<HashInfo, MigError> {
+    let mut reader = staged.open_reader(device)?;
+    let mut hasher = DigestHasher::new(digest);
+
+    progress.start_phase(Phase::Verify, Some(staged.size));
+
+    let mut buffer: Vec<u8> = vec![0; COPY_BUFFER_SIZE];
+    let mut hashed: u64 = 0;
+    loop {
+        let bytes_read = reader.read(&mut buffer).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!(
+                "Failed to read staged file '{}' from '{}'",
+                staged.rel_path.display(),
+                device.display()
+            ),
+        ))?;
+
+        if bytes_read == 0 {
+            break;
+        }
+
+        hasher.input(&buffer[0..bytes_read]);
+        hashed += bytes_read as u64;
+        progress.update(hashed);
+    }
+
+    progress.finish_phase();
+    Ok(hasher.result())
+}
+
+fn copy_data(
+    src: &Path,
+    tgt: &Path,
+    progress: &mut Progress,
+    hasher: Option<&mut DigestHasher>,
+) -> Result<u64, MigError> {
+    let mut src_file = File::open(src).context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to open '{}' for reading", src.display()),
+    ))?;
+
+    let size = src_file
+        .metadata()
+        .context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to retrieve metadata for '{}'", src.display()),
+        ))?
+        .len();
+
+    copy_stream(
+        &mut src_file,
+        &src.display().to_string(),
+        size,
+        tgt,
+        progress,
+        hasher,
+    )
+}
+
+fn copy_stream(
+    reader: &mut dyn Read,
+    src: &str,
+    size: u64,
+    tgt: &Path,
+    progress: &mut Progress,
+    mut hasher: Option<&mut DigestHasher>,
+) -> Result<u64, MigError> {
+    let mut tgt_file = File::create(tgt).context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to open '{}' for writing", tgt.display()),
+    ))?;
+
+    progress.start_phase(Phase::Copy, Some(size));
+
+    let mut buffer: Vec<u8> = vec![0; COPY_BUFFER_SIZE];
+    let mut copied: u64 = 0;
+    loop {
+        let bytes_read = reader.read(&mut buffer).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to read from '{}'", src),
+        ))?;
+
+        if bytes_read == 0 {
+            break;
+        }
+
+        tgt_file
+            .write_all(&buffer[0..bytes_read])
+            .context(MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed to write to '{}'", tgt.display()),
+            ))?;
+
+        if let Some(ref mut hasher) = hasher {
+            hasher.input(&buffer[0..bytes_read]);
+        }
+
+        copied += bytes_read as u64;
+        progress.update(copied);
+    }
+
+    progress.finish_phase();
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read_to_string, remove_file};
+
+    #[test]
+    fn file_sink_events() {
+        let log_path = std::env::temp_dir();
+        let file_name = "balena-migrate-progress-test.log";
+        let sink_path = path_append(&log_path, file_name);
+        let _res = remove_file(&sink_path);
+
+        let config = ProgressCfg {
+            interval: Some(0),
+            sinks: vec![ProgressSinkCfg::File(PathBuf::from(file_name))],
+        };
+
+        let mut progress = Progress::new(Some(&config), Some(&log_path));
+        progress.start_phase(Phase::Flash, Some(1000));
+        progress.update(500);
+        progress.finish_phase();
+
+        let events: Vec<serde_json::Value> = read_to_string(&sink_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        remove_file(&sink_path).unwrap();
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["phase"], "flash");
+        assert_eq!(events[1]["bytes_done"], 500);
+        assert_eq!(events[1]["bytes_total"], 1000);
+        assert_eq!(events[2]["finished"], true);
+    }
+
+    #[test]
+    fn event_text() {
+        let event = ProgressEvent {
+            phase: Phase::Copy,
+            bytes_done: 512,
+            bytes_total: Some(2048),
+            bytes_per_sec: 256,
+            eta_secs: Some(6),
+            elapsed_secs: 2,
+            finished: false,
+        };
+        assert!(event.to_text().starts_with("copy: "));
+        assert!(event.to_text().contains("(25%)"));
+        assert!(event.to_text().ends_with("ETA 6 seconds"));
+    }
+
+    #[test]
+    fn copy_with_digest() {
+        let tgt = path_append(std::env::temp_dir(), "balena-migrate-copy-test.img.gz");
+        let mut progress = Progress::new(None, None);
+        let digest = copy_and_hash(
+            Path::new("./test_data/part.img.gz"),
+            &tgt,
+            &mut progress,
+            &HashInfo::Sha256(String::new()),
+        )
+        .unwrap();
+        remove_file(&tgt).unwrap();
+
+        assert_eq!(
+            digest,
+            HashInfo::Sha256(String::from(
+                "9be2a59a8286c8acacebd69a6ac1ff3caa02f3950f91ba2df1514f9f69f9495e"
+            ))
+        );
+    }
+}
@@ -0,0 +1,597 @@
+use failure::ResultExt;
+use log::{debug, error, info, warn};
+use nix::fcntl::{fallocate, FallocateFlags};
+use nix::unistd::sync;
+use std::fs::{read_to_string, remove_file, rename, File, OpenOptions};
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    common::{
+        bmap::BlockMap,
+        disk_util::{Compression, Disk, PartitionIterator},
+        file_digest::get_default_digest,
+        file_info::RelFileInfo,
+        file_size, format_size_with_unit, path_append,
+        path_info::PathInfo,
+        stage2_config::CheckedImageType,
+        staging::{Extent, StagedFile, Staging},
+        MigErrCtx, MigError, MigErrorKind,
+    },
+    defs::{BACKUP_FILE, DEF_BLOCK_SIZE, STAGING_MARGIN},
+};
+
+// *************************************************************************************************
+// * Stage files on the flash device.
+// * The image and backup stay in the work dir, their physical extents are looked up with the
+// * FIEMAP ioctl and handed to stage 2 which reads them from the raw device. Files with extents
+// * within the area balena OS is written to are relocated: a copy is preallocated and kept if all
+// * of its blocks lie beyond that area. Copies allocated within the area are kept until the file
+// * has been relocated, so the file system hands out other blocks for the next attempt.
+// *************************************************************************************************
+
+// file systems that report extents as offsets in the partition
+const STAGING_FS_TYPES: &[&str] = &["ext2", "ext3", "ext4", "xfs", "vfat"];
+
+const FS_IOC_MAGIC: u8 = b'f';
+const FS_IOC_FIEMAP: u8 = 11;
+
+const FIEMAP_MAX_EXTENTS: usize = 256;
+const FIEMAP_FLAG_SYNC: u32 = 0x0001;
+const FIEMAP_EXTENT_LAST: u32 = 0x0001;
+const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x0800;
+// extents that can not be read from the device as they are
+const FIEMAP_EXTENT_UNUSABLE: u32 = 0x0002 // unknown location
+    | 0x0004 // delayed allocation
+    | 0x0008 // encoded
+    | 0x0080 // encrypted
+    | 0x0100 // not aligned
+    | 0x0200 // inline
+    | 0x0400 // tail packed
+    | FIEMAP_EXTENT_UNWRITTEN;
+
+// copies allocated before giving up on relocating a file
+const RELOCATE_ATTEMPTS: usize = 16;
+const RELOCATE_EXT: &str = "relocate";
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+#[repr(C)]
+struct FiemapHeader {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+}
+
+#[repr(C)]
+struct Fiemap {
+    header: FiemapHeader,
+    extents: [FiemapExtent; FIEMAP_MAX_EXTENTS],
+}
+
+ioctl_readwrite_bad!(
+    fs_ioc_fiemap,
+    request_code_readwrite!(FS_IOC_MAGIC, FS_IOC_FIEMAP, size_of::<FiemapHeader>()),
+    Fiemap
+);
+
+pub(crate) fn stage_files(
+    work_path: &PathInfo,
+    image: &mut CheckedImageType,
+    bmap_file: Option<&RelFileInfo>,
+    has_backup: bool,
+) -> Result<Staging, MigError> {
+    let work_dir = &work_path.path;
+    let fs_type = work_path.device_info.fs_type.as_str();
+    if !STAGING_FS_TYPES.contains(&fs_type) {
+        error!(
+            "Files can not be staged on a '{}' file system, supported are {:?}",
+            fs_type, STAGING_FS_TYPES
+        );
+        return Err(MigError::displayed());
+    }
+
+    let (part_start, part_end) = get_partition_range(&work_path.device_info.device)?;
+    stage_files_in(work_dir, part_start, part_end, image, bmap_file, has_backup)
+}
+
+// stage the files in work_dir located on a partition from part_start to part_end in bytes
+fn stage_files_in(
+    work_dir: &Path,
+    part_start: u64,
+    part_end: u64,
+    image: &mut CheckedImageType,
+    bmap_file: Option<&RelFileInfo>,
+    has_backup: bool,
+) -> Result<Staging, MigError> {
+    let os_end = get_os_end(work_dir, image, bmap_file)? + STAGING_MARGIN;
+    info!(
+        "balena OS will use the first {} of the flash device",
+        format_size_with_unit(os_end)
+    );
+
+    if part_end <= os_end {
+        error!(
+            "The work directory '{}' is on a partition ending at offset {} of the flash device, within the first {} overwritten by balena OS. Staged files can not be relocated beyond that, please use a work directory on a partition beyond that or a device with more memory",
+            work_dir.display(),
+            part_end,
+            format_size_with_unit(os_end)
+        );
+        return Err(MigError::displayed());
+    }
+
+    let mut files: Vec<(&Path, u64, Option<_>)> = match image {
+        CheckedImageType::Flasher(ref image_file) => vec![(
+            image_file.rel_path.as_path(),
+            image_file.size,
+            Some(image_file.hash_info.clone()),
+        )],
+        CheckedImageType::FileSystems(ref fs_dump) => [
+            &fs_dump.boot.archive,
+            &fs_dump.root_a.archive,
+            &fs_dump.root_b.archive,
+            &fs_dump.state.archive,
+            &fs_dump.data.archive,
+        ]
+        .iter()
+        .map(|archive| {
+            (
+                archive.rel_path.as_path(),
+                archive.size,
+                Some(archive.hash_info.clone()),
+            )
+        })
+        .collect(),
+    };
+
+    if has_backup {
+        files.push((
+            Path::new(BACKUP_FILE),
+            file_size(path_append(work_dir, BACKUP_FILE))?,
+            None,
+        ));
+    }
+
+    // make sure all blocks have been allocated before looking them up
+    sync();
+
+    let mut staged_files: Vec<StagedFile> = Vec::new();
+    for (rel_path, size, hash_info) in files {
+        let path = path_append(work_dir, rel_path);
+        let hash_info = if let Some(hash_info) = hash_info {
+            hash_info
+        } else {
+            get_default_digest(&path)?
+        };
+
+        // offsets on the partition below limit are overwritten by balena OS
+        let limit = os_end.saturating_sub(part_start);
+        let mut extents = get_file_extents(&path, FIEMAP_EXTENT_UNUSABLE)?;
+        if let Some(extent) = extents.iter().find(|extent| extent.physical < limit) {
+            info!(
+                "The file '{}' is located at offset {} on the flash device which would be overwritten by balena OS, relocating it",
+                path.display(),
+                extent.physical + part_start,
+            );
+            extents = relocate(&path, limit)?;
+        }
+
+        let extents: Vec<Extent> = extents
+            .into_iter()
+            .map(|extent| Extent {
+                physical: extent.physical + part_start,
+                ..extent
+            })
+            .collect();
+
+        debug!(
+            "stage_files: staged '{}' in {} extents",
+            path.display(),
+            extents.len()
+        );
+
+        staged_files.push(StagedFile {
+            rel_path: rel_path.to_path_buf(),
+            size,
+            hash_info,
+            extents,
+        });
+    }
+
+    Ok(Staging {
+        os_end,
+        files: staged_files,
+    })
+}
+
+// replace a file with a copy located beyond limit on its partition, returns the extents of the
+// copy
+fn relocate(path: &Path, limit: u64) -> Result<Vec<Extent>, MigError> {
+    let size = file_size(path)?;
+    let mut copies: Vec<PathBuf> = Vec::new();
+    let mut res = Ok(None);
+    for attempt in 0..RELOCATE_ATTEMPTS {
+        let copy_path = PathBuf::from(format!("{}.{}{}", path.display(), RELOCATE_EXT, attempt));
+        copies.push(copy_path.clone());
+        res = allocate_beyond(&copy_path, size, limit);
+        match res {
+            Ok(Some(_)) => break,
+            Ok(None) => debug!(
+                "relocate: copy {} of '{}' was allocated within the first {} bytes",
+                attempt,
+                path.display(),
+                limit
+            ),
+            Err(_) => break,
+        }
+    }
+
+    let res = match res {
+        Ok(Some(file)) => {
+            let copy_path = &copies[copies.len() - 1];
+            copy_to(path, file, copy_path, limit).and_then(|extents| {
+                rename(copy_path, path).context(MigErrCtx::from_remark(
+                    MigErrorKind::Upstream,
+                    &format!(
+                        "Failed to rename '{}' to '{}'",
+                        copy_path.display(),
+                        path.display()
+                    ),
+                ))?;
+                Ok(extents)
+            })
+        }
+        Ok(None) => {
+            error!(
+                "Failed to relocate '{}' beyond the first {} bytes of its partition in {} attempts, please use a device with more memory",
+                path.display(),
+                limit,
+                RELOCATE_ATTEMPTS
+            );
+            Err(MigError::displayed())
+        }
+        Err(why) => Err(why),
+    };
+
+    if res.is_ok() {
+        // the last copy replaced the file
+        copies.pop();
+    }
+
+    for copy_path in copies {
+        if let Err(why) = remove_file(&copy_path) {
+            warn!(
+                "Failed to remove '{}', error: {:?}",
+                copy_path.display(),
+                why
+            );
+        }
+    }
+
+    res
+}
+
+// preallocate a file of size bytes, returns it if all of its blocks lie beyond limit
+fn allocate_beyond(path: &Path, size: u64, limit: u64) -> Result<Option<File>, MigError> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to open '{}' for writing", path.display()),
+        ))?;
+
+    fallocate(
+        file.as_raw_fd(),
+        FallocateFlags::empty(),
+        0,
+        size as libc::off_t,
+    )
+    .context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to allocate {} bytes for '{}'", size, path.display()),
+    ))?;
+
+    // the blocks are allocated but not written yet
+    let extents = get_file_extents(path, FIEMAP_EXTENT_UNUSABLE & !FIEMAP_EXTENT_UNWRITTEN)?;
+    if extents.iter().all(|extent| extent.physical >= limit) {
+        Ok(Some(file))
+    } else {
+        Ok(None)
+    }
+}
+
+// copy src to its preallocated copy and make sure the blocks stayed beyond limit
+fn copy_to(src: &Path, mut file: File, path: &Path, limit: u64) -> Result<Vec<Extent>, MigError> {
+    let mut src_file = File::open(src).context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to open '{}' for reading", src.display()),
+    ))?;
+    io::copy(&mut src_file, &mut file).context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to copy '{}' to '{}'", src.display(), path.display()),
+    ))?;
+    file.sync_all().context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to sync '{}'", path.display()),
+    ))?;
+
+    let extents = get_file_extents(path, FIEMAP_EXTENT_UNUSABLE)?;
+    if let Some(extent) = extents.iter().find(|extent| extent.physical < limit) {
+        return Err(MigError::from_remark(
+            MigErrorKind::InvState,
+            &format!(
+                "The copy '{}' was moved to offset {} while writing it",
+                path.display(),
+                extent.physical
+            ),
+        ));
+    }
+    Ok(extents)
+}
+
+// end of the area written by balena OS in bytes
+fn get_os_end(
+    work_dir: &Path,
+    image: &mut CheckedImageType,
+    bmap_file: Option<&RelFileInfo>,
+) -> Result<u64, MigError> {
+    match image {
+        CheckedImageType::Flasher(ref image_file) => {
+            let image_path = path_append(work_dir, &image_file.rel_path);
+            if let Compression::Zip = Compression::from_file(&image_path)? {
+                error!(
+                    "The zip archive '{}' can not be read from the flash device, please use a gzip, xz or zstd compressed image",
+                    image_path.display()
+                );
+                return Err(MigError::displayed());
+            }
+
+            let mut disk = Disk::from_image_file(&image_path)?;
+            let mut os_end = PartitionIterator::new(&mut disk)?
+                .map(|part| (part.start_lba + part.num_sectors) * DEF_BLOCK_SIZE as u64)
+                .max()
+                .unwrap_or(0);
+
+            if let Some(bmap_file) = bmap_file {
+                let bmap = BlockMap::from_file(path_append(work_dir, &bmap_file.rel_path))?;
+                os_end = os_end.max(bmap.image_size);
+            }
+
+            if os_end == 0 {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvParam,
+                    &format!("No partitions found in image '{}'", image_path.display()),
+                ));
+            }
+            Ok(os_end)
+        }
+        CheckedImageType::FileSystems(ref mut fs_dump) => {
+            if fs_dump.max_data != Some(false) {
+                warn!("The data partition will not be maximized, it would overwrite staged files");
+                fs_dump.max_data = Some(false);
+            }
+            Ok(fs_dump.get_layout_end())
+        }
+    }
+}
+
+// start and end of a partition in bytes from sysfs
+fn get_partition_range(partition: &Path) -> Result<(u64, u64), MigError> {
+    let part_name = if let Some(part_name) = partition.file_name() {
+        part_name.to_string_lossy()
+    } else {
+        return Err(MigError::from_remark(
+            MigErrorKind::InvParam,
+            &format!("Invalid partition path '{}'", partition.display()),
+        ));
+    };
+
+    let read_sectors = |name: &str| -> Result<u64, MigError> {
+        let path = format!("/sys/class/block/{}/{}", part_name, name);
+        let value = read_to_string(&path).context(MigErrCtx::from_remark(
+            MigErrorKind::Upstream,
+            &format!("Failed to read '{}'", path),
+        ))?;
+
+        // sysfs always reports 512 byte sectors
+        Ok(value.trim().parse::<u64>().context(MigErrCtx::from_remark(
+            MigErrorKind::InvParam,
+            &format!(
+                "Invalid partition {} '{}' in '{}'",
+                name,
+                value.trim(),
+                path
+            ),
+        ))? * 512)
+    };
+
+    let start = read_sectors("start")?;
+    Ok((start, start + read_sectors("size")?))
+}
+
+// physical extents of a file relative to the start of its partition, fails on extents flagged
+// with any of the unusable flags
+fn get_file_extents(path: &Path, unusable: u32) -> Result<Vec<Extent>, MigError> {
+    let file = File::open(path).context(MigErrCtx::from_remark(
+        MigErrorKind::Upstream,
+        &format!("Failed to open '{}' for reading", path.display()),
+    ))?;
+
+    let mut fiemap = Box::new(Fiemap {
+        header: FiemapHeader {
+            fm_start: 0,
+            fm_length: u64::MAX,
+            fm_flags: FIEMAP_FLAG_SYNC,
+            fm_mapped_extents: 0,
+            fm_extent_count: FIEMAP_MAX_EXTENTS as u32,
+            fm_reserved: 0,
+        },
+        extents: [FiemapExtent::default(); FIEMAP_MAX_EXTENTS],
+    });
+
+    let mut extents: Vec<Extent> = Vec::new();
+    loop {
+        unsafe { fs_ioc_fiemap(file.as_raw_fd(), fiemap.as_mut()) }.context(
+            MigErrCtx::from_remark(
+                MigErrorKind::Upstream,
+                &format!("Failed to retrieve the extents of '{}'", path.display()),
+            ),
+        )?;
+
+        let mapped = fiemap.header.fm_mapped_extents as usize;
+        if mapped == 0 {
+            break;
+        }
+
+        for extent in &fiemap.extents[0..mapped] {
+            if extent.fe_flags & unusable != 0 {
+                return Err(MigError::from_remark(
+                    MigErrorKind::InvState,
+                    &format!(
+                        "The file '{}' has an extent that can not be read from the device, flags: 0x{:x}",
+                        path.display(),
+                        extent.fe_flags
+                    ),
+                ));
+            }
+            extents.push(Extent {
+                logical: extent.fe_logical,
+                physical: extent.fe_physical,
+                length: extent.fe_length,
+            });
+        }
+
+        let last = &fiemap.extents[mapped - 1];
+        if last.fe_flags & FIEMAP_EXTENT_LAST != 0 {
+            break;
+        }
+        fiemap.header.fm_start = last.fe_logical + last.fe_length;
+    }
+
+    Ok(extents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::file_digest::HashInfo;
+    use std::fs::{copy, create_dir_all, remove_dir_all, write};
+    use std::path::PathBuf;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = path_append(std::env::temp_dir(), name);
+        let _res = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn file_extents() {
+        let dir = test_dir("balena-migrate-extents-test");
+        let path = path_append(&dir, "data.bin");
+        let data: Vec<u8> = (0..3 * 1024 * 1024).map(|idx| (idx % 251) as u8).collect();
+        write(&path, &data).unwrap();
+        sync();
+
+        let extents = get_file_extents(&path, FIEMAP_EXTENT_UNUSABLE).unwrap();
+        assert!(!extents.is_empty());
+        // the extents cover the file without gaps
+        let mut logical = 0;
+        for extent in &extents {
+            assert_eq!(extent.logical, logical);
+            logical += extent.length;
+        }
+        assert!(logical >= data.len() as u64);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn relocate_file() {
+        let dir = test_dir("balena-migrate-relocate-test");
+        let path = path_append(&dir, "data.bin");
+        let data: Vec<u8> = (0..1024 * 1024).map(|idx| (idx % 251) as u8).collect();
+        write(&path, &data).unwrap();
+        sync();
+
+        // pretend the start of the file is overwritten by balena OS
+        let limit = get_file_extents(&path, FIEMAP_EXTENT_UNUSABLE)
+            .unwrap()
+            .iter()
+            .map(|extent| extent.physical)
+            .min()
+            .unwrap()
+            + 1;
+
+        let extents = relocate(&path, limit).unwrap();
+        assert!(extents.iter().all(|extent| extent.physical >= limit));
+        assert_eq!(
+            get_file_extents(&path, FIEMAP_EXTENT_UNUSABLE).unwrap(),
+            extents
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        // only the relocated file is left
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stage_image_and_backup() {
+        let dir = test_dir("balena-migrate-staging-test");
+        copy("./test_data/gpt.img.gz", path_append(&dir, "image.img.gz")).unwrap();
+        write(path_append(&dir, BACKUP_FILE), vec![1u8; 8192]).unwrap();
+
+        let image_info = RelFileInfo {
+            rel_path: PathBuf::from("image.img.gz"),
+            size: file_size(path_append(&dir, "image.img.gz")).unwrap(),
+            hash_info: HashInfo::Sha256(String::from("not checked")),
+        };
+        let mut image = CheckedImageType::Flasher(image_info.clone());
+
+        // a partition far beyond the image
+        let part_start: u64 = 1 << 40;
+        let staging =
+            stage_files_in(&dir, part_start, part_start << 1, &mut image, None, true).unwrap();
+        assert!(staging.os_end > STAGING_MARGIN);
+        assert_eq!(staging.files.len(), 2);
+
+        let image_file = staging.get_file("image.img.gz").unwrap();
+        assert_eq!(image_file.size, image_info.size);
+        assert_eq!(image_file.hash_info, image_info.hash_info);
+        let backup_file = staging.get_file(BACKUP_FILE).unwrap();
+        assert_eq!(backup_file.size, 8192);
+        assert_eq!(
+            backup_file.hash_info,
+            get_default_digest(path_append(&dir, BACKUP_FILE)).unwrap()
+        );
+        for file in &staging.files {
+            assert!(file
+                .extents
+                .iter()
+                .all(|extent| extent.physical >= part_start));
+        }
+
+        // a partition within the area written by balena OS is refused up front
+        assert!(stage_files_in(&dir, 0, staging.os_end, &mut image, None, true).is_err());
+
+        remove_dir_all(&dir).unwrap();
+    }
+}